@@ -0,0 +1,263 @@
+// Session annotations: notes taken during a capture, one
+// "RFC3339-timestamp<TAB>text" per line, placed on the trace clock through
+// the realtime clock sync marker written by atrace at start.
+//
+// The clock sync is stamped with whichever trace clock the capture used, so
+// the translation holds for the global, local or boot clock alike.
+
+use crate::capture::{Capture, InstantEvent};
+
+pub struct Annotation {
+    pub line: usize,
+    // Wall clock seconds since the epoch.
+    pub realtime: f64,
+    pub text: String,
+}
+
+fn number(value: &str, digits: usize) -> Option<i64> {
+    if value.len() != digits || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Parse an RFC3339 timestamp like 2021-06-01T14:03:12.250+08:00 into wall
+// clock seconds since the epoch.
+pub fn parse_rfc3339(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if value.len() < 20 || !value.is_char_boundary(19) {
+        return None;
+    }
+    let (datetime, rest) = value.split_at(19);
+    let bytes = datetime.as_bytes();
+    if bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !(bytes[10] == b'T' || bytes[10] == b't' || bytes[10] == b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let year = number(&datetime[0..4], 4)?;
+    let month = number(&datetime[5..7], 2)?;
+    let day = number(&datetime[8..10], 2)?;
+    let hour = number(&datetime[11..13], 2)?;
+    let minute = number(&datetime[14..16], 2)?;
+    let second = number(&datetime[17..19], 2)?;
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let (fraction, zone) = match rest.strip_prefix('.') {
+        Some(rest) => {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return None;
+            }
+            (
+                format!("0.{}", &rest[..end]).parse::<f64>().ok()?,
+                &rest[end..],
+            )
+        }
+        None => (0.0, rest),
+    };
+    let offset = match zone {
+        "Z" | "z" => 0,
+        zone if zone.len() == 6 && zone.as_bytes()[3] == b':' => {
+            let sign = match zone.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            sign * (number(&zone[1..3], 2)? * 3600 + number(&zone[4..6], 2)? * 60)
+        }
+        _ => return None,
+    };
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    Some(seconds as f64 + fraction)
+}
+
+// Parse an annotations file, returning the annotations and a warning per
+// line which could not be read. Blank lines and # comments are skipped.
+pub fn parse_annotations(text: &str) -> (Vec<Annotation>, Vec<String>) {
+    let mut annotations = Vec::new();
+    let mut warnings = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '\t');
+        let timestamp = parts.next().unwrap_or("");
+        let text = parts.next().map(str::trim).unwrap_or("");
+        match parse_rfc3339(timestamp) {
+            Some(realtime) if !text.is_empty() => annotations.push(Annotation {
+                line: index + 1,
+                realtime,
+                text: text.to_string(),
+            }),
+            _ => warnings.push(format!(
+                "annotation line {}: expected RFC3339-timestamp<TAB>text",
+                index + 1
+            )),
+        }
+    }
+    (annotations, warnings)
+}
+
+// Wall clock seconds to the trace clock of the capture, None without a
+// realtime clock sync.
+fn to_trace_clock(capture: &Capture, realtime: f64) -> Option<f64> {
+    capture
+        .realtime_sync
+        .map(|(trace_ts, sync_realtime)| trace_ts + (realtime - sync_realtime))
+}
+
+// Add the annotations falling inside the capture, returning a warning for
+// each one which could not be placed.
+pub fn inject(capture: &mut Capture, annotations: &[Annotation]) -> Vec<String> {
+    if capture.realtime_sync.is_none() {
+        return vec![
+            "capture has no realtime clock sync marker, annotations can't be placed".to_string(),
+        ];
+    }
+    let mut warnings = Vec::new();
+    for annotation in annotations.iter() {
+        let timestamp = to_trace_clock(capture, annotation.realtime).unwrap_or(0.0);
+        if timestamp < capture.first_timestamp || timestamp > capture.last_timestamp {
+            warnings.push(format!(
+                "annotation line {}: outside of the capture, skipped",
+                annotation.line
+            ));
+            continue;
+        }
+        capture.annotations.push(InstantEvent {
+            pid: 0,
+            tid: 0,
+            name: annotation.text.clone(),
+            timestamp,
+            attrs: Vec::new(),
+        });
+    }
+    capture.annotations.sort_by(|a, b| {
+        a.timestamp
+            .partial_cmp(&b.timestamp)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(realtime_sync: bool) -> Capture {
+        let mut data = String::new();
+        if realtime_sync {
+            data.push_str("  app-1234  ( 1234) [000] ...1  10.000000: tracing_mark_write: trace_event_clock_sync: realtime_ts=1700000000000\n");
+        }
+        data.push_str(
+            "  app-1234  ( 1234) [000] ...1  10.000000: tracing_mark_write: B|1234|draw\n",
+        );
+        data.push_str("  app-1234  ( 1234) [000] ...1  11.000000: tracing_mark_write: E|1234\n");
+        Capture::from_bytes(data.as_bytes())
+    }
+
+    #[test]
+    fn rfc3339_timestamps_are_read() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0.0));
+        assert_eq!(
+            parse_rfc3339("2021-06-01T14:03:12.250+08:00"),
+            Some(1_622_527_392.25)
+        );
+        assert_eq!(
+            parse_rfc3339("2023-11-14 22:13:20-01:30"),
+            Some(1_700_000_000.0 + 5400.0)
+        );
+        assert_eq!(parse_rfc3339("2000-02-29T00:00:00z"), Some(951_782_400.0));
+    }
+
+    #[test]
+    fn bad_rfc3339_timestamps_are_refused() {
+        for value in &[
+            "",
+            "2021-06-01",
+            "2021-06-01T14:03:12",
+            "2021-13-01T14:03:12Z",
+            "2021-06-00T14:03:12Z",
+            "2021-06-01T24:03:12Z",
+            "2021-06-01T14:60:12Z",
+            "2021-06-01T14:03:12.Z",
+            "2021-06-01T14:03:12+0800",
+            "2021-06-01T14:03:12*08:00",
+            "2021/06/01T14:03:12Z",
+            "2021-06-01T14:03:1\u{e9}Z",
+        ] {
+            assert_eq!(parse_rfc3339(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn annotation_lines_are_parsed_with_warnings() {
+        let text = "# notes\n\n2023-11-14T22:13:20.5Z\topened the menu \nnot a note\n2023-11-14T22:13:21Z\t\n";
+        let (annotations, warnings) = parse_annotations(text);
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].line, 3);
+        assert_eq!(annotations[0].realtime, 1_700_000_000.5);
+        assert_eq!(annotations[0].text, "opened the menu");
+        assert_eq!(
+            warnings,
+            vec![
+                "annotation line 4: expected RFC3339-timestamp<TAB>text",
+                "annotation line 5: expected RFC3339-timestamp<TAB>text",
+            ]
+        );
+    }
+
+    #[test]
+    fn annotations_are_placed_on_the_trace_clock() {
+        let mut capture = capture(true);
+        let (annotations, _) = parse_annotations(
+            "2023-11-14T22:13:20.75Z\tsecond\n2023-11-14T22:13:20.25Z\tfirst\n2023-11-14T22:13:25Z\tlate\n",
+        );
+        let warnings = inject(&mut capture, &annotations);
+        assert_eq!(
+            warnings,
+            vec!["annotation line 3: outside of the capture, skipped"]
+        );
+        let placed: Vec<_> = capture
+            .annotations
+            .iter()
+            .map(|event| (event.name.as_str(), event.timestamp))
+            .collect();
+        assert_eq!(placed, vec![("first", 10.25), ("second", 10.75)]);
+    }
+
+    #[test]
+    fn annotations_need_a_realtime_sync() {
+        let mut capture = capture(false);
+        let (annotations, _) = parse_annotations("2023-11-14T22:13:20.5Z\tnote\n");
+        let warnings = inject(&mut capture, &annotations);
+        assert_eq!(warnings.len(), 1);
+        assert!(capture.annotations.is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+// Resource limits of atrace itself, so a capture on a production box can't
+// fill the disk or grow without bound.
+//
+// One process-wide budget is shared by the dump, streaming and convert
+// paths: writers take output bytes from it before writing, and buffers check
+// their size against the memory limit.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+pub struct Budget {
+    // 0 for no limit.
+    max_output: AtomicU64,
+    max_memory: AtomicUsize,
+    written: AtomicU64,
+    truncated: AtomicBool,
+}
+
+pub static BUDGET: Budget = Budget {
+    max_output: AtomicU64::new(0),
+    max_memory: AtomicUsize::new(0),
+    written: AtomicU64::new(0),
+    truncated: AtomicBool::new(false),
+};
+
+impl Budget {
+    pub fn set_limits(&self, max_output: u64, max_memory: usize) {
+        self.max_output.store(max_output, Ordering::SeqCst);
+        self.max_memory.store(max_memory, Ordering::SeqCst);
+    }
+
+    // Take up to len output bytes, fewer once the limit is near.
+    pub fn take_output(&self, len: usize) -> usize {
+        let max = self.max_output.load(Ordering::SeqCst);
+        let mut granted = len as u64;
+        let _ = self
+            .written
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |written| {
+                if max > 0 {
+                    granted = (len as u64).min(max.saturating_sub(written));
+                }
+                Some(written + granted)
+            });
+        granted as usize
+    }
+
+    // Start over for the next of several outputs, as the daemon writes.
+    pub fn reset_output(&self) {
+        self.written.store(0, Ordering::SeqCst);
+        self.truncated.store(false, Ordering::SeqCst);
+    }
+
+    // Record that output was cut short for the limit.
+    pub fn mark_truncated(&self) {
+        self.truncated.store(true, Ordering::SeqCst);
+    }
+
+    // Return the part of taken bytes which wasn't written after all.
+    pub fn give_back(&self, len: usize) {
+        self.written.fetch_sub(len as u64, Ordering::SeqCst);
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated.load(Ordering::SeqCst)
+    }
+
+    // The memory limit for our own buffers, None for no limit.
+    pub fn memory_limit(&self) -> Option<usize> {
+        match self.max_memory.load(Ordering::SeqCst) {
+            0 => None,
+            max => Some(max),
+        }
+    }
+
+    // Fail when a buffer would grow past the memory limit.
+    pub fn check_memory(&self, len: usize) -> io::Result<()> {
+        match self.memory_limit() {
+            Some(max) if len > max => Err(io::Error::other("trace exceeds --max-memory-mb")),
+            _ => Ok(()),
+        }
+    }
+}
+
+// A writer which stops once the output budget is used up.
+pub struct LimitedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> LimitedWriter<W> {
+    pub fn new(inner: W) -> LimitedWriter<W> {
+        LimitedWriter { inner }
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let granted = BUDGET.take_output(buf.len());
+        if granted < buf.len() {
+            BUDGET.mark_truncated();
+        }
+        if granted == 0 && !buf.is_empty() {
+            return Err(io::Error::other("output truncated at --max-output-bytes"));
+        }
+        match self.inner.write(&buf[..granted]) {
+            Ok(written) => {
+                BUDGET.give_back(granted - written);
+                Ok(written)
+            }
+            Err(e) => {
+                BUDGET.give_back(granted);
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A budget of its own, the shared one belongs to the running modes.
+    fn budget(max_output: u64, max_memory: usize) -> Budget {
+        let budget = Budget {
+            max_output: AtomicU64::new(0),
+            max_memory: AtomicUsize::new(0),
+            written: AtomicU64::new(0),
+            truncated: AtomicBool::new(false),
+        };
+        budget.set_limits(max_output, max_memory);
+        budget
+    }
+
+    #[test]
+    fn output_is_granted_up_to_the_limit() {
+        let budget = budget(100, 0);
+        assert_eq!(budget.take_output(60), 60);
+        assert_eq!(budget.take_output(60), 40);
+        assert_eq!(budget.take_output(10), 0);
+        assert_eq!(budget.written(), 100);
+        budget.give_back(30);
+        assert_eq!(budget.take_output(50), 30);
+        budget.mark_truncated();
+        assert!(budget.truncated());
+        budget.reset_output();
+        assert_eq!((budget.written(), budget.truncated()), (0, false));
+    }
+
+    #[test]
+    fn no_limit_grants_everything() {
+        let budget = budget(0, 0);
+        assert_eq!(budget.take_output(1 << 40), 1 << 40);
+        assert_eq!(budget.memory_limit(), None);
+        assert!(budget.check_memory(usize::MAX).is_ok());
+    }
+
+    #[test]
+    fn memory_past_the_limit_fails() {
+        let budget = budget(0, 1024);
+        assert_eq!(budget.memory_limit(), Some(1024));
+        assert!(budget.check_memory(1024).is_ok());
+        assert!(budget.check_memory(1025).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// Monitoring of the per-cpu ring buffer fill during a capture, to stop early
+// instead of losing the beginning of the capture to overwrite.
+//
+// Only the tracefs stats files are read, so polling doesn't write anything
+// into the trace itself.
+
+use std::fs;
+use std::path::Path;
+
+// The value of a "key: value" line of a per_cpu/cpuN/stats file.
+pub fn stats_value(stats: &str, key: &str) -> Option<u64> {
+    stats.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        if parts.next()?.trim() != key {
+            return None;
+        }
+        parts.next()?.trim().parse().ok()
+    })
+}
+
+// Fill of a cpu buffer in percent, from its stats and buffer_size_kb.
+pub fn fill_percent(stats: &str, size_kb: u64) -> Option<f64> {
+    if size_kb == 0 {
+        return None;
+    }
+    let bytes = stats_value(stats, "bytes")?;
+    Some(bytes as f64 * 100.0 / (size_kb * 1024) as f64)
+}
+
+pub struct CpuFill {
+    pub cpu: u32,
+    pub percent: f64,
+}
+
+// The fullest cpu buffer under the tracefs root, None when the kernel
+// doesn't report the buffer bytes.
+pub fn fullest_cpu(root: &str) -> Option<CpuFill> {
+    let mut fullest: Option<CpuFill> = None;
+    for entry in fs::read_dir(Path::new(root).join("per_cpu")).ok()? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let cpu = match path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("cpu"))
+            .and_then(|cpu| cpu.parse::<u32>().ok())
+        {
+            Some(cpu) => cpu,
+            None => continue,
+        };
+        let stats = fs::read_to_string(path.join("stats")).unwrap_or_default();
+        let size_kb = fs::read_to_string(path.join("buffer_size_kb"))
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+            .unwrap_or(0);
+        if let Some(percent) = fill_percent(&stats, size_kb) {
+            if fullest
+                .as_ref()
+                .is_none_or(|fullest| percent > fullest.percent)
+            {
+                fullest = Some(CpuFill { cpu, percent });
+            }
+        }
+    }
+    fullest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const STATS: &str = "entries: 120\noverrun: 0\ncommit overrun: 0\nbytes: 3072\n\
+                         oldest event ts:  5.000000\nnow ts:  6.000000\n";
+
+    struct Root(PathBuf);
+
+    impl Root {
+        // Per cpu stats bytes and buffer_size_kb.
+        fn new(name: &str, cpus: &[(u32, &str, u64)]) -> Root {
+            let path =
+                std::env::temp_dir().join(format!("atrace-buffer-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            for (cpu, bytes, size_kb) in cpus {
+                let dir = path.join(format!("per_cpu/cpu{}", cpu));
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join("stats"), format!("entries: 1\nbytes: {}\n", bytes)).unwrap();
+                fs::write(dir.join("buffer_size_kb"), format!("{}\n", size_kb)).unwrap();
+            }
+            Root(path)
+        }
+
+        fn arg(&self) -> String {
+            format!("{}/", self.0.display())
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn stats_values_are_read_by_key() {
+        assert_eq!(stats_value(STATS, "entries"), Some(120));
+        assert_eq!(stats_value(STATS, "overrun"), Some(0));
+        assert_eq!(stats_value(STATS, "commit overrun"), Some(0));
+        assert_eq!(stats_value(STATS, "bytes"), Some(3072));
+        assert_eq!(stats_value(STATS, "oldest event ts"), None);
+        assert_eq!(stats_value(STATS, "missing"), None);
+    }
+
+    #[test]
+    fn fill_is_bytes_over_the_buffer_size() {
+        assert_eq!(fill_percent(STATS, 4), Some(75.0));
+        assert_eq!(fill_percent(STATS, 0), None);
+        assert_eq!(fill_percent("entries: 1\n", 4), None);
+    }
+
+    #[test]
+    fn the_fullest_cpu_is_found() {
+        let root = Root::new(
+            "fullest",
+            &[
+                (0, "1024", 4),
+                (1, "3072", 4),
+                (2, "2048", 4),
+                (10, "4096", 8),
+            ],
+        );
+        let fill = fullest_cpu(&root.arg()).unwrap();
+        assert_eq!((fill.cpu, fill.percent), (1, 75.0));
+    }
+
+    #[test]
+    fn cpus_without_bytes_are_skipped() {
+        let root = Root::new("nobytes", &[(0, "x", 4), (1, "512", 0)]);
+        assert!(fullest_cpu(&root.arg()).is_none());
+        assert!(fullest_cpu(&root.0.join("missing").to_string_lossy()).is_none());
+    }
+}
@@ -0,0 +1,973 @@
+// In-memory model of a dumped capture, built from the parsed ftrace lines.
+//
+// Userspace B/E markers are paired per thread into slices, and function_graph
+// entries and exits per cpu into kernel calls, so the converters and reports
+// don't need to deal with the nesting themselves.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::gaps::{self, Discontinuity, GapDetector};
+use crate::input::{decode_line, open_trace_file, read_lines, MAX_LINE_BYTES};
+use crate::parser::{
+    is_clock_sync, name_hash, parse_clock_sync_realtime, parse_continuation, parse_dict_entry,
+    parse_funcgraph_line, parse_hints, parse_line, parse_lost_events, parse_marker, parse_metadata,
+    split_attrs, split_continuation, FuncgraphCall, Marker, SchedSwitch, TraceHeader,
+    CAPTURE_ID_KEY,
+};
+
+pub struct ThreadInfo {
+    pub pid: i32,
+    pub comm: String,
+}
+
+pub struct Slice {
+    pub pid: i32,
+    pub tid: i32,
+    pub name: String,
+    /// Start timestamp and duration in seconds.
+    pub start: f64,
+    pub duration: f64,
+    pub depth: u32,
+    pub hints: Vec<(String, String)>,
+    // The key=value attributes split from the marker name.
+    pub attrs: Vec<(String, String)>,
+    // The clock jumped back between its begin and end, so the duration
+    // can't be trusted.
+    pub clock_jump: bool,
+}
+
+pub struct AsyncSlice {
+    pub pid: i32,
+    pub name: String,
+    pub cookie: i64,
+    pub start: f64,
+    pub duration: f64,
+}
+
+pub struct CounterSample {
+    pub pid: i32,
+    pub tid: i32,
+    pub name: String,
+    pub timestamp: f64,
+    // Markers carry integers, derived tracks may not.
+    pub value: f64,
+}
+
+pub struct InstantEvent {
+    pub pid: i32,
+    pub tid: i32,
+    pub name: String,
+    pub timestamp: f64,
+    pub attrs: Vec<(String, String)>,
+}
+
+/// A kernel function call traced by the function_graph tracer.
+pub struct KernelCall {
+    pub cpu: u32,
+    pub tid: Option<i32>,
+    pub name: String,
+    pub start: f64,
+    pub duration: f64,
+    pub depth: u32,
+}
+
+#[derive(Default)]
+pub struct Capture {
+    pub header: Option<TraceHeader>,
+    pub clock_sync: Option<f64>,
+    // Trace timestamp and wall clock seconds of the realtime clock sync.
+    pub realtime_sync: Option<(f64, f64)>,
+    // The id atrace gave the capture, from its metadata marker.
+    pub capture_id: Option<String>,
+    // System context written with --system-metadata.
+    pub metadata: Vec<(String, String)>,
+    pub threads: BTreeMap<i32, ThreadInfo>,
+    pub slices: Vec<Slice>,
+    pub async_slices: Vec<AsyncSlice>,
+    pub counters: Vec<CounterSample>,
+    pub instants: Vec<InstantEvent>,
+    pub kernel_calls: Vec<KernelCall>,
+    // Notes taken during the capture, added at convert time.
+    pub annotations: Vec<InstantEvent>,
+    // Synthetic interval ticks, added at convert time.
+    pub intervals: Vec<f64>,
+    pub first_timestamp: f64,
+    pub last_timestamp: f64,
+    pub lines: usize,
+    pub events: usize,
+    pub markers: usize,
+    // E markers without an open B on the same thread.
+    pub unmatched_ends: usize,
+    // B markers still open when the capture ended.
+    pub unfinished: usize,
+    // Split markers with missing or orphaned chunks.
+    pub broken_chunks: usize,
+    // Function exits or entries whose other half is not in the capture.
+    pub truncated_calls: usize,
+    // Events dropped by ring buffer overruns.
+    pub lost_events: u64,
+    // Lines which are neither trace lines nor comments.
+    pub skipped_lines: usize,
+    // Lines with invalid UTF-8, and lines cut at MAX_LINE_BYTES.
+    pub invalid_lines: usize,
+    pub cut_lines: usize,
+    // Suspends and clock jumps found between lines.
+    pub discontinuities: Vec<Discontinuity>,
+    // Full names of hashed marker names by hash, from atrace_dict instants.
+    pub name_dict: BTreeMap<String, String>,
+    // Per tid, the start and end of each stretch it ran on a cpu, from
+    // sched_switch events, in time order. Empty without sched events.
+    pub running: BTreeMap<i32, Vec<(f64, f64)>>,
+}
+
+struct OpenSlice {
+    pid: i32,
+    name: String,
+    start: f64,
+    hints: Vec<(String, String)>,
+    attrs: Vec<(String, String)>,
+    // Backwards clock jumps seen before the begin.
+    jumps: usize,
+}
+
+// An oversized instant marker waiting for its "cont#N|" chunks.
+struct PendingInstant {
+    pid: i32,
+    name: String,
+    timestamp: f64,
+    next: u32,
+    broken: bool,
+}
+
+struct OpenCall {
+    tid: Option<i32>,
+    name: String,
+    start: f64,
+}
+
+// State kept while walking the lines of a capture.
+#[derive(Default)]
+struct Builder {
+    capture: Capture,
+    stacks: BTreeMap<i32, Vec<OpenSlice>>,
+    async_open: BTreeMap<(i32, String, i64), f64>,
+    pending: BTreeMap<i32, PendingInstant>,
+    calls: BTreeMap<u32, Vec<OpenCall>>,
+    // The tid running on each cpu since the last switch, and since when.
+    on_cpu: BTreeMap<u32, (i32, f64)>,
+    gaps: GapDetector,
+}
+
+impl Capture {
+    // Build the model from a dump file, read line by line.
+    pub fn load(path: &str) -> io::Result<Capture> {
+        let (reader, _) = open_trace_file(path)?;
+        let mut builder = Builder::default();
+        read_lines(reader, |bytes| builder.raw_line(bytes))?;
+        Ok(builder.finish())
+    }
+
+    // The duration of a slice as the reports aggregate it: suspend gaps
+    // taken out, and None across a backwards clock jump, unless gaps are
+    // kept with --include-gaps.
+    pub fn report_duration(&self, slice: &Slice) -> Option<f64> {
+        if !gaps::exclude_enabled() {
+            return Some(slice.duration);
+        }
+        if slice.clock_jump {
+            return None;
+        }
+        Some(gaps::without_gaps(
+            slice.start,
+            slice.duration,
+            &self.discontinuities,
+        ))
+    }
+
+    // The on-cpu time of a slice's thread over the same span as
+    // report_duration: suspend gaps taken out, None across a clock jump.
+    pub fn report_on_cpu(&self, slice: &Slice) -> Option<f64> {
+        let end = slice.start + slice.duration;
+        let on_cpu = self.on_cpu_time(slice.tid, slice.start, end);
+        if !gaps::exclude_enabled() {
+            return Some(on_cpu);
+        }
+        if slice.clock_jump {
+            return None;
+        }
+        let in_gaps: f64 = self
+            .discontinuities
+            .iter()
+            .filter(|gap| gap.kind == gaps::GapKind::Idle)
+            .map(|gap| {
+                let (from, to) = (gap.before.max(slice.start), gap.after.min(end));
+                if from < to {
+                    self.on_cpu_time(slice.tid, from, to)
+                } else {
+                    0.0
+                }
+            })
+            .sum();
+        Some((on_cpu - in_gaps).max(0.0))
+    }
+
+    // Build the model from the bytes of a dump, decoding line by line.
+    #[cfg(any(test, feature = "ssh", feature = "adb"))]
+    pub fn from_bytes(data: &[u8]) -> Capture {
+        let mut builder = Builder::default();
+        for bytes in crate::input::split_lines(data) {
+            builder.raw_line(bytes);
+        }
+        builder.finish()
+    }
+
+    // Build the model from marker lines of the shared test fixture.
+    #[cfg(test)]
+    pub fn from_markers(payloads: &[&str]) -> Capture {
+        Capture::from_bytes(crate::fixture::markers(payloads).as_bytes())
+    }
+
+    // The full name of a "<name>#<hash>" marker name, if in the dictionary.
+    pub fn full_name<'a>(&'a self, name: &'a str) -> &'a str {
+        name_hash(name)
+            .and_then(|hash| self.name_dict.get(&hash.to_ascii_lowercase()))
+            .map_or(name, |full| full.as_str())
+    }
+
+    // How long the thread ran on a cpu within start..end, in seconds.
+    pub fn on_cpu_time(&self, tid: i32, start: f64, end: f64) -> f64 {
+        let intervals = match self.running.get(&tid) {
+            Some(intervals) => intervals,
+            None => return 0.0,
+        };
+        let first = intervals.partition_point(|(_, until)| *until <= start);
+        intervals[first..]
+            .iter()
+            .take_while(|(since, _)| *since < end)
+            .map(|(since, until)| until.min(end) - since.max(start))
+            .sum()
+    }
+
+    // Notes on lines which couldn't be read as they were written.
+    pub fn line_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.skipped_lines > 0 {
+            warnings.push(format!(
+                "{} lines not understood, skipped",
+                self.skipped_lines
+            ));
+        }
+        if self.invalid_lines > 0 {
+            warnings.push(format!(
+                "{} lines with invalid UTF-8, replaced",
+                self.invalid_lines
+            ));
+        }
+        if self.cut_lines > 0 {
+            warnings.push(format!(
+                "{} lines longer than {} bytes, cut",
+                self.cut_lines, MAX_LINE_BYTES
+            ));
+        }
+        warnings
+    }
+}
+
+impl Builder {
+    // Decode a line of the dump, counting what couldn't be read as written.
+    fn raw_line(&mut self, bytes: &[u8]) {
+        let line = decode_line(bytes);
+        if line.invalid {
+            self.capture.invalid_lines += 1;
+        }
+        if line.cut {
+            self.capture.cut_lines += 1;
+        }
+        self.line(&line.text);
+    }
+
+    fn line(&mut self, line: &str) {
+        self.capture.lines += 1;
+        if let Some(lost) = parse_lost_events(line) {
+            self.capture.lost_events += lost;
+        } else if let Some(trace_line) = parse_line(line) {
+            self.timestamp(trace_line.timestamp);
+            if let Some(switch) = trace_line.sched_switch() {
+                self.sched_switch(trace_line.cpu, trace_line.timestamp, switch);
+            } else if let Some(call) = trace_line.funcgraph_call() {
+                self.kernel_call(
+                    trace_line.cpu,
+                    Some(trace_line.tid),
+                    trace_line.timestamp,
+                    call,
+                );
+            } else if trace_line.is_marker() {
+                self.marker(
+                    trace_line.comm,
+                    trace_line.tid,
+                    trace_line.tgid,
+                    trace_line.timestamp,
+                    trace_line.payload,
+                );
+            }
+        } else if let Some(graph_line) = parse_funcgraph_line(line) {
+            // Without funcgraph-abstime there is no timestamp to place
+            // the call, so such lines are skipped.
+            let ts = match graph_line.timestamp {
+                Some(ts) => ts,
+                None => return,
+            };
+            self.timestamp(ts);
+            match graph_line.call {
+                FuncgraphCall::Comment(payload) => {
+                    let tid = graph_line.tid.unwrap_or(0);
+                    self.marker(graph_line.comm, tid, None, ts, payload);
+                }
+                call => self.kernel_call(graph_line.cpu, graph_line.tid, ts, call),
+            }
+        } else if !line.trim().is_empty() && !line.trim_start().starts_with('#') {
+            self.capture.skipped_lines += 1;
+        }
+    }
+
+    fn timestamp(&mut self, ts: f64) {
+        if self.capture.events == 0 {
+            self.capture.first_timestamp = ts;
+        }
+        self.capture.events += 1;
+        self.capture.last_timestamp = ts;
+        self.gaps.push(ts);
+    }
+
+    // Handle a trace_marker write from the thread.
+    fn marker(&mut self, comm: &str, tid: i32, tgid: Option<i32>, ts: f64, payload: &str) {
+        if is_clock_sync(payload) {
+            match parse_clock_sync_realtime(payload) {
+                Some(realtime) => self.capture.realtime_sync = Some((ts, realtime)),
+                None => self.capture.clock_sync = Some(ts),
+            }
+            return;
+        }
+        if let Some((key, value)) = parse_metadata(payload) {
+            if key == CAPTURE_ID_KEY {
+                self.capture.capture_id = Some(value.to_string());
+            } else {
+                self.capture
+                    .metadata
+                    .push((key.to_string(), value.to_string()));
+            }
+            return;
+        }
+        if let Some((chunk, rest)) = parse_continuation(payload) {
+            self.push_chunk(tid, chunk, rest);
+            return;
+        }
+        let marker = match parse_marker(payload) {
+            Some(marker) => marker,
+            None => return,
+        };
+        self.capture.markers += 1;
+        let pid = match marker {
+            Marker::Begin { pid, .. }
+            | Marker::Counter { pid, .. }
+            | Marker::AsyncBegin { pid, .. }
+            | Marker::AsyncEnd { pid, .. }
+            | Marker::Instant { pid, .. } => pid,
+            Marker::End { pid } => pid.or(tgid).unwrap_or(tid),
+        };
+        self.capture
+            .threads
+            .entry(tid)
+            .or_insert_with(|| ThreadInfo {
+                pid,
+                comm: comm.trim().to_string(),
+            });
+
+        match marker {
+            Marker::Begin { pid, name, hints } => {
+                let jumps = self.gaps.backwards_jumps();
+                let (name, attrs) = split_attrs(name);
+                self.stacks.entry(tid).or_default().push(OpenSlice {
+                    pid,
+                    name: name.to_string(),
+                    start: ts,
+                    hints: hints.map(parse_hints).unwrap_or_default(),
+                    attrs,
+                    jumps,
+                });
+            }
+            Marker::End { .. } => {
+                let stack = self.stacks.entry(tid).or_default();
+                match stack.pop() {
+                    Some(open) => {
+                        let depth = stack.len() as u32;
+                        self.push_slice(tid, open, ts, depth);
+                    }
+                    None => self.capture.unmatched_ends += 1,
+                }
+            }
+            Marker::Counter { pid, name, value } => {
+                self.capture.counters.push(CounterSample {
+                    pid,
+                    tid,
+                    name: name.to_string(),
+                    timestamp: ts,
+                    value: value as f64,
+                });
+            }
+            Marker::AsyncBegin { pid, name, cookie } => {
+                self.async_open.insert((pid, name.to_string(), cookie), ts);
+            }
+            Marker::AsyncEnd { pid, name, cookie } => {
+                let key = (pid, name.to_string(), cookie);
+                if let Some(start) = self.async_open.remove(&key) {
+                    self.capture.async_slices.push(AsyncSlice {
+                        pid,
+                        name: key.1,
+                        cookie,
+                        start,
+                        duration: ts - start,
+                    });
+                }
+            }
+            Marker::Instant { pid, name } => {
+                // A new instant on the thread ends any earlier split one.
+                if let Some(mut open) = self.pending.remove(&tid) {
+                    open.broken = true;
+                    self.finish_instant(tid, open);
+                }
+                match split_continuation(name) {
+                    (head, Some(next)) => {
+                        self.pending.insert(
+                            tid,
+                            PendingInstant {
+                                pid,
+                                name: head.to_string(),
+                                timestamp: ts,
+                                next,
+                                broken: false,
+                            },
+                        );
+                    }
+                    (name, None) => self.push_instant(pid, tid, name.to_string(), ts),
+                }
+            }
+        }
+    }
+
+    // End the running stretch of the task switched out, and start one for
+    // the task switched in. The idle task, pid 0, isn't tracked.
+    fn sched_switch(&mut self, cpu: u32, ts: f64, switch: SchedSwitch) {
+        // On the first switch seen on the cpu the task ran since the start.
+        let since = match self.on_cpu.get(&cpu) {
+            Some((_, since)) => *since,
+            None => self.capture.first_timestamp,
+        };
+        self.push_running(switch.prev_pid, since, ts);
+        self.on_cpu.insert(cpu, (switch.next_pid, ts));
+    }
+
+    fn push_running(&mut self, tid: i32, start: f64, end: f64) {
+        if tid != 0 && end > start {
+            self.capture
+                .running
+                .entry(tid)
+                .or_default()
+                .push((start, end));
+        }
+    }
+
+    // Handle a function_graph entry, leaf or exit on the cpu.
+    fn kernel_call(&mut self, cpu: u32, tid: Option<i32>, ts: f64, call: FuncgraphCall<'_>) {
+        let stack = self.calls.entry(cpu).or_default();
+        let depth = stack.len() as u32;
+        let call = match call {
+            FuncgraphCall::Entry { name } => {
+                stack.push(OpenCall {
+                    tid,
+                    name: name.to_string(),
+                    start: ts,
+                });
+                return;
+            }
+            // Leaf lines are stamped with the entry time.
+            FuncgraphCall::Leaf { name, duration } => KernelCall {
+                cpu,
+                tid,
+                name: name.to_string(),
+                start: ts,
+                duration,
+                depth,
+            },
+            // Exit lines are stamped with the exit time.
+            FuncgraphCall::Exit { name, duration } => match stack.pop() {
+                Some(open) => KernelCall {
+                    cpu,
+                    tid: open.tid,
+                    name: open.name,
+                    start: open.start,
+                    duration,
+                    depth: depth - 1,
+                },
+                // The entry was lost before the capture started, keep the
+                // call when the exit comment tells its name.
+                None => {
+                    self.capture.truncated_calls += 1;
+                    match name {
+                        Some(name) => KernelCall {
+                            cpu,
+                            tid,
+                            name: name.to_string(),
+                            start: ts - duration,
+                            duration,
+                            depth: 0,
+                        },
+                        None => return,
+                    }
+                }
+            },
+            FuncgraphCall::Comment(_) => return,
+        };
+        self.capture.kernel_calls.push(call);
+    }
+
+    // Append a "cont#N|rest" chunk to the split instant pending on the thread.
+    fn push_chunk(&mut self, tid: i32, chunk: u32, rest: &str) {
+        let mut open = match self.pending.remove(&tid) {
+            Some(open) => open,
+            None => {
+                // The head of this chunk was lost.
+                self.capture.broken_chunks += 1;
+                return;
+            }
+        };
+        if chunk != open.next {
+            // Some middle chunks are missing, keep what arrived.
+            open.broken = true;
+            open.name.push('…');
+        }
+        let (text, next) = split_continuation(rest);
+        open.name.push_str(text);
+        match next {
+            Some(next) => {
+                open.next = next;
+                self.pending.insert(tid, open);
+            }
+            None => self.finish_instant(tid, open),
+        }
+    }
+
+    fn finish_instant(&mut self, tid: i32, open: PendingInstant) {
+        if open.broken {
+            self.capture.broken_chunks += 1;
+        }
+        self.push_instant(open.pid, tid, open.name, open.timestamp);
+    }
+
+    fn push_instant(&mut self, pid: i32, tid: i32, name: String, timestamp: f64) {
+        if let Some((hash, full)) = parse_dict_entry(&name) {
+            self.capture
+                .name_dict
+                .insert(hash.to_ascii_lowercase(), full.to_string());
+            return;
+        }
+        // The header is key=value tokens too, but kept as written.
+        let (name, attrs) = match TraceHeader::parse(&name) {
+            Some(header) => {
+                self.capture.header = Some(header);
+                (name, Vec::new())
+            }
+            None => match split_attrs(&name) {
+                (head, attrs) if !attrs.is_empty() => (head.to_string(), attrs),
+                _ => (name, Vec::new()),
+            },
+        };
+        self.capture.instants.push(InstantEvent {
+            pid,
+            tid,
+            name,
+            timestamp,
+            attrs,
+        });
+    }
+
+    fn push_slice(&mut self, tid: i32, open: OpenSlice, end: f64, depth: u32) {
+        self.capture.slices.push(Slice {
+            pid: open.pid,
+            tid,
+            name: open.name,
+            start: open.start,
+            duration: end - open.start,
+            depth,
+            hints: open.hints,
+            attrs: open.attrs,
+            clock_jump: open.jumps != self.gaps.backwards_jumps(),
+        });
+    }
+
+    // Close what is still open at the end of the capture.
+    fn finish(mut self) -> Capture {
+        let pending = std::mem::take(&mut self.pending);
+        for (tid, mut open) in pending {
+            open.broken = true;
+            self.finish_instant(tid, open);
+        }
+        let end = self.capture.last_timestamp;
+        let on_cpu = std::mem::take(&mut self.on_cpu);
+        for (_, (tid, since)) in on_cpu {
+            self.push_running(tid, since, end);
+        }
+        let stacks = std::mem::take(&mut self.stacks);
+        for (tid, stack) in stacks {
+            let mut depth = stack.len() as u32;
+            for open in stack.into_iter().rev() {
+                depth -= 1;
+                self.capture.unfinished += 1;
+                self.push_slice(tid, open, end, depth);
+            }
+        }
+        for stack in self.calls.values() {
+            self.capture.truncated_calls += stack.len();
+        }
+
+        let mut capture = self.capture;
+        capture.discontinuities = self.gaps.finish();
+        capture.slices.sort_by(|a, b| {
+            a.start
+                .partial_cmp(&b.start)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        for intervals in capture.running.values_mut() {
+            intervals.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        }
+        capture.kernel_calls.sort_by(|a, b| {
+            a.start
+                .partial_cmp(&b.start)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        capture
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::markers;
+
+    const FUNCGRAPH: &[u8] = include_bytes!("../tests/fixtures/funcgraph.txt");
+    const FUNCGRAPH_FLAT: &[u8] = include_bytes!("../tests/fixtures/funcgraph_flat.txt");
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    fn call<'a>(capture: &'a Capture, name: &str) -> &'a KernelCall {
+        capture
+            .kernel_calls
+            .iter()
+            .find(|call| call.name == name)
+            .unwrap_or_else(|| panic!("no call {}", name))
+    }
+
+    #[test]
+    fn chunks_reassemble() {
+        let capture = Capture::from_markers(&[
+            "I|1234|head …cont#1",
+            "cont#1|middle …cont#2",
+            "cont#2|tail",
+        ]);
+        assert_eq!(capture.instants.len(), 1);
+        assert_eq!(capture.instants[0].name, "head middle tail");
+        assert_close(capture.instants[0].timestamp, 10.0);
+        assert_eq!(capture.broken_chunks, 0);
+    }
+
+    #[test]
+    fn chunks_with_missing_middle() {
+        let capture =
+            Capture::from_markers(&["I|1234|head …cont#1", "cont#1|one …cont#2", "cont#3|three"]);
+        assert_eq!(capture.instants.len(), 1);
+        assert_eq!(capture.instants[0].name, "head one …three");
+        assert_eq!(capture.broken_chunks, 1);
+    }
+
+    #[test]
+    fn chunks_with_missing_head_or_tail() {
+        // A chunk without its head is dropped.
+        let capture = Capture::from_markers(&["cont#2|orphan"]);
+        assert!(capture.instants.is_empty());
+        assert_eq!(capture.broken_chunks, 1);
+        // A head whose chunks stop is kept as far as it got, at the next
+        // instant of the thread or at the end.
+        let capture = Capture::from_markers(&[
+            "I|1234|first …cont#1",
+            "I|1234|second",
+            "I|1234|third …cont#1",
+        ]);
+        let names: Vec<&str> = capture
+            .instants
+            .iter()
+            .map(|instant| instant.name.as_str())
+            .collect();
+        assert_eq!(names, ["first ", "second", "third "]);
+        assert_eq!(capture.broken_chunks, 2);
+    }
+
+    #[test]
+    fn chunks_keep_attributes() {
+        let capture = Capture::from_markers(&[
+            "I|1234|upload path=\"/data/a b\" …cont#1",
+            "cont#1|bytes=4096",
+        ]);
+        let instant = &capture.instants[0];
+        assert_eq!(instant.name, "upload");
+        assert_eq!(
+            instant.attrs,
+            [
+                ("path".to_string(), "/data/a b".to_string()),
+                ("bytes".to_string(), "4096".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn funcgraph_calls_in_start_order() {
+        let capture = Capture::from_bytes(FUNCGRAPH);
+        let names: Vec<&str> = capture
+            .kernel_calls
+            .iter()
+            .map(|call| call.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "vfs_read",
+                "sys_openat",
+                "do_sys_open",
+                "getname",
+                "do_filp_open",
+                "kfree"
+            ]
+        );
+    }
+
+    #[test]
+    fn funcgraph_depths_follow_nesting() {
+        let capture = Capture::from_bytes(FUNCGRAPH);
+        assert_eq!(call(&capture, "sys_openat").depth, 0);
+        assert_eq!(call(&capture, "do_sys_open").depth, 1);
+        assert_eq!(call(&capture, "getname").depth, 2);
+        assert_eq!(call(&capture, "do_filp_open").depth, 2);
+        // Other cpus nest on their own.
+        assert_eq!(call(&capture, "kfree").depth, 0);
+        assert_eq!(call(&capture, "kfree").cpu, 1);
+    }
+
+    #[test]
+    fn funcgraph_exits_pair_with_entries() {
+        let capture = Capture::from_bytes(FUNCGRAPH);
+        // Exits are stamped at the exit, calls start at their entry.
+        let open = call(&capture, "sys_openat");
+        assert_close(open.start, 5678.100020);
+        assert_close(open.duration, 24.8e-6);
+        let open = call(&capture, "do_sys_open");
+        assert_close(open.start, 5678.100021);
+        assert_close(open.duration, 11.0e-6);
+        let filp = call(&capture, "do_filp_open");
+        assert_close(filp.start, 5678.100023);
+        assert_close(filp.duration, 6.25e-6);
+        // Leaves are stamped at the entry.
+        let leaf = call(&capture, "getname");
+        assert_close(leaf.start, 5678.100022);
+        assert_close(leaf.duration, 0.512e-6);
+        assert_eq!(leaf.tid, Some(1234));
+    }
+
+    #[test]
+    fn funcgraph_truncated_calls() {
+        let capture = Capture::from_bytes(FUNCGRAPH);
+        // The exit of vfs_read has no entry, schedule has no exit.
+        assert_eq!(capture.truncated_calls, 2);
+        let read = call(&capture, "vfs_read");
+        assert_close(read.start, 5678.100010 - 3.2e-6);
+        assert_eq!(read.depth, 0);
+        assert!(capture
+            .kernel_calls
+            .iter()
+            .all(|call| call.name != "schedule"));
+    }
+
+    #[test]
+    fn funcgraph_comments_are_markers() {
+        let capture = Capture::from_bytes(FUNCGRAPH);
+        assert_eq!(capture.slices.len(), 1);
+        let slice = &capture.slices[0];
+        assert_eq!(slice.name, "open_config");
+        assert_eq!(slice.tid, 1234);
+        assert_close(slice.start, 5678.100031);
+        assert_close(slice.duration, 19e-6);
+    }
+
+    #[test]
+    fn funcgraph_flat_layout() {
+        let capture = Capture::from_bytes(FUNCGRAPH_FLAT);
+        assert_eq!(capture.kernel_calls.len(), 2);
+        let read = call(&capture, "ksys_read");
+        assert_close(read.start, 5678.200000);
+        assert_close(read.duration, 5e-6);
+        assert_eq!(read.depth, 0);
+        let leaf = call(&capture, "fdget_pos");
+        assert_eq!(leaf.depth, 1);
+        assert_close(leaf.duration, 0.3e-6);
+        assert_eq!(capture.instants.len(), 1);
+        assert_eq!(capture.truncated_calls, 0);
+        assert_eq!(capture.skipped_lines, 0);
+    }
+
+    #[test]
+    fn bad_lines_are_counted_not_fatal() {
+        // xorshift, so the lines are the same on every run.
+        let mut state = 0x1234_5678_9abc_def1u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut data = Vec::new();
+        let (mut garbage, mut invalid, mut begins) = (0, 0, 0);
+        for i in 0..2000 {
+            match next() % 4 {
+                // Bytes outside ASCII never parse as a trace line.
+                0 => {
+                    let len = 1 + next() % 100;
+                    let bytes: Vec<u8> = (0..len).map(|_| 0x80 | next() as u8).collect();
+                    if std::str::from_utf8(&bytes).is_err() {
+                        invalid += 1;
+                    }
+                    data.extend_from_slice(&bytes);
+                    garbage += 1;
+                }
+                // A marker whose name holds a cut multibyte character.
+                1 => {
+                    data.extend_from_slice(markers(&[&format!("B|1234|name{}", i)]).as_bytes());
+                    data.pop();
+                    data.extend_from_slice(b"\xe2\x82");
+                    invalid += 1;
+                    begins += 1;
+                }
+                2 => {
+                    data.extend_from_slice(markers(&[&format!("B|1234|name{}", i)]).as_bytes());
+                    data.pop();
+                    begins += 1;
+                }
+                _ => {
+                    data.extend_from_slice(markers(&["E|1234"]).as_bytes());
+                    data.pop();
+                }
+            }
+            data.push(b'\n');
+        }
+        // And one line over the cap, kept as a cut marker.
+        let long = format!("B|1234|{}", "x".repeat(MAX_LINE_BYTES));
+        data.extend_from_slice(markers(&[&long]).as_bytes());
+        begins += 1;
+
+        let capture = Capture::from_bytes(&data);
+        assert_eq!(capture.lines, 2001);
+        assert_eq!(capture.skipped_lines, garbage);
+        assert_eq!(capture.invalid_lines, invalid);
+        assert_eq!(capture.cut_lines, 1);
+        let long_names = capture
+            .slices
+            .iter()
+            .filter(|slice| slice.name.ends_with("[cut]"))
+            .count();
+        assert_eq!(long_names, 1);
+        assert!(capture.slices.len() <= begins);
+        assert_eq!(capture.line_warnings().len(), 3);
+    }
+
+    #[test]
+    fn discontinuities_are_recorded() {
+        let line = |ts: &str, payload: &str| {
+            format!(
+                "  app-1234  ( 1234) [000] ...1  {}: tracing_mark_write: {}\n",
+                ts, payload
+            )
+        };
+        let data = [
+            line("10.000000", "B|1234|draw"),
+            line("10.500000", "E|1234"),
+            line("11.000000", "B|1234|sleep"),
+            line("21.000000", "E|1234"),
+            line("21.500000", "B|1234|load"),
+            line("15.000000", "E|1234"),
+        ]
+        .concat();
+        let capture = Capture::from_bytes(data.as_bytes());
+        let kinds: Vec<bool> = capture
+            .discontinuities
+            .iter()
+            .map(|gap| gap.kind == gaps::GapKind::Idle)
+            .collect();
+        assert_eq!(kinds, vec![true, false]);
+        let durations: Vec<(&str, Option<f64>)> = capture
+            .slices
+            .iter()
+            .map(|slice| (slice.name.as_str(), capture.report_duration(slice)))
+            .collect();
+        // The suspend is taken out, the slice across the jump left out.
+        assert_eq!(
+            durations,
+            vec![("draw", Some(0.5)), ("sleep", Some(0.0)), ("load", None)]
+        );
+    }
+
+    #[test]
+    fn hashed_names_are_restored_from_the_dictionary() {
+        let capture = Capture::from_markers(&[
+            "I|1234|atrace_dict:0a1b2c3d=GET /users/{id}/profile",
+            "I|1234|atrace_dict:0A1B2C3D=GET /users/{id}/profile",
+            "I|1234|atrace_dict:ffffffff=render big list",
+            "B|1234|GET#0a1b2c3d",
+            "E|1234",
+            "B|1234|GET#0A1B2C3D",
+            "E|1234",
+            "C|1234|render#ffffffff|3",
+            "I|1234|render#FFFFFFFF",
+            "I|1234|other#12345678",
+            "I|1234|short#abc",
+        ]);
+        // The dictionary entries aren't instants of their own.
+        assert_eq!(capture.name_dict.len(), 2);
+        assert_eq!(capture.instants.len(), 3);
+        let names: Vec<&str> = capture
+            .slices
+            .iter()
+            .map(|slice| capture.full_name(&slice.name))
+            .collect();
+        assert_eq!(names, vec!["GET /users/{id}/profile"; 2]);
+        assert_eq!(
+            capture.full_name(&capture.counters[0].name),
+            "render big list"
+        );
+        let instants: Vec<&str> = capture
+            .instants
+            .iter()
+            .map(|instant| capture.full_name(&instant.name))
+            .collect();
+        assert_eq!(
+            instants,
+            vec!["render big list", "other#12345678", "short#abc"]
+        );
+        // The capture itself keeps the hashed names for bounded summaries.
+        assert_eq!(capture.slices[0].name, "GET#0a1b2c3d");
+    }
+}
@@ -1,4 +1,4 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, Error, ErrorKind, SubCommand};
 
 pub struct Config {
     pub debug_cmdline: String,
@@ -8,6 +8,77 @@ pub struct Config {
     pub durationsec: u32,
     pub compress: bool,
     pub uncompress_file: String,
+    pub status_file: String,
+    pub no_verify: bool,
+    pub doctor: bool,
+    pub convert_file: String,
+    pub format: String,
+    pub output: String,
+    pub csv_counters: String,
+    pub annotations: String,
+    pub pid_map: String,
+    pub verify: bool,
+    pub expect_markers: usize,
+    pub allow_overruns: bool,
+    pub counter_derive: String,
+    pub counter_scale: String,
+    pub time_base: String,
+    pub remote: String,
+    pub window: String,
+    // The --synthetic-intervals period in seconds, 0 when not given.
+    pub synthetic_interval: f64,
+    pub align_to: String,
+    pub auto_skew_correct: bool,
+    pub summary_file: String,
+    pub top: usize,
+    pub diff_files: Vec<String>,
+    pub diff_threshold: f64,
+    pub diff_min_us: f64,
+    pub strip_suffix: String,
+    pub gap_threshold: f64,
+    pub include_gaps: bool,
+    pub trigger: String,
+    pub pre: f64,
+    pub post: f64,
+    pub max_window_bytes: usize,
+    pub max_triggers: usize,
+    pub stop_on_full: bool,
+    pub full_threshold: f64,
+    pub result: String,
+    pub export_config: String,
+    pub import_config: String,
+    // Import the events of --import-config this kernel has instead of failing.
+    pub best_effort: bool,
+    // Set at session start, see new_capture_id.
+    pub capture_id: String,
+    pub system_metadata: bool,
+    pub run_command: Vec<String>,
+    pub extract_file: String,
+    pub replay_file: String,
+    pub flight_dir: String,
+    // Uncompressed bytes per flight segment.
+    pub flight_segment: usize,
+    pub flight_keep: usize,
+    pub flight_seal: bool,
+    pub replay_speed: f64,
+    pub extract_from: String,
+    pub extract_to: String,
+    pub report_dir: String,
+    pub report_glob: String,
+    pub report_trend: Vec<String>,
+    pub daemon: bool,
+    pub socket: String,
+    pub ctl_command: Vec<String>,
+    pub pick: bool,
+    // Capture right after picking, with the saved profile.
+    pub pick_start: bool,
+    pub max_output_bytes: u64,
+    // The --tracefs directory, empty to select one, see tracefs::select.
+    pub tracefs: String,
+    pub print_marker_path: bool,
+    pub max_memory_mb: usize,
+    pub nice: i32,
+    pub idle_io: bool,
     pub tgid: bool,
     pub begin_async: bool,
     pub stop_async: bool,
@@ -19,11 +90,426 @@ pub struct Config {
     pub cpu_sched: bool,
 }
 
-pub fn parse_options() -> Config {
-    let cmd_arguments = App::new("atrace")
+const LONG_ABOUT: &str = "Launch atrace.
+
+Captures the kernel ftrace buffer together with the userspace markers
+written by tracing-atrace, and converts or reports on dumped traces.
+
+EXAMPLES:
+    Capture 10 seconds of sched and userspace markers, compressed:
+        atrace -T 10 -Z sched > trace.z
+
+    Trace one run of a benchmark into its own file:
+        atrace run -o bench.txt -- ./bench --iters 100
+
+    Keep 2s of history and dump it around each \"timeout\" marker:
+        atrace --trigger timeout --pre 2s --post 1s --max-triggers 3
+
+    Cut 200ms out of a huge capture, on the trace clock:
+        atrace extract --from 12.345s --to 12.545s big.z -o slice.z
+
+    Convert a dump for the Perfetto UI and report the slowest markers:
+        atrace --convert trace.z -o trace.json
+        atrace --summary trace.z --top 10
+
+    Capture 5 seconds on a board over ssh and convert it locally:
+        atrace --remote ssh://root@board -T 5 --format json -o trace.json
+
+    Render one second of the markers as an image to share:
+        atrace --convert trace.z --format svg --window 2s..3s -o trace.svg
+
+    Merge the summaries of a soak run, with the p99 trend of one marker:
+        atrace report soak/ --glob 'trace-*.z' --trend handle_request -o report.json
+
+    Gate a change in CI on marker regressions (exit code 3):
+        atrace --diff before.z after.z --diff-threshold 10";
+
+// An argument combination rejected after parsing. The rule applies when arg
+// is given together with any of others for a conflict, or without all of
+// them for a requirement. "name=value" matches an argument value.
+struct Rule {
+    arg: &'static str,
+    others: &'static [&'static str],
+    conflict: bool,
+    message: &'static str,
+}
+
+// Capture options, which don't apply to the modes reading a trace file.
+const CAPTURE_ARGS: &[&str] = &[
+    "Group",
+    "K",
+    "Z",
+    "BEGIN_ASYNC",
+    "STOP_ASYNC",
+    "DUMP_ASYNC",
+    "STREAM",
+    "trigger",
+    "stop_on_full",
+    "export_config",
+    "import_config",
+    "verify",
+];
+
+// Every flag interacting with others declares it here.
+const RULES: &[Rule] = &[
+    Rule {
+        arg: "STREAM",
+        others: &["Z"],
+        conflict: true,
+        message: "--stream writes plain text, it can't be combined with -Z.",
+    },
+    Rule {
+        arg: "BEGIN_ASYNC",
+        others: &["T"],
+        conflict: true,
+        message: "--async-start returns right away, -T has no effect with it.",
+    },
+    Rule {
+        arg: "BEGIN_ASYNC",
+        others: &["STOP_ASYNC", "DUMP_ASYNC"],
+        conflict: true,
+        message: "--async-start can't be combined with --async-stop or --async-dump.",
+    },
+    Rule {
+        arg: "trigger",
+        others: &["STREAM", "Z", "BEGIN_ASYNC", "STOP_ASYNC", "DUMP_ASYNC"],
+        conflict: true,
+        message: "--trigger streams trace_pipe itself, it can't be combined with --stream, -Z or the async modes.",
+    },
+    Rule {
+        arg: "stop_on_full",
+        others: &["STREAM", "trigger", "BEGIN_ASYNC", "STOP_ASYNC", "DUMP_ASYNC"],
+        conflict: true,
+        message: "--stop-on-full watches the -T capture window, it doesn't apply to streaming or async captures.",
+    },
+    Rule {
+        arg: "verify",
+        others: &["STREAM", "trigger", "BEGIN_ASYNC"],
+        conflict: true,
+        message: "--verify checks the capture as dumped, it doesn't apply to streaming or --async-start.",
+    },
+    Rule {
+        arg: "expect_markers",
+        others: &["verify"],
+        conflict: false,
+        message: "--expect-markers only applies to --verify.",
+    },
+    Rule {
+        arg: "allow_overruns",
+        others: &["verify"],
+        conflict: false,
+        message: "--allow-overruns only applies to --verify.",
+    },
+    Rule {
+        arg: "trace_file",
+        others: CAPTURE_ARGS,
+        conflict: true,
+        message: "--uncompress only reads a trace file, capture options like categories, -K, -Z or --stream don't apply.",
+    },
+    Rule {
+        arg: "status_file",
+        others: CAPTURE_ARGS,
+        conflict: true,
+        message: "--status only reads a trace file, capture options like categories, -K, -Z or --stream don't apply.",
+    },
+    Rule {
+        arg: "convert_file",
+        others: CAPTURE_ARGS,
+        conflict: true,
+        message: "--convert only reads a trace file, capture options like categories, -K, -Z or --stream don't apply.",
+    },
+    Rule {
+        arg: "summary_file",
+        others: CAPTURE_ARGS,
+        conflict: true,
+        message: "--summary only reads a trace file, capture options like categories, -K, -Z or --stream don't apply.",
+    },
+    Rule {
+        arg: "diff_files",
+        others: CAPTURE_ARGS,
+        conflict: true,
+        message: "--diff only reads trace files, capture options like categories, -K, -Z or --stream don't apply.",
+    },
+    Rule {
+        arg: "remote",
+        others: &[
+            "STREAM",
+            "trigger",
+            "BEGIN_ASYNC",
+            "STOP_ASYNC",
+            "DUMP_ASYNC",
+            "K",
+            "Z",
+            "stop_on_full",
+            "verify",
+            "result",
+            "export_config",
+            "import_config",
+            "system_metadata",
+            "trace_file",
+            "status_file",
+            "convert_file",
+            "summary_file",
+            "diff_files",
+            "doctor",
+        ],
+        conflict: true,
+        message: "--remote runs a plain -T capture on the target, streaming, async, function tracing and the trace file modes don't apply.",
+    },
+    Rule {
+        arg: "format",
+        others: &["convert_file", "remote"],
+        conflict: false,
+        message: "--format only applies to --convert and --remote.",
+    },
+    Rule {
+        arg: "csv_counters",
+        others: &["format=csv"],
+        conflict: false,
+        message: "--csv-counters needs --format csv.",
+    },
+    Rule {
+        arg: "time_base",
+        others: &["format=csv"],
+        conflict: false,
+        message: "--time-base needs --format csv.",
+    },
+    Rule {
+        arg: "window",
+        others: &["format=svg"],
+        conflict: false,
+        message: "--window needs --format svg.",
+    },
+    Rule {
+        arg: "synthetic_intervals",
+        others: &["convert_file"],
+        conflict: false,
+        message: "--synthetic-intervals only applies to --convert.",
+    },
+    Rule {
+        arg: "synthetic_intervals",
+        others: &["format=csv", "format=svg", "format=perfetto"],
+        conflict: true,
+        message: "--synthetic-intervals is only drawn in the json output.",
+    },
+    Rule {
+        arg: "align_to",
+        others: &["synthetic_intervals"],
+        conflict: false,
+        message: "--align-to needs --synthetic-intervals.",
+    },
+    Rule {
+        arg: "auto_skew_correct",
+        others: &["convert_file"],
+        conflict: false,
+        message: "--auto-skew-correct only applies to --convert.",
+    },
+    Rule {
+        arg: "strict",
+        others: &["import_config"],
+        conflict: false,
+        message: "--strict needs --import-config.",
+    },
+    Rule {
+        arg: "best_effort",
+        others: &["import_config"],
+        conflict: false,
+        message: "--best-effort needs --import-config.",
+    },
+    Rule {
+        arg: "strict",
+        others: &["best_effort"],
+        conflict: true,
+        message: "--strict and --best-effort can't be combined.",
+    },
+    Rule {
+        arg: "no_verify",
+        others: &[
+            "trace_file",
+            "status_file",
+            "convert_file",
+            "summary_file",
+            "diff_files",
+        ],
+        conflict: false,
+        message: "--no-verify only applies to the modes reading a trace file.",
+    },
+    Rule {
+        arg: "gap_threshold",
+        others: &["status_file", "convert_file", "summary_file", "diff_files"],
+        conflict: false,
+        message: "--gap-threshold only applies to --status, --convert, --summary and --diff.",
+    },
+    Rule {
+        arg: "include_gaps",
+        others: &["summary_file", "diff_files"],
+        conflict: false,
+        message: "--include-gaps only applies to --summary and --diff.",
+    },
+    Rule {
+        arg: "counter_derive",
+        others: &["convert_file"],
+        conflict: false,
+        message: "--counter-derive only applies to --convert.",
+    },
+    Rule {
+        arg: "counter_derive",
+        others: &["format=csv", "format=svg"],
+        conflict: true,
+        message: "--counter-derive tracks are only in the json and perfetto output.",
+    },
+    Rule {
+        arg: "counter_scale",
+        others: &["convert_file"],
+        conflict: false,
+        message: "--counter-scale only applies to --convert.",
+    },
+    Rule {
+        arg: "counter_scale",
+        others: &["format=csv", "format=svg"],
+        conflict: true,
+        message: "--counter-scale tracks are only in the json and perfetto output.",
+    },
+    Rule {
+        arg: "pid_map",
+        others: &["convert_file"],
+        conflict: false,
+        message: "--pid-map only applies to --convert.",
+    },
+    Rule {
+        arg: "top",
+        others: &["summary_file"],
+        conflict: false,
+        message: "--top only applies to --summary.",
+    },
+    Rule {
+        arg: "diff_threshold",
+        others: &["diff_files"],
+        conflict: false,
+        message: "--diff-threshold only applies to --diff.",
+    },
+    Rule {
+        arg: "diff_min_us",
+        others: &["diff_files"],
+        conflict: false,
+        message: "--diff-min-us only applies to --diff.",
+    },
+    Rule {
+        arg: "strip_suffix",
+        others: &["diff_files"],
+        conflict: false,
+        message: "--strip-suffix only applies to --diff.",
+    },
+    Rule {
+        arg: "pre",
+        others: &["trigger"],
+        conflict: false,
+        message: "--pre only applies to --trigger.",
+    },
+    Rule {
+        arg: "post",
+        others: &["trigger"],
+        conflict: false,
+        message: "--post only applies to --trigger.",
+    },
+    Rule {
+        arg: "max_window_bytes",
+        others: &["trigger"],
+        conflict: false,
+        message: "--max-window-bytes only applies to --trigger.",
+    },
+    Rule {
+        arg: "max_triggers",
+        others: &["trigger"],
+        conflict: false,
+        message: "--max-triggers only applies to --trigger.",
+    },
+    Rule {
+        arg: "full_threshold",
+        others: &["stop_on_full"],
+        conflict: false,
+        message: "--full-threshold only applies to --stop-on-full.",
+    },
+];
+
+// Check the given arguments against the rules, present telling whether an
+// argument, or "name=value", was given.
+fn check_rules(rules: &[Rule], present: &dyn Fn(&str) -> bool) -> Result<(), Error> {
+    for rule in rules.iter().filter(|rule| present(rule.arg)) {
+        let conflict = rule.conflict && rule.others.iter().any(|other| present(other));
+        let missing = !rule.conflict && !rule.others.iter().any(|other| present(other));
+        if conflict {
+            return Err(Error::with_description(
+                rule.message,
+                ErrorKind::ArgumentConflict,
+            ));
+        }
+        if missing {
+            return Err(Error::with_description(
+                rule.message,
+                ErrorKind::MissingRequiredArgument,
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_options(matches: &ArgMatches<'_>) -> Result<(), Error> {
+    check_rules(RULES, &|name| {
+        let mut parts = name.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(arg), Some(value)) => matches.value_of(arg) == Some(value),
+            _ => matches.is_present(name),
+        }
+    })
+}
+
+// Parse a duration like 2s, 500ms, 100us or 1m into seconds, a plain number
+// being seconds.
+pub fn parse_duration(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let scale = match unit {
+        "" | "s" => 1.0,
+        "ms" => 0.001,
+        "us" => 0.000_001,
+        "m" => 60.0,
+        _ => return None,
+    };
+    Some(number * scale)
+}
+
+// Parse a size like 64M, 512K or 1G into bytes, a plain number being bytes.
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+// Parse a speed factor like 10x, 0.5x or 2.
+fn parse_speed(value: &str) -> Option<f64> {
+    value
+        .strip_suffix('x')
+        .unwrap_or(value)
+        .parse::<f64>()
+        .ok()
+        .filter(|speed| speed.is_finite() && *speed > 0.0)
+}
+
+fn build_app() -> App<'static, 'static> {
+    App::new("atrace")
         .version(crate_version!())
         .author(crate_authors!())
         .about("Launch atrace.")
+        .long_about(LONG_ABOUT)
         .arg(
             Arg::with_name("A")
                 .short("A")
@@ -74,6 +560,293 @@ pub fn parse_options() -> Config {
                 .number_of_values(1)
                 .help("uncompress trace file which maybe -Z trace output."),
         )
+        .arg(
+            Arg::with_name("no_verify")
+                .long("no-verify")
+                .help("read what is left of a -Z capture failing its integrity check, for salvage."),
+        )
+        .arg(
+            Arg::with_name("status_file")
+                .long("status")
+                .takes_value(true)
+                .help(
+                    "show the status of a dumped trace file, including its tracing-atrace header.",
+                ),
+        )
+        .arg(
+            Arg::with_name("convert_file")
+                .long("convert")
+                .takes_value(true)
+                .help("convert a dumped trace file to the output format."),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .takes_value(true)
+                .help("capture on a target over ssh://[user@]host[:port] or adb://[serial], writing the dump or --format output locally. Needs the ssh or adb feature."),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "perfetto", "csv", "svg"])
+                .help("the output format of --convert and --remote, json for --convert by default. perfetto needs the perfetto feature."),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("write converted output to the file instead of stdout."),
+        )
+        .arg(
+            Arg::with_name("csv_counters")
+                .long("csv-counters")
+                .takes_value(true)
+                .help("also write the counter samples to the file with --format csv."),
+        )
+        .arg(
+            Arg::with_name("annotations")
+                .long("annotations")
+                .takes_value(true)
+                .help("notes file for --convert and --summary, one RFC3339-timestamp<TAB>text per line."),
+        )
+        .arg(
+            Arg::with_name("counter_derive")
+                .long("counter-derive")
+                .takes_value(true)
+                .help("counters of running totals, comma separated as name or name:window, to add a per-second rate track for in the --convert output."),
+        )
+        .arg(
+            Arg::with_name("counter_scale")
+                .long("counter-scale")
+                .takes_value(true)
+                .help("counters to add a scaled track for in the --convert output, comma separated as name:factor. Rate tracks are named <name>/s."),
+        )
+        .arg(
+            Arg::with_name("pid_map")
+                .long("pid-map")
+                .takes_value(true)
+                .help("rewrite container marker pids to host pids at --convert, as host:container,..."),
+        )
+        .arg(
+            Arg::with_name("time_base")
+                .long("time-base")
+                .takes_value(true)
+                .possible_values(&["absolute", "relative"])
+                .help("csv timestamps on the trace clock or from the capture start, relative by default."),
+        )
+        .arg(
+            Arg::with_name("window")
+                .long("window")
+                .takes_value(true)
+                .help("time range drawn by --format svg, from the capture start, like 1s..2500ms."),
+        )
+        .arg(
+            Arg::with_name("synthetic_intervals")
+                .long("synthetic-intervals")
+                .takes_value(true)
+                .help("draw a global instant every period, like 16.6ms, in the --convert json output, to spot missed deadlines."),
+        )
+        .arg(
+            Arg::with_name("align_to")
+                .long("align-to")
+                .takes_value(true)
+                .help("start the --synthetic-intervals at the first marker of this name instead of the capture start."),
+        )
+        .arg(
+            Arg::with_name("auto_skew_correct")
+                .long("auto-skew-correct")
+                .takes_value(false)
+                .help("estimate the offset of the marker clock against the sched_switch events and shift the markers by it in the --convert output."),
+        )
+        .arg(
+            Arg::with_name("gap_threshold")
+                .long("gap-threshold")
+                .takes_value(true)
+                .help("a stretch without events longer than this, like 5s, is reported as a possible suspend, 5s by default, 0 to turn off."),
+        )
+        .arg(
+            Arg::with_name("include_gaps")
+                .long("include-gaps")
+                .help("keep suspend gaps and slices across clock jumps in --summary and --diff durations."),
+        )
+        .arg(
+            Arg::with_name("summary_file")
+                .long("summary")
+                .takes_value(true)
+                .help(
+                    "summarize durations of markers and kernel functions in a dumped trace file.",
+                ),
+        )
+        .arg(
+            Arg::with_name("top")
+                .long("top")
+                .takes_value(true)
+                .help("the number of names shown by --summary, 20 by default."),
+        )
+        .arg(
+            Arg::with_name("diff_files")
+                .long("diff")
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["before", "after"])
+                .help("compare the marker summaries of two dumped trace files."),
+        )
+        .arg(
+            Arg::with_name("diff_threshold")
+                .long("diff-threshold")
+                .takes_value(true)
+                .help("minimum total time change in percent shown by --diff, 5 by default."),
+        )
+        .arg(
+            Arg::with_name("diff_min_us")
+                .long("diff-min-us")
+                .takes_value(true)
+                .help("minimum total time change in microseconds shown by --diff, 100 by default."),
+        )
+        .arg(
+            Arg::with_name("strip_suffix")
+                .long("strip-suffix")
+                .takes_value(true)
+                .help("regex removed from marker names before --diff matches them."),
+        )
+        .arg(
+            Arg::with_name("doctor")
+                .long("doctor")
+                .takes_value(false)
+                .help("write a probe marker and check that it reaches the kernel trace buffer."),
+        )
+        .arg(
+            Arg::with_name("trigger")
+                .long("trigger")
+                .takes_value(true)
+                .help("watch trace_pipe and dump the lines around each line matching the regex."),
+        )
+        .arg(
+            Arg::with_name("pre")
+                .long("pre")
+                .takes_value(true)
+                .help("duration of lines kept before a --trigger match, like 2s or 500ms, 2s by default."),
+        )
+        .arg(
+            Arg::with_name("post")
+                .long("post")
+                .takes_value(true)
+                .help("duration of lines collected after a --trigger match, 1s by default."),
+        )
+        .arg(
+            Arg::with_name("max_window_bytes")
+                .long("max-window-bytes")
+                .takes_value(true)
+                .help("bytes of lines kept before and after a --trigger match, 16MiB by default."),
+        )
+        .arg(
+            Arg::with_name("max_triggers")
+                .long("max-triggers")
+                .takes_value(true)
+                .help("stop watching after this many --trigger dumps, 1 by default, 0 for no limit."),
+        )
+        .arg(
+            Arg::with_name("stop_on_full")
+                .long("stop-on-full")
+                .takes_value(false)
+                .help("stop tracing early once a cpu buffer is filled over --full-threshold."),
+        )
+        .arg(
+            Arg::with_name("full_threshold")
+                .long("full-threshold")
+                .takes_value(true)
+                .help("buffer fill in percent stopping --stop-on-full captures, 90 by default."),
+        )
+        .arg(
+            Arg::with_name("result")
+                .long("result")
+                .takes_value(true)
+                .help("write a json file telling how long the capture ran and why it stopped."),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .help("check the dumped capture is consistent, exiting with 5 and listing the violations in --result when not."),
+        )
+        .arg(
+            Arg::with_name("expect_markers")
+                .long("expect-markers")
+                .takes_value(true)
+                .help("fail --verify with fewer tracing_mark_write lines than this."),
+        )
+        .arg(
+            Arg::with_name("allow_overruns")
+                .long("allow-overruns")
+                .help("don't fail --verify on events lost or overwritten in the buffer."),
+        )
+        .arg(
+            Arg::with_name("export_config")
+                .long("export-effective-config")
+                .takes_value(true)
+                .help("after setup, write the trace events actually enabled on this kernel as a profile file."),
+        )
+        .arg(
+            Arg::with_name("import_config")
+                .long("import-config")
+                .takes_value(true)
+                .help("enable the trace events of a profile written by --export-effective-config."),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .takes_value(false)
+                .help("fail if this kernel lacks an --import-config event, the default."),
+        )
+        .arg(
+            Arg::with_name("best_effort")
+                .long("best-effort")
+                .takes_value(false)
+                .help("enable the --import-config events this kernel has and report the others."),
+        )
+        .arg(
+            Arg::with_name("system_metadata")
+                .long("system-metadata")
+                .takes_value(false)
+                .help("record kernel cmdline, preemption, sched features, online cpus and cpufreq governors in the trace."),
+        )
+        .arg(
+            Arg::with_name("tracefs")
+                .long("tracefs")
+                .takes_value(true)
+                .help("the tracefs directory to use, by default the top level if writable, else the first writable directory under instances/."),
+        )
+        .arg(
+            Arg::with_name("print_marker_path")
+                .long("print-marker-path")
+                .takes_value(false)
+                .help("print the trace_marker path of the selected tracefs directory, for applications to write their markers to."),
+        )
+        .arg(
+            Arg::with_name("max_output_bytes")
+                .long("max-output-bytes")
+                .takes_value(true)
+                .help("stop writing dump, stream and convert output past this many bytes, no limit by default."),
+        )
+        .arg(
+            Arg::with_name("max_memory_mb")
+                .long("max-memory-mb")
+                .takes_value(true)
+                .help("limit the memory of atrace's own trace buffers, no limit by default."),
+        )
+        .arg(
+            Arg::with_name("nice")
+                .long("nice")
+                .takes_value(true)
+                .help("nice value set before dump and convert work."),
+        )
+        .arg(
+            Arg::with_name("idle_io")
+                .long("idle-io")
+                .takes_value(false)
+                .help("use the idle io class for dump and convert work."),
+        )
         .arg(
             Arg::with_name("G")
                 .short("G")
@@ -82,42 +855,223 @@ pub fn parse_options() -> Config {
         )
         .arg(
             Arg::with_name("BEGIN_ASYNC")
-                .long("BEGIN_ASYNC")
+                .long("async-start")
+                .alias("BEGIN_ASYNC")
                 .help("begin trace and rapidly return")
                 .takes_value(false),
         )
         .arg(
             Arg::with_name("DUMP_ASYNC")
-                .long("DUMP_ASYNC")
+                .long("async-dump")
+                .alias("DUMP_ASYNC")
                 .help("dump the trace buffer")
                 .takes_value(false),
         )
         .arg(
             Arg::with_name("STOP_ASYNC")
-                .long("STOP_ASYNC")
+                .long("async-stop")
+                .alias("STOP_ASYNC")
                 .help("stop tracing and rapidly dump buffer")
                 .takes_value(false),
         )
         .arg(
             Arg::with_name("SHOW_CATEGORY")
-                .long("SHOW_CATEGORY")
+                .long("list-categories")
+                .alias("SHOW_CATEGORY")
                 .help("show all the categories")
                 .takes_value(false),
         )
         .arg(
             Arg::with_name("STREAM")
-                .long("STREAM")
+                .long("stream")
+                .alias("STREAM")
                 .help("stream trace to stdout")
                 .takes_value(false),
         )
         .arg(Arg::with_name("Group").multiple(true))
         .arg(
             Arg::with_name("CPU_SCHED")
-                .long("CPU_SCHED")
+                .long("cpu-sched")
+                .alias("CPU_SCHED")
                 .help("capture all cpu schedule infos")
                 .takes_value(false),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("trace one run of a command and dump the trace to a file.")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("the trace file, atrace-run-<command>-<time>.txt by default."),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .multiple(true)
+                        .required(true)
+                        .help("the command and its arguments, after --"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("write the part of a dumped trace file within a time range, in the same format.")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .help("start of the range on the trace clock like 12.345s, or after the first event like +500ms."),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .help("end of the range, in the same forms as --from."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("write the range to the file instead of stdout."),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("the dumped trace file."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("flight")
+                .about("record trace_pipe continuously into rotating compressed segments, keeping the latest ones.")
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the directory of the segments and their index.tsv."),
+                )
+                .arg(
+                    Arg::with_name("segment")
+                        .long("segment")
+                        .takes_value(true)
+                        .help("trace text per segment before compression, like 64M or 512K, 64M by default."),
+                )
+                .arg(
+                    Arg::with_name("keep")
+                        .long("keep")
+                        .takes_value(true)
+                        .help("segments kept, older ones are deleted, 10 by default."),
+                )
+                .arg(
+                    Arg::with_name("seal")
+                        .long("seal")
+                        .takes_value(false)
+                        .help("make the recorder writing to --dir close its open segment, then print the index."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("write the markers of a captured trace into the live trace_marker at their original pace.")
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .takes_value(true)
+                        .help("pace factor like 10x or 0.5x, 1x by default."),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("the dumped trace file."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("merge the marker summaries of the trace files in a directory into one report.")
+                .arg(
+                    Arg::with_name("glob")
+                        .long("glob")
+                        .takes_value(true)
+                        .help("the file names to include, like 'trace-*.z', all files by default."),
+                )
+                .arg(
+                    Arg::with_name("trend")
+                        .long("trend")
+                        .takes_value(true)
+                        .help("marker names, comma separated, whose p99 per file is reported in file order."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["json", "csv"])
+                        .help("the report format, json by default."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("write the report to the file instead of stdout."),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("the directory of trace files."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("keep tracing set up but disabled, capturing on commands sent to a control socket.")
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .takes_value(true)
+                        .help("the control socket, /run/atrace.sock by default. Only its owner may connect."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ctl")
+                .about("send a command to a running atrace daemon.")
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .takes_value(true)
+                        .help("the control socket, /run/atrace.sock by default."),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .multiple(true)
+                        .required(true)
+                        .help("start, stop, dump <path>, snapshot <path> or status."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pick")
+                .about("choose trace events from a tree of the available ones, and print or save them as a profile.")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("save the profile to the file instead of printing it."),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(false)
+                        .requires("output")
+                        .help("start a capture with the saved profile once confirmed, using the capture options given before pick."),
+                ),
+        )
+}
+
+pub fn parse_options() -> Config {
+    let cmd_arguments = build_app().get_matches();
+    if let Err(e) = validate_options(&cmd_arguments) {
+        e.exit();
+    }
 
     let debug_cmdline = cmd_arguments.value_of("A").unwrap_or("").to_string();
     let buflen = cmd_arguments
@@ -144,6 +1098,257 @@ pub fn parse_options() -> Config {
         .value_of("trace_file")
         .unwrap_or("")
         .to_string();
+    let status_file = cmd_arguments
+        .value_of("status_file")
+        .unwrap_or("")
+        .to_string();
+    let no_verify = cmd_arguments.is_present("no_verify");
+    let doctor = cmd_arguments.is_present("doctor");
+    let convert_file = cmd_arguments
+        .value_of("convert_file")
+        .unwrap_or("")
+        .to_string();
+    // Left empty when not given, --remote then writes the raw dump.
+    let format = cmd_arguments
+        .subcommand_matches("report")
+        .and_then(|report| report.value_of("format"))
+        .or_else(|| cmd_arguments.value_of("format"))
+        .unwrap_or("")
+        .to_string();
+    let remote = cmd_arguments.value_of("remote").unwrap_or("").to_string();
+    let run = cmd_arguments.subcommand_matches("run");
+    let run_command = run
+        .and_then(|run| run.values_of("command"))
+        .map(|vals| vals.map(|val| val.to_string()).collect())
+        .unwrap_or_default();
+    let extract = cmd_arguments.subcommand_matches("extract");
+    let flight = cmd_arguments.subcommand_matches("flight");
+    let flight_dir = flight
+        .and_then(|flight| flight.value_of("dir"))
+        .unwrap_or("")
+        .to_string();
+    let flight_segment = flight
+        .and_then(|flight| flight.value_of("segment"))
+        .map_or(Some(64 << 20), parse_size)
+        .filter(|size| *size > 0)
+        .unwrap_or_else(|| {
+            Error::with_description(
+                "--segment expects a size like 64M.",
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+    let flight_keep = flight
+        .and_then(|flight| flight.value_of("keep"))
+        .unwrap_or("10")
+        .parse::<usize>()
+        .ok()
+        .filter(|keep| *keep > 0)
+        .unwrap_or_else(|| {
+            Error::with_description(
+                "--keep expects a count above zero.",
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+    let flight_seal = flight.is_some_and(|flight| flight.is_present("seal"));
+    let replay = cmd_arguments.subcommand_matches("replay");
+    let replay_file = replay
+        .and_then(|replay| replay.value_of("file"))
+        .unwrap_or("")
+        .to_string();
+    let replay_speed = replay
+        .and_then(|replay| replay.value_of("speed"))
+        .map_or(Some(1.0), parse_speed)
+        .unwrap_or_else(|| {
+            Error::with_description(
+                "--speed expects a factor above zero like 10x.",
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+    let extract_file = extract
+        .and_then(|extract| extract.value_of("file"))
+        .unwrap_or("")
+        .to_string();
+    let extract_from = extract
+        .and_then(|extract| extract.value_of("from"))
+        .unwrap_or("")
+        .to_string();
+    let extract_to = extract
+        .and_then(|extract| extract.value_of("to"))
+        .unwrap_or("")
+        .to_string();
+    let report = cmd_arguments.subcommand_matches("report");
+    let report_dir = report
+        .and_then(|report| report.value_of("dir"))
+        .unwrap_or("")
+        .to_string();
+    let report_glob = report
+        .and_then(|report| report.value_of("glob"))
+        .unwrap_or("*")
+        .to_string();
+    let report_trend = report
+        .and_then(|report| report.value_of("trend"))
+        .map(|trend| {
+            trend
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    let daemon = cmd_arguments.subcommand_matches("daemon");
+    let ctl = cmd_arguments.subcommand_matches("ctl");
+    let socket = daemon
+        .and_then(|daemon| daemon.value_of("socket"))
+        .or_else(|| ctl.and_then(|ctl| ctl.value_of("socket")))
+        .unwrap_or("/run/atrace.sock")
+        .to_string();
+    let ctl_command = ctl
+        .and_then(|ctl| ctl.values_of("command"))
+        .map(|vals| vals.map(|val| val.to_string()).collect())
+        .unwrap_or_default();
+    let daemon = daemon.is_some();
+    let pick = cmd_arguments.subcommand_matches("pick");
+    let pick_start = pick.is_some_and(|pick| pick.is_present("start"));
+    let output = run
+        .and_then(|run| run.value_of("output"))
+        .or_else(|| extract.and_then(|extract| extract.value_of("output")))
+        .or_else(|| report.and_then(|report| report.value_of("output")))
+        .or_else(|| pick.and_then(|pick| pick.value_of("output")))
+        .or_else(|| cmd_arguments.value_of("output"))
+        .unwrap_or("")
+        .to_string();
+    let csv_counters = cmd_arguments
+        .value_of("csv_counters")
+        .unwrap_or("")
+        .to_string();
+    let annotations = cmd_arguments
+        .value_of("annotations")
+        .unwrap_or("")
+        .to_string();
+    let pid_map = cmd_arguments.value_of("pid_map").unwrap_or("").to_string();
+    let counter_derive = cmd_arguments
+        .value_of("counter_derive")
+        .unwrap_or("")
+        .to_string();
+    let counter_scale = cmd_arguments
+        .value_of("counter_scale")
+        .unwrap_or("")
+        .to_string();
+    let time_base = cmd_arguments
+        .value_of("time_base")
+        .unwrap_or("relative")
+        .to_string();
+    let window = cmd_arguments.value_of("window").unwrap_or("").to_string();
+    let summary_file = cmd_arguments
+        .value_of("summary_file")
+        .unwrap_or("")
+        .to_string();
+    let top = cmd_arguments
+        .value_of("top")
+        .unwrap_or("20")
+        .parse::<usize>()
+        .unwrap();
+    let diff_files = cmd_arguments
+        .values_of("diff_files")
+        .map(|vals| vals.map(|val| val.to_string()).collect())
+        .unwrap_or_default();
+    let diff_threshold = cmd_arguments
+        .value_of("diff_threshold")
+        .unwrap_or("5")
+        .parse::<f64>()
+        .unwrap();
+    let diff_min_us = cmd_arguments
+        .value_of("diff_min_us")
+        .unwrap_or("100")
+        .parse::<f64>()
+        .unwrap();
+    let strip_suffix = cmd_arguments
+        .value_of("strip_suffix")
+        .unwrap_or("")
+        .to_string();
+    let gap_threshold = cmd_arguments
+        .value_of("gap_threshold")
+        .map_or(Some(5.0), parse_duration)
+        .unwrap_or_else(|| {
+            Error::with_description(
+                "--gap-threshold expects a duration like 5s or 500ms.",
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+    let synthetic_interval = cmd_arguments
+        .value_of("synthetic_intervals")
+        .map_or(Some(0.0), |value| {
+            parse_duration(value).filter(|period| *period > 0.0)
+        })
+        .unwrap_or_else(|| {
+            Error::with_description(
+                "--synthetic-intervals expects a period above zero like 16.6ms.",
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+    let align_to = cmd_arguments.value_of("align_to").unwrap_or("").to_string();
+    let auto_skew_correct = cmd_arguments.is_present("auto_skew_correct");
+    let include_gaps = cmd_arguments.is_present("include_gaps");
+    let trigger = cmd_arguments.value_of("trigger").unwrap_or("").to_string();
+    let pre = parse_duration(cmd_arguments.value_of("pre").unwrap_or("2s")).unwrap();
+    let post = parse_duration(cmd_arguments.value_of("post").unwrap_or("1s")).unwrap();
+    let max_window_bytes = cmd_arguments
+        .value_of("max_window_bytes")
+        .unwrap_or("16777216")
+        .parse::<usize>()
+        .unwrap();
+    let max_triggers = cmd_arguments
+        .value_of("max_triggers")
+        .unwrap_or("1")
+        .parse::<usize>()
+        .unwrap();
+    let stop_on_full = cmd_arguments.is_present("stop_on_full");
+    let full_threshold = cmd_arguments
+        .value_of("full_threshold")
+        .unwrap_or("90")
+        .parse::<f64>()
+        .unwrap();
+    let result = cmd_arguments.value_of("result").unwrap_or("").to_string();
+    let verify = cmd_arguments.is_present("verify");
+    let expect_markers = cmd_arguments
+        .value_of("expect_markers")
+        .unwrap_or("0")
+        .parse::<usize>()
+        .unwrap();
+    let allow_overruns = cmd_arguments.is_present("allow_overruns");
+    let export_config = cmd_arguments
+        .value_of("export_config")
+        .unwrap_or("")
+        .to_string();
+    let import_config = cmd_arguments
+        .value_of("import_config")
+        .unwrap_or("")
+        .to_string();
+    let best_effort = cmd_arguments.is_present("best_effort");
+    let system_metadata = cmd_arguments.is_present("system_metadata");
+    let tracefs = cmd_arguments.value_of("tracefs").unwrap_or("").to_string();
+    let print_marker_path = cmd_arguments.is_present("print_marker_path");
+    let max_output_bytes = cmd_arguments
+        .value_of("max_output_bytes")
+        .unwrap_or("0")
+        .parse::<u64>()
+        .unwrap();
+    let max_memory_mb = cmd_arguments
+        .value_of("max_memory_mb")
+        .unwrap_or("0")
+        .parse::<usize>()
+        .unwrap();
+    let nice = cmd_arguments
+        .value_of("nice")
+        .unwrap_or("0")
+        .parse::<i32>()
+        .unwrap();
+    let idle_io = cmd_arguments.is_present("idle_io");
     let tgid = !cmd_arguments.is_present("G");
 
     let begin_async = cmd_arguments.is_present("BEGIN_ASYNC");
@@ -164,6 +1369,71 @@ pub fn parse_options() -> Config {
         durationsec,
         compress,
         uncompress_file,
+        status_file,
+        no_verify,
+        doctor,
+        convert_file,
+        format,
+        output,
+        csv_counters,
+        annotations,
+        pid_map,
+        verify,
+        expect_markers,
+        allow_overruns,
+        counter_derive,
+        counter_scale,
+        time_base,
+        remote,
+        window,
+        synthetic_interval,
+        align_to,
+        auto_skew_correct,
+        summary_file,
+        top,
+        diff_files,
+        diff_threshold,
+        diff_min_us,
+        strip_suffix,
+        gap_threshold,
+        include_gaps,
+        trigger,
+        pre,
+        post,
+        max_window_bytes,
+        max_triggers,
+        stop_on_full,
+        full_threshold,
+        result,
+        export_config,
+        import_config,
+        best_effort,
+        capture_id: String::new(),
+        system_metadata,
+        run_command,
+        extract_file,
+        replay_file,
+        flight_dir,
+        flight_segment,
+        flight_keep,
+        flight_seal,
+        replay_speed,
+        extract_from,
+        extract_to,
+        report_dir,
+        report_glob,
+        report_trend,
+        daemon,
+        socket,
+        ctl_command,
+        pick: pick.is_some(),
+        pick_start,
+        max_output_bytes,
+        tracefs,
+        print_marker_path,
+        max_memory_mb,
+        nice,
+        idle_io,
         tgid,
         begin_async,
         stop_async,
@@ -174,3 +1444,180 @@ pub fn parse_options() -> Config {
         cpu_sched,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Validate the arguments as given on the command line, after "atrace".
+    fn validate(args: &[&str]) -> Result<(), Error> {
+        let matches = build_app()
+            .get_matches_from_safe(std::iter::once("atrace").chain(args.iter().copied()))?;
+        validate_options(&matches)
+    }
+
+    #[test]
+    fn valid_argument_sets() {
+        let valid: &[&[&str]] = &[
+            &[],
+            &["-T", "5", "-Z"],
+            &["--stream"],
+            &["--async-start"],
+            &["--trigger", "error", "--pre", "2s", "--post", "1s"],
+            &["--stop-on-full", "--full-threshold", "80"],
+            &["--uncompress", "trace.z", "--no-verify"],
+            &["--convert", "trace.txt", "--synthetic-intervals", "16ms"],
+            &[
+                "--convert",
+                "trace.txt",
+                "--synthetic-intervals",
+                "16ms",
+                "--align-to",
+                "vsync",
+            ],
+            &[
+                "--convert",
+                "trace.txt",
+                "--format",
+                "perfetto",
+                "--counter-scale",
+                "rx:2",
+            ],
+            &["--convert", "trace.txt", "--auto-skew-correct"],
+            &[
+                "-T",
+                "5",
+                "--verify",
+                "--expect-markers",
+                "3",
+                "--allow-overruns",
+            ],
+            &["--import-config", "events.toml", "--strict"],
+        ];
+        for args in valid {
+            if let Err(e) = validate(args) {
+                panic!("{:?} rejected: {}", args, e.message);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_argument_sets() {
+        let invalid: &[(&[&str], ErrorKind, &str)] = &[
+            (
+                &["--stream", "-Z"],
+                ErrorKind::ArgumentConflict,
+                "--stream writes plain text",
+            ),
+            (
+                &["--async-start", "-T", "5"],
+                ErrorKind::ArgumentConflict,
+                "-T has no effect",
+            ),
+            (
+                &["--trigger", "error", "--stream"],
+                ErrorKind::ArgumentConflict,
+                "--trigger streams trace_pipe itself",
+            ),
+            (
+                &["--pre", "2s"],
+                ErrorKind::MissingRequiredArgument,
+                "--pre only applies to --trigger.",
+            ),
+            (
+                &["--convert", "trace.txt", "-Z"],
+                ErrorKind::ArgumentConflict,
+                "--convert only reads a trace file",
+            ),
+            (
+                &["--status", "trace.txt", "--stream"],
+                ErrorKind::ArgumentConflict,
+                "--status only reads a trace file",
+            ),
+            (
+                &["--no-verify"],
+                ErrorKind::MissingRequiredArgument,
+                "--no-verify only applies",
+            ),
+            (
+                &[
+                    "--convert",
+                    "trace.txt",
+                    "--format",
+                    "csv",
+                    "--synthetic-intervals",
+                    "16ms",
+                ],
+                ErrorKind::ArgumentConflict,
+                "--synthetic-intervals is only drawn in the json output.",
+            ),
+            (
+                &["--convert", "trace.txt", "--align-to", "vsync"],
+                ErrorKind::MissingRequiredArgument,
+                "--align-to needs --synthetic-intervals.",
+            ),
+            (
+                &[
+                    "--convert",
+                    "trace.txt",
+                    "--format",
+                    "svg",
+                    "--counter-scale",
+                    "rx:2",
+                ],
+                ErrorKind::ArgumentConflict,
+                "--counter-scale tracks are only in the json and perfetto output.",
+            ),
+            (
+                &["--counter-derive", "rx"],
+                ErrorKind::MissingRequiredArgument,
+                "--counter-derive only applies to --convert.",
+            ),
+            (
+                &["--verify", "--stream"],
+                ErrorKind::ArgumentConflict,
+                "--verify checks the capture as dumped",
+            ),
+            (
+                &["--expect-markers", "3"],
+                ErrorKind::MissingRequiredArgument,
+                "--expect-markers only applies to --verify.",
+            ),
+            (
+                &["--strict"],
+                ErrorKind::MissingRequiredArgument,
+                "--strict needs --import-config.",
+            ),
+            (
+                &[
+                    "--import-config",
+                    "events.toml",
+                    "--strict",
+                    "--best-effort",
+                ],
+                ErrorKind::ArgumentConflict,
+                "--strict and --best-effort can't be combined.",
+            ),
+            (
+                &["--full-threshold", "80"],
+                ErrorKind::MissingRequiredArgument,
+                "--full-threshold only applies to --stop-on-full.",
+            ),
+        ];
+        for (args, kind, message) in invalid {
+            match validate(args) {
+                Ok(()) => panic!("{:?} accepted", args),
+                Err(e) => {
+                    assert_eq!(e.kind, *kind, "{:?}: {}", args, e.message);
+                    assert!(
+                        e.message.contains(message),
+                        "{:?}: {:?} doesn't tell {:?}",
+                        args,
+                        e.message,
+                        message
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+// Guardrails for running atrace or the traced apps inside a container.
+//
+// Apps in a PID namespace write their namespace-local pid into markers while
+// the kernel records host pids, and tracefs is often bind-mounted read-only.
+// The checks take the /proc file contents so they work on any snapshot.
+
+use std::collections::BTreeMap;
+
+use crate::capture::Capture;
+
+// The pids of the process from the outermost to its own PID namespace, from
+// the NSpid line of /proc/<pid>/status.
+pub fn nspids(status: &str) -> Vec<i32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))
+        .map(|pids| {
+            pids.split_whitespace()
+                .filter_map(|pid| pid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Whether /proc/<pid>/status tells the process runs in a nested PID namespace.
+pub fn in_pid_namespace(status: &str) -> bool {
+    nspids(status).len() > 1
+}
+
+// Whether the mount holding the path is read-only, from /proc/mounts.
+// None when no mount covers the path.
+pub fn is_read_only_mount(mounts: &str, path: &str) -> Option<bool> {
+    let path = path.trim_end_matches('/');
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 {
+                return None;
+            }
+            let mount_point = fields[1].trim_end_matches('/');
+            let covers = path == mount_point
+                || mount_point.is_empty()
+                || path.starts_with(&format!("{}/", mount_point));
+            if covers {
+                Some((mount_point.len(), fields[3]))
+            } else {
+                None
+            }
+        })
+        // The longest mount point is the one the path lives on.
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, options)| options.split(',').any(|option| option == "ro"))
+}
+
+// Warnings with remediation for the container setups which break a capture.
+pub fn check_environment(status: &str, mounts: &str, tracefs: &str) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if in_pid_namespace(status) {
+        warnings.push(
+            "atrace runs in a PID namespace: marker pids written by apps in it won't match the \
+             kernel pids. Run the container with --pid=host, or map them with --pid-map at \
+             convert time."
+                .to_string(),
+        );
+    }
+    if is_read_only_mount(mounts, tracefs) == Some(true) {
+        warnings.push(format!(
+            "{} is mounted read-only: tracing can't be configured. Mount tracefs read-write \
+             in the container, e.g. -v /sys/kernel/debug:/sys/kernel/debug:rw.",
+            tracefs
+        ));
+    }
+    warnings
+}
+
+// Parse a "host:container,..." pid list into a container to host pid map.
+pub fn parse_pid_map(value: &str) -> Result<BTreeMap<i32, i32>, String> {
+    let mut map = BTreeMap::new();
+    for pair in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let mut pids = pair.splitn(2, ':').map(|pid| pid.trim().parse::<i32>());
+        match (pids.next(), pids.next()) {
+            (Some(Ok(host)), Some(Ok(container))) => {
+                map.insert(container, host);
+            }
+            _ => {
+                return Err(format!(
+                    "invalid pid mapping {:?}, expected host:container",
+                    pair
+                ))
+            }
+        }
+    }
+    Ok(map)
+}
+
+// Rewrite the namespace pids carried by markers into host pids, returning
+// how many were rewritten.
+pub fn apply_pid_map(capture: &mut Capture, map: &BTreeMap<i32, i32>) -> usize {
+    let mut rewritten = 0;
+    let mut rewrite = |pid: &mut i32| {
+        if let Some(host) = map.get(pid) {
+            *pid = *host;
+            rewritten += 1;
+        }
+    };
+    for thread in capture.threads.values_mut() {
+        rewrite(&mut thread.pid);
+    }
+    for slice in capture.slices.iter_mut() {
+        rewrite(&mut slice.pid);
+    }
+    for slice in capture.async_slices.iter_mut() {
+        rewrite(&mut slice.pid);
+    }
+    for counter in capture.counters.iter_mut() {
+        rewrite(&mut counter.pid);
+    }
+    for instant in capture.instants.iter_mut() {
+        rewrite(&mut instant.pid);
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_STATUS: &str = "Name:\tatrace\nTgid:\t4321\nPid:\t4321\nPPid:\t1\nNSpid:\t4321\n";
+    const CONTAINER_STATUS: &str =
+        "Name:\tatrace\nTgid:\t4321\nPid:\t4321\nNSpid:\t4321\t17\nNSpgid:\t4321\t17\n";
+
+    const MOUNTS: &str = "\
+overlay / overlay rw,relatime,lowerdir=/l,upperdir=/u 0 0
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+sysfs /sys sysfs ro,nosuid,nodev,noexec,relatime 0 0
+debugfs /sys/kernel/debug debugfs rw,relatime 0 0
+tracefs /sys/kernel/tracing tracefs ro,relatime 0 0
+";
+
+    #[test]
+    fn nspids_come_from_the_status() {
+        assert_eq!(nspids(HOST_STATUS), vec![4321]);
+        assert_eq!(nspids(CONTAINER_STATUS), vec![4321, 17]);
+        assert!(nspids("Name:\told kernel\n").is_empty());
+        assert!(!in_pid_namespace(HOST_STATUS));
+        assert!(in_pid_namespace(CONTAINER_STATUS));
+    }
+
+    #[test]
+    fn the_longest_mount_point_decides() {
+        assert_eq!(
+            is_read_only_mount(MOUNTS, "/sys/kernel/debug/tracing/"),
+            Some(false)
+        );
+        assert_eq!(
+            is_read_only_mount(MOUNTS, "/sys/kernel/tracing"),
+            Some(true)
+        );
+        assert_eq!(is_read_only_mount(MOUNTS, "/sys/kernel/mm"), Some(true));
+        assert_eq!(is_read_only_mount(MOUNTS, "/proc/self"), Some(false));
+        // A path sharing a prefix with a mount point isn't under it.
+        assert_eq!(is_read_only_mount(MOUNTS, "/system"), Some(false));
+        assert_eq!(is_read_only_mount("", "/sys"), None);
+    }
+
+    #[test]
+    fn warnings_name_the_remediation() {
+        assert!(check_environment(HOST_STATUS, MOUNTS, "/sys/kernel/debug/tracing/").is_empty());
+        let warnings = check_environment(CONTAINER_STATUS, MOUNTS, "/sys/kernel/tracing/");
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("--pid=host") && warnings[0].contains("--pid-map"));
+        assert!(warnings[1].starts_with("/sys/kernel/tracing/ is mounted read-only"));
+    }
+
+    #[test]
+    fn pid_maps_are_parsed() {
+        let map = parse_pid_map(" 4321:17, 4400:42 ,").unwrap();
+        assert_eq!(map.get(&17), Some(&4321));
+        assert_eq!(map.get(&42), Some(&4400));
+        assert_eq!(map.len(), 2);
+        assert!(parse_pid_map("").unwrap().is_empty());
+        for bad in &["4321", "4321:x", "a:17", "4321:17,5"] {
+            assert!(parse_pid_map(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn marker_pids_are_rewritten() {
+        let data = "\
+  app-4321  ( 4321) [000] ...1  10.000000: tracing_mark_write: B|17|draw
+  app-4321  ( 4321) [000] ...1  10.001000: tracing_mark_write: C|17|queue|3
+  app-4321  ( 4321) [000] ...1  10.002000: tracing_mark_write: I|17|tick
+  app-4321  ( 4321) [000] ...1  10.003000: tracing_mark_write: S|17|load|1
+  app-4321  ( 4321) [000] ...1  10.004000: tracing_mark_write: F|17|load|1
+  app-4321  ( 4321) [000] ...1  10.005000: tracing_mark_write: E|17
+  app-4321  ( 4321) [000] ...1  10.006000: tracing_mark_write: I|99|other
+";
+        let mut capture = Capture::from_bytes(data.as_bytes());
+        let map = parse_pid_map("4321:17").unwrap();
+        assert_eq!(apply_pid_map(&mut capture, &map), 5);
+        assert!(capture.threads.values().all(|thread| thread.pid == 4321));
+        assert!(capture.slices.iter().all(|slice| slice.pid == 4321));
+        assert!(capture.async_slices.iter().all(|slice| slice.pid == 4321));
+        assert!(capture.counters.iter().all(|counter| counter.pid == 4321));
+        let pids: Vec<i32> = capture.instants.iter().map(|instant| instant.pid).collect();
+        assert_eq!(pids, vec![4321, 99]);
+        assert_eq!(capture.slices.len(), 1);
+        assert_eq!(capture.async_slices.len(), 1);
+    }
+}
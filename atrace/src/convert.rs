@@ -0,0 +1,423 @@
+// Conversion of a capture into the Chrome Trace Event JSON format, which can
+// be loaded by chrome://tracing and the Perfetto UI.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+
+use crate::capture::Capture;
+use crate::gaps::GapKind;
+
+const HINT_TRACK: &str = "track";
+// Kernel function calls are shown as a "kernel" process with a thread per cpu.
+const KERNEL_PID: i32 = 0;
+// Annotations go to a process of their own, above any real pid.
+const ANNOTATIONS_PID: i32 = i32::MAX;
+const HINT_ARG_PREFIX: &str = "arg.";
+
+// Quote and escape a string for JSON output.
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Trace clock seconds to trace event microseconds.
+fn micros(seconds: f64) -> String {
+    format!("{:.3}", seconds * 1_000_000.0)
+}
+
+// Format key value pairs as a JSON object.
+fn json_object(pairs: &[(String, String)]) -> String {
+    let mut out = String::from("{");
+    for (index, (key, value)) in pairs.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}:{}", json_string(key), json_string(value));
+    }
+    out.push('}');
+    out
+}
+
+// An attribute value as a JSON number or bool when it reads back the same,
+// and as a string otherwise.
+fn json_value(value: &str) -> String {
+    let number = value
+        .parse::<i64>()
+        .map(|n| n.to_string())
+        .ok()
+        .or_else(|| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .map(|n| n.to_string())
+        });
+    match number {
+        Some(number) if number == value => number,
+        _ if value == "true" || value == "false" => value.to_string(),
+        _ => json_string(value),
+    }
+}
+
+// The args of a slice or instant: its arg hints, then its attributes.
+fn json_args(args: &[(String, String)], attrs: &[(String, String)]) -> String {
+    let fields: Vec<String> = args
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .chain(
+            attrs
+                .iter()
+                .map(|(key, value)| format!("{}:{}", json_string(key), json_value(value))),
+        )
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+// Write the capture as a trace event JSON document.
+pub fn write_json(capture: &Capture, out: &mut dyn Write) -> io::Result<()> {
+    let mut events: Vec<String> = Vec::new();
+
+    for (tid, thread) in capture.threads.iter() {
+        // Markers from non-kernel sinks carry no comm.
+        if thread.comm.is_empty() {
+            continue;
+        }
+        events.push(format!(
+            "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":{}}}}}",
+            thread.pid,
+            tid,
+            json_string(&thread.comm)
+        ));
+    }
+
+    for slice in capture.slices.iter() {
+        let mut track = None;
+        let mut args = Vec::new();
+        for (key, value) in slice.hints.iter() {
+            if key == HINT_TRACK {
+                track = Some(value);
+            } else if let Some(arg) = key.strip_prefix(HINT_ARG_PREFIX) {
+                args.push((arg.to_string(), value.clone()));
+            }
+        }
+        let args = json_args(&args, &slice.attrs);
+        match track {
+            // Slices with a track hint go to their own async track.
+            Some(track) => {
+                let track = json_string(track);
+                events.push(format!(
+                    "{{\"name\":{},\"cat\":{},\"ph\":\"b\",\"id\":{},\"ts\":{},\"pid\":{},\"tid\":{},\"args\":{}}}",
+                    json_string(capture.full_name(&slice.name)),
+                    track,
+                    track,
+                    micros(slice.start),
+                    slice.pid,
+                    slice.tid,
+                    args
+                ));
+                events.push(format!(
+                    "{{\"name\":{},\"cat\":{},\"ph\":\"e\",\"id\":{},\"ts\":{},\"pid\":{},\"tid\":{}}}",
+                    json_string(capture.full_name(&slice.name)),
+                    track,
+                    track,
+                    micros(slice.start + slice.duration),
+                    slice.pid,
+                    slice.tid
+                ));
+            }
+            None => {
+                events.push(format!(
+                    "{{\"name\":{},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":{},\"args\":{}}}",
+                    json_string(capture.full_name(&slice.name)),
+                    micros(slice.start),
+                    micros(slice.duration),
+                    slice.pid,
+                    slice.tid,
+                    args
+                ));
+            }
+        }
+    }
+
+    for slice in capture.async_slices.iter() {
+        for (phase, ts) in [("b", slice.start), ("e", slice.start + slice.duration)].iter() {
+            events.push(format!(
+                "{{\"name\":{},\"cat\":\"async\",\"ph\":\"{}\",\"id\":{},\"ts\":{},\"pid\":{}}}",
+                json_string(capture.full_name(&slice.name)),
+                phase,
+                slice.cookie,
+                micros(*ts),
+                slice.pid
+            ));
+        }
+    }
+
+    if !capture.kernel_calls.is_empty() {
+        events.push(format!(
+            "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"kernel\"}}}}",
+            KERNEL_PID
+        ));
+        let mut cpus: Vec<u32> = capture.kernel_calls.iter().map(|call| call.cpu).collect();
+        cpus.sort();
+        cpus.dedup();
+        for cpu in cpus {
+            events.push(format!(
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"cpu {}\"}}}}",
+                KERNEL_PID, cpu, cpu
+            ));
+        }
+    }
+    for call in capture.kernel_calls.iter() {
+        let args = match call.tid {
+            Some(tid) => format!("{{\"tid\":{},\"depth\":{}}}", tid, call.depth),
+            None => format!("{{\"depth\":{}}}", call.depth),
+        };
+        events.push(format!(
+            "{{\"name\":{},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":{},\"args\":{}}}",
+            json_string(&call.name),
+            micros(call.start),
+            micros(call.duration),
+            KERNEL_PID,
+            call.cpu,
+            args
+        ));
+    }
+
+    // JSON has no inf or NaN, such values are left out.
+    for counter in capture
+        .counters
+        .iter()
+        .filter(|counter| counter.value.is_finite())
+    {
+        events.push(format!(
+            "{{\"name\":{},\"ph\":\"C\",\"ts\":{},\"pid\":{},\"args\":{{{}:{}}}}}",
+            json_string(capture.full_name(&counter.name)),
+            micros(counter.timestamp),
+            counter.pid,
+            json_string(capture.full_name(&counter.name)),
+            counter.value
+        ));
+    }
+
+    for instant in capture.instants.iter() {
+        let args = if instant.attrs.is_empty() {
+            String::new()
+        } else {
+            format!(",\"args\":{}", json_args(&[], &instant.attrs))
+        };
+        events.push(format!(
+            "{{\"name\":{},\"ph\":\"i\",\"s\":\"t\",\"ts\":{},\"pid\":{},\"tid\":{}{}}}",
+            json_string(capture.full_name(&instant.name)),
+            micros(instant.timestamp),
+            instant.pid,
+            instant.tid,
+            args
+        ));
+    }
+
+    // Suspends and clock jumps as global instants at the last event before
+    // them.
+    for gap in capture.discontinuities.iter() {
+        let name = match gap.kind {
+            GapKind::Idle => "suspend?",
+            GapKind::Backwards => "clock jump?",
+        };
+        events.push(format!(
+            "{{\"name\":\"{}\",\"ph\":\"i\",\"s\":\"g\",\"ts\":{},\"pid\":0,\"tid\":0,\"args\":{{\"gap_ms\":{:.3},\"resume_ts\":{}}}}}",
+            name,
+            micros(gap.before),
+            gap.length() * 1_000.0,
+            micros(gap.after)
+        ));
+    }
+
+    // Interval ticks as global instants, drawn as lines across all tracks.
+    for (index, ts) in capture.intervals.iter().enumerate() {
+        events.push(format!(
+            "{{\"name\":\"interval\",\"ph\":\"i\",\"s\":\"g\",\"ts\":{},\"pid\":0,\"tid\":0,\"args\":{{\"index\":{}}}}}",
+            micros(*ts),
+            index
+        ));
+    }
+
+    if !capture.annotations.is_empty() {
+        events.push(format!(
+            "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"annotations\"}}}}",
+            ANNOTATIONS_PID
+        ));
+    }
+    for note in capture.annotations.iter() {
+        events.push(format!(
+            "{{\"name\":{},\"ph\":\"i\",\"s\":\"p\",\"ts\":{},\"pid\":{},\"tid\":0}}",
+            json_string(&note.name),
+            micros(note.timestamp),
+            ANNOTATIONS_PID
+        ));
+    }
+
+    let mut metadata = vec![(
+        "converter".to_string(),
+        format!("atrace {}", crate_version!()),
+    )];
+    if let Some(capture_id) = capture.capture_id.as_ref() {
+        metadata.push(("capture-id".to_string(), capture_id.clone()));
+    }
+    if let Some(header) = capture.header.as_ref() {
+        metadata.push(("tracing-atrace".to_string(), header.version.clone()));
+        metadata.push(("marker-format".to_string(), header.format.clone()));
+        metadata.push(("marker-fields".to_string(), header.fields.join(",")));
+        metadata.push(("producer-pid".to_string(), header.pid.to_string()));
+        metadata.push(("producer-exe".to_string(), header.exe.clone()));
+    }
+    for (key, value) in capture.metadata.iter() {
+        metadata.push((format!("system.{}", key), value.clone()));
+    }
+
+    writeln!(out, "{{\"traceEvents\":[")?;
+    for (index, event) in events.iter().enumerate() {
+        let separator = if index + 1 < events.len() { "," } else { "" };
+        writeln!(out, "{}{}", event, separator)?;
+    }
+    writeln!(
+        out,
+        "],\"displayTimeUnit\":\"ns\",\"metadata\":{}}}",
+        json_object(&metadata)
+    )?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(capture: &Capture) -> String {
+        let mut out = Vec::new();
+        write_json(capture, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn non_finite_counters_are_left_out() {
+        let mut capture = Capture::from_markers(&[
+            "C|1234|fine|2",
+            "C|1234|big|3",
+            "C|1234|small|4",
+            "C|1234|none|5",
+        ]);
+        capture.counters[1].value = f64::INFINITY;
+        capture.counters[2].value = f64::NEG_INFINITY;
+        capture.counters[3].value = f64::NAN;
+        let json = json(&capture);
+        assert!(json.contains("\"fine\""), "{}", json);
+        for name in &["big", "small", "none"] {
+            assert!(!json.contains(&format!("\"{}\"", name)), "{}", json);
+        }
+        assert!(!json.contains("inf") && !json.contains("NaN"), "{}", json);
+    }
+
+    #[test]
+    fn arg_hints_become_args() {
+        let json = json(&Capture::from_markers(&[
+            "B|1234|load|arg.size=12,track=io",
+            "E|1234",
+        ]));
+        assert!(json.contains("\"size\":\"12\""), "{}", json);
+        assert!(!json.contains("arg."), "{}", json);
+        // The track hint moves the slice to an async track named by it, and
+        // isn't an arg itself.
+        assert!(
+            json.contains(
+                "{\"name\":\"load\",\"cat\":\"io\",\"ph\":\"b\",\"id\":\"io\",\"ts\":10000000.000,\"pid\":1234,\"tid\":1234,\"args\":{\"size\":\"12\"}}"
+            ),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains(
+                "{\"name\":\"load\",\"cat\":\"io\",\"ph\":\"e\",\"id\":\"io\",\"ts\":10001000.000,\"pid\":1234,\"tid\":1234}"
+            ),
+            "{}",
+            json
+        );
+        assert!(!json.contains("\"ph\":\"X\""), "{}", json);
+        assert!(!json.contains("\"track\""), "{}", json);
+    }
+
+    #[test]
+    fn discontinuities_become_global_instants() {
+        let data = "\
+  app-1234  ( 1234) [000] ...1  10.000000: tracing_mark_write: I|1234|a
+  app-1234  ( 1234) [000] ...1  20.000000: tracing_mark_write: I|1234|b
+  app-1234  ( 1234) [000] ...1  19.000000: tracing_mark_write: I|1234|c
+";
+        let json = json(&Capture::from_bytes(data.as_bytes()));
+        assert!(
+            json.contains("{\"name\":\"suspend?\",\"ph\":\"i\",\"s\":\"g\",\"ts\":10000000"),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains("\"gap_ms\":10000.000,\"resume_ts\":20000000"),
+            "{}",
+            json
+        );
+        assert!(json.contains("{\"name\":\"clock jump?\""), "{}", json);
+    }
+
+    // The "args":{...} object of the first event with the name.
+    fn args_of<'a>(json: &'a str, name: &str) -> &'a str {
+        let event = &json[json.find(&format!("{{\"name\":\"{}\"", name)).unwrap()..];
+        let args = &event[event.find("\"args\":").unwrap()..];
+        &args[..args.find('}').unwrap() + 1]
+    }
+
+    #[test]
+    fn attrs_become_the_same_args_however_written() {
+        let json = json(&Capture::from_markers(&[
+            "B|1234|load_chunk idx=5 size=16384 path=\"a b\" ok=true ratio=0.5 id=007",
+            "E|1234",
+            "I|1234|tick idx=\"5\" size=16384 path=\"a b\" ok=\"true\" ratio=0.5 id=\"007\"",
+        ]));
+        let expected =
+            "\"args\":{\"idx\":5,\"size\":16384,\"path\":\"a b\",\"ok\":true,\"ratio\":0.5,\"id\":\"007\"}";
+        assert_eq!(args_of(&json, "load_chunk"), expected);
+        assert_eq!(args_of(&json, "tick"), expected);
+    }
+
+    #[test]
+    fn hashed_names_are_written_in_full() {
+        let json = json(&Capture::from_markers(&[
+            "I|1234|atrace_dict:0a1b2c3d=GET /users/{id}",
+            "B|1234|GET#0a1b2c3d",
+            "E|1234",
+            "C|1234|GET#0a1b2c3d|2",
+            "I|1234|GET#0a1b2c3d",
+        ]));
+        assert_eq!(
+            json.matches("\"name\":\"GET /users/{id}\"").count(),
+            3,
+            "{}",
+            json
+        );
+        assert!(
+            !json.contains("#0a1b2c3d") && !json.contains("atrace_dict"),
+            "{}",
+            json
+        );
+    }
+}
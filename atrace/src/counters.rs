@@ -0,0 +1,326 @@
+// Derived counter tracks added at convert time, next to the originals.
+//
+// --counter-derive turns a counter of running totals, like bytes sent, into a
+// per-second rate over a sliding window, as a track named "<name>/s". A value
+// going down is a counter reset and starts a new baseline, the rate restarts
+// from it instead of dropping by the whole total. --counter-scale adds a
+// track "<name>*<factor>" of the values multiplied, for unit conversion; it
+// applies after the derivation, so it can scale a rate track too.
+
+use crate::capture::{Capture, CounterSample};
+use crate::cli::parse_duration;
+
+pub struct Derive {
+    pub name: String,
+    // Seconds of samples the rate is taken over.
+    pub window: f64,
+}
+
+pub struct Scale {
+    pub name: String,
+    pub factor: f64,
+}
+
+// Parse comma separated "name[:window]", the window 1s by default.
+pub fn parse_derive(spec: &str) -> Result<Vec<Derive>, String> {
+    spec.split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let (name, window) = match item.rfind(':') {
+                Some(split) => (&item[..split], &item[split + 1..]),
+                None => (item, "1s"),
+            };
+            match parse_duration(window) {
+                Some(window) if window > 0.0 && !name.is_empty() => Ok(Derive {
+                    name: name.to_string(),
+                    window,
+                }),
+                _ => Err(format!(
+                    "invalid --counter-derive {:?}, expected name or name:window like tx_bytes:500ms",
+                    item
+                )),
+            }
+        })
+        .collect()
+}
+
+// Parse comma separated "name:factor".
+pub fn parse_scale(spec: &str) -> Result<Vec<Scale>, String> {
+    spec.split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let parsed = item.rfind(':').and_then(|split| {
+                let factor = item[split + 1..].parse::<f64>().ok()?;
+                Some((&item[..split], factor))
+            });
+            match parsed {
+                Some((name, factor)) if factor.is_finite() && !name.is_empty() => Ok(Scale {
+                    name: name.to_string(),
+                    factor,
+                }),
+                _ => Err(format!(
+                    "invalid --counter-scale {:?}, expected name:factor like tx_bytes:0.001",
+                    item
+                )),
+            }
+        })
+        .collect()
+}
+
+// The per-second rate at each sample of a (timestamp, value) series sorted
+// by time: the change since the oldest sample within the window, in the
+// same baseline. Samples with no earlier one in their baseline give none.
+pub fn rates(samples: &[(f64, f64)], window: f64) -> Vec<(f64, f64)> {
+    let mut rates = Vec::new();
+    // The oldest sample in the window and the current baseline.
+    let mut first = 0;
+    for (index, (ts, value)) in samples.iter().enumerate() {
+        if index > 0 && *value < samples[index - 1].1 {
+            first = index;
+        }
+        while first < index && samples[first].0 < ts - window {
+            first += 1;
+        }
+        let (first_ts, first_value) = samples[first];
+        if first < index && *ts > first_ts {
+            rates.push((*ts, (value - first_value) / (ts - first_ts)));
+        }
+    }
+    rates
+}
+
+// Whether a counter track is the named one, by its stored or full name.
+fn matches(capture: &Capture, counter: &CounterSample, name: &str) -> bool {
+    counter.name == name || capture.full_name(&counter.name) == name
+}
+
+// Add the rate track of each pid with the named counter, giving the samples
+// added.
+pub fn derive(capture: &mut Capture, derive: &Derive) -> usize {
+    let mut pids: Vec<i32> = capture
+        .counters
+        .iter()
+        .filter(|counter| matches(capture, counter, &derive.name))
+        .map(|counter| counter.pid)
+        .collect();
+    pids.sort_unstable();
+    pids.dedup();
+    let name = format!("{}/s", derive.name);
+    let mut added = Vec::new();
+    for pid in pids {
+        let mut series: Vec<&CounterSample> = capture
+            .counters
+            .iter()
+            .filter(|counter| counter.pid == pid && matches(capture, counter, &derive.name))
+            .collect();
+        series.sort_by(|a, b| {
+            a.timestamp
+                .partial_cmp(&b.timestamp)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let samples: Vec<(f64, f64)> = series
+            .iter()
+            .map(|counter| (counter.timestamp, counter.value))
+            .collect();
+        let tid = series[0].tid;
+        for (timestamp, value) in rates(&samples, derive.window) {
+            added.push(CounterSample {
+                pid,
+                tid,
+                name: name.clone(),
+                timestamp,
+                value,
+            });
+        }
+    }
+    let count = added.len();
+    capture.counters.extend(added);
+    count
+}
+
+// Add the scaled track of the named counter, giving the samples added.
+pub fn scale(capture: &mut Capture, scale: &Scale) -> usize {
+    let name = format!("{}*{}", scale.name, scale.factor);
+    let added: Vec<CounterSample> = capture
+        .counters
+        .iter()
+        .filter(|counter| matches(capture, counter, &scale.name))
+        .map(|counter| CounterSample {
+            pid: counter.pid,
+            tid: counter.tid,
+            name: name.clone(),
+            timestamp: counter.timestamp,
+            value: counter.value * scale.factor,
+        })
+        .collect();
+    let count = added.len();
+    capture.counters.extend(added);
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A capture of counter markers, as (pid, timestamp, name, value).
+    fn capture(samples: &[(i32, f64, &str, i64)]) -> Capture {
+        let data: String = samples
+            .iter()
+            .map(|(pid, ts, name, value)| {
+                format!(
+                    "  app-{}  ( {}) [000] ...1  {:.6}: tracing_mark_write: C|{}|{}|{}\n",
+                    pid, pid, ts, pid, name, value
+                )
+            })
+            .collect();
+        Capture::from_bytes(data.as_bytes())
+    }
+
+    // The samples of a track as (pid, timestamp, value), in capture order.
+    fn track(capture: &Capture, name: &str) -> Vec<(i32, f64, f64)> {
+        capture
+            .counters
+            .iter()
+            .filter(|counter| counter.name == name)
+            .map(|counter| (counter.pid, counter.timestamp, counter.value))
+            .collect()
+    }
+
+    #[test]
+    fn rates_over_a_sliding_window() {
+        let samples = [(0.0, 0.0), (1.0, 100.0), (2.0, 300.0), (2.5, 400.0)];
+        assert_eq!(
+            rates(&samples, 1.0),
+            vec![(1.0, 100.0), (2.0, 200.0), (2.5, 200.0)]
+        );
+        // A wider window averages over more samples.
+        assert_eq!(
+            rates(&samples, 10.0),
+            vec![(1.0, 100.0), (2.0, 150.0), (2.5, 160.0)]
+        );
+    }
+
+    #[test]
+    fn rates_with_irregular_sampling() {
+        // A gap longer than the window leaves no sample to take the rate
+        // from, the next sample starts from there.
+        let samples = [
+            (0.0, 0.0),
+            (0.25, 25.0),
+            (0.5, 50.0),
+            (3.0, 300.0),
+            (3.25, 350.0),
+        ];
+        assert_eq!(
+            rates(&samples, 0.5),
+            vec![(0.25, 100.0), (0.5, 100.0), (3.25, 200.0)]
+        );
+        // Samples at the same time give no rate.
+        assert!(rates(&[(1.0, 10.0), (1.0, 20.0)], 1.0).is_empty());
+        assert!(rates(&[], 1.0).is_empty());
+    }
+
+    #[test]
+    fn resets_start_a_new_baseline() {
+        let samples = [
+            (0.0, 1000.0),
+            (1.0, 1100.0),
+            (2.0, 50.0),
+            (3.0, 150.0),
+            (4.0, 250.0),
+        ];
+        // No negative rate at the reset, and the samples before it are left
+        // out of the window after it.
+        assert_eq!(
+            rates(&samples, 2.0),
+            vec![(1.0, 100.0), (3.0, 100.0), (4.0, 100.0)]
+        );
+    }
+
+    #[test]
+    fn derive_adds_a_rate_track_per_pid() {
+        let mut capture = capture(&[
+            (10, 1.0, "tx", 0),
+            (20, 1.0, "tx", 500),
+            (10, 1.5, "tx", 50),
+            (10, 2.0, "rx", 7),
+            (20, 2.0, "tx", 1500),
+            (10, 2.0, "tx", 150),
+        ]);
+        let added = derive(
+            &mut capture,
+            &Derive {
+                name: "tx".to_string(),
+                window: 1.0,
+            },
+        );
+        assert_eq!(added, 3);
+        assert_eq!(
+            track(&capture, "tx/s"),
+            vec![(10, 1.5, 100.0), (10, 2.0, 150.0), (20, 2.0, 1000.0)]
+        );
+        assert_eq!(track(&capture, "tx").len(), 5);
+        assert_eq!(
+            derive(
+                &mut capture,
+                &Derive {
+                    name: "missing".to_string(),
+                    window: 1.0,
+                }
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn scale_applies_to_derived_tracks() {
+        let mut capture = capture(&[(10, 1.0, "tx", 0), (10, 2.0, "tx", 2000)]);
+        derive(
+            &mut capture,
+            &Derive {
+                name: "tx".to_string(),
+                window: 1.0,
+            },
+        );
+        let scale_by = |name: &str, factor: f64| Scale {
+            name: name.to_string(),
+            factor,
+        };
+        assert_eq!(scale(&mut capture, &scale_by("tx", 0.5)), 2);
+        assert_eq!(scale(&mut capture, &scale_by("tx/s", 0.001)), 1);
+        assert_eq!(
+            track(&capture, "tx*0.5"),
+            vec![(10, 1.0, 0.0), (10, 2.0, 1000.0)]
+        );
+        assert_eq!(track(&capture, "tx/s*0.001"), vec![(10, 2.0, 2.0)]);
+    }
+
+    #[test]
+    fn specs_parse_or_name_the_bad_item() {
+        let derives = parse_derive("tx_bytes,rx_bytes:500ms,dev:eth0:2s").unwrap();
+        let derives: Vec<(&str, f64)> = derives
+            .iter()
+            .map(|derive| (derive.name.as_str(), derive.window))
+            .collect();
+        assert_eq!(
+            derives,
+            vec![("tx_bytes", 1.0), ("rx_bytes", 0.5), ("dev:eth0", 2.0)]
+        );
+        for bad in &["tx:0", ":1s", "tx:soon"] {
+            let error = parse_derive(bad).err().unwrap();
+            assert!(error.contains(&format!("{:?}", bad)), "{}", error);
+        }
+
+        let scales = parse_scale("tx:0.001,dev:eth0:8").unwrap();
+        let scales: Vec<(&str, f64)> = scales
+            .iter()
+            .map(|scale| (scale.name.as_str(), scale.factor))
+            .collect();
+        assert_eq!(scales, vec![("tx", 0.001), ("dev:eth0", 8.0)]);
+        for bad in &["tx", "tx:inf", ":2", "tx:x"] {
+            let error = parse_scale(bad).err().unwrap();
+            assert!(error.contains(&format!("{:?}", bad)), "{}", error);
+        }
+        assert!(parse_derive("").unwrap().is_empty());
+    }
+}
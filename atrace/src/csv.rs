@@ -0,0 +1,118 @@
+// Conversion of a capture into CSV tables for spreadsheets: one row per
+// completed marker slice, and optionally one row per counter sample.
+
+use std::io::{self, Write};
+
+use crate::capture::Capture;
+
+// Quote a field when it holds a separator, quote or line break, doubling
+// the quotes inside, as spreadsheets expect.
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Timestamps in microseconds, either on the trace clock or from the start of
+// the capture.
+fn timestamp_us(capture: &Capture, seconds: f64, relative: bool) -> String {
+    let base = if relative {
+        capture.first_timestamp
+    } else {
+        0.0
+    };
+    format!("{:.3}", (seconds - base) * 1_000_000.0)
+}
+
+fn comm(capture: &Capture, tid: i32) -> &str {
+    capture
+        .threads
+        .get(&tid)
+        .map(|thread| thread.comm.as_str())
+        .unwrap_or("")
+}
+
+// Write the marker slices of the capture, in start order.
+pub fn write_slices(capture: &Capture, relative: bool, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "pid,tid,comm,name,start_us,duration_us,depth")?;
+    for slice in capture.slices.iter() {
+        writeln!(
+            out,
+            "{},{},{},{},{},{:.3},{}",
+            slice.pid,
+            slice.tid,
+            csv_field(comm(capture, slice.tid)),
+            csv_field(&slice.name),
+            timestamp_us(capture, slice.start, relative),
+            slice.duration * 1_000_000.0,
+            slice.depth
+        )?;
+    }
+    out.flush()
+}
+
+// Write the counter samples of the capture.
+pub fn write_counters(capture: &Capture, relative: bool, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "pid,tid,comm,name,timestamp_us,value")?;
+    for counter in capture.counters.iter() {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            counter.pid,
+            counter.tid,
+            csv_field(comm(capture, counter.tid)),
+            csv_field(&counter.name),
+            timestamp_us(capture, counter.timestamp, relative),
+            counter.value
+        )?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/csv.txt");
+
+    fn csv(write: fn(&Capture, bool, &mut dyn Write) -> io::Result<()>, relative: bool) -> String {
+        let mut out = Vec::new();
+        write(&Capture::from_bytes(FIXTURE), relative, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("plain name"), "plain name");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn slices_match_the_golden_files() {
+        assert_eq!(
+            csv(write_slices, false),
+            include_str!("../tests/fixtures/csv_slices.csv")
+        );
+        assert_eq!(
+            csv(write_slices, true),
+            include_str!("../tests/fixtures/csv_slices_relative.csv")
+        );
+    }
+
+    #[test]
+    fn counters_match_the_golden_files() {
+        assert_eq!(
+            csv(write_counters, false),
+            include_str!("../tests/fixtures/csv_counters.csv")
+        );
+        assert_eq!(
+            csv(write_counters, true),
+            include_str!("../tests/fixtures/csv_counters_relative.csv")
+        );
+    }
+}
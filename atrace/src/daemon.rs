@@ -0,0 +1,305 @@
+// Control socket of the resident collector started by `atrace daemon`.
+//
+// The daemon keeps tracing set up but disabled until told otherwise. A
+// client connects to the unix socket and writes command lines, each
+// answered by one line: "ok" or "error:", followed by the detail. The socket
+// is created with mode 0600, so its permissions are the authentication.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+use std::time::Duration;
+
+// How often the accept loop checks whether to shut down.
+const ACCEPT_POLL_MS: u64 = 100;
+// A client idle for this long is dropped, so it can't block the others.
+const CLIENT_TIMEOUT_SECS: u64 = 10;
+const MAX_LINE: u64 = 4096;
+
+pub enum Command {
+    // Clear the buffer and enable tracing.
+    Start,
+    // Disable tracing, keeping the buffer.
+    Stop,
+    // Disable tracing, write the buffer to the path and clear it.
+    Dump(String),
+    // Write the buffer to the path while tracing goes on.
+    Snapshot(String),
+    Status,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let path = words.next();
+        if words.next().is_some() {
+            return Err(format!("too many arguments to {}", command));
+        }
+        match (command, path) {
+            ("start", None) => Ok(Command::Start),
+            ("stop", None) => Ok(Command::Stop),
+            ("status", None) => Ok(Command::Status),
+            ("dump", Some(path)) => Ok(Command::Dump(path.to_string())),
+            ("snapshot", Some(path)) => Ok(Command::Snapshot(path.to_string())),
+            ("dump", None) | ("snapshot", None) => Err(format!("{} needs a path", command)),
+            ("start", Some(_)) | ("stop", Some(_)) | ("status", Some(_)) => {
+                Err(format!("{} takes no argument", command))
+            }
+            ("", _) => Err("empty command".to_string()),
+            _ => Err(format!(
+                "unknown command {:?}, expected start, stop, dump, snapshot or status",
+                command
+            )),
+        }
+    }
+
+    // Whether the command carries a path, which a client must make absolute
+    // as the daemon runs in another directory.
+    pub fn takes_path(command: &str) -> bool {
+        command == "dump" || command == "snapshot"
+    }
+}
+
+// What the daemon does for each command, the detail of the reply or why it
+// failed.
+pub trait Collector {
+    fn run(&mut self, command: Command) -> Result<String, String>;
+}
+
+// Answer the command lines read from a client until it closes.
+pub fn handle<R: BufRead, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    collector: &mut dyn Collector,
+) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.by_ref().take(MAX_LINE).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE {
+            writeln!(writer, "error: command longer than {} bytes", MAX_LINE)?;
+            return Ok(());
+        }
+        let reply = match Command::parse(&line).and_then(|command| collector.run(command)) {
+            Ok(detail) if detail.is_empty() => "ok".to_string(),
+            Ok(detail) => format!("ok {}", detail),
+            Err(e) => format!("error: {}", e),
+        };
+        writeln!(writer, "{}", reply)?;
+        writer.flush()?;
+    }
+}
+
+// Bind the socket, replacing one left over by a daemon which didn't shut
+// down cleanly, but not one still answering.
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("a daemon is already listening on {}", path),
+        ));
+    }
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    // Created 0600 from the start, no other user may connect in between.
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(mask) };
+    listener
+}
+
+// Serve clients one at a time until stop returns true, then remove the
+// socket.
+pub fn serve(
+    listener: UnixListener,
+    path: &str,
+    collector: &mut dyn Collector,
+    stop: &dyn Fn() -> bool,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut result = Ok(());
+    while !stop() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let served = stream
+                    .set_nonblocking(false)
+                    .and_then(|_| {
+                        stream.set_read_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)))
+                    })
+                    .and_then(|_| handle(BufReader::new(&stream), &stream, collector));
+                if let Err(e) = served {
+                    eprintln!("warning: control client dropped: {}", e);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(ACCEPT_POLL_MS))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    let _ = fs::remove_file(path);
+    result
+}
+
+// Send one command line to the daemon and wait for its reply.
+pub fn request(path: &str, line: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", line)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    if reply.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the daemon closed the connection without a reply",
+        ));
+    }
+    Ok(reply.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    // Records the commands run, failing dumps to a path under /fail.
+    #[derive(Default)]
+    struct Recorder {
+        commands: Vec<String>,
+    }
+
+    impl Collector for Recorder {
+        fn run(&mut self, command: Command) -> Result<String, String> {
+            let (name, detail) = match command {
+                Command::Start => ("start", String::new()),
+                Command::Stop => ("stop", String::new()),
+                Command::Status => ("status", "tracing off".to_string()),
+                Command::Dump(path) if path.starts_with("/fail") => {
+                    return Err(format!("write {} fail", path))
+                }
+                Command::Dump(path) => ("dump", path),
+                Command::Snapshot(path) => ("snapshot", path),
+            };
+            self.commands.push(name.to_string());
+            Ok(detail)
+        }
+    }
+
+    // Write the input to one end of a socket pair served by handle, giving
+    // the reply lines and the commands run.
+    fn converse(input: &[u8]) -> (Vec<String>, Vec<String>) {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let serving = thread::spawn(move || {
+            let mut recorder = Recorder::default();
+            handle(BufReader::new(&server), &server, &mut recorder).unwrap();
+            recorder.commands
+        });
+        client.write_all(input).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let replies = BufReader::new(&client)
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        (replies, serving.join().unwrap())
+    }
+
+    #[test]
+    fn commands_are_answered_in_order() {
+        let (replies, commands) =
+            converse(b"start\nstatus\ndump /tmp/out.txt\nsnapshot /tmp/snap\nstop\n");
+        assert_eq!(
+            replies,
+            [
+                "ok",
+                "ok tracing off",
+                "ok /tmp/out.txt",
+                "ok /tmp/snap",
+                "ok"
+            ]
+        );
+        assert_eq!(commands, ["start", "status", "dump", "snapshot", "stop"]);
+    }
+
+    #[test]
+    fn unknown_command_is_an_error_and_the_client_stays() {
+        let (replies, commands) = converse(b"restart\nstart\n");
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].starts_with("error: unknown command \"restart\""));
+        assert_eq!(replies[1], "ok");
+        assert_eq!(commands, ["start"]);
+    }
+
+    #[test]
+    fn dump_needs_a_path() {
+        let (replies, commands) = converse(b"dump\ndump a b\n\nstart now\n");
+        assert_eq!(
+            replies,
+            [
+                "error: dump needs a path",
+                "error: too many arguments to dump",
+                "error: empty command",
+                "error: start takes no argument",
+            ]
+        );
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn collector_failure_is_replied() {
+        let (replies, _) = converse(b"dump /fail/out.txt\n");
+        assert_eq!(replies, ["error: write /fail/out.txt fail"]);
+    }
+
+    #[test]
+    fn oversized_line_closes_the_client() {
+        let mut input = vec![b'x'; MAX_LINE as usize + 100];
+        input.extend_from_slice(b"\nstart\n");
+        let (replies, commands) = converse(&input);
+        assert_eq!(replies, ["error: command longer than 4096 bytes"]);
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn last_line_without_newline_is_run() {
+        let (replies, commands) = converse(b"status");
+        assert_eq!(replies, ["ok tracing off"]);
+        assert_eq!(commands, ["status"]);
+    }
+
+    #[test]
+    fn request_through_the_socket() {
+        let path = std::env::temp_dir().join(format!("atrace-daemon-{}.sock", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let listener = bind(&path).unwrap();
+        assert_eq!(bind(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        let stop = Arc::new(AtomicBool::new(false));
+        let serving = {
+            let (path, stop) = (path.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut recorder = Recorder::default();
+                serve(listener, &path, &mut recorder, &|| {
+                    stop.load(Ordering::SeqCst)
+                })
+                .unwrap();
+                recorder.commands
+            })
+        };
+        assert_eq!(request(&path, "start").unwrap(), "ok");
+        assert_eq!(request(&path, "dump").unwrap(), "error: dump needs a path");
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(serving.join().unwrap(), ["start"]);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,231 @@
+// Comparison of the marker summaries of two captures, to read out the
+// regressions and improvements after a change.
+
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::capture::Capture;
+use crate::summary::{Summary, SummaryEntry};
+
+pub struct DiffOptions {
+    // Minimum total-time change in percent of the before value.
+    pub threshold_percent: f64,
+    // Minimum absolute total-time change in seconds.
+    pub min_delta: f64,
+}
+
+pub struct DiffRow {
+    pub name: String,
+    pub count_before: usize,
+    pub count_after: usize,
+    /// Deltas in seconds, after minus before.
+    pub total_delta: f64,
+    pub p50_delta: f64,
+    pub p99_delta: f64,
+    // Total-time change in percent, None for names new in the after capture.
+    pub percent: Option<f64>,
+}
+
+impl DiffRow {
+    pub fn is_regression(&self) -> bool {
+        self.total_delta > 0.0
+    }
+}
+
+// Summarize the marker slices, with dynamic name suffixes matching the strip
+// regex removed so names match across captures.
+pub fn summarize(capture: &Capture, strip: Option<&Regex>) -> Summary {
+    let names: Vec<(String, f64)> = capture
+        .slices
+        .iter()
+        .filter_map(|slice| {
+            let duration = capture.report_duration(slice)?;
+            let name = match strip {
+                Some(strip) => strip.replace_all(&slice.name, "").into_owned(),
+                None => slice.name.clone(),
+            };
+            Some((name, duration))
+        })
+        .collect();
+    Summary::from_durations(
+        names
+            .iter()
+            .map(|(name, duration)| (name.as_str(), *duration)),
+    )
+}
+
+fn delta(
+    before: Option<&SummaryEntry>,
+    after: Option<&SummaryEntry>,
+    f: &dyn Fn(&SummaryEntry) -> f64,
+) -> f64 {
+    after.map(f).unwrap_or(0.0) - before.map(f).unwrap_or(0.0)
+}
+
+// Compare two summaries per name, keeping only meaningful changes, sorted by
+// regression first: the largest total-time increase leads.
+pub fn diff_summaries(before: &Summary, after: &Summary, options: &DiffOptions) -> Vec<DiffRow> {
+    let names: BTreeSet<&str> = before
+        .entries
+        .iter()
+        .chain(after.entries.iter())
+        .map(|entry| entry.name.as_str())
+        .collect();
+    let mut rows: Vec<DiffRow> = names
+        .into_iter()
+        .filter_map(|name| {
+            let old = before.entries.iter().find(|entry| entry.name == name);
+            let new = after.entries.iter().find(|entry| entry.name == name);
+            let total_delta = delta(old, new, &|entry| entry.total);
+            let percent = match old {
+                Some(old) if old.total > 0.0 => Some(total_delta / old.total * 100.0),
+                _ => None,
+            };
+            if total_delta.abs() < options.min_delta {
+                return None;
+            }
+            if let Some(percent) = percent {
+                if percent.abs() < options.threshold_percent {
+                    return None;
+                }
+            }
+            Some(DiffRow {
+                name: name.to_string(),
+                count_before: old.map(|entry| entry.count).unwrap_or(0),
+                count_after: new.map(|entry| entry.count).unwrap_or(0),
+                total_delta,
+                p50_delta: delta(old, new, &|entry| entry.percentile(50.0)),
+                p99_delta: delta(old, new, &|entry| entry.percentile(99.0)),
+                percent,
+            })
+        })
+        .collect();
+    rows.sort_by(|a, b| {
+        b.total_delta
+            .partial_cmp(&a.total_delta)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    rows
+}
+
+pub fn print_diff(rows: &[DiffRow], out: &mut dyn Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<40} {:>8} {:>8} {:>12} {:>9} {:>10} {:>10}",
+        "name", "before", "after", "total(ms)", "total(%)", "p50(us)", "p99(us)"
+    )?;
+    for row in rows.iter() {
+        let percent = match row.percent {
+            Some(percent) => format!("{:+.1}", percent),
+            None => "new".to_string(),
+        };
+        writeln!(
+            out,
+            "{:<40} {:>8} {:>8} {:>+12.3} {:>9} {:>+10.1} {:>+10.1}",
+            row.name,
+            row.count_before,
+            row.count_after,
+            row.total_delta * 1_000.0,
+            percent,
+            row.p50_delta * 1_000_000.0,
+            row.p99_delta * 1_000_000.0
+        )?;
+    }
+    let regressions = rows.iter().filter(|row| row.is_regression()).count();
+    writeln!(
+        out,
+        "{} regressions, {} improvements",
+        regressions,
+        rows.len() - regressions
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BEFORE: &[u8] = include_bytes!("../tests/fixtures/diff_before.txt");
+    const AFTER: &[u8] = include_bytes!("../tests/fixtures/diff_after.txt");
+
+    fn rows(strip: Option<&str>, options: &DiffOptions) -> Vec<DiffRow> {
+        let strip = strip.map(|strip| Regex::new(strip).unwrap());
+        let before = summarize(&Capture::from_bytes(BEFORE), strip.as_ref());
+        let after = summarize(&Capture::from_bytes(AFTER), strip.as_ref());
+        diff_summaries(&before, &after, options)
+    }
+
+    fn close(value: f64, expected: f64) -> bool {
+        (value - expected).abs() < 1e-9
+    }
+
+    const ALL: DiffOptions = DiffOptions {
+        threshold_percent: 0.0,
+        min_delta: 0.0,
+    };
+
+    #[test]
+    fn deltas_are_after_minus_before() {
+        let rows = rows(Some("#[0-9]+$"), &ALL);
+        let names: Vec<&str> = rows.iter().map(|row| row.name.as_str()).collect();
+        // Sorted by total-time delta, the largest regression first.
+        assert_eq!(names, vec!["draw", "parse", "idle", "load"]);
+
+        let draw = &rows[0];
+        assert_eq!((draw.count_before, draw.count_after), (2, 3));
+        assert!(close(draw.total_delta, 0.004));
+        assert!(close(draw.percent.unwrap(), 100.0));
+        assert!(close(draw.p50_delta, 0.001));
+        assert!(close(draw.p99_delta, 0.001));
+
+        let parse = &rows[1];
+        assert_eq!((parse.count_before, parse.count_after), (0, 1));
+        assert!(close(parse.total_delta, 0.001));
+        assert_eq!(parse.percent, None);
+
+        let load = &rows[3];
+        assert!(close(load.total_delta, -0.006));
+        assert!(close(load.percent.unwrap(), -60.0));
+        assert!(close(load.p50_delta, -0.006));
+        assert!(!load.is_regression());
+    }
+
+    #[test]
+    fn small_changes_are_hidden() {
+        let options = DiffOptions {
+            threshold_percent: 5.0,
+            min_delta: 0.0015,
+        };
+        let rows = rows(Some("#[0-9]+$"), &options);
+        let names: Vec<&str> = rows.iter().map(|row| row.name.as_str()).collect();
+        assert_eq!(names, vec!["draw", "load"]);
+    }
+
+    #[test]
+    fn names_differ_without_strip() {
+        let rows = rows(None, &ALL);
+        let gone: Vec<&str> = rows
+            .iter()
+            .filter(|row| row.count_after == 0)
+            .map(|row| row.name.as_str())
+            .collect();
+        assert_eq!(gone, vec!["draw#1", "draw#2"]);
+        assert!(rows
+            .iter()
+            .filter(|row| row.count_after == 0)
+            .all(|row| close(row.percent.unwrap(), -100.0)));
+    }
+
+    #[test]
+    fn table_counts_regressions() {
+        let mut out = Vec::new();
+        print_diff(&rows(Some("#[0-9]+$"), &ALL), &mut out).unwrap();
+        let table = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].starts_with("name "));
+        assert!(lines[1].starts_with("draw ") && lines[1].contains("+100.0"));
+        assert!(lines[2].starts_with("parse ") && lines[2].contains(" new "));
+        assert_eq!(lines[5], "2 regressions, 2 improvements");
+    }
+}
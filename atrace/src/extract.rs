@@ -0,0 +1,376 @@
+// Extraction of a time range out of a dumped trace, so the interesting part
+// of a huge capture can be handed on by itself.
+//
+// Lines are kept by their timestamp, while the header, clock sync, metadata
+// and tracing-atrace header lines are always kept so the slice stays
+// self-describing. B/E and S/F markers are paired over the whole capture
+// in a first pass, and both halves of a pair overlapping the window are
+// kept in the second so no slice loses its begin or end to the cut. Neither
+// pass holds more of the dump than a line.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+use crate::cli::parse_duration;
+use crate::input::{read_lines, MAX_LINE_BYTES};
+use crate::parser::{
+    is_clock_sync, parse_funcgraph_line, parse_line, parse_marker, parse_metadata, Marker,
+    TraceHeader,
+};
+
+// A window bound, on the trace clock or after the first event with "+".
+#[derive(Clone, Copy)]
+pub enum Bound {
+    Absolute(f64),
+    Relative(f64),
+}
+
+impl Bound {
+    // Parse "12.345s" or "+500ms".
+    pub fn parse(value: &str) -> Option<Bound> {
+        match value.trim().strip_prefix('+') {
+            Some(relative) => parse_duration(relative).map(Bound::Relative),
+            None => parse_duration(value).map(Bound::Absolute),
+        }
+    }
+
+    fn resolve(self, first: f64) -> f64 {
+        match self {
+            Bound::Absolute(seconds) => seconds,
+            Bound::Relative(seconds) => first + seconds,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LineKind {
+    // Kept whatever its time.
+    Always,
+    Timed(f64),
+    // A B or S marker, paired with its E or F line.
+    Open(f64),
+    Close(f64),
+}
+
+fn timestamp(line: &str) -> Option<f64> {
+    match parse_line(line) {
+        Some(trace_line) => Some(trace_line.timestamp),
+        None => parse_funcgraph_line(line).and_then(|graph_line| graph_line.timestamp),
+    }
+}
+
+fn classify(line: &str) -> LineKind {
+    if let Some(trace_line) = parse_line(line) {
+        let ts = trace_line.timestamp;
+        if !trace_line.is_marker() {
+            return LineKind::Timed(ts);
+        }
+        let payload = trace_line.payload;
+        if is_clock_sync(payload) || parse_metadata(payload).is_some() {
+            return LineKind::Always;
+        }
+        return match parse_marker(payload) {
+            Some(Marker::Begin { .. }) | Some(Marker::AsyncBegin { .. }) => LineKind::Open(ts),
+            Some(Marker::End { .. }) | Some(Marker::AsyncEnd { .. }) => LineKind::Close(ts),
+            Some(Marker::Instant { name, .. }) if TraceHeader::parse(name).is_some() => {
+                LineKind::Always
+            }
+            _ => LineKind::Timed(ts),
+        };
+    }
+    match parse_funcgraph_line(line).and_then(|graph_line| graph_line.timestamp) {
+        Some(ts) => LineKind::Timed(ts),
+        // Headers, comments and lines without a timestamp to place them.
+        None => LineKind::Always,
+    }
+}
+
+// What the first pass over a dump learns for the second: where the capture
+// starts and, for each paired B/E and S/F line, the time of its partner.
+pub struct Pairing {
+    first: Option<f64>,
+    partners: BTreeMap<usize, f64>,
+    pub lines: usize,
+}
+
+// Pair the markers of the dump, holding only the open ones as it goes.
+pub fn scan(reader: impl BufRead) -> io::Result<Pairing> {
+    let mut pairing = Pairing {
+        first: None,
+        partners: BTreeMap::new(),
+        lines: 0,
+    };
+    let mut stacks: BTreeMap<i32, Vec<(usize, f64)>> = BTreeMap::new();
+    let mut async_open: BTreeMap<(i32, String, i64), (usize, f64)> = BTreeMap::new();
+    read_lines(reader, |bytes| {
+        let index = pairing.lines;
+        pairing.lines += 1;
+        let line = String::from_utf8_lossy(bytes);
+        if pairing.first.is_none() {
+            pairing.first = timestamp(&line);
+        }
+        let trace_line = match parse_line(&line) {
+            Some(trace_line) if trace_line.is_marker() => trace_line,
+            _ => return,
+        };
+        let ts = trace_line.timestamp;
+        let open = match parse_marker(trace_line.payload) {
+            Some(Marker::Begin { .. }) => {
+                stacks.entry(trace_line.tid).or_default().push((index, ts));
+                None
+            }
+            Some(Marker::End { .. }) => stacks.entry(trace_line.tid).or_default().pop(),
+            Some(Marker::AsyncBegin { pid, name, cookie }) => {
+                async_open.insert((pid, name.to_string(), cookie), (index, ts));
+                None
+            }
+            Some(Marker::AsyncEnd { pid, name, cookie }) => {
+                async_open.remove(&(pid, name.to_string(), cookie))
+            }
+            _ => None,
+        };
+        if let Some((open, start)) = open {
+            pairing.partners.insert(open, ts);
+            pairing.partners.insert(index, start);
+        }
+    })?;
+    Ok(pairing)
+}
+
+// Copy the lines of the dump within from..to, both inclusive, an open bound
+// reaching to the capture end, given the pairing of a first pass over the
+// same dump. Kept lines are copied byte for byte, invalid UTF-8 included.
+// Gives the number of lines kept.
+pub fn extract(
+    mut reader: impl BufRead,
+    pairing: &Pairing,
+    from: Option<Bound>,
+    to: Option<Bound>,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    // The first timestamp of any line, as the capture start elsewhere.
+    let first = pairing.first.unwrap_or(0.0);
+    let from = from.map_or(f64::NEG_INFINITY, |bound| bound.resolve(first));
+    let to = to.map_or(f64::INFINITY, |bound| bound.resolve(first));
+
+    let mut line = Vec::new();
+    let mut kept = 0;
+    for index in 0.. {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        let text = String::from_utf8_lossy(&line[..line.len().min(MAX_LINE_BYTES)]);
+        let keep = match classify(&text) {
+            LineKind::Always => true,
+            LineKind::Timed(ts) => ts >= from && ts <= to,
+            // A slice is kept when it overlaps the window, a missing half
+            // meaning it was open from the capture start or to its end.
+            LineKind::Open(start) => {
+                let end = pairing
+                    .partners
+                    .get(&index)
+                    .copied()
+                    .unwrap_or(f64::INFINITY);
+                start <= to && end >= from
+            }
+            LineKind::Close(end) => {
+                let start = pairing
+                    .partners
+                    .get(&index)
+                    .copied()
+                    .unwrap_or(f64::NEG_INFINITY);
+                start <= to && end >= from
+            }
+        };
+        if keep {
+            out.write_all(&line)?;
+            out.write_all(b"\n")?;
+            kept += 1;
+        }
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(ts: f64, tid: i32, payload: &str) -> String {
+        format!(
+            "  app-{0}  ( 1234) [000] ...1  {1:.6}: tracing_mark_write: {2}\n",
+            tid, ts, payload
+        )
+    }
+
+    fn event(ts: f64) -> String {
+        format!(
+            "  <idle>-0  (-----) [001] d..2  {:.6}: sched_switch: prev_comm=swapper\n",
+            ts
+        )
+    }
+
+    fn run(data: &str, from: &str, to: &str) -> Vec<String> {
+        let bound = |value: &str| {
+            if value.is_empty() {
+                None
+            } else {
+                Some(Bound::parse(value).unwrap())
+            }
+        };
+        let pairing = scan(data.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        let kept = extract(data.as_bytes(), &pairing, bound(from), bound(to), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), kept);
+        out.lines().map(str::to_string).collect()
+    }
+
+    fn payloads(lines: &[String]) -> Vec<&str> {
+        lines
+            .iter()
+            .map(|line| match line.find(": tracing_mark_write: ") {
+                Some(at) => &line[at + ": tracing_mark_write: ".len()..],
+                None => "event",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn events_outside_the_window_are_dropped() {
+        let data = [event(1.0), event(2.0), event(3.0), event(4.0)].concat();
+        let lines = run(&data, "2s", "3s");
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" 2.000000:"));
+        assert!(lines[1].contains(" 3.000000:"));
+    }
+
+    #[test]
+    fn begin_before_the_window_is_kept() {
+        let data = [
+            line(1.0, 10, "B|1234|outer"),
+            event(1.5),
+            line(2.5, 10, "E|1234"),
+            event(2.6),
+        ]
+        .concat();
+        assert_eq!(
+            payloads(&run(&data, "2s", "3s")),
+            ["B|1234|outer", "E|1234", "event"]
+        );
+    }
+
+    #[test]
+    fn end_after_the_window_is_kept() {
+        let data = [
+            line(2.5, 10, "B|1234|outer"),
+            line(2.6, 10, "I|1234|inside"),
+            line(4.0, 10, "E|1234"),
+        ]
+        .concat();
+        assert_eq!(
+            payloads(&run(&data, "2s", "3s")),
+            ["B|1234|outer", "I|1234|inside", "E|1234"]
+        );
+    }
+
+    #[test]
+    fn pairs_around_the_window_are_kept_and_pairs_beside_it_dropped() {
+        let data = [
+            line(1.0, 10, "B|1234|spanning"),
+            line(1.1, 11, "B|1234|before"),
+            line(1.2, 11, "E|1234"),
+            line(5.0, 10, "E|1234"),
+            line(6.0, 11, "B|1234|after"),
+            line(6.1, 11, "E|1234"),
+        ]
+        .concat();
+        assert_eq!(
+            payloads(&run(&data, "2s", "3s")),
+            ["B|1234|spanning", "E|1234"]
+        );
+    }
+
+    #[test]
+    fn nested_pairs_are_matched_per_tid() {
+        // The inner pair of tid 10 closes before the window, the outer one
+        // inside it; tid 11 interleaves its own pair.
+        let data = [
+            line(1.0, 10, "B|1234|outer"),
+            line(1.1, 10, "B|1234|inner"),
+            line(1.2, 11, "B|1234|other"),
+            line(1.3, 10, "E|1234"),
+            line(2.5, 10, "E|1234"),
+            line(3.5, 11, "E|1234"),
+        ]
+        .concat();
+        let lines = run(&data, "2s", "3s");
+        assert_eq!(
+            payloads(&lines),
+            ["B|1234|outer", "B|1234|other", "E|1234", "E|1234"]
+        );
+        assert!(lines[2].contains(" 2.500000:"));
+        assert!(lines[3].contains(" 3.500000:"));
+    }
+
+    #[test]
+    fn unpaired_halves_reach_to_the_capture_edges() {
+        let data = [
+            line(1.0, 10, "E|1234"),
+            line(2.5, 11, "E|1234"),
+            line(4.0, 12, "B|1234|open"),
+            line(2.6, 13, "B|1234|opened_inside"),
+        ]
+        .concat();
+        // Only the halves whose reach overlaps the window are kept.
+        let lines = run(&data, "2s", "3s");
+        assert_eq!(payloads(&lines), ["E|1234", "B|1234|opened_inside"]);
+        assert!(lines[0].contains(" 2.500000:"));
+    }
+
+    #[test]
+    fn async_pairs_match_on_cookie() {
+        let data = [
+            line(1.0, 10, "S|1234|load|1"),
+            line(1.0, 10, "S|1234|load|2"),
+            line(1.5, 11, "F|1234|load|2"),
+            line(2.5, 11, "F|1234|load|1"),
+        ]
+        .concat();
+        assert_eq!(
+            payloads(&run(&data, "2s", "3s")),
+            ["S|1234|load|1", "F|1234|load|1"]
+        );
+    }
+
+    #[test]
+    fn relative_bounds_count_from_the_first_timestamp() {
+        let data = [
+            "# tracer: nop\n".to_string(),
+            event(10.0),
+            event(10.4),
+            event(11.0),
+        ]
+        .concat();
+        let lines = run(&data, "+300ms", "+500ms");
+        assert_eq!(lines, ["# tracer: nop", event(10.4).trim_end()]);
+    }
+
+    #[test]
+    fn lines_are_copied_byte_for_byte() {
+        let mut data = event(2.5).into_bytes();
+        data.pop();
+        data.extend_from_slice(b" \xff\r\n");
+        let pairing = scan(&data[..]).unwrap();
+        let mut out = Vec::new();
+        extract(&data[..], &pairing, None, None, &mut out).unwrap();
+        assert_eq!(out, [&data[..data.len() - 2], b"\n"].concat());
+        assert_eq!(pairing.lines, 1);
+    }
+}
@@ -0,0 +1,445 @@
+// The flight recorder behind `atrace flight`: trace_pipe streamed into
+// compressed segment files in a directory, the oldest deleted past a count so
+// disk use stays bounded.
+//
+// A segment collects lines up to a size, then is compressed as -Z writes it
+// and renamed into place, so every segment file is complete and readable by
+// the other modes on its own. The lines of the open segment are appended to
+// a partial file as they come, and a crash leaves them there for the next
+// run to close as a segment of its own. The index
+// file maps each segment to the trace clock range of its lines and is
+// rewritten after each segment. Sealing closes the open segment early, on
+// SIGUSR1 from `atrace flight --seal`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+use crate::input::{decode_line, deflate_data, read_trace_file, split_lines};
+use crate::parser::parse_line;
+
+pub const INDEX_FILE: &str = "index.tsv";
+pub const PID_FILE: &str = "flight.pid";
+pub const PARTIAL_FILE: &str = "segment.partial";
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_SUFFIX: &str = ".z";
+
+// Set by the SIGUSR1 handler, the recorder seals at the next line.
+pub static SEAL_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+pub struct Segment {
+    pub number: u64,
+    // Trace clock range of the lines, 0 when none had a timestamp.
+    pub first: f64,
+    pub last: f64,
+    pub lines: usize,
+}
+
+impl Segment {
+    pub fn file_name(&self) -> String {
+        segment_name(self.number)
+    }
+}
+
+fn segment_name(number: u64) -> String {
+    format!("{}{:06}{}", SEGMENT_PREFIX, number, SEGMENT_SUFFIX)
+}
+
+fn segment_number(name: &str) -> Option<u64> {
+    name.strip_prefix(SEGMENT_PREFIX)?
+        .strip_suffix(SEGMENT_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+// The line timestamps and count of some trace text.
+fn time_range(data: &[u8]) -> (f64, f64, usize) {
+    let mut range: Option<(f64, f64)> = None;
+    let mut lines = 0;
+    for raw in split_lines(data) {
+        lines += 1;
+        if let Some(line) = parse_line(&decode_line(raw).text) {
+            let (first, _) = range.unwrap_or((line.timestamp, line.timestamp));
+            range = Some((first, line.timestamp));
+        }
+    }
+    let (first, last) = range.unwrap_or((0.0, 0.0));
+    (first, last, lines)
+}
+
+// The index: a comment with the seal count, then a line per segment of
+// file name, first and last timestamp and line count, tab separated.
+pub fn parse_index(text: &str) -> (Vec<Segment>, u64) {
+    let mut segments = Vec::new();
+    let mut seals = 0;
+    for line in text.lines() {
+        if let Some(count) = line.strip_prefix("# seals ") {
+            seals = count.trim().parse().unwrap_or(0);
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 4 {
+            continue;
+        }
+        let parsed = (
+            segment_number(fields[0]),
+            fields[1].parse::<f64>(),
+            fields[2].parse::<f64>(),
+            fields[3].parse::<usize>(),
+        );
+        if let (Some(number), Ok(first), Ok(last), Ok(lines)) = parsed {
+            segments.push(Segment {
+                number,
+                first,
+                last,
+                lines,
+            });
+        }
+    }
+    (segments, seals)
+}
+
+pub fn format_index(segments: &[Segment], seals: u64) -> String {
+    let mut text = format!("# seals {}\n# file\tfirst\tlast\tlines\n", seals);
+    for segment in segments.iter() {
+        text.push_str(&format!(
+            "{}\t{:.6}\t{:.6}\t{}\n",
+            segment.file_name(),
+            segment.first,
+            segment.last,
+            segment.lines
+        ));
+    }
+    text
+}
+
+// Write through a temporary file and a rename, so readers never see a
+// partial file.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path)
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    segment_bytes: usize,
+    keep: usize,
+    // Oldest first.
+    segments: Vec<Segment>,
+    next: u64,
+    seals: u64,
+    // The lines of the open segment, also appended to the partial file.
+    current: Vec<u8>,
+    partial: File,
+}
+
+impl Recorder {
+    // Open the directory, picking up the segments of earlier runs. Segment
+    // files missing from the index, left by a crash before it was written,
+    // are scanned for their range, and the lines of a segment left open are
+    // closed into one.
+    pub fn open(dir: &Path, segment_bytes: usize, keep: usize) -> io::Result<Recorder> {
+        fs::create_dir_all(dir)?;
+        let (indexed, seals) =
+            parse_index(&fs::read_to_string(dir.join(INDEX_FILE)).unwrap_or_default());
+        let mut numbers: Vec<u64> = fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| segment_number(&entry.file_name().to_string_lossy()))
+            .collect();
+        numbers.sort_unstable();
+        let mut segments = Vec::new();
+        for number in numbers {
+            match indexed.iter().position(|segment| segment.number == number) {
+                Some(index) => segments.push(indexed[index]),
+                None => {
+                    let path = dir.join(segment_name(number));
+                    let (first, last, lines) = read_trace_file(&path.to_string_lossy())
+                        .map(|data| time_range(&data))
+                        .unwrap_or((0.0, 0.0, 0));
+                    segments.push(Segment {
+                        number,
+                        first,
+                        last,
+                        lines,
+                    });
+                }
+            }
+        }
+        let next = segments.last().map_or(1, |segment| segment.number + 1);
+        let partial_path = dir.join(PARTIAL_FILE);
+        let current = match fs::read(&partial_path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let partial = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial_path)?;
+        let mut recorder = Recorder {
+            dir: dir.to_path_buf(),
+            segment_bytes,
+            keep: keep.max(1),
+            segments,
+            next,
+            seals,
+            current,
+            partial,
+        };
+        if recorder.current.is_empty() {
+            // The kept count may have been lowered since the last run.
+            recorder.prune()?;
+        } else {
+            // A crash can cut the last line short.
+            if !recorder.current.ends_with(b"\n") {
+                recorder.current.push(b'\n');
+            }
+            recorder.close_segment()?;
+        }
+        Ok(recorder)
+    }
+
+    // Add a line of trace_pipe output, without its newline.
+    pub fn push(&mut self, line: &[u8]) -> io::Result<()> {
+        let start = self.current.len();
+        self.current.extend_from_slice(line);
+        self.current.push(b'\n');
+        self.partial.write_all(&self.current[start..])?;
+        if self.current.len() >= self.segment_bytes {
+            self.close_segment()?;
+        }
+        Ok(())
+    }
+
+    // Close the open segment, if it has lines, and count the seal in the
+    // index so the requester sees it was done.
+    pub fn seal(&mut self) -> io::Result<()> {
+        if !self.current.is_empty() {
+            self.close_segment()?;
+        }
+        self.seals += 1;
+        self.write_index()
+    }
+
+    fn close_segment(&mut self) -> io::Result<()> {
+        let data = std::mem::take(&mut self.current);
+        let (first, last, lines) = time_range(&data);
+        let segment = Segment {
+            number: self.next,
+            first,
+            last,
+            lines,
+        };
+        write_atomic(&self.dir.join(segment.file_name()), &deflate_data(&data)?)?;
+        // The lines are in the segment now, a crash from here on must not
+        // recover them twice.
+        self.partial.set_len(0)?;
+        self.next += 1;
+        self.segments.push(segment);
+        self.prune()?;
+        self.write_index()
+    }
+
+    // Delete the oldest segments past the kept count.
+    fn prune(&mut self) -> io::Result<()> {
+        while self.segments.len() > self.keep {
+            let oldest = self.segments.remove(0);
+            if let Err(e) = fs::remove_file(self.dir.join(oldest.file_name())) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_index(&self) -> io::Result<()> {
+        write_atomic(
+            &self.dir.join(INDEX_FILE),
+            format_index(&self.segments, self.seals).as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Dir {
+            let path =
+                std::env::temp_dir().join(format!("atrace-flight-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            Dir(path)
+        }
+
+        fn segment_files(&self) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| segment_number(name).is_some())
+                .collect();
+            names.sort();
+            names
+        }
+
+        fn segment(&self, number: u64) -> String {
+            let path = self.0.join(segment_name(number));
+            String::from_utf8(read_trace_file(&path.to_string_lossy()).unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn line(i: usize) -> String {
+        format!(
+            "  app-1234  ( 1234) [000] ...1  {}.500000: tracing_mark_write: I|1234|line {}",
+            10 + i,
+            i
+        )
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let segments = [
+            Segment {
+                number: 3,
+                first: 10.5,
+                last: 12.25,
+                lines: 40,
+            },
+            Segment {
+                number: 4,
+                first: 0.0,
+                last: 0.0,
+                lines: 0,
+            },
+        ];
+        let text = format_index(&segments, 2);
+        let (parsed, seals) = parse_index(&text);
+        assert_eq!(seals, 2);
+        assert_eq!(parsed.len(), 2);
+        for (parsed, segment) in parsed.iter().zip(segments.iter()) {
+            assert_eq!(parsed.number, segment.number);
+            assert_eq!(parsed.first, segment.first);
+            assert_eq!(parsed.last, segment.last);
+            assert_eq!(parsed.lines, segment.lines);
+        }
+        assert_eq!(format_index(&parsed, seals), text);
+    }
+
+    #[test]
+    fn index_skips_malformed_lines() {
+        let text = "# seals x\n\
+                    segment-000001.z\t1.0\t2.0\t3\n\
+                    segment-000002.z\t1.0\t2.0\n\
+                    other.z\t1.0\t2.0\t3\n\
+                    segment-000003.z\tnan?\t2.0\t3\n";
+        let (segments, seals) = parse_index(text);
+        assert_eq!(seals, 0);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].number, 1);
+    }
+
+    #[test]
+    fn segments_roll_and_prune() {
+        let dir = Dir::new("prune");
+        let one = line(0).len() + 1;
+        let mut recorder = Recorder::open(&dir.0, one * 2, 2).unwrap();
+        for i in 0..7 {
+            recorder.push(line(i).as_bytes()).unwrap();
+        }
+        assert_eq!(
+            dir.segment_files(),
+            ["segment-000002.z", "segment-000003.z"]
+        );
+        let (indexed, _) = parse_index(&fs::read_to_string(dir.0.join(INDEX_FILE)).unwrap());
+        assert_eq!(indexed.len(), 2);
+        assert_eq!(indexed[0].number, 2);
+        assert_eq!(indexed[0].first, 12.5);
+        assert_eq!(indexed[0].last, 13.5);
+        assert_eq!(indexed[1].lines, 2);
+        assert_eq!(dir.segment(3), format!("{}\n{}\n", line(4), line(5)));
+        // The seventh line is still open.
+        recorder.seal().unwrap();
+        assert_eq!(dir.segment(4), format!("{}\n", line(6)));
+        assert_eq!(dir.segment_files().len(), 2);
+    }
+
+    #[test]
+    fn lowered_keep_prunes_on_open() {
+        let dir = Dir::new("keep");
+        let mut recorder = Recorder::open(&dir.0, 1, 10).unwrap();
+        for i in 0..5 {
+            recorder.push(line(i).as_bytes()).unwrap();
+        }
+        drop(recorder);
+        Recorder::open(&dir.0, 1, 3).unwrap();
+        assert_eq!(
+            dir.segment_files(),
+            ["segment-000003.z", "segment-000004.z", "segment-000005.z"]
+        );
+    }
+
+    #[test]
+    fn open_segment_is_recovered() {
+        let dir = Dir::new("recover");
+        let mut recorder = Recorder::open(&dir.0, 1 << 20, 4).unwrap();
+        for i in 0..3 {
+            recorder.push(line(i).as_bytes()).unwrap();
+        }
+        // A crash: the recorder goes away without sealing.
+        drop(recorder);
+        assert!(dir.segment_files().is_empty());
+
+        let recorder = Recorder::open(&dir.0, 1 << 20, 4).unwrap();
+        assert_eq!(dir.segment_files(), ["segment-000001.z"]);
+        assert_eq!(
+            dir.segment(1),
+            format!("{}\n{}\n{}\n", line(0), line(1), line(2))
+        );
+        assert_eq!(fs::metadata(dir.0.join(PARTIAL_FILE)).unwrap().len(), 0);
+        assert_eq!(recorder.segments[0].lines, 3);
+        assert_eq!(recorder.next, 2);
+    }
+
+    #[test]
+    fn cut_partial_line_is_kept() {
+        let dir = Dir::new("cut");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(
+            dir.0.join(PARTIAL_FILE),
+            format!("{}\n{}", line(0), &line(1)[..20]),
+        )
+        .unwrap();
+        Recorder::open(&dir.0, 1 << 20, 4).unwrap();
+        assert_eq!(dir.segment(1), format!("{}\n{}\n", line(0), &line(1)[..20]));
+    }
+
+    #[test]
+    fn unindexed_segment_is_scanned() {
+        let dir = Dir::new("scan");
+        let mut recorder = Recorder::open(&dir.0, 1 << 20, 4).unwrap();
+        recorder.push(line(1).as_bytes()).unwrap();
+        recorder.push(line(2).as_bytes()).unwrap();
+        recorder.seal().unwrap();
+        drop(recorder);
+        fs::remove_file(dir.0.join(INDEX_FILE)).unwrap();
+        let recorder = Recorder::open(&dir.0, 1 << 20, 4).unwrap();
+        assert_eq!(recorder.segments.len(), 1);
+        assert_eq!(recorder.segments[0].first, 11.5);
+        assert_eq!(recorder.segments[0].last, 12.5);
+        assert_eq!(recorder.segments[0].lines, 2);
+        assert_eq!(recorder.seals, 0);
+    }
+}
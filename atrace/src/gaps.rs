@@ -0,0 +1,218 @@
+// Detection of clock discontinuities in a capture, as left by a suspend and
+// resume: a long stretch without any event on any cpu, or a timestamp
+// jumping backwards, which breaks marker pairing.
+//
+// The detector only sees the timestamps of the lines in file order. ftrace
+// merges the per-cpu buffers by time, so the stream is monotonic unless the
+// clock itself jumped.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// 5 seconds as f64 bits, the default --gap-threshold.
+const DEFAULT_THRESHOLD_BITS: u64 = 0x4014_0000_0000_0000;
+
+static THRESHOLD: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD_BITS);
+static EXCLUDE: AtomicBool = AtomicBool::new(true);
+
+// Set the gap length reported as a discontinuity in seconds, 0 to only
+// report backwards jumps, and whether summaries leave gaps out of durations.
+pub fn configure(threshold: f64, exclude: bool) {
+    THRESHOLD.store(threshold.to_bits(), Ordering::SeqCst);
+    EXCLUDE.store(exclude, Ordering::SeqCst);
+}
+
+pub fn threshold() -> f64 {
+    f64::from_bits(THRESHOLD.load(Ordering::SeqCst))
+}
+
+pub fn exclude_enabled() -> bool {
+    EXCLUDE.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum GapKind {
+    // Nothing was traced for longer than the threshold, likely a suspend.
+    Idle,
+    // The clock went backwards.
+    Backwards,
+}
+
+#[derive(Clone, Copy)]
+pub struct Discontinuity {
+    pub kind: GapKind,
+    // The timestamps on either side.
+    pub before: f64,
+    pub after: f64,
+}
+
+impl Discontinuity {
+    pub fn length(&self) -> f64 {
+        (self.after - self.before).abs()
+    }
+
+    pub fn describe(&self) -> String {
+        match self.kind {
+            GapKind::Idle => format!(
+                "no events for {:.3}s after {:.6}, possibly a suspend",
+                self.length(),
+                self.before
+            ),
+            GapKind::Backwards => format!(
+                "clock jumped back {:.6}s from {:.6} to {:.6}, markers across it may pair wrongly",
+                self.length(),
+                self.before,
+                self.after
+            ),
+        }
+    }
+}
+
+pub struct GapDetector {
+    threshold: f64,
+    last: Option<f64>,
+    found: Vec<Discontinuity>,
+}
+
+impl Default for GapDetector {
+    fn default() -> GapDetector {
+        GapDetector::new(threshold())
+    }
+}
+
+impl GapDetector {
+    pub fn new(threshold: f64) -> GapDetector {
+        GapDetector {
+            threshold,
+            last: None,
+            found: Vec::new(),
+        }
+    }
+
+    // Feed the timestamp of the next line.
+    pub fn push(&mut self, ts: f64) {
+        if let Some(last) = self.last {
+            let kind = if ts < last {
+                Some(GapKind::Backwards)
+            } else if self.threshold > 0.0 && ts - last > self.threshold {
+                Some(GapKind::Idle)
+            } else {
+                None
+            };
+            if let Some(kind) = kind {
+                self.found.push(Discontinuity {
+                    kind,
+                    before: last,
+                    after: ts,
+                });
+            }
+        }
+        self.last = Some(ts);
+    }
+
+    // How many times the clock went back so far, to tell slices across a
+    // jump.
+    pub fn backwards_jumps(&self) -> usize {
+        self.found
+            .iter()
+            .filter(|gap| gap.kind == GapKind::Backwards)
+            .count()
+    }
+
+    pub fn finish(self) -> Vec<Discontinuity> {
+        self.found
+    }
+}
+
+// A slice duration without the idle gaps inside it.
+pub fn without_gaps(start: f64, duration: f64, gaps: &[Discontinuity]) -> f64 {
+    let end = start + duration;
+    let idle: f64 = gaps
+        .iter()
+        .filter(|gap| gap.kind == GapKind::Idle)
+        .map(|gap| (gap.after.min(end) - gap.before.max(start)).max(0.0))
+        .sum();
+    (duration - idle).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(threshold: f64, timestamps: &[f64]) -> Vec<Discontinuity> {
+        let mut detector = GapDetector::new(threshold);
+        for ts in timestamps {
+            detector.push(*ts);
+        }
+        detector.finish()
+    }
+
+    fn idle(before: f64, after: f64) -> Discontinuity {
+        Discontinuity {
+            kind: GapKind::Idle,
+            before,
+            after,
+        }
+    }
+
+    #[test]
+    fn steady_stream_has_no_discontinuity() {
+        assert!(detect(5.0, &[1.0, 1.0, 2.0, 6.5, 11.0]).is_empty());
+        assert!(detect(5.0, &[]).is_empty());
+    }
+
+    #[test]
+    fn long_gaps_are_idle() {
+        let found = detect(5.0, &[1.0, 2.0, 7.5, 8.0, 8.0, 20.0]);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|gap| gap.kind == GapKind::Idle));
+        assert_eq!((found[0].before, found[0].after), (2.0, 7.5));
+        assert_eq!((found[1].before, found[1].after), (8.0, 20.0));
+        assert_eq!(found[1].length(), 12.0);
+        assert_eq!(
+            found[0].describe(),
+            "no events for 5.500s after 2.000000, possibly a suspend"
+        );
+        // A threshold of 0 turns the gap check off.
+        assert!(detect(0.0, &[1.0, 100.0]).is_empty());
+    }
+
+    #[test]
+    fn backwards_jumps_are_counted() {
+        let mut detector = GapDetector::new(5.0);
+        for ts in &[10.0, 11.0, 3.0, 4.0] {
+            detector.push(*ts);
+        }
+        assert_eq!(detector.backwards_jumps(), 1);
+        detector.push(3.5);
+        assert_eq!(detector.backwards_jumps(), 2);
+        let found = detector.finish();
+        assert!(found.iter().all(|gap| gap.kind == GapKind::Backwards));
+        assert_eq!((found[0].before, found[0].after), (11.0, 3.0));
+        assert_eq!(found[0].length(), 8.0);
+        assert!(found[0]
+            .describe()
+            .starts_with("clock jumped back 8.000000s"));
+        // Even with the gap check off.
+        assert_eq!(detect(0.0, &[2.0, 1.0]).len(), 1);
+    }
+
+    #[test]
+    fn idle_gaps_are_taken_out_of_durations() {
+        let gaps = [
+            idle(2.0, 12.0),
+            idle(20.0, 30.0),
+            Discontinuity {
+                kind: GapKind::Backwards,
+                before: 40.0,
+                after: 35.0,
+            },
+        ];
+        // Inside a slice, overlapping its start or end, or outside it.
+        assert_eq!(without_gaps(1.0, 13.0, &gaps), 3.0);
+        assert_eq!(without_gaps(5.0, 10.0, &gaps), 3.0);
+        assert_eq!(without_gaps(25.0, 10.0, &gaps), 5.0);
+        assert_eq!(without_gaps(13.0, 5.0, &gaps), 5.0);
+        assert_eq!(without_gaps(36.0, 6.0, &gaps), 6.0);
+        assert_eq!(without_gaps(3.0, 4.0, &gaps), 0.0);
+    }
+}
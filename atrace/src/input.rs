@@ -0,0 +1,531 @@
+// Reading of dumped trace files, which are either plain text or the zlib
+// stream written with -Z, checked against its integrity footer. Files
+// derived from a trace are written back in the same form. Compressed files
+// are inflated as they are read, so a capture is never held whole.
+//
+// Dumps are split into lines as bytes and decoded one line at a time, as an
+// application may have traced binary data: a bad line is replaced lossily
+// and counted instead of failing the whole file.
+
+use libc::{c_void, free, malloc, memset};
+use libz_sys::{
+    deflate, deflateEnd, deflateInit_, inflate, inflateEnd, inflateInit_, z_stream, z_streamp,
+    zlibVersion, Z_BUF_ERROR, Z_DEFAULT_COMPRESSION, Z_FINISH, Z_NO_FLUSH, Z_OK, Z_STREAM_END,
+};
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+
+use crate::budget::BUDGET;
+use crate::integrity::{self, Checksum, FOOTER_LEN};
+
+const INFLATE_CHUNK: usize = 64 * 1024;
+// Longer lines are cut, so one huge line can't balloon the parsed model.
+pub const MAX_LINE_BYTES: usize = 64 * 1024;
+const CUT_MARK: &str = "…[cut]";
+
+// Whether the data starts with a zlib stream header.
+fn is_zlib(data: &[u8]) -> bool {
+    data.len() >= 2
+        && data[0] & 0x0f == 8
+        && (u16::from(data[0]) * 256 + u16::from(data[1])) % 31 == 0
+}
+
+// A reader uncompressing a zlib stream as it is read, which verifies the
+// footer after it once the stream ends. Only a chunk of each side is held,
+// however large the capture.
+pub struct Inflater<R: BufRead> {
+    inner: R,
+    stream: z_streamp,
+    checksum: Checksum,
+    done: bool,
+}
+
+impl<R: BufRead> Inflater<R> {
+    pub fn new(inner: R) -> io::Result<Inflater<R>> {
+        let size = mem::size_of::<z_stream>();
+        let stream: z_streamp = unsafe { malloc(size) as *mut z_stream };
+        if stream.is_null() {
+            return Err(io::Error::other("out of memory"));
+        }
+        unsafe {
+            memset(stream as *mut c_void, 0, size);
+        }
+        let ret = unsafe { inflateInit_(stream, zlibVersion(), size.try_into().unwrap()) };
+        if ret != Z_OK {
+            unsafe { free(stream as *mut c_void) };
+            return Err(io::Error::other("inflateInit fail"));
+        }
+        Ok(Inflater {
+            inner,
+            stream,
+            checksum: Checksum::new(),
+            done: false,
+        })
+    }
+
+    // Inflate into buf, giving the bytes produced and whether the stream
+    // ended.
+    fn inflate(&mut self, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+        loop {
+            let input = self.inner.fill_buf()?;
+            let (ret, consumed, produced) = unsafe {
+                (*self.stream).next_in = input.as_ptr() as *mut u8;
+                (*self.stream).avail_in = input.len().try_into().unwrap();
+                (*self.stream).next_out = buf.as_mut_ptr();
+                (*self.stream).avail_out = buf.len().try_into().unwrap();
+                let ret = inflate(self.stream, Z_NO_FLUSH);
+                (
+                    ret,
+                    input.len() - (*self.stream).avail_in as usize,
+                    buf.len() - (*self.stream).avail_out as usize,
+                )
+            };
+            let eof = input.is_empty();
+            self.inner.consume(consumed);
+            self.checksum.update(&buf[..produced]);
+            match ret {
+                Z_STREAM_END => return Ok((produced, true)),
+                Z_OK if produced > 0 => return Ok((produced, false)),
+                Z_OK => continue,
+                // No more input before the end of stream: a truncated file.
+                Z_BUF_ERROR if eof => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("capture truncated at byte {}", self.checksum.len()),
+                    ))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("capture corrupted at byte {}", self.checksum.len()),
+                    ))
+                }
+            }
+        }
+    }
+
+    // Check the bytes left after the stream against the footer.
+    fn verify(&mut self) -> io::Result<()> {
+        let mut trailer = Vec::new();
+        // One byte past the footer tells trailing data apart from it.
+        (&mut self.inner)
+            .take(FOOTER_LEN as u64 + 1)
+            .read_to_end(&mut trailer)?;
+        self.checksum
+            .verify(&trailer)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<R: BufRead> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let result = self.inflate(buf).and_then(|(produced, end)| {
+            if end {
+                self.done = true;
+                self.verify()?;
+            }
+            Ok(produced)
+        });
+        match result {
+            Ok(produced) => Ok(produced),
+            // End with what could be read under --no-verify.
+            Err(e) if !integrity::verify_enabled() => {
+                eprintln!("warning: {}, continuing with --no-verify", e);
+                self.done = true;
+                Ok(0)
+            }
+            Err(e) => {
+                self.done = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Drop for Inflater<R> {
+    fn drop(&mut self) {
+        unsafe {
+            inflateEnd(self.stream);
+            free(self.stream as *mut c_void);
+        }
+    }
+}
+
+// Read all of a reader, within the memory budget.
+fn read_all(mut reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut chunk = vec![0u8; INFLATE_CHUNK];
+    loop {
+        let len = reader.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(out);
+        }
+        out.extend_from_slice(&chunk[..len]);
+        BUDGET.check_memory(out.len())?;
+    }
+}
+
+// A writer compressing into a zlib stream as it is written, followed by
+// the integrity footer on finish, as -Z writes it.
+pub struct Deflater<W: Write> {
+    inner: W,
+    stream: z_streamp,
+    chunk: Vec<u8>,
+    checksum: Checksum,
+}
+
+impl<W: Write> Deflater<W> {
+    pub fn new(inner: W) -> io::Result<Deflater<W>> {
+        let size = mem::size_of::<z_stream>();
+        let stream: z_streamp = unsafe { malloc(size) as *mut z_stream };
+        if stream.is_null() {
+            return Err(io::Error::other("out of memory"));
+        }
+        unsafe {
+            memset(stream as *mut c_void, 0, size);
+        }
+        let ret = unsafe {
+            deflateInit_(
+                stream,
+                Z_DEFAULT_COMPRESSION,
+                zlibVersion(),
+                size.try_into().unwrap(),
+            )
+        };
+        if ret != Z_OK {
+            unsafe { free(stream as *mut c_void) };
+            return Err(io::Error::other("deflateInit fail"));
+        }
+        Ok(Deflater {
+            inner,
+            stream,
+            chunk: vec![0u8; INFLATE_CHUNK],
+            checksum: Checksum::new(),
+        })
+    }
+
+    // Deflate data until all of it is taken, or the stream ends on
+    // Z_FINISH.
+    fn deflate(&mut self, data: &[u8], flush: i32) -> io::Result<()> {
+        unsafe {
+            (*self.stream).next_in = data.as_ptr() as *mut u8;
+            (*self.stream).avail_in = data.len().try_into().unwrap();
+        }
+        loop {
+            let (ret, produced, avail_in) = unsafe {
+                (*self.stream).next_out = self.chunk.as_mut_ptr();
+                (*self.stream).avail_out = INFLATE_CHUNK.try_into().unwrap();
+                let ret = deflate(self.stream, flush);
+                (
+                    ret,
+                    INFLATE_CHUNK - (*self.stream).avail_out as usize,
+                    (*self.stream).avail_in,
+                )
+            };
+            self.inner.write_all(&self.chunk[..produced])?;
+            match ret {
+                Z_STREAM_END => return Ok(()),
+                Z_OK | Z_BUF_ERROR if flush == Z_NO_FLUSH && avail_in == 0 => return Ok(()),
+                Z_OK | Z_BUF_ERROR => continue,
+                _ => return Err(io::Error::other("deflate fail")),
+            }
+        }
+    }
+
+    // End the stream and write the footer, the last write.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.deflate(&[], Z_FINISH)?;
+        self.inner.write_all(&self.checksum.footer())?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for Deflater<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.deflate(buf, Z_NO_FLUSH)?;
+        self.checksum.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Deflater<W> {
+    fn drop(&mut self) {
+        unsafe {
+            deflateEnd(self.stream);
+            free(self.stream as *mut c_void);
+        }
+    }
+}
+
+// Deflate data into a zlib stream followed by the integrity footer, as -Z
+// writes it.
+pub fn deflate_data(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() / 4);
+    let mut deflater = Deflater::new(&mut out)?;
+    deflater.write_all(data)?;
+    deflater.finish()?;
+    drop(deflater);
+    Ok(out)
+}
+
+// Open a trace file for reading, uncompressed as it is read when written
+// with -Z, and tell which.
+pub fn open_trace_file(path: &str) -> io::Result<(Box<dyn BufRead>, bool)> {
+    let mut file = BufReader::with_capacity(INFLATE_CHUNK, File::open(path)?);
+    if is_zlib(file.fill_buf()?) {
+        let inflater = Inflater::new(file)?;
+        Ok((
+            Box::new(BufReader::with_capacity(INFLATE_CHUNK, inflater)),
+            true,
+        ))
+    } else {
+        Ok((Box::new(file), false))
+    }
+}
+
+// Read a trace file and tell whether it was written with -Z.
+pub fn read_trace_file_with_format(path: &str) -> io::Result<(Vec<u8>, bool)> {
+    let (reader, compressed) = open_trace_file(path)?;
+    read_all(reader).map(|data| (data, compressed))
+}
+
+// Read a trace file, uncompressing it when written with -Z.
+pub fn read_trace_file(path: &str) -> io::Result<Vec<u8>> {
+    read_trace_file_with_format(path).map(|(data, _)| data)
+}
+
+// Give each line of a trace to f as split_lines splits it, reading as it
+// goes. Bytes past what decode_line keeps of a long line are skipped
+// rather than held.
+pub fn read_lines(mut reader: impl BufRead, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut line = Vec::new();
+    let mut started = false;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let (used, ended) = match buf.iter().position(|byte| *byte == b'\n') {
+            Some(newline) => (newline + 1, true),
+            None => (buf.len(), false),
+        };
+        let part = &buf[..if ended { used - 1 } else { used }];
+        let room = (MAX_LINE_BYTES + 4).saturating_sub(line.len());
+        line.extend_from_slice(&part[..part.len().min(room)]);
+        reader.consume(used);
+        started = true;
+        if ended {
+            f(line.strip_suffix(b"\r").unwrap_or(&line));
+            line.clear();
+            started = false;
+        }
+    }
+    if started {
+        f(line.strip_suffix(b"\r").unwrap_or(&line));
+    }
+    Ok(())
+}
+
+// Split a dump into byte lines like str::lines: "\n" or "\r\n" ends a
+// line and a final newline adds no empty line.
+pub fn split_lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let empty = data.is_empty();
+    let data = match data.last() {
+        Some(b'\n') => &data[..data.len() - 1],
+        _ => data,
+    };
+    data.split(|byte| *byte == b'\n')
+        .filter(move |_| !empty)
+        .map(|line| match line.last() {
+            Some(b'\r') => &line[..line.len() - 1],
+            _ => line,
+        })
+}
+
+pub struct DecodedLine<'a> {
+    pub text: Cow<'a, str>,
+    // Invalid UTF-8 was replaced.
+    pub invalid: bool,
+    // Cut at MAX_LINE_BYTES, the text then ends with a mark.
+    pub cut: bool,
+}
+
+pub fn decode_line(line: &[u8]) -> DecodedLine<'_> {
+    let cut = line.len() > MAX_LINE_BYTES;
+    let line = if cut {
+        // Back off to the start of a character split by the cut.
+        let mut end = MAX_LINE_BYTES;
+        while end > MAX_LINE_BYTES - 3 && line[end] & 0xc0 == 0x80 {
+            end -= 1;
+        }
+        &line[..end]
+    } else {
+        line
+    };
+    let text = String::from_utf8_lossy(line);
+    let invalid = matches!(text, Cow::Owned(_));
+    let text = if cut {
+        Cow::Owned(format!("{}{}", text, CUT_MARK))
+    } else {
+        text
+    };
+    DecodedLine { text, invalid, cut }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inflate_data(data: &[u8]) -> io::Result<Vec<u8>> {
+        read_all(Inflater::new(data)?)
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..2000)
+            .map(|i| {
+                format!(
+                    "  app-1234  ( 1234) [000] ...1  10.{:06}: tracing_mark_write: B|1234|step {}\n",
+                    i,
+                    i * 7
+                )
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn error(data: &[u8]) -> io::Error {
+        match inflate_data(data) {
+            Ok(_) => panic!("damaged capture was accepted"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        let compressed = deflate_data(&data).unwrap();
+        assert!(is_zlib(&compressed));
+        assert_eq!(inflate_data(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let compressed = deflate_data(&sample()).unwrap();
+        let e = error(&compressed[..compressed.len() / 2]);
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(e.to_string().starts_with("capture truncated at byte"));
+    }
+
+    #[test]
+    fn flipped_byte_in_stream() {
+        let mut compressed = deflate_data(&sample()).unwrap();
+        let middle = compressed.len() / 2;
+        compressed[middle] ^= 0x10;
+        assert!(error(&compressed).to_string().starts_with("capture "));
+    }
+
+    #[test]
+    fn flipped_byte_in_footer() {
+        let mut compressed = deflate_data(&sample()).unwrap();
+        let last = compressed.len() - 1;
+        compressed[last] ^= 0x01;
+        let e = error(&compressed);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("checksum mismatch"), "{}", e);
+    }
+
+    #[test]
+    fn missing_footer_is_a_legacy_capture() {
+        let data = sample();
+        let compressed = deflate_data(&data).unwrap();
+        let legacy = &compressed[..compressed.len() - integrity::FOOTER_LEN];
+        assert_eq!(inflate_data(legacy).unwrap(), data);
+    }
+
+    #[test]
+    fn partial_footer() {
+        let compressed = deflate_data(&sample()).unwrap();
+        let e = error(&compressed[..compressed.len() - 8]);
+        assert!(e.to_string().contains("footer is incomplete"), "{}", e);
+    }
+
+    fn lines_of(reader: impl BufRead) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        read_lines(reader, |line| lines.push(line.to_vec())).unwrap();
+        lines
+    }
+
+    #[test]
+    fn read_lines_splits_like_split_lines() {
+        for data in &[
+            &b""[..],
+            b"\n",
+            b"a",
+            b"a\n",
+            b"a\r\nb\r\n",
+            b"a\n\nb",
+            b"a\n\n",
+        ] {
+            let expected: Vec<Vec<u8>> = split_lines(data).map(<[u8]>::to_vec).collect();
+            assert_eq!(lines_of(&data[..]), expected, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn read_lines_bounds_long_lines() {
+        let mut data = vec![b'x'; MAX_LINE_BYTES * 3];
+        data.extend_from_slice(b"\nshort\n");
+        // A small buffer makes the long line span many reads.
+        let lines = lines_of(BufReader::with_capacity(1000, &data[..]));
+        assert_eq!(lines.len(), 2);
+        assert!(decode_line(&lines[0]).cut);
+        assert!(lines[0].len() < MAX_LINE_BYTES * 2);
+        assert_eq!(lines[1], b"short");
+    }
+
+    #[test]
+    fn inflater_streams_in_small_reads() {
+        let data = sample();
+        let compressed = deflate_data(&data).unwrap();
+        let mut inflater = Inflater::new(BufReader::with_capacity(100, &compressed[..])).unwrap();
+        let mut out = Vec::new();
+        let mut chunk = [0u8; 333];
+        loop {
+            let len = inflater.read(&mut chunk).unwrap();
+            if len == 0 {
+                break;
+            }
+            out.extend_from_slice(&chunk[..len]);
+        }
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn inflater_fails_lines_on_a_truncated_stream() {
+        let compressed = deflate_data(&sample()).unwrap();
+        let cut = &compressed[..compressed.len() / 2];
+        let mut lines = 0;
+        let e =
+            read_lines(BufReader::new(Inflater::new(cut).unwrap()), |_| lines += 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(lines > 0);
+    }
+
+    #[test]
+    fn data_after_footer() {
+        let mut compressed = deflate_data(&sample()).unwrap();
+        compressed.extend_from_slice(b"junk");
+        let e = error(&compressed);
+        assert!(e.to_string().contains("unexpected data"), "{}", e);
+    }
+}
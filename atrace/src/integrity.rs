@@ -0,0 +1,92 @@
+// Integrity footer of -Z captures, so a file truncated or damaged in a copy
+// fails with the byte it breaks at instead of odd parse errors.
+//
+// The footer follows the zlib stream: the "ATRF" magic, the uncompressed
+// byte count as u64 and the crc32 of the uncompressed bytes as u32, both
+// little-endian. Captures written before the footer have none, and are only
+// checked by the zlib stream itself.
+
+use libz_sys::crc32;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const FOOTER_MAGIC: &[u8; 4] = b"ATRF";
+pub const FOOTER_LEN: usize = 16;
+
+// Cleared by --no-verify, to salvage what is left of a damaged capture.
+static VERIFY: AtomicBool = AtomicBool::new(true);
+
+pub fn set_verify(verify: bool) {
+    VERIFY.store(verify, Ordering::SeqCst);
+}
+
+pub fn verify_enabled() -> bool {
+    VERIFY.load(Ordering::SeqCst)
+}
+
+// Byte count and crc32 of the uncompressed content, updated as it streams.
+pub struct Checksum {
+    len: u64,
+    crc: u32,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum { len: 0, crc: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        // crc32 takes at most u32::MAX bytes per call.
+        for chunk in data.chunks(u32::MAX as usize) {
+            self.crc = unsafe { crc32(self.crc.into(), chunk.as_ptr(), chunk.len() as u32) } as u32;
+        }
+        self.len += data.len() as u64;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn footer(&self) -> [u8; FOOTER_LEN] {
+        let mut footer = [0u8; FOOTER_LEN];
+        footer[..4].copy_from_slice(FOOTER_MAGIC);
+        footer[4..12].copy_from_slice(&self.len.to_le_bytes());
+        footer[12..].copy_from_slice(&self.crc.to_le_bytes());
+        footer
+    }
+
+    // Check the content against the bytes following the zlib stream.
+    pub fn verify(&self, trailer: &[u8]) -> Result<(), String> {
+        if trailer.is_empty() {
+            return Ok(());
+        }
+        let magic = trailer.len().min(FOOTER_MAGIC.len());
+        if trailer.len() < FOOTER_LEN && trailer[..magic] == FOOTER_MAGIC[..magic] {
+            return Err(format!(
+                "capture truncated at byte {}: the footer is incomplete",
+                self.len
+            ));
+        }
+        if trailer.len() != FOOTER_LEN || &trailer[..4] != FOOTER_MAGIC {
+            return Err(format!(
+                "capture corrupted at byte {}: unexpected data after the compressed trace",
+                self.len
+            ));
+        }
+        let len = u64::from_le_bytes(trailer[4..12].try_into().unwrap());
+        let crc = u32::from_le_bytes(trailer[12..].try_into().unwrap());
+        if len > self.len {
+            Err(format!(
+                "capture truncated at byte {}, {} bytes expected",
+                self.len, len
+            ))
+        } else if len != self.len || crc != self.crc {
+            Err(format!(
+                "capture corrupted: checksum mismatch over {} bytes",
+                self.len
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
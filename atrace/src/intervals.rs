@@ -0,0 +1,154 @@
+// Synthetic gridlines at a fixed period, injected at convert time to check
+// a periodic workload like a render loop against its deadlines.
+//
+// The ticks are aligned to the first marker of a chosen name, or to the
+// capture start, and placed at start + k * period rather than by adding up
+// the period, so a long capture doesn't drift from the grid.
+
+use crate::capture::Capture;
+
+// More ticks than this is a period given in the wrong unit.
+const MAX_TICKS: usize = 1_000_000;
+
+// Where the grid starts: the first slice, async slice or instant named
+// align_to, or the capture start.
+pub fn align_start(capture: &Capture, align_to: Option<&str>) -> Option<f64> {
+    let name = match align_to {
+        Some(name) => name,
+        None => return Some(capture.first_timestamp),
+    };
+    let slices = capture
+        .slices
+        .iter()
+        .filter(|slice| slice.name == name)
+        .map(|slice| slice.start);
+    let async_slices = capture
+        .async_slices
+        .iter()
+        .filter(|slice| slice.name == name)
+        .map(|slice| slice.start);
+    let instants = capture
+        .instants
+        .iter()
+        .filter(|instant| instant.name == name)
+        .map(|instant| instant.timestamp);
+    slices
+        .chain(async_slices)
+        .chain(instants)
+        .fold(None, |first: Option<f64>, ts| {
+            Some(first.map_or(ts, |first| first.min(ts)))
+        })
+}
+
+// The tick timestamps from start to end, both inclusive.
+pub fn ticks(start: f64, period: f64, end: f64) -> Result<Vec<f64>, String> {
+    if period.is_nan() || period <= 0.0 {
+        return Err("the interval period must be above zero".to_string());
+    }
+    if end < start {
+        return Ok(Vec::new());
+    }
+    let count = ((end - start) / period).floor() as usize + 1;
+    if count > MAX_TICKS {
+        return Err(format!(
+            "a {}s period gives {} intervals over the capture, more than {}",
+            period, count, MAX_TICKS
+        ));
+    }
+    Ok((0..count)
+        .map(|index| start + index as f64 * period)
+        .filter(|ts| *ts <= end)
+        .collect())
+}
+
+// Add the ticks to the capture, giving their count.
+pub fn inject(capture: &mut Capture, period: f64, align_to: Option<&str>) -> Result<usize, String> {
+    let start = align_start(capture, align_to).ok_or_else(|| {
+        format!(
+            "no marker named {:?} to align the intervals to",
+            align_to.unwrap_or("")
+        )
+    })?;
+    capture.intervals = ticks(start, period, capture.last_timestamp)?;
+    Ok(capture.intervals.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture() -> Capture {
+        let data = "\
+  app-1234  ( 1234) [000] ...1  10.000000: tracing_mark_write: I|1234|start
+  app-1234  ( 1234) [000] ...1  10.005000: tracing_mark_write: B|1234|frame_begin
+  app-1234  ( 1234) [000] ...1  10.010000: tracing_mark_write: E|1234
+  app-1234  ( 1234) [000] ...1  10.004000: tracing_mark_write: S|1234|frame_begin|1
+  app-1234  ( 1234) [000] ...1  10.020000: tracing_mark_write: F|1234|frame_begin|1
+  app-1234  ( 1234) [000] ...1  10.030000: tracing_mark_write: I|1234|vsync
+  app-1234  ( 1234) [000] ...1  10.100000: tracing_mark_write: I|1234|end
+";
+        Capture::from_bytes(data.as_bytes())
+    }
+
+    #[test]
+    fn ticks_cover_the_range_inclusively() {
+        assert_eq!(
+            ticks(1.0, 0.25, 2.0).unwrap(),
+            vec![1.0, 1.25, 1.5, 1.75, 2.0]
+        );
+        assert_eq!(ticks(1.0, 0.25, 1.9).unwrap(), vec![1.0, 1.25, 1.5, 1.75]);
+        assert_eq!(ticks(1.0, 5.0, 2.0).unwrap(), vec![1.0]);
+        assert!(ticks(2.0, 1.0, 1.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn long_ranges_do_not_drift() {
+        let period = 0.0166;
+        let ticks = ticks(100.0, period, 100.0 + 999_999.0 * period).unwrap();
+        assert_eq!(ticks.len(), 1_000_000);
+        // Adding up the period drifts away from the grid, placing each tick
+        // from the start doesn't.
+        let summed = (0..999_999).fold(100.0, |ts, _| ts + period);
+        let last = ticks[ticks.len() - 1];
+        assert!((last - (100.0 + 999_999.0 * period)).abs() < 1e-9);
+        assert!((summed - last).abs() > 1e-9);
+        for (index, ts) in ticks.iter().enumerate().step_by(99_991) {
+            assert_eq!(*ts, 100.0 + index as f64 * period);
+        }
+    }
+
+    #[test]
+    fn bad_periods_are_refused() {
+        assert!(ticks(0.0, 0.0, 1.0).is_err());
+        assert!(ticks(0.0, -1.0, 1.0).is_err());
+        assert!(ticks(0.0, f64::NAN, 1.0).is_err());
+        let error = ticks(0.0, 0.000_001, 10.0).unwrap_err();
+        assert!(error.contains("more than 1000000"), "{}", error);
+    }
+
+    #[test]
+    fn grid_aligns_to_the_first_marker_of_the_name() {
+        let capture = capture();
+        assert_eq!(align_start(&capture, None), Some(10.0));
+        // The async slice of the name begins before the slice.
+        assert_eq!(align_start(&capture, Some("frame_begin")), Some(10.004));
+        assert_eq!(align_start(&capture, Some("vsync")), Some(10.03));
+        assert_eq!(align_start(&capture, Some("missing")), None);
+    }
+
+    #[test]
+    fn inject_fills_the_capture_intervals() {
+        let mut capture = capture();
+        assert_eq!(inject(&mut capture, 0.025, Some("vsync")), Ok(3));
+        let expected = [10.03, 10.055, 10.08];
+        assert_eq!(capture.intervals.len(), expected.len());
+        for (ts, expected) in capture.intervals.iter().zip(expected.iter()) {
+            assert!((ts - expected).abs() < 1e-9, "{} {}", ts, expected);
+        }
+        let error = inject(&mut capture, 0.025, Some("missing")).unwrap_err();
+        assert_eq!(
+            error,
+            "no marker named \"missing\" to align the intervals to"
+        );
+    }
+}
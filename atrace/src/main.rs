@@ -1,13 +1,14 @@
 #[macro_use(crate_version, crate_authors)]
 extern crate clap;
 use libc::{
-    access, c_int, c_void, close, creat, free, malloc, memset, open, read, sendfile, sigaction,
-    sigfillset, siginfo_t, sigset_t, write, EINVAL, F_OK, O_RDWR, SIGHUP, SIGINT, SIGQUIT, SIGSYS,
+    access, c_int, c_void, close, creat, free, kill, malloc, memset, read, sendfile, sigaction,
+    sigfillset, siginfo_t, sigset_t, write, EINVAL, ENOSYS, F_OK, SIGHUP, SIGINT, SIGQUIT, SIGSYS,
     SIGTERM, STDOUT_FILENO, W_OK,
 };
 use libz_sys::{
     self, deflate, deflateEnd, deflateInit_, inflate, inflateEnd, inflateInit_, z_stream,
-    z_streamp, zlibVersion, Z_DEFAULT_COMPRESSION, Z_FINISH, Z_NO_FLUSH, Z_OK,
+    z_streamp, zlibVersion, Z_BUF_ERROR, Z_DEFAULT_COMPRESSION, Z_FINISH, Z_NO_FLUSH, Z_OK,
+    Z_STREAM_END,
 };
 use std::convert::TryInto;
 use std::fmt::Write as FmtWrite;
@@ -22,19 +23,108 @@ use std::process::exit;
 use std::ptr::null_mut;
 use std::string::String;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 //command-line parsing
 mod cli;
-
+//ftrace text output parsing
+mod parser;
+//capture model built from parsed lines
+mod capture;
+//trace event json conversion
+mod convert;
+//per-name duration reports
+mod summary;
+//trace file reading
+mod input;
+//comparison of two captures
+mod diff;
+//perfetto protobuf conversion
+#[cfg(feature = "perfetto")]
+mod perfetto;
+//trigger windows over trace_pipe
+mod watch;
+//csv timeline export
+mod csv;
+//session annotations
+mod annotations;
+//container pid namespace and mount checks
+mod container;
+//ring buffer fill monitoring
+mod buffer;
+//system context collectors
+mod sysinfo;
+//output and memory limits
+mod budget;
+//compressed capture integrity footer
+mod integrity;
+//time range extraction
+mod extract;
+//suspend and clock jump detection
+mod gaps;
+//multi-capture reports
+mod report;
+//resident collector control socket
+mod daemon;
+//svg timeline rendering
+mod svg;
+//synthetic interval gridlines
+mod intervals;
+//kernel specific event profiles
+mod profile;
+//interactive event picker
+mod pick;
+//marker clock skew estimation
+mod skew;
+//tracefs root and instance selection
+mod tracefs;
+//marker replay into a live buffer
+mod replay;
+//flight recorder segments
+mod flight;
+//derived counter tracks
+mod counters;
+//dumped capture consistency checks
+mod verify;
+//remote capture over ssh or adb
+#[cfg(any(feature = "ssh", feature = "adb"))]
+mod remote;
+//session lock of the tracefs directory
+mod session;
+//marker line fixtures shared with the command line tests
+#[cfg(test)]
+#[path = "../tests/common/fixture.rs"]
+mod fixture;
+
+use self::budget::{LimitedWriter, BUDGET};
+use self::capture::Capture;
 use self::cli::{parse_options, Config};
+use self::integrity::{Checksum, FOOTER_LEN};
+use self::summary::Summary;
 
-const SYSTEM_KERNEL_DEBUG_TRACE: &str = "/sys/kernel/debug/tracing/";
 const BUFFER_LEN: usize = 64 * 1024;
 const FILE_LEN: usize = 64 * 1024 * 1024;
 const MAX_FILE_PATH_LEN: usize = 256;
+// Exit code of --diff when regressions are found, for CI gates.
+const EXIT_REGRESSIONS: i32 = 3;
+// Exit code of --remote when the target can't be reached, as opposed to
+// tracing failing on the target.
+#[cfg(any(feature = "ssh", feature = "adb"))]
+const EXIT_TRANSPORT: i32 = 4;
+// Exit code of a capture failing --verify.
+const EXIT_VERIFY: i32 = 5;
+// Interval of the --stop-on-full buffer checks.
+const FILL_POLL_MS: u64 = 250;
+// ioprio_set(2) arguments for the idle io class.
+const IOPRIO_WHO_PROCESS: c_int = 1;
+const IOPRIO_CLASS_IDLE: c_int = 3;
+const IOPRIO_CLASS_SHIFT: c_int = 13;
 
 static mut G_TRACE_ABORTED: bool = false;
+// The child of atrace run, which signals are forwarded to.
+static mut G_CHILD_PID: i32 = 0;
+// si_code of the signals sent by the kernel, e.g. from the terminal.
+const SI_KERNEL: c_int = 0x80;
 
 /// Wrapper to interpret syscall exit codes and provide a rustacean `io::Result`
 pub struct SyscallReturnCode(pub c_int);
@@ -98,11 +188,23 @@ pub fn register_sig_handler() -> Result<(), io::Error> {
     Ok(())
 }
 
-extern "C" fn sigsys_handler(_num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
+extern "C" fn sigseal_handler(_num: c_int, _info: *mut siginfo_t, _unused: *mut c_void) {
+    flight::SEAL_REQUESTED.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+extern "C" fn sigsys_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
     // Safe because we're just reading some fields from a supposedly valid argument.
     let _si_signo = unsafe { (*info).si_signo };
-    let _si_code = unsafe { (*info).si_code };
+    let si_code = unsafe { (*info).si_code };
     unsafe {
+        // Under atrace run the signal is meant for the child. Signals from
+        // the terminal already reach it through its process group.
+        if G_CHILD_PID > 0 {
+            if si_code != SI_KERNEL {
+                kill(G_CHILD_PID, num);
+            }
+            return;
+        }
         G_TRACE_ABORTED = true;
     }
 }
@@ -199,6 +301,564 @@ fn is_traceclock_mode(mode: &str) -> bool {
     true
 }
 
+// Local time as YYYYmmdd-HHMMSS, for naming output files.
+fn local_timestamp() -> String {
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let now = unsafe { libc::time(null_mut()) };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+// A short id shared by the trace, the output files and the result of one
+// capture: the local time and a random suffix.
+fn new_capture_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    let seed = u64::from(nanos) ^ (u64::from(std::process::id()) << 32);
+    // splitmix64 finalizer, to spread the few changing bits.
+    let mut hash = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    format!("{}-{:06x}", local_timestamp(), hash & 0xff_ffff)
+}
+
+// Write the lines around a trigger to a new file named by the capture id.
+fn write_trigger_dump(config: &Config, dump: &watch::Dump, index: usize) -> bool {
+    let prefix = if config.output.is_empty() {
+        "atrace-trigger"
+    } else {
+        config.output.as_str()
+    };
+    let path = format!("{}-{}-{}.txt", prefix, config.capture_id, index);
+    let mut out = match open_output(&path) {
+        Ok(out) => out,
+        Err(_) => {
+            println!("open output file:{:?} fail.\n", &path);
+            return false;
+        }
+    };
+    let mut result = writeln!(out, "# atrace trigger: {}", dump.trigger);
+    if dump.dropped > 0 {
+        result = result.and_then(|_| {
+            writeln!(
+                out,
+                "# lines dropped over --max-window-bytes: {}",
+                dump.dropped
+            )
+        });
+    }
+    for line in dump.lines.iter() {
+        result = result.and_then(|_| writeln!(out, "{}", line));
+    }
+    match result.and_then(|_| out.flush()) {
+        Ok(()) => {
+            println!("trigger {} dumped to {}", index, path);
+            true
+        }
+        Err(e) => {
+            eprintln!("write trigger dump {:?} fail: {}", path, e);
+            false
+        }
+    }
+}
+
+// Watch trace_pipe for the --trigger pattern, dumping the window around each
+// match until --max-triggers dumps are written or tracing is interrupted.
+fn watch_trace(config: &Config) -> i32 {
+    let pattern = match regex::Regex::new(&config.trigger) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            println!("invalid --trigger regex: {}\n", e);
+            return -1;
+        }
+    };
+    lower_priority(config);
+    // The pre window and the post collection may both be full at once.
+    let max_bytes = match BUDGET.memory_limit() {
+        Some(max) => config.max_window_bytes.min(max / 2),
+        None => config.max_window_bytes,
+    };
+    let mut watcher = watch::Watcher::new(watch::WatchOptions {
+        pattern,
+        pre: config.pre,
+        post: config.post,
+        max_bytes,
+        max_triggers: config.max_triggers,
+    });
+    let mut pipe = match std::fs::File::open(strcat_for_file_path("trace_pipe")) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            println!("open trace_pipe fail: {}\n", e);
+            return -1;
+        }
+    };
+
+    let mut buf = vec![0u8; BUFFER_LEN];
+    let mut pending: Vec<u8> = Vec::new();
+    let mut ret = 0;
+    while !watcher.done() && !unsafe { G_TRACE_ABORTED } {
+        let n = match pipe.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            // Interrupted by the signal handler, check whether to stop.
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                println!("read trace_pipe fail: {}\n", e);
+                ret = -1;
+                break;
+            }
+        };
+        pending.extend_from_slice(&buf[..n]);
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]);
+            if let Some(dump) = watcher.push(&line) {
+                if !write_trigger_dump(config, &dump, watcher.triggers) {
+                    ret = -1;
+                }
+            }
+        }
+    }
+    if let Some(dump) = watcher.finish() {
+        if !write_trigger_dump(config, &dump, watcher.triggers) {
+            ret = -1;
+        }
+    }
+    ret
+}
+
+// How the capture window ended, for --result.
+struct CaptureEnd {
+    elapsed: f64,
+    reason: &'static str,
+    // The cpu whose buffer filled up, with its fill in percent.
+    full_cpu: Option<buffer::CpuFill>,
+}
+
+// Wait out the -T capture window, stopping early when --stop-on-full sees a
+// cpu buffer filled over the threshold.
+fn wait_capture(config: &Config) -> CaptureEnd {
+    let window = Duration::from_secs(config.durationsec.into());
+    if !config.stop_on_full {
+        thread::sleep(window);
+        return CaptureEnd {
+            elapsed: window.as_secs_f64(),
+            reason: "duration",
+            full_cpu: None,
+        };
+    }
+    if buffer::fullest_cpu(tracefs::root()).is_none() {
+        println!("warning: the kernel doesn't report buffer bytes, --stop-on-full is inactive");
+    }
+    let start = Instant::now();
+    let poll = Duration::from_millis(FILL_POLL_MS);
+    let mut reason = "duration";
+    let mut full_cpu = None;
+    while start.elapsed() < window {
+        if unsafe { G_TRACE_ABORTED } {
+            reason = "interrupted";
+            break;
+        }
+        thread::sleep(poll.min(window - start.elapsed().min(window)));
+        if let Some(fill) = buffer::fullest_cpu(tracefs::root()) {
+            if fill.percent >= config.full_threshold {
+                reason = "buffer_full";
+                full_cpu = Some(fill);
+                break;
+            }
+        }
+    }
+    CaptureEnd {
+        elapsed: start.elapsed().as_secs_f64().min(window.as_secs_f64()),
+        reason,
+        full_cpu,
+    }
+}
+
+// Write the --result JSON describing how the capture ended. The async modes
+// have no capture window, they only tell the output and the verify result.
+fn write_result(
+    config: &Config,
+    end: Option<&CaptureEnd>,
+    violations: Option<&[verify::Violation]>,
+) -> bool {
+    let mut json = format!(
+        "{{\"capture_id\":{}",
+        convert::json_string(&config.capture_id)
+    );
+    if let Some(end) = end {
+        let _ = write!(
+            json,
+            ",\"duration_s\":{},\"effective_duration_s\":{:.3},\"stopped_early\":{},\"reason\":{}",
+            config.durationsec,
+            end.elapsed,
+            end.reason != "duration",
+            convert::json_string(end.reason)
+        );
+    }
+    let _ = write!(
+        json,
+        ",\"output_bytes\":{},\"truncated\":{}",
+        BUDGET.written(),
+        BUDGET.truncated()
+    );
+    if let Some(fill) = end.and_then(|end| end.full_cpu.as_ref()) {
+        let _ = write!(
+            json,
+            ",\"full_cpu\":{},\"fill_percent\":{:.1}",
+            fill.cpu, fill.percent
+        );
+    }
+    if let Some(violations) = violations {
+        let list: Vec<String> = violations
+            .iter()
+            .map(|violation| {
+                format!(
+                    "{{\"check\":{},\"detail\":{}}}",
+                    convert::json_string(violation.check),
+                    convert::json_string(&violation.detail)
+                )
+            })
+            .collect();
+        let _ = write!(
+            json,
+            ",\"verify\":{{\"passed\":{},\"violations\":[{}]}}",
+            violations.is_empty(),
+            list.join(",")
+        );
+    }
+    json.push_str("}\n");
+    match std::fs::write(&config.result, json) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("write result file:{:?} fail: {}\n", &config.result, e);
+            false
+        }
+    }
+}
+
+// Write a marker into the trace from atrace itself.
+fn write_marker(marker: &str) -> bool {
+    trace_write_string(
+        &strcat_for_file_path("trace_marker"),
+        &format!("{}\n", marker),
+    )
+}
+
+// Wait for the child, returning its wait status and resource usage.
+fn wait_child(pid: i32) -> io::Result<(c_int, libc::rusage)> {
+    let mut status: c_int = 0;
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    loop {
+        let ret = unsafe { libc::wait4(pid, &mut status, 0, &mut usage) };
+        if ret == pid {
+            return Ok((status, usage));
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+// Trace one run of a command end to end: bracket it with markers, dump the
+// trace to a file and exit with the command's exit code.
+fn run_command(config: &Config) -> i32 {
+    let program = &config.run_command[0];
+    let name = std::path::Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(program)
+        .to_string();
+    let pid = std::process::id();
+
+    let _session = match lock_session("trace a command") {
+        Some(lock) => lock,
+        None => return -1,
+    };
+    let mut ret = setup_trace(config);
+    ret &= set_tracing_enabled(true);
+    ret &= clear_trace();
+    if !ret {
+        println!("unable to start tracing, please check debugfs setup correctly\n");
+        cleanup_trace(config);
+        return -1;
+    }
+    write_clock_sync_marker(&config.capture_id);
+    if config.system_metadata {
+        write_system_metadata();
+    }
+    write_marker(&format!("I|{}|run_start:{}", pid, name));
+
+    let child = match std::process::Command::new(program)
+        .args(&config.run_command[1..])
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            println!("run {:?} fail: {}\n", program, e);
+            set_tracing_enabled(false);
+            cleanup_trace(config);
+            return 127;
+        }
+    };
+    let child_pid = child.id() as i32;
+    unsafe { G_CHILD_PID = child_pid };
+    let waited = wait_child(child_pid);
+    unsafe { G_CHILD_PID = 0 };
+    let code = match waited {
+        Ok((status, usage)) => {
+            let code = if libc::WIFEXITED(status) {
+                libc::WEXITSTATUS(status)
+            } else {
+                128 + libc::WTERMSIG(status)
+            };
+            let micros = |time: libc::timeval| time.tv_sec * 1_000_000 + time.tv_usec;
+            write_marker(&format!("C|{}|run_maxrss_kb|{}", pid, usage.ru_maxrss));
+            write_marker(&format!(
+                "C|{}|run_utime_us|{}",
+                pid,
+                micros(usage.ru_utime)
+            ));
+            write_marker(&format!(
+                "C|{}|run_stime_us|{}",
+                pid,
+                micros(usage.ru_stime)
+            ));
+            write_marker(&format!("I|{}|run_end:{} exit={}", pid, name, code));
+            code
+        }
+        Err(e) => {
+            println!("wait {:?} fail: {}\n", program, e);
+            -1
+        }
+    };
+    set_tracing_enabled(false);
+
+    let path = if config.output.is_empty() {
+        format!("atrace-run-{}-{}.txt", name, config.capture_id)
+    } else {
+        config.output.clone()
+    };
+    let dumped = match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+    {
+        Ok(f) => {
+            let fd = f.into_raw_fd();
+            lower_priority(config);
+            let dumped = print_trace(config, fd) >= 0;
+            unsafe { close(fd) };
+            dumped
+        }
+        Err(e) => {
+            println!("open output file:{:?} fail: {}\n", &path, e);
+            false
+        }
+    };
+    clear_trace();
+    cleanup_trace(config);
+    if !dumped {
+        return -1;
+    }
+    println!("{} exited with {}, trace dumped to {}", name, code, path);
+    code
+}
+
+// The tracefs settings setup_trace changes, restored to their values from
+// before `atrace daemon` when it shuts down.
+const DAEMON_SETTINGS: &[&str] = &[
+    "tracing_on",
+    "buffer_size_kb",
+    "options/overwrite",
+    "options/record-cmd",
+    "options/print-tgid",
+    "trace_clock",
+    "current_tracer",
+];
+
+fn save_settings() -> Vec<(&'static str, String)> {
+    DAEMON_SETTINGS
+        .iter()
+        .filter_map(|name| {
+            let value = read_trace_option(name).ok()?;
+            // trace_clock lists every clock with the current one in brackets,
+            // buffer_size_kb may add "(expanded: N)".
+            let value = match (value.find('['), value.find(']')) {
+                (Some(open), Some(close)) if open < close => &value[open + 1..close],
+                _ => value.split_whitespace().next().unwrap_or(""),
+            };
+            Some((*name, value.to_string()))
+        })
+        .collect()
+}
+
+// Restore in reverse, so tracing_on is set back last.
+fn restore_settings(saved: &[(&'static str, String)]) {
+    for (name, value) in saved.iter().rev() {
+        if !trace_write_string(&strcat_for_file_path(name), value) {
+            println!("warning: restore {} to {} fail", name, value);
+        }
+    }
+}
+
+// Dump the trace buffer to a new file, giving the bytes written.
+fn dump_to_file(config: &Config, path: &str) -> Result<String, String> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| format!("open {} fail: {}", path, e))?;
+    BUDGET.reset_output();
+    let fd = file.into_raw_fd();
+    let ret = print_trace(config, fd);
+    unsafe { close(fd) };
+    if ret < 0 {
+        return Err(format!("dump to {} fail", path));
+    }
+    Ok(format!(
+        "{} bytes dumped to {}{}",
+        BUDGET.written(),
+        path,
+        if BUDGET.truncated() {
+            ", truncated at --max-output-bytes"
+        } else {
+            ""
+        }
+    ))
+}
+
+// Carries out the control socket commands on the local tracefs.
+struct TraceCollector<'a> {
+    config: &'a Config,
+    // A new id on every start.
+    capture_id: String,
+}
+
+impl daemon::Collector for TraceCollector<'_> {
+    fn run(&mut self, command: daemon::Command) -> Result<String, String> {
+        match command {
+            daemon::Command::Start => {
+                if !clear_trace() || !set_tracing_enabled(true) {
+                    return Err("enable tracing fail".to_string());
+                }
+                self.capture_id = new_capture_id();
+                write_clock_sync_marker(&self.capture_id);
+                if self.config.system_metadata {
+                    write_system_metadata();
+                }
+                Ok(format!("tracing, capture {}", self.capture_id))
+            }
+            daemon::Command::Stop => match set_tracing_enabled(false) {
+                true => Ok("stopped".to_string()),
+                false => Err("disable tracing fail".to_string()),
+            },
+            daemon::Command::Dump(path) => {
+                set_tracing_enabled(false);
+                let dumped = dump_to_file(self.config, &path)?;
+                clear_trace();
+                Ok(dumped)
+            }
+            daemon::Command::Snapshot(path) => dump_to_file(self.config, &path),
+            daemon::Command::Status => {
+                let tracing = read_trace_option("tracing_on").unwrap_or_default() == "1";
+                let buffer = read_trace_option("buffer_size_kb").unwrap_or_default();
+                let mut status = format!(
+                    "tracing {}, capture {}, buffer {} kb",
+                    if tracing { "on" } else { "off" },
+                    self.capture_id,
+                    buffer
+                );
+                if let Some(fill) = buffer::fullest_cpu(tracefs::root()) {
+                    let _ = write!(status, ", cpu {} {:.1}% full", fill.cpu, fill.percent);
+                }
+                Ok(status)
+            }
+        }
+    }
+}
+
+// Keep tracing set up but disabled, capturing on the commands sent to the
+// control socket until interrupted, then restore the settings found at start.
+fn run_daemon(config: &Config) -> i32 {
+    let _session = match lock_session("start a daemon") {
+        Some(lock) => lock,
+        None => return -1,
+    };
+    let listener = match daemon::bind(&config.socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("listen on {} fail: {}", config.socket, e);
+            return -1;
+        }
+    };
+    let saved = save_settings();
+    if !setup_trace(config) || !set_tracing_enabled(false) {
+        println!("unable to set up tracing, please check debugfs setup correctly\n");
+        restore_settings(&saved);
+        let _ = std::fs::remove_file(&config.socket);
+        return -1;
+    }
+    lower_priority(config);
+    println!("atrace daemon listening on {}", config.socket);
+    let mut collector = TraceCollector {
+        config,
+        capture_id: config.capture_id.clone(),
+    };
+    let result = daemon::serve(listener, &config.socket, &mut collector, &|| unsafe {
+        G_TRACE_ABORTED
+    });
+    set_tracing_enabled(false);
+    cleanup_trace(config);
+    restore_settings(&saved);
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("control socket {} fail: {}", config.socket, e);
+            -1
+        }
+    }
+}
+
+// Send the `atrace ctl` command to the daemon, with its path made absolute
+// as the daemon runs elsewhere.
+fn control_daemon(config: &Config) -> i32 {
+    let mut words = config.ctl_command.clone();
+    if words.len() > 1 && daemon::Command::takes_path(&words[0]) {
+        if let Ok(cwd) = std::env::current_dir() {
+            words[1] = cwd.join(&words[1]).to_string_lossy().into_owned();
+        }
+    }
+    match daemon::request(&config.socket, &words.join(" ")) {
+        Ok(reply) => {
+            println!("{}", reply);
+            if reply.starts_with("ok") {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            println!("send to the daemon on {} fail: {}", config.socket, e);
+            -1
+        }
+    }
+}
+
 // Stream trace to stdout.
 fn stream_trace() {
     // TODO: support stream trace with trace_pipe.
@@ -375,12 +1035,39 @@ fn trace_write_string(filename: &str, str: &str) -> bool {
     write_string(filename, str)
 }
 
-fn write_clock_sync_marker() {
-    // TODO:with real time
+// Write the system context items as metadata markers.
+fn write_system_metadata() {
+    for (key, value) in sysinfo::collect(std::path::Path::new("/")) {
+        trace_write_string(
+            &strcat_for_file_path("trace_marker"),
+            &format!("{} {}={}\n", parser::METADATA_PREFIX, key, value),
+        );
+    }
+}
+
+fn write_clock_sync_marker(capture_id: &str) {
     trace_write_string(
         &strcat_for_file_path("trace_marker"),
         "trace_event_clock_sync: parent_ts=9000000\n",
     );
+    trace_write_string(
+        &strcat_for_file_path("trace_marker"),
+        &format!(
+            "{} {}={}\n",
+            parser::METADATA_PREFIX,
+            parser::CAPTURE_ID_KEY,
+            capture_id
+        ),
+    );
+    // Wall clock milliseconds, to place annotations on the trace clock.
+    let realtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or(0);
+    trace_write_string(
+        &strcat_for_file_path("trace_marker"),
+        &format!("trace_event_clock_sync: realtime_ts={}\n", realtime),
+    );
 }
 
 // Enable or disable certain kernel ftrace options by write 1 or 0 to the file.
@@ -394,7 +1081,7 @@ fn set_kernel_option_enable(filename: &str, enable: bool) -> bool {
 
 fn strcat_for_file_path(str: &str) -> String {
     let mut path = String::with_capacity(MAX_FILE_PATH_LEN);
-    path.push_str(SYSTEM_KERNEL_DEBUG_TRACE);
+    path.push_str(tracefs::root());
     path.push_str(str);
     path
 }
@@ -402,6 +1089,7 @@ fn strcat_for_file_path(str: &str) -> String {
 // Clean up trace settings.
 fn cleanup_trace(config: &Config) {
     disable_kernel_trace_events(config);
+    disable_profile_events(config);
     set_trace_recordcmd_enable(false);
     set_trace_overwrite_enable(true);
     set_trace_buffer_size(1);
@@ -410,16 +1098,129 @@ fn cleanup_trace(config: &Config) {
     set_kernel_trace_funcs("");
 }
 
-fn print_trace(config: &Config) -> i32 {
-    let filename = &strcat_for_file_path("trace\0");
-    let trace_fd = unsafe { open(filename.as_ptr() as *const c_char, O_RDWR) };
-    if trace_fd < 0 {
-        return -1;
+// Write a buffer within the --max-output-bytes budget, a short count means
+// the budget or the output ran out.
+unsafe fn write_output(fd: c_int, buf: *const u8, len: usize) -> isize {
+    let granted = BUDGET.take_output(len);
+    if granted < len {
+        BUDGET.mark_truncated();
+    }
+    if granted == 0 {
+        return 0;
+    }
+    let written = loop {
+        let written = write(fd, buf as *const c_void, granted);
+        if written >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            break written;
+        }
+    };
+    BUDGET.give_back(granted - written.max(0) as usize);
+    written
+}
+
+// Lower our own cpu and io priority before heavy dump work, per --nice and
+// --idle-io, so the capture doesn't compete with the traced workload.
+fn lower_priority(config: &Config) {
+    if config.nice != 0 && unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, config.nice) } != 0 {
+        println!(
+            "warning: set nice {} fail: {}",
+            config.nice,
+            io::Error::last_os_error()
+        );
+    }
+    if config.idle_io {
+        let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+            println!(
+                "warning: set idle io class fail: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+// Read and write one buffer, for outputs sendfile can't write to.
+fn read_write(trace_fd: c_int, out_fd: c_int, buf: &mut [u8]) -> isize {
+    let n = unsafe { read(trace_fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+    if n <= 0 {
+        return n;
+    }
+    let mut written = 0;
+    while written < n as usize {
+        let ret = unsafe {
+            write(
+                out_fd,
+                buf[written..].as_ptr() as *const c_void,
+                n as usize - written,
+            )
+        };
+        if ret < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return -1;
+        }
+        written += ret as usize;
+    }
+    n
+}
+
+// Copy the trace to the output with sendfile, falling back to a read/write
+// loop when the kernel can't sendfile from the trace file to this output.
+fn copy_trace(trace_fd: c_int, out_fd: c_int) -> i32 {
+    let mut use_sendfile = true;
+    let mut buf = vec![0u8; BUFFER_LEN];
+    loop {
+        let count = BUDGET.take_output(FILE_LEN);
+        if count == 0 {
+            // Only a truncation when trace data is left over.
+            let mut probe = 0u8;
+            if unsafe { read(trace_fd, &mut probe as *mut u8 as *mut c_void, 1) } > 0 {
+                BUDGET.mark_truncated();
+            }
+            return 0;
+        }
+        let copied = if use_sendfile {
+            unsafe { sendfile(out_fd, trace_fd, null_mut(), count) }
+        } else {
+            read_write(trace_fd, out_fd, &mut buf[..count.min(BUFFER_LEN)])
+        };
+        let err = io::Error::last_os_error();
+        BUDGET.give_back(count - copied.max(0) as usize);
+        if copied > 0 {
+            continue;
+        } else if copied == 0 {
+            return 0;
+        }
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            // Nothing was copied, the read/write loop starts at the same offset.
+            Some(EINVAL) | Some(ENOSYS) if use_sendfile => use_sendfile = false,
+            _ => {
+                eprintln!("dump trace fail: {}", err);
+                return -1;
+            }
+        }
     }
+}
+
+fn print_trace(config: &Config, out_fd: c_int) -> i32 {
+    // Read-only, dumping must never be able to modify the trace.
+    let trace_fd = match OpenOptions::new()
+        .read(true)
+        .open(strcat_for_file_path("trace"))
+    {
+        Ok(file) => file.into_raw_fd(),
+        Err(e) => {
+            eprintln!("open trace fail: {}", e);
+            return -1;
+        }
+    };
 
-    let mut ret: i32 = 0;
+    let mut ret: i32;
     if config.compress {
         let mut refresh = Z_NO_FLUSH;
+        let mut checksum = Checksum::new();
         let size = mem::size_of::<z_stream>().try_into().unwrap();
         let stream: z_streamp = unsafe { malloc(size) as *mut z_stream };
         unsafe {
@@ -469,6 +1270,7 @@ fn print_trace(config: &Config) -> i32 {
                 (*stream).avail_out = BUFFER_LEN.try_into().unwrap();
             }
         }
+        let mut failed = false;
         unsafe {
             while Z_OK == ret {
                 if (*stream).avail_in == 0 {
@@ -476,20 +1278,30 @@ fn print_trace(config: &Config) -> i32 {
                         .try_into()
                         .unwrap();
                     if ret < 0 {
+                        let err = io::Error::last_os_error();
+                        if err.kind() == io::ErrorKind::Interrupted {
+                            ret = Z_OK;
+                            continue;
+                        }
+                        eprintln!("read trace fail: {}", err);
+                        failed = true;
                         break;
                     } else if ret == 0 {
                         refresh = Z_FINISH;
                     } else {
                         (*stream).next_in = pibuf;
                         (*stream).avail_in = ret.try_into().unwrap();
+                        checksum.update(std::slice::from_raw_parts(pibuf, ret as usize));
                     }
                 }
 
                 if (*stream).avail_out == 0 {
-                    ret = write(STDOUT_FILENO, pobuf as *mut c_void, BUFFER_LEN)
-                        .try_into()
-                        .unwrap();
+                    ret = write_output(out_fd, pobuf, BUFFER_LEN).try_into().unwrap();
                     if ret < BUFFER_LEN as i32 {
+                        if ret < 0 {
+                            eprintln!("write trace fail: {}", io::Error::last_os_error());
+                            failed = true;
+                        }
                         (*stream).avail_out = BUFFER_LEN.try_into().unwrap();
                         break;
                     }
@@ -498,15 +1310,24 @@ fn print_trace(config: &Config) -> i32 {
                 }
                 ret = deflate(stream, refresh);
             }
+            let finished = ret == Z_STREAM_END;
 
             if ((*stream).avail_out as usize) < BUFFER_LEN {
-                ret = write(
-                    STDOUT_FILENO,
-                    pobuf as *mut c_void,
-                    BUFFER_LEN - (*stream).avail_out as usize,
-                )
-                .try_into()
-                .unwrap();
+                ret = write_output(out_fd, pobuf, BUFFER_LEN - (*stream).avail_out as usize)
+                    .try_into()
+                    .unwrap();
+                if ret < 0 && !failed {
+                    eprintln!("write trace fail: {}", io::Error::last_os_error());
+                    failed = true;
+                }
+            }
+            // A cut stream has no footer, readers report it as truncated.
+            if finished && !BUDGET.truncated() {
+                let footer = checksum.footer();
+                if write_output(out_fd, footer.as_ptr(), footer.len()) < 0 && !failed {
+                    eprintln!("write trace fail: {}", io::Error::last_os_error());
+                    failed = true;
+                }
             }
 
             deflateEnd(stream);
@@ -514,12 +1335,11 @@ fn print_trace(config: &Config) -> i32 {
             free(pobuf as *mut c_void);
             free(stream as *mut c_void);
         }
-    } else {
-        let mut byte = unsafe { sendfile(STDOUT_FILENO, trace_fd, null_mut(), FILE_LEN) };
-
-        while byte > 0 {
-            byte = unsafe { sendfile(STDOUT_FILENO, trace_fd, null_mut(), FILE_LEN) };
+        if failed {
+            ret = -1;
         }
+    } else {
+        ret = copy_trace(trace_fd, out_fd);
     }
 
     if trace_fd >= 0 {
@@ -530,6 +1350,7 @@ fn print_trace(config: &Config) -> i32 {
 }
 
 fn uncompress_trace(config: &Config) -> i32 {
+    lower_priority(config);
     let f = OpenOptions::new()
         .create(false)
         .read(true)
@@ -538,6 +1359,7 @@ fn uncompress_trace(config: &Config) -> i32 {
     let mut ret: i32;
     if !f.is_err() {
         let mut refresh = Z_NO_FLUSH;
+        let mut checksum = Checksum::new();
         let size = mem::size_of::<z_stream>().try_into().unwrap();
         let stream: z_streamp = unsafe { malloc(size) as *mut z_stream };
         unsafe {
@@ -589,7 +1411,8 @@ fn uncompress_trace(config: &Config) -> i32 {
                 }
 
                 if (*stream).avail_out == 0 {
-                    ret = write(STDOUT_FILENO, pobuf as *mut c_void, BUFFER_LEN)
+                    checksum.update(std::slice::from_raw_parts(pobuf, BUFFER_LEN));
+                    ret = write_output(STDOUT_FILENO, pobuf, BUFFER_LEN)
                         .try_into()
                         .unwrap();
                     if ret < BUFFER_LEN as i32 {
@@ -601,17 +1424,46 @@ fn uncompress_trace(config: &Config) -> i32 {
                 }
                 ret = inflate(stream, refresh);
             }
+            let inflated = ret;
 
             if ((*stream).avail_out as usize) < BUFFER_LEN {
-                ret = write(
-                    STDOUT_FILENO,
-                    pobuf as *mut c_void,
-                    BUFFER_LEN - (*stream).avail_out as usize,
-                )
-                .try_into()
-                .unwrap();
+                let len = BUFFER_LEN - (*stream).avail_out as usize;
+                checksum.update(std::slice::from_raw_parts(pobuf, len));
+                ret = write_output(STDOUT_FILENO, pobuf, len).try_into().unwrap();
             }
 
+            // Verify the footer following the stream, unless the output
+            // itself was cut short.
+            if !BUDGET.truncated() {
+                let verified = if inflated == Z_STREAM_END {
+                    let mut trailer =
+                        std::slice::from_raw_parts((*stream).next_in, (*stream).avail_in as usize)
+                            .to_vec();
+                    let mut rest = [0u8; FOOTER_LEN + 1];
+                    while trailer.len() <= FOOTER_LEN {
+                        let len = read(fd, rest.as_mut_ptr() as *mut c_void, rest.len());
+                        if len <= 0 {
+                            break;
+                        }
+                        trailer.extend_from_slice(&rest[..len as usize]);
+                    }
+                    checksum.verify(&trailer)
+                } else if inflated == Z_BUF_ERROR || inflated == Z_OK {
+                    Err(format!("capture truncated at byte {}", checksum.len()))
+                } else {
+                    Err(format!("capture corrupted at byte {}", checksum.len()))
+                };
+                if let Err(e) = verified {
+                    if integrity::verify_enabled() {
+                        eprintln!("{}", e);
+                        ret = -1;
+                    } else {
+                        eprintln!("warning: {}, continuing with --no-verify", e);
+                    }
+                }
+            }
+            close(fd);
+
             inflateEnd(stream);
             free(pibuf as *mut c_void);
             free(pobuf as *mut c_void);
@@ -621,11 +1473,626 @@ fn uncompress_trace(config: &Config) -> i32 {
         println!("open trace file:{:?} fail.\n", &config.uncompress_file);
         return -1;
     }
-    return ret;
+    // ret holds the length of the last write on success, not an exit code.
+    if ret < 0 {
+        -1
+    } else {
+        0
+    }
+}
+
+// Show what a dumped trace file contains, including the tracing-atrace header
+// marker which describes the producer of the userspace markers.
+fn show_trace_status(config: &Config) -> i32 {
+    let capture = match Capture::load(&config.status_file) {
+        Ok(capture) => capture,
+        Err(e) => {
+            println!("open trace file:{:?} fail: {}\n", &config.status_file, e);
+            return -1;
+        }
+    };
+
+    println!("trace file: {}", config.status_file);
+    println!(
+        "lines: {}, events: {}, markers: {}",
+        capture.lines, capture.events, capture.markers
+    );
+    if capture.broken_chunks > 0 {
+        println!(
+            "split markers with missing chunks: {}",
+            capture.broken_chunks
+        );
+    }
+    if capture.lost_events > 0 {
+        println!("events lost to buffer overruns: {}", capture.lost_events);
+    }
+    for warning in capture.line_warnings() {
+        println!("warning: {}", warning);
+    }
+    for gap in capture.discontinuities.iter() {
+        println!("warning: {}", gap.describe());
+    }
+    println!(
+        "clock sync: {}",
+        if capture.clock_sync.is_some() {
+            "present"
+        } else {
+            "missing"
+        }
+    );
+    if let Some(capture_id) = capture.capture_id.as_ref() {
+        println!("capture id: {}", capture_id);
+    }
+    if let Some((_, text)) = describe_skew(&capture) {
+        println!("marker clock skew: {}", text);
+    }
+    match capture.header.as_ref() {
+        Some(header) => {
+            println!("tracing-atrace header:");
+            println!("  version: {}", header.version);
+            println!("  format: {}", header.format);
+            println!("  fields: {}", header.fields.join(","));
+            println!("  pid: {}", header.pid);
+            println!("  exe: {}", header.exe);
+        }
+        None => println!("tracing-atrace header: none"),
+    }
+    if !capture.metadata.is_empty() {
+        println!("system metadata:");
+        for (key, value) in capture.metadata.iter() {
+            println!("  {}: {}", key, value);
+        }
+    }
+    0
+}
+
+// Read a small tracefs file like tracing_on, trimmed.
+fn read_trace_option(name: &str) -> io::Result<String> {
+    std::fs::read_to_string(strcat_for_file_path(name)).map(|value| value.trim().to_string())
+}
+
+// Check end to end that a marker written to trace_marker reaches the kernel
+// buffer, reporting each step so a missing permission is easy to spot.
+fn run_doctor() -> i32 {
+    let mut healthy = true;
+    let root = file_is_exist(&format!("{}\0", tracefs::root()));
+    println!(
+        "tracefs {}: {}",
+        tracefs::root(),
+        if root { "found" } else { "missing" }
+    );
+    match read_trace_option("tracing_on") {
+        Ok(value) => println!(
+            "tracing_on: {}",
+            if value == "1" {
+                "on"
+            } else {
+                "off, markers are not recorded"
+            }
+        ),
+        Err(e) => println!("tracing_on: unreadable ({})", e),
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    let probe = format!("atrace-doctor-probe-{}-{}", std::process::id(), now);
+    let written = OpenOptions::new()
+        .write(true)
+        .open(strcat_for_file_path("trace_marker"))
+        .and_then(|mut marker| {
+            marker.write_all(format!("I|{}|{}\n", std::process::id(), probe).as_bytes())
+        });
+    match written {
+        Ok(()) => println!("trace_marker write: ok"),
+        Err(e) => {
+            println!("trace_marker write: fail ({})", e);
+            return 1;
+        }
+    }
+
+    // The probe shows up in the trace file once the kernel committed it.
+    let mut delivered = None;
+    for _ in 0..10 {
+        match std::fs::read(strcat_for_file_path("trace")) {
+            Ok(contents) => {
+                let found = String::from_utf8_lossy(&contents).contains(&probe);
+                delivered = Some(Ok(found));
+                if found {
+                    break;
+                }
+            }
+            Err(e) => {
+                delivered = Some(Err(e));
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    match delivered {
+        Some(Ok(true)) => println!("kernel buffer: probe marker delivered"),
+        Some(Ok(false)) => {
+            println!("kernel buffer: probe marker not found");
+            healthy = false;
+        }
+        Some(Err(ref e)) if e.kind() == io::ErrorKind::PermissionDenied => {
+            println!("kernel buffer: cannot verify, no read permission on trace")
+        }
+        Some(Err(e)) => println!("kernel buffer: cannot verify ({})", e),
+        None => {}
+    }
+    if healthy {
+        0
+    } else {
+        1
+    }
+}
+
+// Open the converted output file, or stdout when no output file is given.
+fn open_output(output: &str) -> io::Result<Box<dyn IoWrite>> {
+    if output.is_empty() {
+        Ok(Box::new(io::BufWriter::new(LimitedWriter::new(
+            io::stdout(),
+        ))))
+    } else {
+        let f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output)?;
+        Ok(Box::new(io::BufWriter::new(LimitedWriter::new(f))))
+    }
+}
+
+// Place the --annotations notes on the capture, warning about the notes
+// which can't be placed.
+fn add_annotations(capture: &mut Capture, path: &str) -> bool {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            println!("open annotations file:{:?} fail: {}\n", path, e);
+            return false;
+        }
+    };
+    let (notes, mut warnings) = annotations::parse_annotations(&text);
+    warnings.extend(annotations::inject(capture, &notes));
+    for warning in warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+    true
+}
+
+// Convert a dumped trace file to the configured output format.
+fn convert_trace(config: &Config) -> i32 {
+    lower_priority(config);
+    match Capture::load(&config.convert_file) {
+        Ok(capture) => convert_capture(config, capture),
+        Err(e) => {
+            println!("open trace file:{:?} fail: {}\n", &config.convert_file, e);
+            -1
+        }
+    }
+}
+
+// Describe the estimated marker clock skew, None without sched data.
+fn describe_skew(capture: &Capture) -> Option<(skew::Skew, String)> {
+    if capture.running.is_empty() {
+        return None;
+    }
+    let skew = skew::estimate(&capture.running, &skew::marker_times(capture));
+    let text = if skew.violations < skew::MIN_VIOLATIONS {
+        format!(
+            "none detected, {} of {} markers outside their thread's cpu time",
+            skew.violations, skew.checked
+        )
+    } else {
+        format!(
+            "markers {:.1}us {}, {} of {} markers outside their thread's cpu time, {} after correction",
+            skew.offset.abs() * 1e6,
+            if skew.offset >= 0.0 { "late" } else { "early" },
+            skew.violations,
+            skew.checked,
+            skew.remaining
+        )
+    };
+    Some((skew, text))
+}
+
+// Shift the markers by the estimated skew for --auto-skew-correct.
+fn correct_skew(capture: &mut Capture) {
+    match describe_skew(capture) {
+        Some((skew, text)) => {
+            eprintln!("marker clock skew: {}", text);
+            if skew.violations >= skew::MIN_VIOLATIONS {
+                skew::apply(capture, skew.offset);
+            }
+        }
+        None => eprintln!("warning: no sched_switch events, marker clock skew not estimated"),
+    }
+}
+
+// Write a loaded capture in the configured output format.
+fn convert_capture(config: &Config, mut capture: Capture) -> i32 {
+    for warning in capture.line_warnings() {
+        eprintln!("warning: {}", warning);
+    }
+    if !config.annotations.is_empty() && !add_annotations(&mut capture, &config.annotations) {
+        return -1;
+    }
+    if config.auto_skew_correct {
+        correct_skew(&mut capture);
+    }
+    if !config.counter_derive.is_empty() || !config.counter_scale.is_empty() {
+        let parsed = counters::parse_derive(&config.counter_derive).and_then(|derives| {
+            counters::parse_scale(&config.counter_scale).map(|scales| (derives, scales))
+        });
+        let (derives, scales) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                println!("{}\n", e);
+                return -1;
+            }
+        };
+        for derive in derives.iter() {
+            if counters::derive(&mut capture, derive) == 0 {
+                eprintln!("warning: no rate derived for counter {:?}", derive.name);
+            }
+        }
+        for scale in scales.iter() {
+            if counters::scale(&mut capture, scale) == 0 {
+                eprintln!("warning: no counter {:?} to scale", scale.name);
+            }
+        }
+    }
+    if config.synthetic_interval > 0.0 {
+        let align_to = Some(config.align_to.as_str()).filter(|name| !name.is_empty());
+        if let Err(e) = intervals::inject(&mut capture, config.synthetic_interval, align_to) {
+            println!("{}\n", e);
+            return -1;
+        }
+    }
+    if !config.pid_map.is_empty() {
+        let map = match container::parse_pid_map(&config.pid_map) {
+            Ok(map) => map,
+            Err(e) => {
+                println!("{}\n", e);
+                return -1;
+            }
+        };
+        if container::apply_pid_map(&mut capture, &map) == 0 {
+            eprintln!("warning: no marker pid matched --pid-map");
+        }
+    }
+    let mut out = match open_output(&config.output) {
+        Ok(out) => out,
+        Err(_) => {
+            println!("open output file:{:?} fail.\n", &config.output);
+            return -1;
+        }
+    };
+    let result = match config.format.as_str() {
+        "" | "json" => convert::write_json(&capture, &mut out),
+        "csv" => {
+            let relative = config.time_base == "relative";
+            if !config.csv_counters.is_empty() {
+                let written = open_output(&config.csv_counters).and_then(|mut counters| {
+                    csv::write_counters(&capture, relative, &mut counters)
+                });
+                if let Err(e) = written {
+                    println!(
+                        "write counters file:{:?} fail: {}\n",
+                        &config.csv_counters, e
+                    );
+                    return -1;
+                }
+            }
+            csv::write_slices(&capture, relative, &mut out)
+        }
+        "svg" => {
+            let window = if config.window.is_empty() {
+                svg::Window::ALL
+            } else {
+                match svg::Window::parse(&config.window) {
+                    Some(window) => window,
+                    None => {
+                        println!(
+                            "invalid window {:?}, expected start..end.\n",
+                            &config.window
+                        );
+                        return -1;
+                    }
+                }
+            };
+            svg::write_svg(&svg::layout(&capture, window), &mut out)
+        }
+        #[cfg(feature = "perfetto")]
+        "perfetto" => perfetto::write_perfetto(&capture, &mut out),
+        #[cfg(not(feature = "perfetto"))]
+        "perfetto" => {
+            println!("atrace was built without the perfetto feature.\n");
+            return -1;
+        }
+        format => {
+            println!("unsupported output format:{:?}\n", format);
+            return -1;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("convert trace file fail: {}", e);
+            -1
+        }
+    }
+}
+
+// Capture on the --remote target, then write the raw dump or convert it
+// locally.
+#[cfg(any(feature = "ssh", feature = "adb"))]
+fn remote_trace(config: &Config) -> i32 {
+    let transport = match remote::connect(&config.remote) {
+        Ok(transport) => transport,
+        Err(e) => {
+            println!("{}\n", e);
+            return -1;
+        }
+    };
+    let options = remote::RemoteCapture {
+        capture_id: config.capture_id.clone(),
+        buffer_kb: config.buflen,
+        duration: config.durationsec,
+        overwrite: config.overwrite,
+        cpu_sched: config.cpu_sched,
+    };
+    let dump = match remote::capture(transport.as_ref(), &options, &|| unsafe { G_TRACE_ABORTED }) {
+        Ok(dump) => dump,
+        Err(e) => {
+            eprintln!("{}: {}", transport.name(), e);
+            return match e {
+                remote::RemoteError::Transport(_) => EXIT_TRANSPORT,
+                remote::RemoteError::Tracing(_) => -1,
+            };
+        }
+    };
+    if let Err(e) = BUDGET.check_memory(dump.len()) {
+        eprintln!("{}", e);
+        return -1;
+    }
+    if !config.format.is_empty() {
+        return convert_capture(config, Capture::from_bytes(&dump));
+    }
+    let written = open_output(&config.output).and_then(|mut out| {
+        out.write_all(&dump)?;
+        out.flush()
+    });
+    if BUDGET.truncated() {
+        eprintln!("trace output truncated at {} bytes", BUDGET.written());
+    }
+    match written {
+        Ok(()) => 0,
+        Err(_) if BUDGET.truncated() => 0,
+        Err(e) => {
+            eprintln!("write trace output fail: {}", e);
+            -1
+        }
+    }
+}
+
+#[cfg(not(any(feature = "ssh", feature = "adb")))]
+fn remote_trace(_config: &Config) -> i32 {
+    println!("atrace was built without the ssh or adb feature.\n");
+    -1
+}
+
+// Write the --from..--to range of a dumped trace file in its own format.
+fn extract_trace(config: &Config) -> i32 {
+    lower_priority(config);
+    let mut bounds = Vec::new();
+    for bound in [&config.extract_from, &config.extract_to].iter() {
+        if bound.is_empty() {
+            bounds.push(None);
+            continue;
+        }
+        match extract::Bound::parse(bound) {
+            Some(parsed) => bounds.push(Some(parsed)),
+            None => {
+                println!(
+                    "invalid time {:?}, expected like 12.345s or +500ms.\n",
+                    bound
+                );
+                return -1;
+            }
+        }
+    }
+    // Pair the markers in a first pass, then copy the kept lines in a
+    // second over the file opened again.
+    let pairing = match input::open_trace_file(&config.extract_file)
+        .and_then(|(reader, _)| extract::scan(reader))
+    {
+        Ok(pairing) => pairing,
+        Err(e) => {
+            println!("open trace file:{:?} fail: {}\n", &config.extract_file, e);
+            return -1;
+        }
+    };
+    let written = input::open_trace_file(&config.extract_file).and_then(|(reader, compressed)| {
+        let out = open_output(&config.output)?;
+        if compressed {
+            let mut out = input::Deflater::new(out)?;
+            let kept = extract::extract(reader, &pairing, bounds[0], bounds[1], &mut out)?;
+            out.finish()?;
+            Ok(kept)
+        } else {
+            let mut out = out;
+            let kept = extract::extract(reader, &pairing, bounds[0], bounds[1], &mut out)?;
+            out.flush()?;
+            Ok(kept)
+        }
+    });
+    match written {
+        Ok(kept) => {
+            eprintln!("kept {} of {} lines", kept, pairing.lines);
+            0
+        }
+        Err(e) => {
+            eprintln!("write extracted trace fail: {}", e);
+            -1
+        }
+    }
+}
+
+// Merge the marker summaries of the matching files of the report directory.
+fn report_traces(config: &Config) -> i32 {
+    lower_priority(config);
+    let mut files: Vec<std::path::PathBuf> = match std::fs::read_dir(&config.report_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| report::glob_match(&config.report_glob, name))
+            })
+            .collect(),
+        Err(e) => {
+            println!("open directory:{:?} fail: {}\n", &config.report_dir, e);
+            return -1;
+        }
+    };
+    if files.is_empty() {
+        println!(
+            "no file in {:?} matches {:?}.\n",
+            &config.report_dir, &config.report_glob
+        );
+        return -1;
+    }
+    // Chunk files are named in capture order.
+    files.sort();
+    let mut merged = report::Report::new(&config.report_trend);
+    for file in files.iter() {
+        let name = file.to_string_lossy();
+        match Capture::load(&name) {
+            Ok(capture) => merged.add(&name, &capture),
+            Err(e) => {
+                println!("open trace file:{:?} fail: {}\n", name, e);
+                return -1;
+            }
+        }
+    }
+    let written = open_output(&config.output).and_then(|mut out| match config.format.as_str() {
+        "csv" => merged.write_csv(&mut out),
+        _ => merged.write_json(&mut out),
+    });
+    match written {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("write report fail: {}", e);
+            -1
+        }
+    }
+}
+
+// Print the duration summary of a dumped trace file.
+fn summarize_trace(config: &Config) -> i32 {
+    lower_priority(config);
+    let mut capture = match Capture::load(&config.summary_file) {
+        Ok(capture) => capture,
+        Err(e) => {
+            println!("open trace file:{:?} fail: {}\n", &config.summary_file, e);
+            return -1;
+        }
+    };
+    if !config.annotations.is_empty() && !add_annotations(&mut capture, &config.annotations) {
+        return -1;
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for gap in capture.discontinuities.iter() {
+        let _ = writeln!(out, "warning: {}", gap.describe());
+    }
+    if !capture.discontinuities.is_empty() {
+        let _ = writeln!(
+            out,
+            "{}\n",
+            if config.include_gaps {
+                "durations include the gaps."
+            } else {
+                "durations leave out the gaps and slices across clock jumps."
+            }
+        );
+    }
+    let _ = Summary::markers(&capture).print("markers", config.top, &mut out);
+    if !capture.kernel_calls.is_empty() {
+        let _ = Summary::kernel_calls(&capture).print("kernel functions", config.top, &mut out);
+        if capture.truncated_calls > 0 {
+            let _ = writeln!(
+                out,
+                "truncated kernel function calls: {}",
+                capture.truncated_calls
+            );
+        }
+    }
+    if !capture.annotations.is_empty() {
+        let _ = writeln!(out, "annotations");
+        for note in capture.annotations.iter() {
+            let _ = writeln!(
+                out,
+                "{:>12.3} ms  {}",
+                (note.timestamp - capture.first_timestamp) * 1_000.0,
+                note.name
+            );
+        }
+    }
+    0
+}
+
+// Compare the marker summaries of the two trace files given to --diff.
+fn diff_traces(config: &Config) -> i32 {
+    lower_priority(config);
+    let strip = if config.strip_suffix.is_empty() {
+        None
+    } else {
+        match regex::Regex::new(&config.strip_suffix) {
+            Ok(strip) => Some(strip),
+            Err(e) => {
+                println!("invalid --strip-suffix regex: {}\n", e);
+                return -1;
+            }
+        }
+    };
+    let mut summaries = Vec::new();
+    for file in config.diff_files.iter() {
+        match Capture::load(file) {
+            Ok(capture) => summaries.push(diff::summarize(&capture, strip.as_ref())),
+            Err(e) => {
+                println!("open trace file:{:?} fail: {}\n", file, e);
+                return -1;
+            }
+        }
+    }
+    let options = diff::DiffOptions {
+        threshold_percent: config.diff_threshold,
+        min_delta: config.diff_min_us / 1_000_000.0,
+    };
+    let rows = diff::diff_summaries(&summaries[0], &summaries[1], &options);
+    let stdout = io::stdout();
+    let _ = diff::print_diff(&rows, &mut stdout.lock());
+    if rows.iter().any(|row| row.is_regression()) {
+        EXIT_REGRESSIONS
+    } else {
+        0
+    }
 }
 
 fn main() {
     let mut config = parse_options();
+    config.capture_id = new_capture_id();
+    BUDGET.set_limits(config.max_output_bytes, config.max_memory_mb * 1024 * 1024);
+    integrity::set_verify(!config.no_verify);
+    gaps::configure(config.gap_threshold, !config.include_gaps);
+    tracefs::configure(&config.tracefs);
     // These are for async tracing.
     // Whether begin trace now.
     let mut begin = true;
@@ -658,7 +2125,7 @@ fn main() {
         println!("no support categories");
         exit(0);
     }
-    if config.stream {
+    if config.stream || !config.trigger.is_empty() {
         trace_stream = true;
         dump = false;
     }
@@ -666,18 +2133,116 @@ fn main() {
     let _ = register_sig_handler();
     let mut ret = true;
 
+    // check marker path query in args.
+    if config.print_marker_path {
+        println!("{}trace_marker", tracefs::root());
+        exit(0);
+    }
+
     // check uncompress trace content in args.
     if !config.uncompress_file.is_empty() {
         let result = uncompress_trace(&config);
         exit(result);
     }
 
+    // check time range extraction in args.
+    if !config.extract_file.is_empty() {
+        exit(extract_trace(&config));
+    }
+
+    // check multi-capture report in args.
+    if !config.report_dir.is_empty() {
+        exit(report_traces(&config));
+    }
+
+    // check resident collector in args.
+    if config.daemon {
+        exit(run_daemon(&config));
+    }
+    if !config.ctl_command.is_empty() {
+        exit(control_daemon(&config));
+    }
+
+    // check interactive event picking in args.
+    if config.pick {
+        let result = run_pick(&config);
+        if result != 0 || !config.pick_start {
+            exit(result);
+        }
+        config.import_config = config.output.clone();
+        config.output = String::new();
+    }
+
+    // check flight recorder in args.
+    if !config.flight_dir.is_empty() {
+        exit(if config.flight_seal {
+            seal_flight(&config)
+        } else {
+            run_flight(&config)
+        });
+    }
+
+    // check marker replay in args.
+    if !config.replay_file.is_empty() {
+        exit(replay_trace(&config));
+    }
+
+    // check one-shot command tracing in args.
+    if !config.run_command.is_empty() {
+        exit(run_command(&config));
+    }
+
+    // check marker delivery in args.
+    if config.doctor {
+        exit(run_doctor());
+    }
+
+    // check remote capture in args.
+    if !config.remote.is_empty() {
+        exit(remote_trace(&config));
+    }
+
+    // check trace file status in args.
+    if !config.status_file.is_empty() {
+        exit(show_trace_status(&config));
+    }
+
+    // check trace file comparison in args.
+    if !config.diff_files.is_empty() {
+        exit(diff_traces(&config));
+    }
+
+    // check trace file summary in args.
+    if !config.summary_file.is_empty() {
+        exit(summarize_trace(&config));
+    }
+
+    // check trace file conversion in args.
+    if !config.convert_file.is_empty() {
+        exit(convert_trace(&config));
+    }
+
     // begin trace after sleep time
     if config.sleepsec > 0 {
         thread::sleep(Duration::from_millis((config.sleepsec * 1000).into()));
     }
 
+    // warn about container setups which break the capture.
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    for warning in container::check_environment(&status, &mounts, tracefs::root()) {
+        println!("warning: {}", warning);
+    }
+
+    let _session = match lock_session("capture") {
+        Some(lock) => lock,
+        None => exit(-1),
+    };
+
     // prepare with setup trace
+    let mut capture_end = None;
+    let mut violations = None;
+    let mut dump_failed = false;
     ret &= setup_trace(&config);
     ret &= set_tracing_enabled(true);
 
@@ -687,12 +2252,26 @@ fn main() {
             let _ = io::stdout().flush();
         }
         ret = clear_trace();
-        write_clock_sync_marker();
+        write_clock_sync_marker(&config.capture_id);
+        if config.system_metadata {
+            write_system_metadata();
+        }
         if ret && !trace_async && !trace_stream {
-            thread::sleep(Duration::from_millis((config.durationsec * 1000).into()));
+            let end = wait_capture(&config);
+            if let Some(fill) = end.full_cpu.as_ref() {
+                println!(
+                    "cpu {} buffer {:.1}% full, stopped after {:.3}s",
+                    fill.cpu, fill.percent, end.elapsed
+                );
+            }
+            capture_end = Some(end);
         }
         // TODO: support trace_stream
-        if trace_stream {
+        if trace_stream && !config.trigger.is_empty() {
+            if watch_trace(&config) != 0 {
+                ret = false;
+            }
+        } else if trace_stream {
             stream_trace();
         }
     }
@@ -704,7 +2283,17 @@ fn main() {
     if ret && dump {
         if !unsafe { G_TRACE_ABORTED } {
             let _ = io::stdout().flush();
-            print_trace(&config);
+            lower_priority(&config);
+            if print_trace(&config, STDOUT_FILENO) < 0 {
+                eprintln!("dump trace fail, the output is incomplete");
+                dump_failed = true;
+            }
+            if BUDGET.truncated() {
+                eprintln!("trace output truncated at {} bytes", BUDGET.written());
+            }
+            if config.verify {
+                violations = Some(verify_capture(&config));
+            }
         } else {
             let _ = io::stdout().flush();
         }
@@ -713,9 +2302,51 @@ fn main() {
         println!("unable to start tracing, please check debugfs setup correctly\n");
     }
 
+    if !config.result.is_empty() && (capture_end.is_some() || violations.is_some()) {
+        write_result(&config, capture_end.as_ref(), violations.as_deref());
+    }
+
     if stop {
         cleanup_trace(&config);
     }
+
+    if let Some(violations) = violations.as_ref() {
+        for violation in violations.iter() {
+            eprintln!("verify failed: {}: {}", violation.check, violation.detail);
+        }
+    }
+    if dump_failed {
+        exit(-1);
+    }
+    if violations.is_some_and(|violations| !violations.is_empty()) {
+        exit(EXIT_VERIFY);
+    }
+}
+
+// Check the dumped capture for --verify. The kernel buffer is read again,
+// it still holds what was dumped until it is cleared.
+fn verify_capture(config: &Config) -> Vec<verify::Violation> {
+    let mut violations = Vec::new();
+    if BUDGET.truncated() {
+        violations.push(verify::Violation {
+            check: "output",
+            detail: format!("trace output truncated at {} bytes", BUDGET.written()),
+        });
+    }
+    match std::fs::read(strcat_for_file_path("trace")) {
+        Ok(data) => {
+            let expect = verify::Expect {
+                markers: config.expect_markers,
+                allow_overruns: config.allow_overruns,
+            };
+            violations.extend(verify::verify(&verify::scan(&data), &expect));
+        }
+        Err(e) => violations.push(verify::Violation {
+            check: "read",
+            detail: format!("read trace fail: {}", e),
+        }),
+    }
+    violations
 }
 
 // Set up all kernel ftrace settings for this capture.
@@ -745,5 +2376,393 @@ fn setup_trace(config: &Config) -> bool {
     // First, disable all the events.
     ret &= disable_kernel_trace_events(config);
 
+    // Enable the events of an imported profile.
+    if ret && !config.import_config.is_empty() {
+        ret &= import_events(config);
+    }
+
+    // Record what this kernel actually enabled.
+    if ret && !config.export_config.is_empty() {
+        ret &= export_events(config);
+    }
+
+    ret
+}
+
+// Enable the events listed in the --import-config profile. Strict imports
+// fail before enabling anything if this kernel lacks one of them, best
+// effort ones report the missing events and enable the rest.
+fn import_events(config: &Config) -> bool {
+    let tracing = std::path::Path::new(tracefs::root());
+    let profile = match std::fs::read_to_string(&config.import_config)
+        .map_err(|e| e.to_string())
+        .and_then(|text| profile::Profile::parse(&text))
+    {
+        Ok(profile) => profile,
+        Err(e) => {
+            println!("error reading profile {}: {}\n", config.import_config, e);
+            return false;
+        }
+    };
+    let release = profile::kernel_release();
+    if !profile.kernel.is_empty() && profile.kernel != release {
+        println!(
+            "note: profile {} was written on kernel {}, this is {}",
+            config.import_config, profile.kernel, release
+        );
+    }
+    let delta = profile::delta(&profile.events, &profile::available_events(tracing));
+    if !delta.missing.is_empty() {
+        if !config.best_effort {
+            println!(
+                "error: this kernel lacks {} of the {} profile events: {}\n",
+                delta.missing.len(),
+                profile.events.len(),
+                delta.missing.join(", ")
+            );
+            return false;
+        }
+        println!(
+            "warning: enabling {} of the {} profile events, this kernel lacks: {}",
+            delta.achievable.len(),
+            profile.events.len(),
+            delta.missing.join(", ")
+        );
+    }
+    let mut ret = true;
+    for event in delta.achievable.iter() {
+        ret &= set_kernel_option_enable(
+            &strcat_for_file_path(&format!("events/{}/enable", event)),
+            true,
+        );
+    }
     ret
 }
+
+// Disable the events --import-config enabled, ignoring the ones this kernel
+// lacks.
+fn disable_profile_events(config: &Config) {
+    if config.import_config.is_empty() {
+        return;
+    }
+    let text = std::fs::read_to_string(&config.import_config).unwrap_or_default();
+    if let Ok(profile) = profile::Profile::parse(&text) {
+        for event in profile.events.iter() {
+            let enable = strcat_for_file_path(&format!("events/{}/enable", event));
+            if std::path::Path::new(&enable).exists() {
+                set_kernel_option_enable(&enable, false);
+            }
+        }
+    }
+}
+
+// The pid of the flight recorder writing to the directory, if running.
+fn flight_pid(dir: &std::path::Path) -> Option<i32> {
+    let pid = std::fs::read_to_string(dir.join(flight::PID_FILE))
+        .ok()?
+        .trim()
+        .parse::<i32>()
+        .ok()?;
+    if pid > 0 && unsafe { kill(pid, 0) } == 0 {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+// Stream trace_pipe into rotating segments in the --dir directory until
+// interrupted, sealing on SIGUSR1.
+fn run_flight(config: &Config) -> i32 {
+    let dir = std::path::Path::new(&config.flight_dir);
+    if let Some(pid) = flight_pid(dir) {
+        println!(
+            "a flight recorder, pid {}, is already writing to {}\n",
+            pid, config.flight_dir
+        );
+        return -1;
+    }
+    if let Some(max) = BUDGET.memory_limit() {
+        if config.flight_segment > max {
+            println!(
+                "--segment {} is over the --max-memory-mb limit, an open segment is kept in memory.\n",
+                config.flight_segment
+            );
+            return -1;
+        }
+    }
+    let _session = match lock_session("record") {
+        Some(lock) => lock,
+        None => return -1,
+    };
+    let mut recorder = match flight::Recorder::open(dir, config.flight_segment, config.flight_keep)
+    {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            println!("open flight directory {} fail: {}\n", config.flight_dir, e);
+            return -1;
+        }
+    };
+    let pid_file = dir.join(flight::PID_FILE);
+    if let Err(e) = std::fs::write(&pid_file, format!("{}\n", std::process::id())) {
+        println!("write {} fail: {}\n", pid_file.display(), e);
+        return -1;
+    }
+    let _ = register_signal_handler(libc::SIGUSR1, sigseal_handler);
+    if !setup_trace(config) || !set_tracing_enabled(true) {
+        println!("unable to start tracing, please check debugfs setup correctly\n");
+        let _ = std::fs::remove_file(&pid_file);
+        return -1;
+    }
+    lower_priority(config);
+    let mut ret = 0;
+    match std::fs::File::open(strcat_for_file_path("trace_pipe")) {
+        Ok(mut pipe) => {
+            println!(
+                "flight recorder writing to {}, seal with atrace flight --seal --dir {}",
+                config.flight_dir, config.flight_dir
+            );
+            let mut buf = vec![0u8; BUFFER_LEN];
+            let mut pending: Vec<u8> = Vec::new();
+            while !unsafe { G_TRACE_ABORTED } {
+                let result = match pipe.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        pending.extend_from_slice(&buf[..n]);
+                        let mut result = Ok(());
+                        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                            let line: Vec<u8> = pending.drain(..=end).collect();
+                            result = result.and_then(|_| recorder.push(&line[..end]));
+                        }
+                        result
+                    }
+                    // Interrupted by a signal, check whether to seal or stop.
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                    Err(e) => {
+                        println!("read trace_pipe fail: {}\n", e);
+                        ret = -1;
+                        break;
+                    }
+                };
+                let result = result.and_then(|_| {
+                    if flight::SEAL_REQUESTED.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                        recorder.seal()
+                    } else {
+                        Ok(())
+                    }
+                });
+                if let Err(e) = result {
+                    println!("write flight segment fail: {}\n", e);
+                    ret = -1;
+                    break;
+                }
+            }
+            for line in pending
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+            {
+                let _ = recorder.push(line);
+            }
+            if let Err(e) = recorder.seal() {
+                println!("write flight segment fail: {}\n", e);
+                ret = -1;
+            }
+        }
+        Err(e) => {
+            println!("open trace_pipe fail: {}\n", e);
+            ret = -1;
+        }
+    }
+    set_tracing_enabled(false);
+    cleanup_trace(config);
+    let _ = std::fs::remove_file(&pid_file);
+    ret
+}
+
+// Ask the flight recorder writing to --dir to close its open segment, wait
+// for the index to count the seal and print it.
+fn seal_flight(config: &Config) -> i32 {
+    let dir = std::path::Path::new(&config.flight_dir);
+    let pid = match flight_pid(dir) {
+        Some(pid) => pid,
+        None => {
+            println!("no flight recorder is writing to {}\n", config.flight_dir);
+            return -1;
+        }
+    };
+    let index = dir.join(flight::INDEX_FILE);
+    let seals = |text: &str| flight::parse_index(text).1;
+    let before = seals(&std::fs::read_to_string(&index).unwrap_or_default());
+    if unsafe { kill(pid, libc::SIGUSR1) } != 0 {
+        println!(
+            "signal flight recorder {} fail: {}\n",
+            pid,
+            io::Error::last_os_error()
+        );
+        return -1;
+    }
+    // The recorder seals at its next trace_pipe read, which returns at the
+    // latest when the signal interrupts it.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        let text = std::fs::read_to_string(&index).unwrap_or_default();
+        if seals(&text) > before {
+            print!("{}", text);
+            return 0;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    println!("flight recorder {} didn't seal within 5s\n", pid);
+    -1
+}
+
+// Take the session lock of the tracefs directory for the action, saying why
+// when another run holds it.
+fn lock_session(action: &str) -> Option<session::SessionLock> {
+    match session::acquire(tracefs::root()) {
+        Ok(lock) => Some(lock),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            println!(
+                "another atrace session is using {}, refusing to {}.\n",
+                tracefs::root(),
+                action
+            );
+            None
+        }
+        Err(e) => {
+            println!("lock {} fail: {}\n", tracefs::root(), e);
+            None
+        }
+    }
+}
+
+// Write the markers of a captured trace into trace_marker at their original
+// pace scaled by --speed.
+fn replay_trace(config: &Config) -> i32 {
+    // Replayed markers would end up in the captures of a live session.
+    let _session = match lock_session("replay into it") {
+        Some(lock) => lock,
+        None => return -1,
+    };
+    let data = match input::read_trace_file(&config.replay_file) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("open trace file:{:?} fail: {}\n", &config.replay_file, e);
+            return -1;
+        }
+    };
+    let parsed = replay::markers(&data);
+    drop(data);
+    if parsed.cut > 0 {
+        eprintln!("warning: {} cut marker lines skipped", parsed.cut);
+    }
+    if read_trace_option("tracing_on").is_ok_and(|value| value == "0") {
+        eprintln!("warning: tracing is off, the replayed markers won't be recorded");
+    }
+    let mut marker = match OpenOptions::new()
+        .write(true)
+        .open(strcat_for_file_path("trace_marker"))
+    {
+        Ok(marker) => marker,
+        Err(e) => {
+            println!("open trace_marker fail: {}\n", e);
+            return -1;
+        }
+    };
+    let start = Instant::now();
+    let now = || start.elapsed().as_secs_f64();
+    let sleep = |seconds: f64| thread::sleep(Duration::from_secs_f64(seconds));
+    let abort = || unsafe { G_TRACE_ABORTED };
+    match replay::replay(
+        &parsed.markers,
+        config.replay_speed,
+        &mut marker,
+        &now,
+        &sleep,
+        &abort,
+    ) {
+        Ok(stats) => {
+            println!(
+                "replayed {} of {} markers in {:.3}s at {}x, at most {:.1}ms late{}",
+                stats.replayed,
+                parsed.markers.len(),
+                now(),
+                config.replay_speed,
+                stats.max_late * 1e3,
+                if stats.interrupted {
+                    ", interrupted"
+                } else {
+                    ""
+                }
+            );
+            0
+        }
+        Err(e) => {
+            println!("write trace_marker fail: {}\n", e);
+            -1
+        }
+    }
+}
+
+// Pick trace events interactively, then print the profile or save it to
+// the output file.
+fn run_pick(config: &Config) -> i32 {
+    let available = profile::available_events(std::path::Path::new(tracefs::root()));
+    if available.is_empty() {
+        println!(
+            "no trace events found in {}available_events\n",
+            tracefs::root()
+        );
+        return -1;
+    }
+    let mut picker = pick::Picker::new(&available, &[]);
+    match pick::run(&mut picker) {
+        Ok(true) => (),
+        Ok(false) => {
+            eprintln!("pick aborted");
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("pick failed: {}", e);
+            return -1;
+        }
+    }
+    let profile = profile::Profile {
+        kernel: profile::kernel_release(),
+        events: picker.selected(),
+    };
+    if profile.events.is_empty() {
+        eprintln!("no events selected");
+        return 1;
+    }
+    if config.output.is_empty() {
+        print!("{}", profile.to_toml());
+        eprintln!("capture with: atrace --import-config <this file>");
+        return 0;
+    }
+    if let Err(e) = std::fs::write(&config.output, profile.to_toml()) {
+        println!("error writing {}: {}\n", config.output, e);
+        return -1;
+    }
+    println!(
+        "saved {} events to {}, capture with: atrace --import-config {}",
+        profile.events.len(),
+        config.output,
+        config.output
+    );
+    0
+}
+
+// Write the events enabled after setup as a profile.
+fn export_events(config: &Config) -> bool {
+    let profile = profile::Profile {
+        kernel: profile::kernel_release(),
+        events: profile::enabled_events(std::path::Path::new(tracefs::root())),
+    };
+    match std::fs::write(&config.export_config, profile.to_toml()) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("error writing {}: {}\n", config.export_config, e);
+            false
+        }
+    }
+}
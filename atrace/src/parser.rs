@@ -0,0 +1,974 @@
+// Parsing of the ftrace text output dumped by atrace.
+//
+// A typical line with print-tgid enabled looks like:
+//
+//   chat-1234  ( 1234) [003] ...1  5678.123456: tracing_mark_write: B|1234|process
+//
+// The tgid column and the irq-info flags column are optional, depending on
+// kernel version and the options set up for the capture.
+//
+// Markers written to an ordinary file instead of the kernel trace_marker are
+// prefixed with an ftrace style timestamp only:
+//
+//   [  5678.123456] B|1234|process
+//
+// With -K the function_graph tracer is used, and the dump shows calls with
+// their durations instead, where trace_marker writes appear as comments:
+//
+//   5678.123456 |   1)   chat-1234    |   0.512 us    |    getname();
+//   5678.123470 |   1)   chat-1234    |               |  /* B|1234|process */
+use std::fmt;
+
+const MARKER_EVENT: &str = "tracing_mark_write";
+const FUNCGRAPH_ENTRY_EVENT: &str = "funcgraph_entry";
+const FUNCGRAPH_EXIT_EVENT: &str = "funcgraph_exit";
+const SCHED_SWITCH_EVENT: &str = "sched_switch";
+const CLOCK_SYNC_PREFIX: &str = "trace_event_clock_sync:";
+const REALTIME_SYNC_KEY: &str = "realtime_ts=";
+pub const METADATA_PREFIX: &str = "atrace_metadata:";
+// Metadata key of the id atrace gives each capture.
+pub const CAPTURE_ID_KEY: &str = "capture_id";
+const HEADER_PREFIX: &str = "tracing-atrace";
+const DICT_PREFIX: &str = "atrace_dict:";
+const CONTINUATION_TOKEN: &str = "…cont#";
+const CONTINUATION_PREFIX: &str = "cont#";
+
+/// One parsed line of ftrace text output.
+pub struct TraceLine<'a> {
+    pub comm: &'a str,
+    pub tid: i32,
+    pub tgid: Option<i32>,
+    pub cpu: u32,
+    /// Trace clock timestamp in seconds.
+    pub timestamp: f64,
+    pub event: &'a str,
+    pub payload: &'a str,
+}
+
+impl<'a> TraceLine<'a> {
+    pub fn is_marker(&self) -> bool {
+        self.event == MARKER_EVENT
+    }
+
+    pub fn sched_switch(&self) -> Option<SchedSwitch> {
+        if self.event != SCHED_SWITCH_EVENT {
+            return None;
+        }
+        let split = self.payload.find(" ==> ")?;
+        Some(SchedSwitch {
+            prev_pid: switch_pid(&self.payload[..split], "prev_pid=")?,
+            next_pid: switch_pid(&self.payload[split..], "next_pid=")?,
+        })
+    }
+
+    // Parse the function call of a flat function_graph line, if any.
+    pub fn funcgraph_call(&self) -> Option<FuncgraphCall<'a>> {
+        if self.event != FUNCGRAPH_ENTRY_EVENT && self.event != FUNCGRAPH_EXIT_EVENT {
+            return None;
+        }
+        let split = self.payload.find('|')?;
+        parse_funcgraph_call(&self.payload[..split], &self.payload[split + 1..])
+    }
+}
+
+/// A context switch on the cpu of the line, from the sched_switch event:
+/// "prev_comm=chat prev_pid=1234 ... ==> next_comm=swapper/3 next_pid=0 ...".
+pub struct SchedSwitch {
+    pub prev_pid: i32,
+    pub next_pid: i32,
+}
+
+// The pid value after key in a sched_switch half, comm may hold spaces.
+fn switch_pid(text: &str, key: &str) -> Option<i32> {
+    let start = text.rfind(key)? + key.len();
+    text[start..].split_whitespace().next()?.parse::<i32>().ok()
+}
+
+/// A userspace marker written through trace_marker in the systrace format.
+pub enum Marker<'a> {
+    Begin {
+        pid: i32,
+        name: &'a str,
+        hints: Option<&'a str>,
+    },
+    End {
+        pid: Option<i32>,
+    },
+    Counter {
+        pid: i32,
+        name: &'a str,
+        value: i64,
+    },
+    AsyncBegin {
+        pid: i32,
+        name: &'a str,
+        cookie: i64,
+    },
+    AsyncEnd {
+        pid: i32,
+        name: &'a str,
+        cookie: i64,
+    },
+    Instant {
+        pid: i32,
+        name: &'a str,
+    },
+}
+
+// Whether a marker payload is the clock sync written by atrace at start.
+pub fn is_clock_sync(payload: &str) -> bool {
+    payload.starts_with(CLOCK_SYNC_PREFIX)
+}
+
+// The wall clock seconds carried by a "realtime_ts=<ms>" clock sync.
+pub fn parse_clock_sync_realtime(payload: &str) -> Option<f64> {
+    let value = payload
+        .strip_prefix(CLOCK_SYNC_PREFIX)?
+        .trim()
+        .strip_prefix(REALTIME_SYNC_KEY)?;
+    value.parse::<i64>().ok().map(|ms| ms as f64 / 1_000.0)
+}
+
+// The key and value of an "atrace_metadata: <key>=<value>" marker.
+pub fn parse_metadata(payload: &str) -> Option<(&str, &str)> {
+    let mut parts = payload.strip_prefix(METADATA_PREFIX)?.trim().splitn(2, '=');
+    Some((parts.next()?, parts.next()?))
+}
+
+// The count of a "CPU:3 [LOST 1234 EVENTS]" line, written where a ring
+// buffer overran.
+pub fn parse_lost_events(line: &str) -> Option<u64> {
+    let rest = line.trim().strip_prefix("CPU:")?;
+    let rest = &rest[rest.find("[LOST ")? + "[LOST ".len()..];
+    rest.strip_suffix(" EVENTS]")?.trim().parse().ok()
+}
+
+// Parse one ftrace text line, comment and header lines give None.
+pub fn parse_line(line: &str) -> Option<TraceLine<'_>> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    if line.trim_start().starts_with('#') {
+        return None;
+    }
+    if line.starts_with('[') {
+        return parse_file_sink_line(line);
+    }
+    let (cpu_start, cpu_end) = find_cpu_column(line)?;
+    let cpu = line[cpu_start + 1..cpu_end].parse::<u32>().ok()?;
+
+    let mut task = line[..cpu_start].trim();
+    let mut tgid = None;
+    if task.ends_with(')') {
+        let open = task.rfind('(')?;
+        tgid = task[open + 1..task.len() - 1].trim().parse::<i32>().ok();
+        task = task[..open].trim_end();
+    }
+    let dash = task.rfind('-')?;
+    let comm = &task[..dash];
+    let tid = task[dash + 1..].parse::<i32>().ok()?;
+
+    let rest = &line[cpu_end + 1..];
+    let ts_end = rest.find(": ")?;
+    let timestamp = rest[..ts_end]
+        .split_whitespace()
+        .last()?
+        .parse::<f64>()
+        .ok()?;
+    let rest = &rest[ts_end + 2..];
+    let (event, payload) = match rest.find(':') {
+        Some(index) => (&rest[..index], rest[index + 1..].trim_start()),
+        None => (rest.trim_end(), ""),
+    };
+    Some(TraceLine {
+        comm,
+        tid,
+        tgid,
+        cpu,
+        timestamp,
+        event,
+        payload,
+    })
+}
+
+/// One line of function_graph tracer output in the graph layout.
+pub struct FuncgraphLine<'a> {
+    pub timestamp: Option<f64>,
+    pub cpu: u32,
+    pub comm: &'a str,
+    pub tid: Option<i32>,
+    pub call: FuncgraphCall<'a>,
+}
+
+pub enum FuncgraphCall<'a> {
+    // "func() {"
+    Entry {
+        name: &'a str,
+    },
+    // "func();" with its duration in seconds.
+    Leaf {
+        name: &'a str,
+        duration: f64,
+    },
+    // "} /* func */" with its duration in seconds.
+    Exit {
+        name: Option<&'a str>,
+        duration: f64,
+    },
+    // "/* text */", used for trace_marker writes.
+    Comment(&'a str),
+}
+
+// Parse a graph layout line like
+// "5678.123456 |   1)   chat-1234    |   0.512 us    |    getname();".
+pub fn parse_funcgraph_line(line: &str) -> Option<FuncgraphLine<'_>> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    if line.trim_start().starts_with('#') {
+        return None;
+    }
+    let mut fields = line.splitn(2, '|');
+    let first = fields.next()?;
+    let mut rest = fields.next()?;
+    let timestamp = first.trim().parse::<f64>().ok();
+    let cpu_column = match timestamp {
+        Some(_) => {
+            let mut fields = rest.splitn(2, '|');
+            let cpu_column = fields.next()?;
+            rest = fields.next()?;
+            cpu_column
+        }
+        None => first,
+    };
+
+    // "  1)   chat-1234  " holds the cpu and, with funcgraph-proc, the task.
+    let cpu_column = cpu_column.trim();
+    let paren = cpu_column.find(')')?;
+    let cpu = cpu_column[..paren].trim().parse::<u32>().ok()?;
+    let task = cpu_column[paren + 1..].trim();
+    let (comm, tid) = match task.rfind('-') {
+        Some(dash) => match task[dash + 1..].parse::<i32>() {
+            Ok(tid) => (&task[..dash], Some(tid)),
+            Err(_) => (task, None),
+        },
+        None => (task, None),
+    };
+
+    let split = rest.find('|')?;
+    let call = parse_funcgraph_call(&rest[..split], &rest[split + 1..])?;
+    Some(FuncgraphLine {
+        timestamp,
+        cpu,
+        comm,
+        tid,
+        call,
+    })
+}
+
+// Parse the duration and function columns shared by both function_graph
+// layouts.
+pub fn parse_funcgraph_call<'a>(duration: &str, function: &'a str) -> Option<FuncgraphCall<'a>> {
+    let function = function.trim();
+    // Durations are flagged with an overhead mark like "+" or "!" when long.
+    let duration = duration
+        .trim()
+        .trim_start_matches(|c| {
+            c == '+' || c == '!' || c == '#' || c == '*' || c == '@' || c == '$'
+        })
+        .trim();
+    let duration = duration
+        .strip_suffix("us")
+        .and_then(|us| us.trim().parse::<f64>().ok())
+        .map(|us| us / 1_000_000.0);
+
+    if let Some(comment) = function.strip_prefix('}') {
+        return Some(FuncgraphCall::Exit {
+            name: strip_comment(comment.trim()),
+            duration: duration?,
+        });
+    }
+    if let Some(text) = strip_comment(function) {
+        return Some(FuncgraphCall::Comment(text));
+    }
+    if let Some(name) = function.strip_suffix("() {") {
+        return Some(FuncgraphCall::Entry { name });
+    }
+    if let Some(name) = function.strip_suffix("();") {
+        return Some(FuncgraphCall::Leaf {
+            name,
+            duration: duration?,
+        });
+    }
+    None
+}
+
+// The text of a "/* text */" comment.
+fn strip_comment(text: &str) -> Option<&str> {
+    Some(text.strip_prefix("/*")?.strip_suffix("*/")?.trim())
+}
+
+// Parse a marker line written to a non-kernel sink, the comm and thread are
+// unknown there so the marker pid stands in for the thread.
+fn parse_file_sink_line(line: &str) -> Option<TraceLine<'_>> {
+    let close = line.find("] ")?;
+    let timestamp = line[1..close].trim().parse::<f64>().ok()?;
+    let payload = &line[close + 2..];
+    let pid = payload
+        .split('|')
+        .nth(1)
+        .and_then(|pid| pid.trim().parse::<i32>().ok())
+        .unwrap_or(0);
+    Some(TraceLine {
+        comm: "",
+        tid: pid,
+        tgid: Some(pid),
+        cpu: 0,
+        timestamp,
+        event: MARKER_EVENT,
+        payload,
+    })
+}
+
+// Locate the "[NNN]" cpu column, returning the positions of both brackets.
+fn find_cpu_column(line: &str) -> Option<(usize, usize)> {
+    let bytes = line.as_bytes();
+    let mut start = 0;
+    while let Some(offset) = line[start..].find('[') {
+        let open = start + offset;
+        let mut index = open + 1;
+        while index < bytes.len() && bytes[index].is_ascii_digit() {
+            index += 1;
+        }
+        if index > open + 1 && index < bytes.len() && bytes[index] == b']' {
+            return Some((open, index));
+        }
+        start = open + 1;
+    }
+    None
+}
+
+// Parse a systrace style marker payload like "B|1234|name".
+pub fn parse_marker(payload: &str) -> Option<Marker<'_>> {
+    let payload = payload.trim_end_matches(['\n', '\r']);
+    let mut fields = payload.splitn(3, '|');
+    let kind = fields.next()?;
+    let pid = fields.next().and_then(|pid| pid.trim().parse::<i32>().ok());
+    let rest = fields.next();
+    match kind {
+        "B" => {
+            let (name, hints) = split_hints(rest?);
+            Some(Marker::Begin {
+                pid: pid?,
+                name,
+                hints,
+            })
+        }
+        "E" => Some(Marker::End { pid }),
+        "I" => Some(Marker::Instant {
+            pid: pid?,
+            name: rest?,
+        }),
+        "C" | "S" | "F" => {
+            let rest = rest?;
+            let split = rest.rfind('|')?;
+            let name = &rest[..split];
+            let value = rest[split + 1..].trim().parse::<i64>().ok()?;
+            let pid = pid?;
+            Some(match kind {
+                "C" => Marker::Counter { pid, name, value },
+                "S" => Marker::AsyncBegin {
+                    pid,
+                    name,
+                    cookie: value,
+                },
+                _ => Marker::AsyncEnd {
+                    pid,
+                    name,
+                    cookie: value,
+                },
+            })
+        }
+        _ => None,
+    }
+}
+
+// The hash and full name of an "atrace_dict:<hash>=<name>" instant, written
+// once per hashed name, which markers then carry as "<name>#<hash>".
+pub fn parse_dict_entry(name: &str) -> Option<(&str, &str)> {
+    let mut parts = name.strip_prefix(DICT_PREFIX)?.splitn(2, '=');
+    let hash = parts.next()?.trim();
+    let full = parts.next()?;
+    if hash.len() != 8 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((hash, full))
+}
+
+// The hash of a "<name>#<8 hex digits>" marker name.
+pub fn name_hash(name: &str) -> Option<&str> {
+    let split = name.rfind('#')?;
+    let hash = &name[split + 1..];
+    if hash.len() == 8 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(hash)
+    } else {
+        None
+    }
+}
+
+// Split a trailing "…cont#N" token from an oversized marker which was split
+// into several writes, giving the text and the number of the next chunk.
+pub fn split_continuation(text: &str) -> (&str, Option<u32>) {
+    if let Some(split) = text.rfind(CONTINUATION_TOKEN) {
+        if let Ok(next) = text[split + CONTINUATION_TOKEN.len()..].parse::<u32>() {
+            return (&text[..split], Some(next));
+        }
+    }
+    (text, None)
+}
+
+// Parse a "cont#N|rest" chunk continuing an oversized marker.
+pub fn parse_continuation(payload: &str) -> Option<(u32, &str)> {
+    let payload = payload.trim_end_matches(['\n', '\r']);
+    if !payload.starts_with(CONTINUATION_PREFIX) {
+        return None;
+    }
+    let rest = &payload[CONTINUATION_PREFIX.len()..];
+    let split = rest.find('|')?;
+    let chunk = rest[..split].parse::<u32>().ok()?;
+    Some((chunk, &rest[split + 1..]))
+}
+
+// Split the optional "|track=io,arg.shard=3" hints suffix from a marker name.
+fn split_hints(name: &str) -> (&str, Option<&str>) {
+    match name.rfind('|') {
+        Some(split) if name[split + 1..].contains('=') => {
+            (&name[..split], Some(&name[split + 1..]))
+        }
+        _ => (name, None),
+    }
+}
+
+// Parse hints like "track=io,arg.shard=3" into key value pairs.
+pub fn parse_hints(hints: &str) -> Vec<(String, String)> {
+    hints
+        .split(',')
+        .filter_map(|hint| {
+            let mut kv = hint.splitn(2, '=');
+            let key = kv.next()?.trim();
+            let value = kv.next()?.trim();
+            if key.is_empty() {
+                return None;
+            }
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// Parse one "key=value" token at the start of text, giving the pair and the
+// length of the token, or None with the length of the plain token there.
+fn parse_attr(text: &str) -> (Option<(String, String)>, usize) {
+    let plain = text.find(char::is_whitespace).unwrap_or(text.len());
+    let split = match text[..plain].find('=') {
+        Some(split) => split,
+        None => return (None, plain),
+    };
+    let key = &text[..split];
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+    {
+        return (None, plain);
+    }
+    let rest = &text[split + 1..];
+    if !rest.starts_with('"') {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        return (
+            Some((key.to_string(), rest[..end].to_string())),
+            split + 1 + end,
+        );
+    }
+    let mut value = String::new();
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => {
+                let end = split + 1 + index + 1;
+                // A closing quote must end the token.
+                return match text[end..].chars().next() {
+                    Some(next) if !next.is_whitespace() => (None, plain),
+                    _ => (Some((key.to_string(), value)), end),
+                };
+            }
+            '\\' => match chars.next() {
+                Some((_, escaped)) => value.push(escaped),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    // An unterminated quote.
+    (None, plain)
+}
+
+// Split the trailing attributes from a marker name like
+// `load_chunk idx=5 path="a b"`, the key=value grammar shared by the
+// tracing-atrace V2 format and libatrace. Values with whitespace, quotes or
+// backslashes are quoted, with "\" escaping the next character. A name made
+// only of attributes is kept whole.
+pub fn split_attrs(name: &str) -> (&str, Vec<(String, String)>) {
+    // Each token's start and its pair, if an attribute.
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    while index < name.len() {
+        let rest = &name[index..];
+        let skip = rest.len() - rest.trim_start().len();
+        if skip > 0 {
+            index += skip;
+            continue;
+        }
+        let (attr, len) = parse_attr(rest);
+        tokens.push((index, attr));
+        index += len;
+    }
+    let first = tokens
+        .iter()
+        .rposition(|(_, attr)| attr.is_none())
+        .map_or(0, |plain| plain + 1);
+    if first == 0 || first == tokens.len() {
+        return (name, Vec::new());
+    }
+    let head = name[..tokens[first].0].trim_end();
+    let attrs = tokens.drain(first..).filter_map(|(_, attr)| attr).collect();
+    (head, attrs)
+}
+
+/// The one-time metadata marker written by tracing-atrace on installation,
+/// e.g. "tracing-atrace v0.2.0 fmt=v2 fields=data,msg pid=1234 exe=gateway".
+#[derive(Default)]
+pub struct TraceHeader {
+    pub version: String,
+    pub format: String,
+    pub fields: Vec<String>,
+    pub pid: i32,
+    pub exe: String,
+}
+
+impl TraceHeader {
+    // Parse the header from an instant marker name, None if not a header.
+    pub fn parse(name: &str) -> Option<TraceHeader> {
+        let mut tokens = name.split_whitespace();
+        if tokens.next()? != HEADER_PREFIX {
+            return None;
+        }
+        let version = tokens.next()?;
+        if !version.starts_with('v') {
+            return None;
+        }
+        let mut header = TraceHeader {
+            version: version[1..].to_string(),
+            ..Default::default()
+        };
+        for token in tokens {
+            let mut kv = token.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let value = kv.next().unwrap_or("");
+            match key {
+                "fmt" => header.format = value.to_string(),
+                "fields" => {
+                    header.fields = value
+                        .split(',')
+                        .filter(|field| !field.is_empty())
+                        .map(|field| field.to_string())
+                        .collect()
+                }
+                "pid" => header.pid = value.parse::<i32>().unwrap_or(0),
+                "exe" => header.exe = value.to_string(),
+                _ => {}
+            }
+        }
+        Some(header)
+    }
+}
+
+impl fmt::Display for TraceHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} v{} fmt={} fields={} pid={} exe={}",
+            HEADER_PREFIX,
+            self.version,
+            self.format,
+            self.fields.join(","),
+            self.pid,
+            self.exe
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{decode_line, MAX_LINE_BYTES};
+
+    // The attribute grammar as the producers write it: bare values unless
+    // they hold whitespace, quotes or backslashes, which are escaped.
+    fn format_attrs(name: &str, attrs: &[(&str, &str)]) -> String {
+        let mut out = name.to_string();
+        for (key, value) in attrs {
+            out.push(' ');
+            out.push_str(key);
+            out.push('=');
+            if value.is_empty()
+                || value
+                    .chars()
+                    .any(|c| c.is_whitespace() || c == '"' || c == '\\')
+            {
+                out.push('"');
+                for c in value.chars() {
+                    if c == '"' || c == '\\' {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+                out.push('"');
+            } else {
+                out.push_str(value);
+            }
+        }
+        out
+    }
+
+    fn pairs(attrs: &[(&str, &str)]) -> Vec<(String, String)> {
+        attrs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn attrs_round_trip_through_the_grammar() {
+        let cases: &[(&str, &[(&str, &str)])] = &[
+            ("load_chunk", &[("idx", "5"), ("size", "16384")]),
+            ("open file", &[("path", "/tmp/a b.txt"), ("mode", "rw")]),
+            ("quote", &[("msg", "say \"hi\""), ("dir", "C:\\temp\\")]),
+            ("empty", &[("value", ""), ("k.with-dots_1", "x=y")]),
+            ("unicode", &[("city", "Zürich"), ("tab", "a\tb")]),
+        ];
+        for (name, attrs) in cases {
+            let marker = format_attrs(name, attrs);
+            let (head, parsed) = split_attrs(&marker);
+            assert_eq!(head, *name, "{}", marker);
+            assert_eq!(parsed, pairs(attrs), "{}", marker);
+        }
+    }
+
+    #[test]
+    fn names_without_attrs_are_kept_whole() {
+        for name in &[
+            "plain",
+            "two words",
+            "idx=5",
+            "a=1 b=2",
+            "trailing idx=5 word",
+            "open path=\"unterminated",
+            "bad key!=1",
+            "closing path=\"a\"b",
+        ] {
+            assert_eq!(split_attrs(name), (*name, Vec::new()), "{}", name);
+        }
+        // Attributes stop at the last plain token.
+        let (head, attrs) = split_attrs("load a=1 chunk idx=5");
+        assert_eq!(head, "load a=1 chunk");
+        assert_eq!(attrs, pairs(&[("idx", "5")]));
+    }
+
+    #[test]
+    fn dict_entries_and_name_hashes() {
+        assert_eq!(
+            parse_dict_entry("atrace_dict:0a1b2c3d=GET /a=b"),
+            Some(("0a1b2c3d", "GET /a=b"))
+        );
+        assert_eq!(parse_dict_entry("atrace_dict:0a1b2c3=x"), None);
+        assert_eq!(parse_dict_entry("atrace_dict:0a1b2c3g=x"), None);
+        assert_eq!(parse_dict_entry("atrace_dict:0a1b2c3d"), None);
+        assert_eq!(parse_dict_entry("dict:0a1b2c3d=x"), None);
+
+        assert_eq!(name_hash("GET#0a1b2c3d"), Some("0a1b2c3d"));
+        assert_eq!(name_hash("a#b#DEADBEEF"), Some("DEADBEEF"));
+        assert_eq!(name_hash("GET#0a1b2c3d0"), None);
+        assert_eq!(name_hash("GET#0a1b2c3z"), None);
+        assert_eq!(name_hash("GET 0a1b2c3d"), None);
+    }
+
+    #[test]
+    fn file_sink_lines_parse() {
+        let begin = parse_line("[  123.456789] B|4321|draw\n").unwrap();
+        assert!(begin.is_marker());
+        assert_eq!((begin.comm, begin.tid, begin.tgid), ("", 4321, Some(4321)));
+        assert_eq!((begin.cpu, begin.timestamp), (0, 123.456789));
+        assert_eq!(begin.payload, "B|4321|draw");
+        let end = parse_line("[  123.457000] E|4321").unwrap();
+        assert!(end.is_marker());
+        assert_eq!(
+            (end.tid, end.timestamp, end.payload),
+            (4321, 123.457, "E|4321")
+        );
+        assert!(parse_line("[not a time] B|4321|draw").is_none());
+        assert!(parse_line("[  123.456789]").is_none());
+
+        // The lines build a slice of the marker pid like kernel lines do.
+        let capture = crate::capture::Capture::from_bytes(
+            b"[  123.456789] B|4321|draw\n[  123.457000] E|4321\n",
+        );
+        assert_eq!(capture.slices.len(), 1);
+        let slice = &capture.slices[0];
+        assert_eq!(
+            (slice.name.as_str(), slice.pid, slice.tid),
+            ("draw", 4321, 4321)
+        );
+        assert_eq!(slice.start, 123.456789);
+        assert!((slice.duration - 211e-6).abs() < 1e-9, "{}", slice.duration);
+    }
+
+    #[test]
+    fn funcgraph_graph_layout_line() {
+        let line = parse_funcgraph_line(
+            " 5678.100022 |   0)   chat-1234    |   0.512 us    |      getname();",
+        )
+        .unwrap();
+        assert_eq!(line.timestamp, Some(5678.100022));
+        assert_eq!(line.cpu, 0);
+        assert_eq!(line.comm, "chat");
+        assert_eq!(line.tid, Some(1234));
+        match line.call {
+            FuncgraphCall::Leaf { name, duration } => {
+                assert_eq!(name, "getname");
+                assert!((duration - 0.512e-6).abs() < 1e-12);
+            }
+            _ => panic!("not a leaf"),
+        }
+    }
+
+    #[test]
+    fn funcgraph_without_abstime() {
+        let line = parse_funcgraph_line(" 1)   kworker/1:2-88   |               |  sys_openat() {")
+            .unwrap();
+        assert_eq!(line.timestamp, None);
+        assert_eq!(line.cpu, 1);
+        assert_eq!(line.comm, "kworker/1:2");
+        assert_eq!(line.tid, Some(88));
+        assert!(matches!(
+            line.call,
+            FuncgraphCall::Entry { name: "sys_openat" }
+        ));
+    }
+
+    #[test]
+    fn funcgraph_exits_with_overhead_marks() {
+        for (duration, expected) in [("+ 11.000 us", 11e-6), ("! 150.500 us", 150.5e-6)] {
+            match parse_funcgraph_call(duration, "} /* do_sys_open */").unwrap() {
+                FuncgraphCall::Exit { name, duration } => {
+                    assert_eq!(name, Some("do_sys_open"));
+                    assert!((duration - expected).abs() < 1e-12);
+                }
+                _ => panic!("not an exit"),
+            }
+        }
+        assert!(matches!(
+            parse_funcgraph_call("4.000 us", "}"),
+            Some(FuncgraphCall::Exit { name: None, .. })
+        ));
+        // An exit needs its duration.
+        assert!(parse_funcgraph_call("", "}").is_none());
+    }
+
+    #[test]
+    fn funcgraph_comment_carries_marker() {
+        match parse_funcgraph_call("", "/* B|1234|open_config */").unwrap() {
+            FuncgraphCall::Comment(text) => assert_eq!(text, "B|1234|open_config"),
+            _ => panic!("not a comment"),
+        }
+    }
+
+    #[test]
+    fn funcgraph_flat_layout_line() {
+        let line = parse_line(
+            "  chat-1234  [000] ....  5678.200000: funcgraph_entry:                   |  ksys_read() {",
+        )
+        .unwrap();
+        assert!(matches!(
+            line.funcgraph_call(),
+            Some(FuncgraphCall::Entry { name: "ksys_read" })
+        ));
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let text = "tracing-atrace v0.2.0 fmt=v2 fields=data,msg pid=1234 exe=gateway";
+        let header = TraceHeader::parse(text).unwrap();
+        assert_eq!(header.version, "0.2.0");
+        assert_eq!(header.format, "v2");
+        assert_eq!(header.fields, ["data", "msg"]);
+        assert_eq!(header.pid, 1234);
+        assert_eq!(header.exe, "gateway");
+        assert_eq!(header.to_string(), text);
+        let again = TraceHeader::parse(&header.to_string()).unwrap();
+        assert_eq!(again.to_string(), text);
+    }
+
+    #[test]
+    fn header_with_missing_fields() {
+        let header = TraceHeader::parse("tracing-atrace v0.1.0 fmt=v1 extra=1").unwrap();
+        assert!(header.fields.is_empty());
+        assert_eq!(header.pid, 0);
+        assert_eq!(
+            header.to_string(),
+            "tracing-atrace v0.1.0 fmt=v1 fields= pid=0 exe="
+        );
+    }
+
+    #[test]
+    fn header_rejects_other_instants() {
+        assert!(TraceHeader::parse("tracing-atrace").is_none());
+        assert!(TraceHeader::parse("tracing-atrace 0.2.0").is_none());
+        assert!(TraceHeader::parse("request_done id=5").is_none());
+        assert!(TraceHeader::parse("").is_none());
+    }
+
+    #[test]
+    fn header_marker_line() {
+        let line = parse_line(
+            "  gateway-1234  ( 1234) [002] ...1  100.000001: tracing_mark_write: \
+             I|1234|tracing-atrace v0.2.0 fmt=v2 fields=data pid=1234 exe=gateway",
+        )
+        .unwrap();
+        match parse_marker(line.payload) {
+            Some(Marker::Instant { pid, name }) => {
+                assert_eq!(pid, 1234);
+                assert_eq!(TraceHeader::parse(name).unwrap().exe, "gateway");
+            }
+            _ => panic!("not an instant"),
+        }
+    }
+
+    #[test]
+    fn continuation_tokens() {
+        assert_eq!(split_continuation("head …cont#1"), ("head ", Some(1)));
+        assert_eq!(split_continuation("no token"), ("no token", None));
+        assert_eq!(split_continuation("bad …cont#x"), ("bad …cont#x", None));
+        assert_eq!(parse_continuation("cont#12|rest"), Some((12, "rest")));
+        assert_eq!(parse_continuation("cont#12 rest"), None);
+        assert_eq!(parse_continuation("B|1|cont#1|x"), None);
+    }
+
+    // A xorshift generator, so the fuzz cases are the same on every run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    const TEMPLATES: &[&str] = &[
+        "  app-1234  ( 1234) [000] ...1  10.000100: tracing_mark_write: B|1234|draw|k=v",
+        "  app-1234  ( 1234) [000] ...1  10.000200: tracing_mark_write: E|1234",
+        "  app-1234  ( 1234) [001] ...1  10.000300: tracing_mark_write: C|1234|queue|3",
+        "  app-1234  ( 1234) [001] ...1  10.000400: tracing_mark_write: S|1234|load|7",
+        "  app-1234  ( 1234) [001] ...1  10.000500: tracing_mark_write: I|1234|x#ab12{k=v}",
+        "  app-1234  ( 1234) [001] ...1  10.000600: tracing_mark_write: cont#2|tail",
+        "  <idle>-0  (-----) [002] d..2  10.000700: sched_switch: prev_comm=swapper",
+        "CPU:3 [LOST 12 EVENTS]",
+        " 5678.100022 |   0)   chat-1234    |   0.512 us    |      getname();",
+        " 1)   kworker/1:2-88   | + 12.300 us   |  } /* sys_openat */",
+        " 1)   kworker/1:2-88   |               |  /* B|1234|open */",
+        "  app-1234  ( 1234) [000] ...1  10.000800: tracing_mark_write: trace_event_clock_sync: realtime_ts=1700000000000",
+        "# tracer: nop",
+    ];
+
+    // Run every parser over the line, which must not panic whatever it is.
+    fn parse_everything(line: &str) {
+        if let Some(trace_line) = parse_line(line) {
+            let payload = trace_line.payload;
+            let _ = is_clock_sync(payload);
+            let _ = parse_clock_sync_realtime(payload);
+            let _ = parse_metadata(payload);
+            let _ = parse_continuation(payload);
+            match parse_marker(payload) {
+                Some(Marker::Begin { name, hints, .. }) => {
+                    let (name, _) = split_attrs(name);
+                    let _ = parse_dict_entry(name);
+                    let _ = name_hash(name);
+                    let _ = split_continuation(name);
+                    let _ = parse_hints(hints.unwrap_or(""));
+                }
+                Some(Marker::Instant { name, .. }) => {
+                    let _ = split_attrs(name);
+                    let _ = TraceHeader::parse(name);
+                }
+                _ => {}
+            }
+        }
+        if let Some(graph_line) = parse_funcgraph_line(line) {
+            if let FuncgraphCall::Comment(text) = graph_line.call {
+                let _ = parse_marker(text);
+            }
+        }
+        let _ = parse_lost_events(line);
+        let _ = parse_hints(line);
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for _ in 0..20000 {
+            let len = random.below(200);
+            let bytes: Vec<u8> = (0..len).map(|_| random.next() as u8).collect();
+            parse_everything(&decode_line(&bytes).text);
+        }
+    }
+
+    #[test]
+    fn mangled_lines_never_panic() {
+        let mut random = Random(0x9e37_79b9_7f4a_7c15);
+        // Bytes which end fields or start multibyte characters.
+        let specials = b"|:()[]{}#=. -/*\xc3\xa9\xe2\x82\xac\xf0\x9f";
+        for _ in 0..50000 {
+            let mut bytes = TEMPLATES[random.below(TEMPLATES.len())].as_bytes().to_vec();
+            for _ in 0..1 + random.below(4) {
+                let at = random.below(bytes.len() + 1);
+                match random.below(4) {
+                    0 => bytes.truncate(at),
+                    1 => bytes.insert(at, specials[random.below(specials.len())]),
+                    2 if at < bytes.len() => {
+                        bytes.remove(at);
+                    }
+                    _ => bytes.insert(at, random.next() as u8),
+                }
+            }
+            parse_everything(&decode_line(&bytes).text);
+        }
+    }
+
+    #[test]
+    fn cut_multibyte_characters_never_panic() {
+        let line = "  app-1234  ( 1234) [000] ...1  10.000100: tracing_mark_write: B|1234|\u{e9}\u{20ac}\u{1f600}";
+        for end in 0..=line.len() {
+            parse_everything(&decode_line(&line.as_bytes()[..end]).text);
+        }
+        // Lines cut at the length cap inside a character.
+        for pad in 0..4 {
+            let mut bytes = vec![b'x'; MAX_LINE_BYTES - pad];
+            bytes.extend("\u{1f600}\u{1f600}".as_bytes());
+            let decoded = decode_line(&bytes);
+            assert!(decoded.cut);
+            assert!(!decoded.invalid);
+            parse_everything(&decoded.text);
+        }
+    }
+}
@@ -0,0 +1,460 @@
+// Conversion of a capture into the Perfetto protobuf trace format, which is
+// far smaller than JSON and loads faster in the Perfetto UI.
+//
+// Only the minimal subset of perfetto/trace/trace_packet.proto and
+// track_event/*.proto used here is vendored below, keeping the upstream field
+// numbers. Fields which are oneof members upstream are encoded as plain
+// optional fields, which is the same on the wire.
+
+use prost::Message;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::capture::Capture;
+
+const SEQUENCE_ID: u32 = 1;
+// Kernel function calls are shown as a "kernel" process with a track per cpu.
+const KERNEL_PID: i32 = 0;
+
+const TYPE_SLICE_BEGIN: i32 = 1;
+const TYPE_SLICE_END: i32 = 2;
+const TYPE_INSTANT: i32 = 3;
+const TYPE_COUNTER: i32 = 4;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Trace {
+    #[prost(message, repeated, tag = "1")]
+    pub packet: Vec<TracePacket>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TracePacket {
+    #[prost(uint64, optional, tag = "8")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "10")]
+    pub trusted_packet_sequence_id: Option<u32>,
+    #[prost(message, optional, tag = "11")]
+    pub track_event: Option<TrackEvent>,
+    #[prost(message, optional, tag = "60")]
+    pub track_descriptor: Option<TrackDescriptor>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TrackDescriptor {
+    #[prost(uint64, optional, tag = "1")]
+    pub uuid: Option<u64>,
+    #[prost(string, optional, tag = "2")]
+    pub name: Option<String>,
+    #[prost(message, optional, tag = "3")]
+    pub process: Option<ProcessDescriptor>,
+    #[prost(message, optional, tag = "4")]
+    pub thread: Option<ThreadDescriptor>,
+    #[prost(uint64, optional, tag = "5")]
+    pub parent_uuid: Option<u64>,
+    #[prost(message, optional, tag = "8")]
+    pub counter: Option<CounterDescriptor>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProcessDescriptor {
+    #[prost(int32, optional, tag = "1")]
+    pub pid: Option<i32>,
+    #[prost(string, optional, tag = "6")]
+    pub process_name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ThreadDescriptor {
+    #[prost(int32, optional, tag = "1")]
+    pub pid: Option<i32>,
+    #[prost(int32, optional, tag = "2")]
+    pub tid: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    pub thread_name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CounterDescriptor {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TrackEvent {
+    #[prost(int32, optional, tag = "9")]
+    pub r#type: Option<i32>,
+    #[prost(uint64, optional, tag = "11")]
+    pub track_uuid: Option<u64>,
+    #[prost(string, optional, tag = "23")]
+    pub name: Option<String>,
+    #[prost(int64, optional, tag = "30")]
+    pub counter_value: Option<i64>,
+    #[prost(double, optional, tag = "44")]
+    pub double_counter_value: Option<f64>,
+}
+
+// Trace clock seconds to packet nanoseconds since base, packet timestamps
+// being unsigned.
+fn nanos(seconds: f64, base: f64) -> u64 {
+    ((seconds - base) * 1_000_000_000.0).round() as u64
+}
+
+// The base of the packet timestamps: 0 keeps the trace clock, but when skew
+// correction moved events before it, the earliest event is taken instead.
+fn time_base(capture: &Capture) -> f64 {
+    let starts = capture
+        .slices
+        .iter()
+        .map(|slice| slice.start)
+        .chain(capture.async_slices.iter().map(|slice| slice.start))
+        .chain(capture.kernel_calls.iter().map(|call| call.start))
+        .chain(capture.counters.iter().map(|counter| counter.timestamp))
+        .chain(capture.instants.iter().map(|instant| instant.timestamp))
+        .chain(capture.annotations.iter().map(|note| note.timestamp));
+    starts.fold(0.0, f64::min)
+}
+
+// A track event with the ordering key used to sort the packets.
+struct PendingEvent {
+    timestamp: u64,
+    // Ends sort before begins at the same timestamp.
+    phase: u32,
+    // Nesting depth, begins outer first and ends inner first.
+    depth: i64,
+    event: TrackEvent,
+}
+
+#[derive(Default)]
+struct Tracks {
+    next_uuid: u64,
+    packets: Vec<TracePacket>,
+    processes: BTreeMap<i32, u64>,
+    threads: BTreeMap<i32, u64>,
+    named: BTreeMap<(i32, String), u64>,
+}
+
+impl Tracks {
+    fn descriptor(&mut self, descriptor: TrackDescriptor) -> u64 {
+        self.next_uuid += 1;
+        let uuid = self.next_uuid;
+        self.packets.push(TracePacket {
+            trusted_packet_sequence_id: Some(SEQUENCE_ID),
+            track_descriptor: Some(TrackDescriptor {
+                uuid: Some(uuid),
+                ..descriptor
+            }),
+            ..Default::default()
+        });
+        uuid
+    }
+
+    fn process(&mut self, pid: i32, name: &str) -> u64 {
+        if let Some(uuid) = self.processes.get(&pid) {
+            return *uuid;
+        }
+        let uuid = self.descriptor(TrackDescriptor {
+            process: Some(ProcessDescriptor {
+                pid: Some(pid),
+                process_name: Some(name.to_string()),
+            }),
+            ..Default::default()
+        });
+        self.processes.insert(pid, uuid);
+        uuid
+    }
+
+    // A named child track of the process, for counters, async slices and cpus.
+    fn named(&mut self, pid: i32, name: &str, counter: bool) -> u64 {
+        let key = (pid, name.to_string());
+        if let Some(uuid) = self.named.get(&key) {
+            return *uuid;
+        }
+        let parent = self.process(pid, "");
+        let uuid = self.descriptor(TrackDescriptor {
+            name: Some(name.to_string()),
+            parent_uuid: Some(parent),
+            counter: if counter {
+                Some(CounterDescriptor {})
+            } else {
+                None
+            },
+            ..Default::default()
+        });
+        self.named.insert(key, uuid);
+        uuid
+    }
+}
+
+// Build the trace packets for the capture.
+pub fn build_trace(capture: &Capture) -> Trace {
+    let mut tracks = Tracks::default();
+    let mut events: Vec<PendingEvent> = Vec::new();
+    let base = time_base(capture);
+
+    for (tid, thread) in capture.threads.iter() {
+        let parent = if *tid == thread.pid {
+            tracks.process(thread.pid, &thread.comm)
+        } else {
+            tracks.process(thread.pid, "")
+        };
+        let uuid = tracks.descriptor(TrackDescriptor {
+            parent_uuid: Some(parent),
+            thread: Some(ThreadDescriptor {
+                pid: Some(thread.pid),
+                tid: Some(*tid),
+                thread_name: Some(thread.comm.clone()),
+            }),
+            ..Default::default()
+        });
+        tracks.threads.insert(*tid, uuid);
+    }
+
+    let slice = |events: &mut Vec<PendingEvent>,
+                 uuid: u64,
+                 name: &str,
+                 start: f64,
+                 duration: f64,
+                 depth: i64| {
+        events.push(PendingEvent {
+            timestamp: nanos(start, base),
+            phase: 1,
+            depth,
+            event: TrackEvent {
+                r#type: Some(TYPE_SLICE_BEGIN),
+                track_uuid: Some(uuid),
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+        });
+        events.push(PendingEvent {
+            timestamp: nanos(start + duration, base),
+            phase: 0,
+            depth: -depth,
+            event: TrackEvent {
+                r#type: Some(TYPE_SLICE_END),
+                track_uuid: Some(uuid),
+                ..Default::default()
+            },
+        });
+    };
+
+    for s in capture.slices.iter() {
+        if let Some(uuid) = tracks.threads.get(&s.tid) {
+            slice(
+                &mut events,
+                *uuid,
+                &s.name,
+                s.start,
+                s.duration,
+                i64::from(s.depth),
+            );
+        }
+    }
+    for s in capture.async_slices.iter() {
+        let uuid = tracks.named(s.pid, &format!("{} {}", s.name, s.cookie), false);
+        slice(&mut events, uuid, &s.name, s.start, s.duration, 0);
+    }
+    if !capture.kernel_calls.is_empty() {
+        tracks.process(KERNEL_PID, "kernel");
+    }
+    for call in capture.kernel_calls.iter() {
+        let uuid = tracks.named(KERNEL_PID, &format!("cpu {}", call.cpu), false);
+        slice(
+            &mut events,
+            uuid,
+            &call.name,
+            call.start,
+            call.duration,
+            i64::from(call.depth),
+        );
+    }
+
+    for counter in capture.counters.iter() {
+        let uuid = tracks.named(counter.pid, &counter.name, true);
+        // Integers as such, derived tracks may need the double field.
+        let integer = counter.value.fract() == 0.0 && counter.value.abs() < 9.0e15;
+        events.push(PendingEvent {
+            timestamp: nanos(counter.timestamp, base),
+            phase: 1,
+            depth: 0,
+            event: TrackEvent {
+                r#type: Some(TYPE_COUNTER),
+                track_uuid: Some(uuid),
+                counter_value: Some(counter.value as i64).filter(|_| integer),
+                double_counter_value: Some(counter.value).filter(|_| !integer),
+                ..Default::default()
+            },
+        });
+    }
+    for instant in capture.instants.iter() {
+        if let Some(uuid) = tracks.threads.get(&instant.tid) {
+            events.push(PendingEvent {
+                timestamp: nanos(instant.timestamp, base),
+                phase: 1,
+                depth: 0,
+                event: TrackEvent {
+                    r#type: Some(TYPE_INSTANT),
+                    track_uuid: Some(*uuid),
+                    name: Some(instant.name.clone()),
+                    ..Default::default()
+                },
+            });
+        }
+    }
+
+    if !capture.annotations.is_empty() {
+        let uuid = tracks.descriptor(TrackDescriptor {
+            name: Some("annotations".to_string()),
+            ..Default::default()
+        });
+        for note in capture.annotations.iter() {
+            events.push(PendingEvent {
+                timestamp: nanos(note.timestamp, base),
+                phase: 1,
+                depth: 0,
+                event: TrackEvent {
+                    r#type: Some(TYPE_INSTANT),
+                    track_uuid: Some(uuid),
+                    name: Some(note.name.clone()),
+                    ..Default::default()
+                },
+            });
+        }
+    }
+
+    events.sort_by_key(|event| (event.timestamp, event.phase, event.depth));
+    let mut packets = tracks.packets;
+    packets.extend(events.into_iter().map(|event| TracePacket {
+        timestamp: Some(event.timestamp),
+        trusted_packet_sequence_id: Some(SEQUENCE_ID),
+        track_event: Some(event.event),
+        ..Default::default()
+    }));
+    Trace { packet: packets }
+}
+
+// Write the capture as a Perfetto protobuf trace.
+pub fn write_perfetto(capture: &Capture, out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(&build_trace(capture).encode_to_vec())?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CounterSample;
+
+    fn decode(capture: &Capture) -> Trace {
+        let mut data = Vec::new();
+        write_perfetto(capture, &mut data).unwrap();
+        Trace::decode(data.as_slice()).unwrap()
+    }
+
+    fn capture() -> Capture {
+        let lines = [
+            "  app-100  ( 100) [000] ...1  1.000000: tracing_mark_write: B|100|outer",
+            "  app-100  ( 100) [000] ...1  1.000000: tracing_mark_write: B|100|inner",
+            "  worker-101  ( 100) [001] ...1  1.500000: tracing_mark_write: C|100|queue|7",
+            "  app-100  ( 100) [000] ...1  2.000000: tracing_mark_write: E|100",
+            "  app-100  ( 100) [000] ...1  2.000000: tracing_mark_write: E|100",
+        ];
+        Capture::from_bytes(lines.join("\n").as_bytes())
+    }
+
+    fn descriptors(trace: &Trace) -> Vec<&TrackDescriptor> {
+        trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.track_descriptor.as_ref())
+            .collect()
+    }
+
+    fn events(trace: &Trace) -> Vec<(u64, &TrackEvent)> {
+        trace
+            .packet
+            .iter()
+            .filter_map(|packet| Some((packet.timestamp?, packet.track_event.as_ref()?)))
+            .collect()
+    }
+
+    #[test]
+    fn threads_are_parented_to_their_process() {
+        let trace = decode(&capture());
+        let descriptors = descriptors(&trace);
+        let process = descriptors
+            .iter()
+            .find(|track| track.process.is_some())
+            .unwrap();
+        let info = process.process.as_ref().unwrap();
+        assert_eq!(info.pid, Some(100));
+        assert_eq!(info.process_name.as_deref(), Some("app"));
+        let threads: Vec<&&TrackDescriptor> = descriptors
+            .iter()
+            .filter(|track| track.thread.is_some())
+            .collect();
+        assert_eq!(threads.len(), 2);
+        for thread in threads {
+            assert_eq!(thread.parent_uuid, process.uuid);
+            assert_eq!(thread.thread.as_ref().unwrap().pid, Some(100));
+        }
+        let counter = descriptors
+            .iter()
+            .find(|track| track.counter.is_some())
+            .unwrap();
+        assert_eq!(counter.name.as_deref(), Some("queue"));
+        assert_eq!(counter.parent_uuid, process.uuid);
+    }
+
+    #[test]
+    fn nested_slices_begin_outer_first_and_end_inner_first() {
+        let trace = decode(&capture());
+        let slices: Vec<(u64, i32, Option<&str>)> = events(&trace)
+            .into_iter()
+            .filter(|(_, event)| event.r#type != Some(TYPE_COUNTER))
+            .map(|(ts, event)| (ts, event.r#type.unwrap(), event.name.as_deref()))
+            .collect();
+        assert_eq!(
+            slices,
+            [
+                (1_000_000_000, TYPE_SLICE_BEGIN, Some("outer")),
+                (1_000_000_000, TYPE_SLICE_BEGIN, Some("inner")),
+                (2_000_000_000, TYPE_SLICE_END, None),
+                (2_000_000_000, TYPE_SLICE_END, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn counter_values() {
+        let mut capture = capture();
+        capture.counters.push(CounterSample {
+            pid: 100,
+            tid: 101,
+            name: "queue/s".to_string(),
+            timestamp: 1.75,
+            value: 2.5,
+        });
+        let trace = decode(&capture);
+        let counters: Vec<(u64, &TrackEvent)> = events(&trace)
+            .into_iter()
+            .filter(|(_, event)| event.r#type == Some(TYPE_COUNTER))
+            .collect();
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters[0].0, 1_500_000_000);
+        assert_eq!(counters[0].1.counter_value, Some(7));
+        assert_eq!(counters[0].1.double_counter_value, None);
+        assert_eq!(counters[1].0, 1_750_000_000);
+        assert_eq!(counters[1].1.counter_value, None);
+        assert_eq!(counters[1].1.double_counter_value, Some(2.5));
+        assert_ne!(counters[0].1.track_uuid, counters[1].1.track_uuid);
+    }
+
+    #[test]
+    fn negative_timestamps_are_rebased() {
+        let mut capture = capture();
+        // As after skew correction moved markers before the trace clock 0.
+        crate::skew::apply(&mut capture, 1.25);
+        let trace = decode(&capture);
+        let times: Vec<u64> = events(&trace).into_iter().map(|(ts, _)| ts).collect();
+        assert_eq!(times[0], 0);
+        assert_eq!(times[times.len() - 1], 1_000_000_000);
+        assert!(times.contains(&500_000_000));
+    }
+}
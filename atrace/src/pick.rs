@@ -0,0 +1,591 @@
+// Interactive trace event picker behind `atrace pick`.
+//
+// The available events are shown as a tree of subsystems which can be
+// expanded and toggled. The selection model is kept apart from the drawing:
+// the full-screen picker needs the tui feature and a capable terminal, the
+// numbered menu works on any terminal.
+
+use std::io::{self, BufRead, Write};
+
+pub struct Event {
+    pub name: String,
+    pub selected: bool,
+}
+
+pub struct System {
+    pub name: String,
+    pub events: Vec<Event>,
+    pub expanded: bool,
+}
+
+// One line of the tree as shown.
+pub enum Row {
+    System(usize),
+    Event(usize, usize),
+}
+
+pub struct Picker {
+    pub systems: Vec<System>,
+}
+
+// A rough rate class of events known to be frequent, to warn before
+// enabling them. Unknown events have no estimate.
+pub fn cost(event: &str) -> Option<&'static str> {
+    let system = event.split('/').next().unwrap_or("");
+    match event {
+        "sched/sched_switch" | "sched/sched_wakeup" | "sched/sched_waking" => {
+            Some("high, every context switch")
+        }
+        "power/cpu_idle" | "power/cpu_frequency" => Some("high, every idle or frequency change"),
+        "timer/hrtimer_expire_entry" | "timer/hrtimer_expire_exit" => Some("high, every timer"),
+        _ => match system {
+            "syscalls" | "raw_syscalls" => Some("very high, every system call"),
+            "irq" | "ipi" => Some("high, every interrupt"),
+            "kmem" => Some("very high, every allocation"),
+            "block" | "workqueue" => Some("medium"),
+            _ => None,
+        },
+    }
+}
+
+impl Picker {
+    // Group "system/event" names into collapsed subsystems, selecting the
+    // ones in selected.
+    pub fn new(available: &[String], selected: &[String]) -> Picker {
+        let mut systems: Vec<System> = Vec::new();
+        let mut sorted: Vec<&String> = available.iter().collect();
+        sorted.sort();
+        sorted.dedup();
+        for full in sorted {
+            let mut parts = full.splitn(2, '/');
+            let system = parts.next().unwrap_or("");
+            let name = match parts.next() {
+                Some(name) => name,
+                None => continue,
+            };
+            if systems.last().is_none_or(|last| last.name != system) {
+                systems.push(System {
+                    name: system.to_string(),
+                    events: Vec::new(),
+                    expanded: false,
+                });
+            }
+            systems.last_mut().unwrap().events.push(Event {
+                name: name.to_string(),
+                selected: selected.contains(full),
+            });
+        }
+        Picker { systems }
+    }
+
+    pub fn rows(&self) -> Vec<Row> {
+        let mut rows = Vec::new();
+        for (index, system) in self.systems.iter().enumerate() {
+            rows.push(Row::System(index));
+            if system.expanded {
+                rows.extend((0..system.events.len()).map(|event| Row::Event(index, event)));
+            }
+        }
+        rows
+    }
+
+    fn toggle_system(&mut self, index: usize) {
+        let system = &mut self.systems[index];
+        let all = system.events.iter().all(|event| event.selected);
+        for event in system.events.iter_mut() {
+            event.selected = !all;
+        }
+    }
+
+    // The selected events as "system/event", sorted.
+    pub fn selected(&self) -> Vec<String> {
+        self.systems
+            .iter()
+            .flat_map(|system| {
+                system
+                    .events
+                    .iter()
+                    .filter(|event| event.selected)
+                    .map(move |event| format!("{}/{}", system.name, event.name))
+            })
+            .collect()
+    }
+
+    // The text of a row: checkbox, name, and the selection count of a
+    // subsystem or the cost of an event.
+    pub fn label(&self, row: &Row) -> String {
+        match *row {
+            Row::System(index) => {
+                let system = &self.systems[index];
+                let count = system.events.iter().filter(|event| event.selected).count();
+                let mark = if count == 0 {
+                    ' '
+                } else if count == system.events.len() {
+                    'x'
+                } else {
+                    '-'
+                };
+                format!(
+                    "{} [{}] {} ({}/{})",
+                    if system.expanded { 'v' } else { '>' },
+                    mark,
+                    system.name,
+                    count,
+                    system.events.len()
+                )
+            }
+            Row::Event(system, event) => {
+                let system = &self.systems[system];
+                let event = &system.events[event];
+                let full = format!("{}/{}", system.name, event.name);
+                format!(
+                    "    [{}] {}{}",
+                    if event.selected { 'x' } else { ' ' },
+                    event.name,
+                    cost(&full)
+                        .map(|cost| format!("  rate: {}", cost))
+                        .unwrap_or_default()
+                )
+            }
+        }
+    }
+
+    // Apply a numbered menu answer: subsystem numbers, or "N.M" for event M
+    // of subsystem N, and ranges of either like 2-4 or 3.1-3.5. Subsystem
+    // numbers toggle the whole subsystem.
+    pub fn apply_menu(&mut self, answer: &str) -> Result<(), String> {
+        for token in answer.split(|c: char| c == ',' || c.is_whitespace()) {
+            if token.is_empty() {
+                continue;
+            }
+            let mut bounds = token.splitn(2, '-');
+            let first = self.menu_item(bounds.next().unwrap_or(""))?;
+            let last = match bounds.next() {
+                Some(last) => self.menu_item(last)?,
+                None => first,
+            };
+            match (first, last) {
+                ((system, None), (last_system, None)) if system <= last_system => {
+                    for index in system..=last_system {
+                        self.toggle_system(index);
+                    }
+                }
+                ((system, Some(event)), (last_system, Some(last_event)))
+                    if system == last_system && event <= last_event =>
+                {
+                    for event in self.systems[system].events[event..=last_event].iter_mut() {
+                        event.selected = !event.selected;
+                    }
+                }
+                _ => return Err(format!("{:?} is not a valid range", token)),
+            }
+        }
+        Ok(())
+    }
+
+    // A 1-based menu number as a subsystem and event index.
+    fn menu_item(&self, item: &str) -> Result<(usize, Option<usize>), String> {
+        let invalid = || format!("{:?} is not a listed number", item);
+        let mut parts = item.splitn(2, '.');
+        let system = parts
+            .next()
+            .and_then(|system| system.parse::<usize>().ok())
+            .filter(|system| *system >= 1 && *system <= self.systems.len())
+            .ok_or_else(invalid)?
+            - 1;
+        let event = match parts.next() {
+            Some(event) => Some(
+                event
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|event| *event >= 1 && *event <= self.systems[system].events.len())
+                    .ok_or_else(invalid)?
+                    - 1,
+            ),
+            None => None,
+        };
+        Ok((system, event))
+    }
+}
+
+// Row operations of the full-screen picker.
+#[cfg(feature = "tui")]
+impl Picker {
+    // Toggle a row. A subsystem is selected whole unless it already is, then
+    // cleared.
+    pub fn toggle(&mut self, row: usize) {
+        match self.rows().get(row) {
+            Some(Row::System(index)) => self.toggle_system(*index),
+            Some(Row::Event(system, event)) => {
+                let event = &mut self.systems[*system].events[*event];
+                event.selected = !event.selected;
+            }
+            None => (),
+        }
+    }
+
+    // Expand or collapse the subsystem of a row, giving the row of the
+    // subsystem afterwards, where a cursor on a collapsed event goes.
+    pub fn expand(&mut self, row: usize, expanded: bool) -> usize {
+        let system = match self.rows().get(row) {
+            Some(Row::System(index)) => *index,
+            Some(Row::Event(index, _)) if !expanded => *index,
+            _ => return row,
+        };
+        self.systems[system].expanded = expanded;
+        self.rows()
+            .iter()
+            .position(|row| matches!(row, Row::System(index) if *index == system))
+            .unwrap_or(row)
+    }
+}
+
+// The numbered menu for dumb terminals: list the expanded subsystems and
+// read answers until an empty line confirms or "q" aborts.
+pub fn run_menu(
+    picker: &mut Picker,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> io::Result<bool> {
+    loop {
+        for row in picker.rows() {
+            let number = match row {
+                Row::System(system) => format!("{}", system + 1),
+                Row::Event(system, event) => format!("{}.{}", system + 1, event + 1),
+            };
+            writeln!(out, "{:>7}  {}", number, picker.label(&row))?;
+        }
+        write!(
+            out,
+            "toggle numbers like 2 or 3.1-3.4, +N to list the events of N, empty to confirm, q to abort: "
+        )?;
+        out.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            return Ok(false);
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Ok(true);
+        }
+        if answer == "q" {
+            return Ok(false);
+        }
+        let result = match answer.strip_prefix('+') {
+            Some(number) => picker.menu_item(number).and_then(|item| match item {
+                (system, None) => {
+                    picker.systems[system].expanded = !picker.systems[system].expanded;
+                    Ok(())
+                }
+                _ => Err(format!("{:?} is not a subsystem number", number)),
+            }),
+            None => picker.apply_menu(answer),
+        };
+        if let Err(e) = result {
+            writeln!(out, "{}", e)?;
+        }
+    }
+}
+
+// Whether the full-screen picker can be drawn on this terminal.
+#[cfg(feature = "tui")]
+fn capable_terminal() -> bool {
+    let dumb = std::env::var("TERM").map_or(true, |term| term.is_empty() || term == "dumb");
+    !dumb
+        && unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1
+        }
+}
+
+// Run the full-screen picker if possible, else the numbered menu on stderr
+// so stdout stays free for the profile. True when confirmed.
+pub fn run(picker: &mut Picker) -> io::Result<bool> {
+    #[cfg(feature = "tui")]
+    {
+        if capable_terminal() {
+            return run_tui(picker);
+        }
+    }
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    run_menu(picker, &mut input, &mut io::stderr())
+}
+
+// The full-screen picker: arrows or j/k move, space toggles, right/left or
+// enter expand and collapse, c confirms, q or escape aborts.
+#[cfg(feature = "tui")]
+fn run_tui(picker: &mut Picker) -> io::Result<bool> {
+    use crossterm::{cursor, execute, terminal};
+
+    terminal::enable_raw_mode()?;
+    let mut out = io::stdout();
+    let result = execute!(out, terminal::EnterAlternateScreen, cursor::Hide)
+        .and_then(|_| tui_loop(picker, &mut out));
+    let _ = execute!(out, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    result
+}
+
+#[cfg(feature = "tui")]
+fn tui_loop(picker: &mut Picker, out: &mut io::Stdout) -> io::Result<bool> {
+    use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind};
+    use crossterm::style::Stylize;
+    use crossterm::{cursor, queue, style, terminal};
+
+    // The highlighted row and the first row shown.
+    let mut current = 0;
+    let mut top = 0;
+    loop {
+        let (width, height) = terminal::size()?;
+        // The last line holds the key help.
+        let lines = (height as usize).saturating_sub(1).max(1);
+        if current < top {
+            top = current;
+        } else if current >= top + lines {
+            top = current + 1 - lines;
+        }
+        queue!(out, terminal::Clear(terminal::ClearType::All))?;
+        let rows = picker.rows();
+        for (line, row) in rows.iter().enumerate().skip(top).take(lines) {
+            let mut text: String = picker.label(row).chars().take(width as usize).collect();
+            if line == current {
+                text = format!("{}", text.reverse());
+            }
+            queue!(
+                out,
+                cursor::MoveTo(0, (line - top) as u16),
+                style::Print(text)
+            )?;
+        }
+        let help = format!(
+            "{} selected  space toggle  enter expand  c confirm  q abort",
+            picker.selected().len()
+        );
+        queue!(
+            out,
+            cursor::MoveTo(0, lines as u16),
+            style::Print(help.chars().take(width as usize).collect::<String>())
+        )?;
+        out.flush()?;
+        let key = match event::read()? {
+            TermEvent::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        let last = rows.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => current = current.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => current = (current + 1).min(last),
+            KeyCode::PageUp => current = current.saturating_sub(lines),
+            KeyCode::PageDown => current = (current + lines).min(last),
+            KeyCode::Char(' ') => picker.toggle(current),
+            KeyCode::Right | KeyCode::Char('l') => current = picker.expand(current, true),
+            KeyCode::Left | KeyCode::Char('h') => current = picker.expand(current, false),
+            KeyCode::Enter => {
+                let expanded = match rows.get(current) {
+                    Some(Row::System(index)) => !picker.systems[*index].expanded,
+                    _ => false,
+                };
+                current = picker.expand(current, expanded);
+            }
+            KeyCode::Char('c') => return Ok(true),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn picker(selected: &[&str]) -> Picker {
+        Picker::new(
+            &strings(&[
+                "sched/sched_wakeup",
+                "irq/irq_handler_exit",
+                "sched/sched_switch",
+                "irq/irq_handler_entry",
+                "sched/sched_switch",
+                "no_slash",
+                "block/block_rq_issue",
+            ]),
+            &strings(selected),
+        )
+    }
+
+    fn labels(picker: &Picker) -> Vec<String> {
+        picker.rows().iter().map(|row| picker.label(row)).collect()
+    }
+
+    // Run the menu over scripted answers, giving the result and the output.
+    fn menu(picker: &mut Picker, answers: &str) -> (bool, String) {
+        let mut out = Vec::new();
+        let confirmed = run_menu(picker, &mut answers.as_bytes(), &mut out).unwrap();
+        (confirmed, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn new_groups_sorted_events_into_collapsed_systems() {
+        let picker = picker(&["sched/sched_switch", "power/cpu_idle"]);
+        let names: Vec<(&str, Vec<&str>)> = picker
+            .systems
+            .iter()
+            .map(|system| {
+                assert!(!system.expanded);
+                (
+                    system.name.as_str(),
+                    system
+                        .events
+                        .iter()
+                        .map(|event| event.name.as_str())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("block", vec!["block_rq_issue"]),
+                ("irq", vec!["irq_handler_entry", "irq_handler_exit"]),
+                ("sched", vec!["sched_switch", "sched_wakeup"]),
+            ]
+        );
+        // Selected events this kernel lacks are dropped.
+        assert_eq!(picker.selected(), strings(&["sched/sched_switch"]));
+    }
+
+    #[test]
+    fn rows_and_labels_follow_expansion() {
+        let mut picker = picker(&["sched/sched_switch", "irq/irq_handler_entry"]);
+        assert_eq!(
+            labels(&picker),
+            strings(&["> [ ] block (0/1)", "> [-] irq (1/2)", "> [-] sched (1/2)",])
+        );
+        picker.systems[0].expanded = true;
+        picker.systems[2].expanded = true;
+        assert_eq!(
+            labels(&picker),
+            strings(&[
+                "v [ ] block (0/1)",
+                "    [ ] block_rq_issue  rate: medium",
+                "> [-] irq (1/2)",
+                "v [-] sched (1/2)",
+                "    [x] sched_switch  rate: high, every context switch",
+                "    [ ] sched_wakeup  rate: high, every context switch",
+            ])
+        );
+    }
+
+    #[test]
+    fn menu_numbers_toggle_systems_and_events() {
+        let mut picker = picker(&["sched/sched_switch"]);
+        // A partly selected subsystem is selected whole, then cleared.
+        picker.apply_menu("3").unwrap();
+        assert_eq!(
+            picker.selected(),
+            strings(&["sched/sched_switch", "sched/sched_wakeup"])
+        );
+        picker.apply_menu("3").unwrap();
+        assert!(picker.selected().is_empty());
+
+        picker.apply_menu("1-2, 3.2").unwrap();
+        assert_eq!(
+            picker.selected(),
+            strings(&[
+                "block/block_rq_issue",
+                "irq/irq_handler_entry",
+                "irq/irq_handler_exit",
+                "sched/sched_wakeup",
+            ])
+        );
+        picker.apply_menu("2.1-2.2 3.1-3.2").unwrap();
+        assert_eq!(
+            picker.selected(),
+            strings(&["block/block_rq_issue", "sched/sched_switch"])
+        );
+    }
+
+    #[test]
+    fn menu_errors_leave_earlier_tokens_applied() {
+        let mut picker = picker(&[]);
+        let cases = [
+            ("0", "\"0\" is not a listed number"),
+            ("4", "\"4\" is not a listed number"),
+            ("1.2", "\"1.2\" is not a listed number"),
+            ("x", "\"x\" is not a listed number"),
+            ("3-1", "\"3-1\" is not a valid range"),
+            ("2.1-3.1", "\"2.1-3.1\" is not a valid range"),
+            ("1-2.1", "\"1-2.1\" is not a valid range"),
+        ];
+        for (answer, error) in cases.iter() {
+            assert_eq!(picker.apply_menu(answer), Err(error.to_string()));
+        }
+        assert!(picker.selected().is_empty());
+        assert!(picker.apply_menu("1 5").is_err());
+        assert_eq!(picker.selected(), strings(&["block/block_rq_issue"]));
+    }
+
+    #[test]
+    fn run_menu_confirms_aborts_and_lists_events() {
+        let mut picker = picker(&[]);
+        let (confirmed, out) = menu(&mut picker, "+3\n3.2\n9\n\n");
+        assert!(confirmed);
+        assert_eq!(picker.selected(), strings(&["sched/sched_wakeup"]));
+        assert!(picker.systems[2].expanded);
+        assert!(out.contains("      3  > [ ] sched (0/2)\n"));
+        assert!(out.contains("    3.2      [x] sched_wakeup  rate: high"));
+        assert!(out.contains("\"9\" is not a listed number\n"));
+
+        let (confirmed, out) = menu(&mut picker, "+3.1\nq\n");
+        assert!(!confirmed);
+        assert!(out.contains("\"3.1\" is not a subsystem number\n"));
+        // The end of input aborts too.
+        assert!(!menu(&mut picker, "1\n").0);
+    }
+
+    #[test]
+    fn cost_of_known_events() {
+        assert_eq!(
+            cost("sched/sched_switch"),
+            Some("high, every context switch")
+        );
+        assert_eq!(
+            cost("syscalls/sys_enter_read"),
+            Some("very high, every system call")
+        );
+        assert_eq!(cost("block/block_rq_issue"), Some("medium"));
+        assert_eq!(cost("sched/sched_process_exit"), None);
+        assert_eq!(cost("ext4/ext4_sync_file_enter"), None);
+    }
+
+    #[cfg(feature = "tui")]
+    #[test]
+    fn rows_toggle_and_expand() {
+        let mut picker = picker(&[]);
+        // Expanding sched keeps the cursor on its row.
+        assert_eq!(picker.expand(2, true), 2);
+        picker.toggle(4);
+        assert_eq!(picker.selected(), strings(&["sched/sched_wakeup"]));
+        picker.toggle(1);
+        assert_eq!(
+            picker.selected(),
+            strings(&[
+                "irq/irq_handler_entry",
+                "irq/irq_handler_exit",
+                "sched/sched_wakeup",
+            ])
+        );
+        // Collapsing from an event moves the cursor to its subsystem, an
+        // event can't be expanded and rows past the end do nothing.
+        assert_eq!(picker.expand(3, true), 3);
+        assert_eq!(picker.expand(3, false), 2);
+        assert!(!picker.systems[2].expanded);
+        picker.toggle(9);
+        assert_eq!(picker.expand(9, true), 9);
+        assert_eq!(picker.selected().len(), 3);
+    }
+}
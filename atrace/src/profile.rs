@@ -0,0 +1,357 @@
+// Event profiles: the trace events enabled on one kernel, saved by
+// --export-effective-config and replayed by --import-config on later runs.
+//
+// Event availability differs between kernel versions, so a profile records
+// the kernel it was written on and importing it reports which of its events
+// this kernel lacks. The file is a small TOML subset:
+//
+//   kernel = "6.6.8"
+//   events = [
+//       "sched/sched_switch",
+//       "sched/sched_wakeup",
+//   ]
+
+use std::fs;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct Profile {
+    // The kernel release the profile was written on, informational.
+    pub kernel: String,
+    // Events as "system/event", the directory under events/.
+    pub events: Vec<String>,
+}
+
+// A name is used as a path under events/, so only plain components pass.
+fn valid_event(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('/').collect();
+    parts.len() == 2
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+fn parse_string(value: &str, line: usize) -> Result<String, String> {
+    let value = value.trim();
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return Err(format!("line {}: expected a quoted string", line));
+    }
+    let inner = &value[1..value.len() - 1];
+    if inner.contains('"') || inner.contains('\\') {
+        return Err(format!("line {}: escapes are not supported", line));
+    }
+    Ok(inner.to_string())
+}
+
+impl Profile {
+    pub fn parse(text: &str) -> Result<Profile, String> {
+        let mut profile = Profile::default();
+        // The line of the array being read, if inside one.
+        let mut array: Option<usize> = None;
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let content = raw.split('#').next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+            let items = if array.is_some() {
+                content
+            } else {
+                let mut pair = content.splitn(2, '=');
+                let key = pair.next().unwrap_or("").trim();
+                let value = pair
+                    .next()
+                    .ok_or_else(|| format!("line {}: expected key = value", line))?
+                    .trim();
+                match key {
+                    "kernel" => {
+                        profile.kernel = parse_string(value, line)?;
+                        continue;
+                    }
+                    "events" if value.starts_with('[') => {
+                        array = Some(line);
+                        &value[1..]
+                    }
+                    "events" => return Err(format!("line {}: events must be an array", line)),
+                    // Keys of newer versions are skipped.
+                    _ => continue,
+                }
+            };
+            let (items, closed) = match items.find(']') {
+                Some(end) if items[end + 1..].trim().is_empty() => (&items[..end], true),
+                Some(_) => return Err(format!("line {}: text after the events array", line)),
+                None => (items, false),
+            };
+            for item in items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+            {
+                let event = parse_string(item, line)?;
+                if !valid_event(&event) {
+                    return Err(format!(
+                        "line {}: {:?} is not a system/event name",
+                        line, event
+                    ));
+                }
+                if !profile.events.contains(&event) {
+                    profile.events.push(event);
+                }
+            }
+            if closed {
+                array = None;
+            }
+        }
+        if let Some(line) = array {
+            return Err(format!("line {}: events array is not closed", line));
+        }
+        Ok(profile)
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut text = String::from("# Trace events enabled by atrace.\n");
+        text.push_str(&format!("kernel = \"{}\"\n", self.kernel));
+        text.push_str("events = [\n");
+        for event in self.events.iter() {
+            text.push_str(&format!("    \"{}\",\n", event));
+        }
+        text.push_str("]\n");
+        text
+    }
+}
+
+// Events of a profile split by whether this kernel has them.
+pub struct Delta {
+    pub achievable: Vec<String>,
+    pub missing: Vec<String>,
+}
+
+pub fn delta(requested: &[String], available: &[String]) -> Delta {
+    let (achievable, missing) = requested
+        .iter()
+        .cloned()
+        .partition(|event| available.contains(event));
+    Delta {
+        achievable,
+        missing,
+    }
+}
+
+// The events this kernel offers, from tracefs available_events, which lists
+// them as "system:event".
+pub fn available_events(tracing: &Path) -> Vec<String> {
+    fs::read_to_string(tracing.join("available_events"))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().replacen(':', "/", 1))
+        .filter(|event| valid_event(event))
+        .collect()
+}
+
+// The events whose enable file reads 1, sorted.
+pub fn enabled_events(tracing: &Path) -> Vec<String> {
+    let mut events = Vec::new();
+    let systems = match fs::read_dir(tracing.join("events")) {
+        Ok(systems) => systems,
+        Err(_) => return events,
+    };
+    for system in systems.filter_map(Result::ok) {
+        let entries = match fs::read_dir(system.path()) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for event in entries.filter_map(Result::ok) {
+            let enabled = fs::read_to_string(event.path().join("enable"))
+                .map(|value| value.trim() == "1")
+                .unwrap_or(false);
+            if enabled {
+                events.push(format!(
+                    "{}/{}",
+                    system.file_name().to_string_lossy(),
+                    event.file_name().to_string_lossy()
+                ));
+            }
+        }
+    }
+    events.sort();
+    events
+}
+
+pub fn kernel_release() -> String {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|release| release.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // The events of two kernels, the newer one has some the older lacks.
+    const KERNEL_5_4: &[&str] = &[
+        "sched/sched_switch",
+        "sched/sched_wakeup",
+        "irq/irq_handler_entry",
+        "power/cpu_frequency",
+    ];
+    const KERNEL_6_6: &[&str] = &[
+        "sched/sched_switch",
+        "sched/sched_wakeup",
+        "sched/sched_ext_dump",
+        "irq/irq_handler_entry",
+        "irq/softirq_entry",
+        "power/cpu_frequency",
+    ];
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(name: &str, files: &[(&str, &str)]) -> Root {
+            let path = std::env::temp_dir().join(format!(
+                "atrace-profile-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            for (file, contents) in files {
+                let file = path.join(file);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, contents).unwrap();
+            }
+            fs::create_dir_all(&path).unwrap();
+            Root(path)
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parse_reads_kernel_and_events() {
+        let text = "# written on the 6.6 fleet\n\
+                    kernel = \"6.6.8\"\n\
+                    future = 3\n\
+                    events = [\n\
+                    \x20   \"sched/sched_switch\", # the scheduler\n\
+                    \x20   \"sched/sched_wakeup\", \"sched/sched_switch\",\n\
+                    \n\
+                    ]\n";
+        let profile = Profile::parse(text).unwrap();
+        assert_eq!(profile.kernel, "6.6.8");
+        assert_eq!(
+            profile.events,
+            strings(&["sched/sched_switch", "sched/sched_wakeup"])
+        );
+
+        let inline = Profile::parse("events = [\"irq/softirq_entry\"]").unwrap();
+        assert_eq!(inline.kernel, "");
+        assert_eq!(inline.events, strings(&["irq/softirq_entry"]));
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let cases = [
+            ("kernel = 6.6", "line 1: expected a quoted string"),
+            ("kernel = \"6.\\6\"", "line 1: escapes are not supported"),
+            ("\nkernel", "line 2: expected key = value"),
+            (
+                "events = \"sched/sched_switch\"",
+                "line 1: events must be an array",
+            ),
+            (
+                "events = [\n\"a/b\"] x",
+                "line 2: text after the events array",
+            ),
+            (
+                "events = [\"../sched\"]",
+                "line 1: \"../sched\" is not a system/event name",
+            ),
+            (
+                "events = [\"sched/sched_switch\"",
+                "line 1: events array is not closed",
+            ),
+        ];
+        for (text, error) in cases.iter() {
+            assert_eq!(
+                Profile::parse(text).err().as_deref(),
+                Some(*error),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn to_toml_round_trips() {
+        let profile = Profile {
+            kernel: "5.4.210".to_string(),
+            events: strings(KERNEL_5_4),
+        };
+        let parsed = Profile::parse(&profile.to_toml()).unwrap();
+        assert_eq!(parsed.kernel, profile.kernel);
+        assert_eq!(parsed.events, profile.events);
+    }
+
+    #[test]
+    fn delta_between_kernels() {
+        // A 6.6 profile imported on 5.4 loses the events 5.4 lacks, in the
+        // profile's order.
+        let on_5_4 = delta(&strings(KERNEL_6_6), &strings(KERNEL_5_4));
+        assert_eq!(on_5_4.achievable, strings(KERNEL_5_4));
+        assert_eq!(
+            on_5_4.missing,
+            strings(&["sched/sched_ext_dump", "irq/softirq_entry"])
+        );
+
+        // The other way round everything is achievable.
+        let on_6_6 = delta(&strings(KERNEL_5_4), &strings(KERNEL_6_6));
+        assert_eq!(on_6_6.achievable, strings(KERNEL_5_4));
+        assert!(on_6_6.missing.is_empty());
+    }
+
+    #[test]
+    fn available_events_from_tracefs() {
+        let root = Root::new(
+            "available",
+            &[(
+                "available_events",
+                "sched:sched_switch\nirq:softirq_entry\nbad line\n\nftrace:print\n",
+            )],
+        );
+        assert_eq!(
+            available_events(&root.0),
+            strings(&["sched/sched_switch", "irq/softirq_entry", "ftrace/print"])
+        );
+        assert!(available_events(&root.0.join("missing")).is_empty());
+    }
+
+    #[test]
+    fn enabled_events_from_tracefs() {
+        let root = Root::new(
+            "enabled",
+            &[
+                ("events/sched/sched_switch/enable", "1\n"),
+                ("events/sched/sched_wakeup/enable", "0\n"),
+                ("events/irq/irq_handler_entry/enable", "1\n"),
+                ("events/irq/enable", "X\n"),
+                ("events/power/cpu_frequency/format", ""),
+            ],
+        );
+        assert_eq!(
+            enabled_events(&root.0),
+            strings(&["irq/irq_handler_entry", "sched/sched_switch"])
+        );
+        assert!(enabled_events(&root.0.join("missing")).is_empty());
+    }
+}
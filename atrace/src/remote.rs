@@ -0,0 +1,554 @@
+// Captures on a remote target reached over ssh or adb. The tracefs writes
+// run as shell commands on the target and the dump streams back over the
+// same connection, so the target only needs a shell, not atrace itself.
+
+use std::fmt;
+use std::process::{Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::parser;
+
+// Where tracefs may be mounted on the target, the kernel's own mount point
+// first, then the debugfs one older systems only have.
+const REMOTE_TRACE_ROOTS: &[&str] = &["/sys/kernel/tracing/", "/sys/kernel/debug/tracing/"];
+// How often the capture wait checks for an abort.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// Appended to adb commands, whose exit code adb doesn't always pass on.
+#[cfg(feature = "adb")]
+const ADB_STATUS_TAG: &str = "atrace-remote-status=";
+
+// Transport failures are kept apart from tracing failures on the target,
+// they need different fixes.
+pub enum RemoteError {
+    Transport(String),
+    Tracing(String),
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteError::Transport(e) => write!(f, "transport failure: {}", e),
+            RemoteError::Tracing(e) => write!(f, "tracing failure on the target: {}", e),
+        }
+    }
+}
+
+pub trait Transport {
+    // Run a shell command on the target, returning its stdout.
+    fn shell(&self, command: &str) -> Result<Vec<u8>, RemoteError>;
+    fn name(&self) -> String;
+}
+
+// The tracefs operations of a capture, done on the target.
+pub trait RemoteFs {
+    fn write_file(&self, path: &str, value: &str) -> Result<(), RemoteError>;
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, RemoteError>;
+    fn exists(&self, path: &str) -> Result<bool, RemoteError>;
+}
+
+// Quote a value for the remote shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+impl<T: Transport + ?Sized> RemoteFs for T {
+    fn write_file(&self, path: &str, value: &str) -> Result<(), RemoteError> {
+        let command = if value.is_empty() {
+            format!(": > {}", shell_quote(path))
+        } else {
+            format!("echo {} > {}", shell_quote(value), shell_quote(path))
+        };
+        self.shell(&command).map(|_| ())
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, RemoteError> {
+        self.shell(&format!("cat {}", shell_quote(path)))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, RemoteError> {
+        let quoted = shell_quote(path);
+        self.shell(&format!("if [ -e {} ]; then echo yes; fi", quoted))
+            .map(|stdout| stdout.starts_with(b"yes"))
+    }
+}
+
+fn stderr_text(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).trim().to_string()
+}
+
+#[cfg(feature = "ssh")]
+pub struct Ssh {
+    destination: String,
+    port: Option<String>,
+}
+
+#[cfg(feature = "ssh")]
+impl Transport for Ssh {
+    fn shell(&self, command: &str) -> Result<Vec<u8>, RemoteError> {
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-o", "BatchMode=yes"]);
+        if let Some(port) = self.port.as_ref() {
+            ssh.args(["-p", port]);
+        }
+        let output = ssh
+            .arg(&self.destination)
+            .arg(command)
+            .output()
+            .map_err(|e| RemoteError::Transport(format!("run ssh fail: {}", e)))?;
+        match output.status.code() {
+            Some(0) => Ok(output.stdout),
+            // ssh exits with 255 for its own errors.
+            Some(255) | None => Err(RemoteError::Transport(stderr_text(&output))),
+            Some(_) => Err(RemoteError::Tracing(format!(
+                "{}: {}",
+                command,
+                stderr_text(&output)
+            ))),
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("ssh://{}", self.destination)
+    }
+}
+
+#[cfg(feature = "adb")]
+pub struct Adb {
+    serial: String,
+}
+
+#[cfg(feature = "adb")]
+impl Transport for Adb {
+    fn shell(&self, command: &str) -> Result<Vec<u8>, RemoteError> {
+        let mut adb = Command::new("adb");
+        if !self.serial.is_empty() {
+            adb.args(["-s", &self.serial]);
+        }
+        // exec-out keeps the dump bytes intact, unlike a shell pty.
+        let output = adb
+            .arg("exec-out")
+            .arg(format!("{}; echo {}$?", command, ADB_STATUS_TAG))
+            .output()
+            .map_err(|e| RemoteError::Transport(format!("run adb fail: {}", e)))?;
+        let mut stdout = output.stdout.clone();
+        let tag = ADB_STATUS_TAG.as_bytes();
+        let status = match stdout.windows(tag.len()).rposition(|window| window == tag) {
+            Some(at) => {
+                let status = String::from_utf8_lossy(&stdout[at + tag.len()..])
+                    .trim()
+                    .to_string();
+                stdout.truncate(at);
+                status
+            }
+            // The command never ran: no device, or the connection dropped.
+            None => return Err(RemoteError::Transport(stderr_text(&output))),
+        };
+        if status == "0" {
+            Ok(stdout)
+        } else {
+            Err(RemoteError::Tracing(format!(
+                "{}: exit {} {}",
+                command,
+                status,
+                stderr_text(&output)
+            )))
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("adb://{}", self.serial)
+    }
+}
+
+// Open the transport for a ssh://[user@]host[:port] or adb://[serial] url.
+pub fn connect(url: &str) -> Result<Box<dyn Transport>, String> {
+    if let Some(target) = url.strip_prefix("ssh://") {
+        #[cfg(feature = "ssh")]
+        {
+            let mut parts = target.rsplitn(2, ':');
+            let (port, destination) = match (parts.next(), parts.next()) {
+                (Some(port), Some(destination)) if port.parse::<u16>().is_ok() => {
+                    (Some(port.to_string()), destination.to_string())
+                }
+                _ => (None, target.to_string()),
+            };
+            return Ok(Box::new(Ssh { destination, port }));
+        }
+        #[cfg(not(feature = "ssh"))]
+        {
+            let _ = target;
+            return Err("atrace was built without the ssh feature".to_string());
+        }
+    }
+    if let Some(serial) = url.strip_prefix("adb://") {
+        #[cfg(feature = "adb")]
+        {
+            return Ok(Box::new(Adb {
+                serial: serial.to_string(),
+            }));
+        }
+        #[cfg(not(feature = "adb"))]
+        {
+            let _ = serial;
+            return Err("atrace was built without the adb feature".to_string());
+        }
+    }
+    Err(format!(
+        "unsupported remote {:?}, expected ssh://host or adb://serial",
+        url
+    ))
+}
+
+pub struct RemoteCapture {
+    pub capture_id: String,
+    pub buffer_kb: u32,
+    pub duration: u32,
+    pub overwrite: bool,
+    pub cpu_sched: bool,
+}
+
+fn enable(value: bool) -> &'static str {
+    if value {
+        "1"
+    } else {
+        "0"
+    }
+}
+
+fn trace_path(root: &str, name: &str) -> String {
+    format!("{}{}", root, name)
+}
+
+// The tracefs directory of the target, the first one holding a
+// trace_marker.
+fn find_root<F: RemoteFs + ?Sized>(fs: &F) -> Result<&'static str, RemoteError> {
+    for root in REMOTE_TRACE_ROOTS {
+        if fs.exists(&trace_path(root, "trace_marker"))? {
+            return Ok(root);
+        }
+    }
+    Err(RemoteError::Tracing(format!(
+        "no tracefs found at {}",
+        REMOTE_TRACE_ROOTS.join(" or ")
+    )))
+}
+
+// The settings a capture changes, put back as they were found after it.
+fn changed_settings(options: &RemoteCapture) -> Vec<&'static str> {
+    let mut names = vec![
+        "options/overwrite",
+        "buffer_size_kb",
+        "trace_clock",
+        "options/record-cmd",
+    ];
+    if options.cpu_sched {
+        names.push("events/sched/sched_switch/enable");
+    }
+    names
+}
+
+// The value to write back for the content a setting file reads as. The
+// clock in use is the bracketed one, a buffer size may read like
+// "7 (expanded: 1408)".
+fn setting_value(name: &str, content: &[u8]) -> String {
+    let content = String::from_utf8_lossy(content);
+    let mut words = content.split_whitespace();
+    let value = if name == "trace_clock" {
+        words.find_map(|clock| clock.strip_prefix('[')?.strip_suffix(']'))
+    } else {
+        words.next()
+    };
+    value.unwrap_or("").to_string()
+}
+
+fn read_settings<F: RemoteFs + ?Sized>(
+    fs: &F,
+    root: &str,
+    options: &RemoteCapture,
+) -> Result<Vec<(&'static str, String)>, RemoteError> {
+    changed_settings(options)
+        .into_iter()
+        .map(|name| {
+            let content = fs.read_file(&trace_path(root, name))?;
+            Ok((name, setting_value(name, &content)))
+        })
+        .collect()
+}
+
+// Run a capture on the target and return the dumped trace. The wait ends
+// early once aborted returns true.
+pub fn capture<F: RemoteFs + ?Sized>(
+    fs: &F,
+    options: &RemoteCapture,
+    aborted: &dyn Fn() -> bool,
+) -> Result<Vec<u8>, RemoteError> {
+    let root = find_root(fs)?;
+    let saved = read_settings(fs, root, options)?;
+    let dump = setup(fs, root, options).and_then(|_| {
+        let end = Instant::now() + Duration::from_secs(options.duration.into());
+        while !aborted() && Instant::now() < end {
+            thread::sleep(POLL_INTERVAL.min(end.saturating_duration_since(Instant::now())));
+        }
+        fs.write_file(&trace_path(root, "tracing_on"), "0")?;
+        fs.read_file(&trace_path(root, "trace"))
+    });
+    // Restore the target even when the capture failed.
+    let cleanup = cleanup(fs, root, &saved);
+    let dump = dump?;
+    cleanup?;
+    Ok(dump)
+}
+
+fn setup<F: RemoteFs + ?Sized>(
+    fs: &F,
+    root: &str,
+    options: &RemoteCapture,
+) -> Result<(), RemoteError> {
+    let path = |name| trace_path(root, name);
+    fs.write_file(&path("tracing_on"), "0")?;
+    fs.write_file(&path("options/overwrite"), enable(options.overwrite))?;
+    fs.write_file(&path("buffer_size_kb"), &options.buffer_kb.to_string())?;
+    fs.write_file(&path("trace_clock"), "global")?;
+    fs.write_file(&path("options/record-cmd"), "1")?;
+    if options.cpu_sched {
+        fs.write_file(&path("events/sched/sched_switch/enable"), "1")?;
+    }
+    fs.write_file(&path("trace"), "")?;
+    fs.write_file(&path("tracing_on"), "1")?;
+    fs.write_file(
+        &path("trace_marker"),
+        "trace_event_clock_sync: parent_ts=9000000",
+    )?;
+    fs.write_file(
+        &path("trace_marker"),
+        &format!(
+            "{} {}={}",
+            parser::METADATA_PREFIX,
+            parser::CAPTURE_ID_KEY,
+            options.capture_id
+        ),
+    )
+}
+
+// Every setting is restored even when one fails, the first error is kept.
+fn cleanup<F: RemoteFs + ?Sized>(
+    fs: &F,
+    root: &str,
+    saved: &[(&'static str, String)],
+) -> Result<(), RemoteError> {
+    let mut settings = vec![("tracing_on", "0"), ("trace", "")];
+    // A value which couldn't be read is left as the capture set it.
+    settings.extend(
+        saved
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| (*name, value.as_str())),
+    );
+    let mut result = Ok(());
+    for (name, value) in settings {
+        let written = fs.write_file(&trace_path(root, name), value);
+        if result.is_ok() {
+            result = written;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A target whose shell is the local one, with the tracefs paths moved
+    // under a temporary directory.
+    struct Local {
+        dir: PathBuf,
+        commands: RefCell<Vec<String>>,
+    }
+
+    impl Local {
+        fn new(name: &str) -> Local {
+            let dir =
+                std::env::temp_dir().join(format!("atrace-remote-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Local {
+                dir,
+                commands: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.dir.join(path.trim_start_matches('/'))
+        }
+
+        fn write(&self, path: &str, content: &str) {
+            let path = self.path(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        fn read(&self, path: &str) -> String {
+            fs::read_to_string(self.path(path)).unwrap()
+        }
+
+        // A tracefs root holding what a capture reads and writes.
+        fn tracefs(&self, root: &str) {
+            for (name, content) in &[
+                ("trace", "# tracer: nop\n"),
+                ("trace_marker", ""),
+                ("tracing_on", "1\n"),
+                ("buffer_size_kb", "7 (expanded: 1408)\n"),
+                ("trace_clock", "local global counter [mono] boot\n"),
+                ("options/overwrite", "0\n"),
+                ("options/record-cmd", "1\n"),
+                ("events/sched/sched_switch/enable", "0\n"),
+            ] {
+                self.write(&format!("{}{}", root, name), content);
+            }
+        }
+    }
+
+    impl Drop for Local {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    impl Transport for Local {
+        fn shell(&self, command: &str) -> Result<Vec<u8>, RemoteError> {
+            self.commands.borrow_mut().push(command.to_string());
+            let moved = format!("{}/sys/", self.dir.display());
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command.replace("/sys/", &moved))
+                .output()
+                .map_err(|e| RemoteError::Transport(e.to_string()))?;
+            if output.status.success() {
+                Ok(output.stdout)
+            } else {
+                Err(RemoteError::Tracing(stderr_text(&output)))
+            }
+        }
+
+        fn name(&self) -> String {
+            "local".to_string()
+        }
+    }
+
+    fn options() -> RemoteCapture {
+        RemoteCapture {
+            capture_id: "remote-test".to_string(),
+            buffer_kb: 4096,
+            duration: 0,
+            overwrite: true,
+            cpu_sched: true,
+        }
+    }
+
+    #[test]
+    fn kernel_mount_point_is_probed_first() {
+        let target = Local::new("probe-both");
+        target.tracefs("/sys/kernel/tracing/");
+        target.tracefs("/sys/kernel/debug/tracing/");
+        assert_eq!(find_root(&target).ok(), Some("/sys/kernel/tracing/"));
+    }
+
+    #[test]
+    fn debugfs_mount_point_is_the_fallback() {
+        let target = Local::new("probe-debugfs");
+        target.tracefs("/sys/kernel/debug/tracing/");
+        assert_eq!(find_root(&target).ok(), Some("/sys/kernel/debug/tracing/"));
+    }
+
+    #[test]
+    fn missing_tracefs_fails_before_any_write() {
+        let target = Local::new("probe-none");
+        match capture(&target, &options(), &|| false) {
+            Err(RemoteError::Tracing(e)) => assert!(e.starts_with("no tracefs found")),
+            _ => panic!("capture without tracefs succeeded"),
+        }
+        assert!(target
+            .commands
+            .borrow()
+            .iter()
+            .all(|command| command.starts_with("if [ -e ")));
+    }
+
+    #[test]
+    fn capture_restores_prior_settings() {
+        let target = Local::new("restore");
+        let root = "/sys/kernel/tracing/";
+        target.tracefs(root);
+        let dump = capture(&target, &options(), &|| false).ok().unwrap();
+        // The dump is read before the trace is cleared.
+        assert_eq!(dump, b"");
+        let setting = |name: &str| target.read(&format!("{}{}", root, name));
+        assert_eq!(setting("buffer_size_kb"), "7\n");
+        assert_eq!(setting("trace_clock"), "mono\n");
+        assert_eq!(setting("options/overwrite"), "0\n");
+        assert_eq!(setting("options/record-cmd"), "1\n");
+        assert_eq!(setting("events/sched/sched_switch/enable"), "0\n");
+        assert_eq!(setting("tracing_on"), "0\n");
+        assert_eq!(setting("trace"), "");
+        assert_eq!(
+            setting("trace_marker"),
+            format!(
+                "{} {}=remote-test\n",
+                parser::METADATA_PREFIX,
+                parser::CAPTURE_ID_KEY
+            )
+        );
+        let commands = target.commands.borrow();
+        assert!(
+            commands.contains(&"echo '4096' > '/sys/kernel/tracing/buffer_size_kb'".to_string())
+        );
+        assert!(commands.contains(&"echo 'global' > '/sys/kernel/tracing/trace_clock'".to_string()));
+    }
+
+    #[test]
+    fn failed_setup_still_restores() {
+        let target = Local::new("failed-setup");
+        let root = "/sys/kernel/tracing/";
+        target.tracefs(root);
+        // A marker file which can't be written fails the setup last.
+        fs::remove_file(target.path("/sys/kernel/tracing/trace_marker")).unwrap();
+        fs::create_dir(target.path("/sys/kernel/tracing/trace_marker")).unwrap();
+        assert!(matches!(
+            capture(&target, &options(), &|| false),
+            Err(RemoteError::Tracing(_))
+        ));
+        assert_eq!(target.read("/sys/kernel/tracing/buffer_size_kb"), "7\n");
+        assert_eq!(target.read("/sys/kernel/tracing/trace_clock"), "mono\n");
+        assert_eq!(target.read("/sys/kernel/tracing/tracing_on"), "0\n");
+    }
+
+    #[test]
+    fn setting_values() {
+        assert_eq!(setting_value("trace_clock", b"[local] global\n"), "local");
+        assert_eq!(setting_value("trace_clock", b"local global"), "");
+        assert_eq!(setting_value("buffer_size_kb", b"1408\n"), "1408");
+        assert_eq!(
+            setting_value("buffer_size_kb", b"7 (expanded: 1408)\n"),
+            "7"
+        );
+        assert_eq!(setting_value("options/overwrite", b""), "");
+    }
+
+    #[test]
+    fn values_are_quoted_for_the_shell() {
+        let target = Local::new("quote");
+        target.write("/sys/kernel/tracing/trace_marker", "");
+        target
+            .write_file("/sys/kernel/tracing/trace_marker", "it's $HOME `x`")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            target.read("/sys/kernel/tracing/trace_marker"),
+            "it's $HOME `x`\n"
+        );
+    }
+}
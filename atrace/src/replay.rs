@@ -0,0 +1,313 @@
+// Replay of the markers of a captured trace into the live trace_marker, for
+// developing viewers and exercising the daemon and trigger modes with
+// realistic content and load.
+//
+// Only tracing_mark_write payloads are replayed, kernel events and the clock
+// syncs of the original capture are skipped. Each marker is due at its
+// original offset from the first marker divided by the speed, and waits are
+// measured from the replay start rather than from the previous marker, so
+// late writes don't add up.
+
+use std::io::{self, Write};
+
+use crate::input::{decode_line, split_lines};
+use crate::parser::{is_clock_sync, parse_line};
+
+// Longest single sleep, so an interrupt is noticed quickly.
+const MAX_SLEEP: f64 = 0.1;
+
+pub struct Marker {
+    // Seconds since the first marker, on the original trace clock.
+    pub offset: f64,
+    pub payload: String,
+}
+
+pub struct Markers {
+    pub markers: Vec<Marker>,
+    // Marker lines cut by the line length limit, which can't be replayed
+    // faithfully.
+    pub cut: usize,
+}
+
+pub fn markers(data: &[u8]) -> Markers {
+    let mut markers = Vec::new();
+    let mut cut = 0;
+    let mut first = None;
+    for raw in split_lines(data) {
+        let decoded = decode_line(raw);
+        let line = match parse_line(&decoded.text) {
+            Some(line) if line.is_marker() && !is_clock_sync(line.payload) => line,
+            _ => continue,
+        };
+        if decoded.cut {
+            cut += 1;
+            continue;
+        }
+        let first = *first.get_or_insert(line.timestamp);
+        markers.push(Marker {
+            // A clock going back is replayed without waiting.
+            offset: (line.timestamp - first).max(0.0),
+            payload: line.payload.to_string(),
+        });
+    }
+    Markers { markers, cut }
+}
+
+pub struct Stats {
+    pub replayed: usize,
+    // The latest a marker was written after it was due, in seconds.
+    pub max_late: f64,
+    pub interrupted: bool,
+}
+
+// Write the markers to out at their due times. The clock gives the seconds
+// since the replay start, sleep waits, and abort is checked between waits.
+pub fn replay(
+    markers: &[Marker],
+    speed: f64,
+    out: &mut dyn Write,
+    now: &dyn Fn() -> f64,
+    sleep: &dyn Fn(f64),
+    abort: &dyn Fn() -> bool,
+) -> io::Result<Stats> {
+    let mut stats = Stats {
+        replayed: 0,
+        max_late: 0.0,
+        interrupted: false,
+    };
+    for marker in markers.iter() {
+        let due = marker.offset / speed;
+        loop {
+            if abort() {
+                stats.interrupted = true;
+                return Ok(stats);
+            }
+            let wait = due - now();
+            if wait <= 0.0 {
+                break;
+            }
+            sleep(wait.min(MAX_SLEEP));
+        }
+        // One write per marker, trace_marker takes each write as a record.
+        let line = format!("{}\n", marker.payload);
+        if out.write(line.as_bytes())? != line.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "marker written partially",
+            ));
+        }
+        stats.replayed += 1;
+        stats.max_late = stats.max_late.max(now() - due);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::MAX_LINE_BYTES;
+    use std::cell::{Cell, RefCell};
+
+    fn line(ts: f64, payload: &str) -> String {
+        format!(
+            "  app-1234  ( 1234) [000] ...1  {:.6}: tracing_mark_write: {}\n",
+            ts, payload
+        )
+    }
+
+    fn offsets(markers: &[Marker]) -> Vec<(f64, &str)> {
+        markers
+            .iter()
+            .map(|marker| (marker.offset, marker.payload.as_str()))
+            .collect()
+    }
+
+    // A mock clock: sleeping advances it by the time asked plus an
+    // oversleep, and the sink records when each marker was written.
+    struct Clock {
+        now: Cell<f64>,
+        oversleep: f64,
+        sleeps: Cell<usize>,
+    }
+
+    impl Clock {
+        fn new(oversleep: f64) -> Clock {
+            Clock {
+                now: Cell::new(0.0),
+                oversleep,
+                sleeps: Cell::new(0),
+            }
+        }
+
+        fn sleep(&self, seconds: f64) {
+            assert!(seconds > 0.0 && seconds <= MAX_SLEEP, "{}", seconds);
+            self.sleeps.set(self.sleeps.get() + 1);
+            self.now.set(self.now.get() + seconds + self.oversleep);
+        }
+    }
+
+    struct Sink<'a> {
+        clock: &'a Clock,
+        written: &'a RefCell<Vec<(f64, String)>>,
+        // Bytes accepted per write, all when None.
+        accept: Option<usize>,
+    }
+
+    impl Write for Sink<'_> {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let len = self.accept.unwrap_or(data.len()).min(data.len());
+            self.written.borrow_mut().push((
+                self.clock.now.get(),
+                String::from_utf8_lossy(&data[..len]).into_owned(),
+            ));
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Replay the markers at offsets, aborting once abort_after are written.
+    fn run(
+        offsets: &[f64],
+        speed: f64,
+        clock: &Clock,
+        abort_after: usize,
+    ) -> (Stats, Vec<(f64, String)>) {
+        let markers: Vec<Marker> = offsets
+            .iter()
+            .enumerate()
+            .map(|(index, offset)| Marker {
+                offset: *offset,
+                payload: format!("I|1234|m{}", index),
+            })
+            .collect();
+        let written = RefCell::new(Vec::new());
+        let mut sink = Sink {
+            clock,
+            written: &written,
+            accept: None,
+        };
+        let stats = replay(
+            &markers,
+            speed,
+            &mut sink,
+            &|| clock.now.get(),
+            &|seconds| clock.sleep(seconds),
+            &|| written.borrow().len() >= abort_after,
+        )
+        .unwrap();
+        (stats, written.into_inner())
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn markers_skip_clock_syncs_kernel_events_and_cut_lines() {
+        let data = [
+            "# tracer: nop\n".to_string(),
+            line(5.0, "trace_event_clock_sync: parent_ts=5.0"),
+            line(5.5, "B|1234|draw"),
+            "  <idle>-0  (-----) [001] d..2  5.600000: sched_switch: prev_comm=swapper\n"
+                .to_string(),
+            line(5.7, &format!("I|1234|{}", "x".repeat(MAX_LINE_BYTES))),
+            line(5.75, "E|1234"),
+            // A clock going back is replayed at once.
+            line(5.25, "I|1234|late"),
+        ]
+        .concat();
+        let parsed = markers(data.as_bytes());
+        assert_eq!(parsed.cut, 1);
+        let offsets = offsets(&parsed.markers);
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(offsets[0], (0.0, "B|1234|draw"));
+        assert_close(offsets[1].0, 0.25);
+        assert_eq!(offsets[1].1, "E|1234");
+        assert_eq!(offsets[2], (0.0, "I|1234|late"));
+    }
+
+    #[test]
+    fn due_times_follow_the_speed() {
+        for speed in [1.0, 2.0, 0.5].iter() {
+            let clock = Clock::new(0.0);
+            let (stats, written) = run(&[0.0, 0.25, 1.0, 1.0], *speed, &clock, usize::MAX);
+            assert_eq!(stats.replayed, 4);
+            assert!(!stats.interrupted);
+            assert_close(stats.max_late, 0.0);
+            let times: Vec<f64> = written.iter().map(|(time, _)| *time).collect();
+            for (time, offset) in times.iter().zip([0.0, 0.25, 1.0, 1.0].iter()) {
+                assert_close(*time, offset / speed);
+            }
+            // Waits are cut into short sleeps so an interrupt is noticed.
+            assert!(clock.sleeps.get() as f64 >= 1.0 / speed / MAX_SLEEP);
+        }
+        let clock = Clock::new(0.0);
+        let (_, written) = run(&[0.0, 0.5], 1.0, &clock, usize::MAX);
+        let payloads: Vec<&str> = written.iter().map(|(_, line)| line.as_str()).collect();
+        assert_eq!(payloads, vec!["I|1234|m0\n", "I|1234|m1\n"]);
+    }
+
+    #[test]
+    fn oversleeping_does_not_add_up() {
+        // Every sleep overruns by 40ms. Waiting from the previous marker
+        // would be 400ms late by the tenth, from the start it stays within
+        // one overrun.
+        let clock = Clock::new(0.04);
+        let offsets: Vec<f64> = (0..=10).map(f64::from).collect();
+        let (stats, written) = run(&offsets, 1.0, &clock, usize::MAX);
+        assert_eq!(stats.replayed, 11);
+        assert!(stats.max_late > 0.0 && stats.max_late <= 0.04 + 1e-9);
+        for ((time, _), offset) in written.iter().zip(offsets.iter()) {
+            assert!(
+                *time >= *offset && *time <= offset + 0.04 + 1e-9,
+                "{}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn abort_stops_part_way() {
+        let clock = Clock::new(0.0);
+        let (stats, written) = run(&[0.0, 0.5, 1.0, 1.5], 1.0, &clock, 2);
+        assert!(stats.interrupted);
+        assert_eq!(stats.replayed, 2);
+        assert_eq!(written.len(), 2);
+        // It stopped before waiting for the third marker.
+        assert_close(clock.now.get(), 0.5);
+    }
+
+    #[test]
+    fn partial_writes_are_errors() {
+        let clock = Clock::new(0.0);
+        let written = RefCell::new(Vec::new());
+        let mut sink = Sink {
+            clock: &clock,
+            written: &written,
+            accept: Some(4),
+        };
+        let markers = [Marker {
+            offset: 0.0,
+            payload: "I|1234|long".to_string(),
+        }];
+        let error = replay(
+            &markers,
+            1.0,
+            &mut sink,
+            &|| clock.now.get(),
+            &|seconds| clock.sleep(seconds),
+            &|| false,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+    }
+}
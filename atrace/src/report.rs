@@ -0,0 +1,435 @@
+// Aggregation of the marker summaries of many captures into one report, for
+// soak tests writing a capture per chunk.
+//
+// Captures are loaded one at a time and folded into per-name statistics, so
+// memory stays bounded by one capture plus the histograms. Counts, totals
+// and maxima merge exactly. Percentiles can't be merged from percentiles, so
+// each name keeps a log-scale histogram with 8 buckets per doubling, and the
+// reported percentiles are the upper bound of the bucket holding the rank,
+// capped at the max: at most about 9% above the exact value.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::capture::Capture;
+use crate::convert::json_string;
+use crate::csv::csv_field;
+
+const BUCKETS_PER_DOUBLING: f64 = 8.0;
+
+#[derive(Default, Clone)]
+pub struct Histogram {
+    // Count per bucket, keyed by bucket index.
+    buckets: BTreeMap<u32, u64>,
+}
+
+// Durations are bucketed in nanoseconds, bucket 0 holding 1ns and less.
+fn bucket(duration: f64) -> u32 {
+    let nanos = duration * 1e9;
+    if nanos <= 1.0 {
+        0
+    } else {
+        (nanos.log2() * BUCKETS_PER_DOUBLING).ceil() as u32
+    }
+}
+
+fn bucket_upper(index: u32) -> f64 {
+    2f64.powf(f64::from(index) / BUCKETS_PER_DOUBLING) / 1e9
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: f64) {
+        *self.buckets.entry(bucket(duration)).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (index, count) in other.buckets.iter() {
+            *self.buckets.entry(*index).or_insert(0) += count;
+        }
+    }
+
+    // Nearest-rank percentile, p in 0..=100, as a bucket upper bound.
+    pub fn percentile(&self, p: f64) -> f64 {
+        let total: u64 = self.buckets.values().sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let rank = ((p / 100.0 * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter() {
+            seen += count;
+            if seen >= rank {
+                return bucket_upper(*index);
+            }
+        }
+        0.0
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct NameStats {
+    pub count: u64,
+    // Durations in seconds.
+    pub total: f64,
+    pub max: f64,
+    pub histogram: Histogram,
+}
+
+impl NameStats {
+    pub fn record(&mut self, duration: f64) {
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+        self.histogram.record(duration);
+    }
+
+    pub fn merge(&mut self, other: &NameStats) {
+        self.count += other.count;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+        self.histogram.merge(&other.histogram);
+    }
+
+    // The count-weighted mean over everything merged.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total / self.count as f64
+        }
+    }
+
+    pub fn percentile(&self, p: f64) -> f64 {
+        self.histogram.percentile(p).min(self.max)
+    }
+}
+
+// Per-name statistics of one capture.
+pub fn chunk_stats(capture: &Capture) -> BTreeMap<String, NameStats> {
+    let mut stats: BTreeMap<String, NameStats> = BTreeMap::new();
+    for slice in capture.slices.iter() {
+        if let Some(duration) = capture.report_duration(slice) {
+            stats
+                .entry(slice.name.clone())
+                .or_default()
+                .record(duration);
+        }
+    }
+    stats
+}
+
+pub struct Chunk {
+    pub file: String,
+    pub markers: usize,
+    pub warnings: Vec<String>,
+    // p99 of the trend names found in the chunk.
+    pub trend: Vec<(String, f64)>,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub chunks: Vec<Chunk>,
+    pub names: BTreeMap<String, NameStats>,
+    trend_names: Vec<String>,
+}
+
+impl Report {
+    pub fn new(trend_names: &[String]) -> Report {
+        Report {
+            trend_names: trend_names.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    // Fold one capture into the report.
+    pub fn add(&mut self, file: &str, capture: &Capture) {
+        let stats = chunk_stats(capture);
+        let mut warnings = Vec::new();
+        if capture.lost_events > 0 {
+            warnings.push(format!(
+                "ring buffer overrun, {} events lost",
+                capture.lost_events
+            ));
+        }
+        if capture.unfinished > 0 {
+            warnings.push(format!("{} slices unfinished", capture.unfinished));
+        }
+        if capture.unmatched_ends > 0 {
+            warnings.push(format!("{} unmatched ends", capture.unmatched_ends));
+        }
+        for gap in capture.discontinuities.iter() {
+            warnings.push(gap.describe());
+        }
+        warnings.extend(capture.line_warnings());
+        let trend = self
+            .trend_names
+            .iter()
+            .filter_map(|name| {
+                stats
+                    .get(name)
+                    .map(|stats| (name.clone(), stats.percentile(99.0)))
+            })
+            .collect();
+        for (name, chunk) in stats.iter() {
+            self.names.entry(name.clone()).or_default().merge(chunk);
+        }
+        self.chunks.push(Chunk {
+            file: file.to_string(),
+            markers: capture.markers,
+            warnings,
+            trend,
+        });
+    }
+
+    // Names by total duration, descending.
+    fn sorted_names(&self) -> Vec<(&String, &NameStats)> {
+        let mut names: Vec<(&String, &NameStats)> = self.names.iter().collect();
+        names.sort_by(|a, b| {
+            b.1.total
+                .partial_cmp(&a.1.total)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        names
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{{\"chunks\":[")?;
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            let warnings: Vec<String> = chunk.warnings.iter().map(|w| json_string(w)).collect();
+            writeln!(
+                out,
+                "{{\"file\":{},\"markers\":{},\"warnings\":[{}]}}{}",
+                json_string(&chunk.file),
+                chunk.markers,
+                warnings.join(","),
+                if index + 1 < self.chunks.len() {
+                    ","
+                } else {
+                    ""
+                }
+            )?;
+        }
+        writeln!(out, "],\"names\":[")?;
+        let names = self.sorted_names();
+        for (index, (name, stats)) in names.iter().enumerate() {
+            writeln!(
+                out,
+                "{{\"name\":{},\"count\":{},\"total_ms\":{:.3},\"mean_us\":{:.1},\"p50_us\":{:.1},\"p90_us\":{:.1},\"p99_us\":{:.1},\"max_us\":{:.1}}}{}",
+                json_string(name),
+                stats.count,
+                stats.total * 1e3,
+                stats.mean() * 1e6,
+                stats.percentile(50.0) * 1e6,
+                stats.percentile(90.0) * 1e6,
+                stats.percentile(99.0) * 1e6,
+                stats.max * 1e6,
+                if index + 1 < names.len() { "," } else { "" }
+            )?;
+        }
+        write!(out, "]")?;
+        if !self.trend_names.is_empty() {
+            write!(out, ",\"trend\":{{")?;
+            for (index, name) in self.trend_names.iter().enumerate() {
+                let points: Vec<String> = self
+                    .chunks
+                    .iter()
+                    .filter_map(|chunk| {
+                        chunk
+                            .trend
+                            .iter()
+                            .find(|(trend, _)| trend == name)
+                            .map(|(_, p99)| {
+                                format!(
+                                    "{{\"file\":{},\"p99_us\":{:.1}}}",
+                                    json_string(&chunk.file),
+                                    p99 * 1e6
+                                )
+                            })
+                    })
+                    .collect();
+                write!(
+                    out,
+                    "{}{}:[{}]",
+                    if index > 0 { "," } else { "" },
+                    json_string(name),
+                    points.join(",")
+                )?;
+            }
+            write!(out, "}}")?;
+        }
+        writeln!(out, "}}")?;
+        out.flush()
+    }
+
+    // One table: the merged statistics as "total" rows, then the trend as
+    // "chunk" rows of p99 per file.
+    pub fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "section,name,file,count,total_ms,mean_us,p50_us,p90_us,p99_us,max_us"
+        )?;
+        for (name, stats) in self.sorted_names() {
+            writeln!(
+                out,
+                "total,{},,{},{:.3},{:.1},{:.1},{:.1},{:.1},{:.1}",
+                csv_field(name),
+                stats.count,
+                stats.total * 1e3,
+                stats.mean() * 1e6,
+                stats.percentile(50.0) * 1e6,
+                stats.percentile(90.0) * 1e6,
+                stats.percentile(99.0) * 1e6,
+                stats.max * 1e6
+            )?;
+        }
+        for chunk in self.chunks.iter() {
+            for (name, p99) in chunk.trend.iter() {
+                writeln!(
+                    out,
+                    "chunk,{},{},,,,,,{:.1},",
+                    csv_field(name),
+                    csv_field(&chunk.file),
+                    p99 * 1e6
+                )?;
+            }
+        }
+        out.flush()
+    }
+}
+
+// Match a file name against a glob of "*" and "?" wildcards.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last "*" was and the name position it matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    fn chunk(durations_us: &[f64]) -> NameStats {
+        let mut stats = NameStats::default();
+        for duration in durations_us {
+            stats.record(duration * 1e-6);
+        }
+        stats
+    }
+
+    #[test]
+    fn three_chunks_merge_exactly() {
+        let chunks = [chunk(&[1.0, 2.0]), chunk(&[4.0, 4.0, 4.0]), chunk(&[50.0])];
+        let mut merged = NameStats::default();
+        for stats in chunks.iter() {
+            merged.merge(stats);
+        }
+        assert_eq!(merged.count, 6);
+        assert_close(merged.total, 65e-6);
+        assert_close(merged.max, 50e-6);
+        // Weighted by count, not the 18.5us mean of the chunk means.
+        assert_close(merged.mean(), 65e-6 / 6.0);
+
+        // 1, 2 and 4us fall in the buckets up to 1.024, 2.048 and 4.096us,
+        // 50us in the one up to 50.5us, which is capped at the max.
+        assert_close(merged.percentile(10.0), 1.024e-6);
+        assert_close(merged.percentile(30.0), 2.048e-6);
+        assert_close(merged.percentile(50.0), 4.096e-6);
+        assert_close(merged.percentile(80.0), 4.096e-6);
+        assert_close(merged.percentile(90.0), 50e-6);
+        assert_close(merged.percentile(99.0), 50e-6);
+        assert!(merged.histogram.percentile(99.0) > 50e-6);
+
+        // Merging is the same in any order.
+        let mut reversed = NameStats::default();
+        for stats in chunks.iter().rev() {
+            reversed.merge(stats);
+        }
+        assert_eq!(reversed.count, merged.count);
+        assert_close(reversed.total, merged.total);
+        for p in [10.0, 50.0, 90.0].iter() {
+            assert_close(reversed.percentile(*p), merged.percentile(*p));
+        }
+        assert_eq!(NameStats::default().percentile(50.0), 0.0);
+    }
+
+    fn report() -> Report {
+        let mut report = Report::new(&["draw".to_string()]);
+        report.add("a.txt", &Capture::from_markers(&["B|1234|draw", "E|1234"]));
+        report.add(
+            "b.txt",
+            &Capture::from_markers(&["B|1234|draw", "B|1234|load", "E|1234", "E|1234"]),
+        );
+        report.add(
+            "c.txt",
+            &Capture::from_markers(&["B|1234|load", "E|1234", "E|1234"]),
+        );
+        report
+    }
+
+    #[test]
+    fn json_rows() {
+        let mut out = Vec::new();
+        report().write_json(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"chunks\":[\n\
+             {\"file\":\"a.txt\",\"markers\":2,\"warnings\":[]},\n\
+             {\"file\":\"b.txt\",\"markers\":4,\"warnings\":[]},\n\
+             {\"file\":\"c.txt\",\"markers\":3,\"warnings\":[\"1 unmatched ends\"]}\n\
+             ],\"names\":[\n\
+             {\"name\":\"draw\",\"count\":2,\"total_ms\":4.000,\"mean_us\":2000.0,\"p50_us\":1048.6,\"p90_us\":3000.0,\"p99_us\":3000.0,\"max_us\":3000.0},\n\
+             {\"name\":\"load\",\"count\":2,\"total_ms\":2.000,\"mean_us\":1000.0,\"p50_us\":1000.0,\"p90_us\":1000.0,\"p99_us\":1000.0,\"max_us\":1000.0}\n\
+             ],\"trend\":{\"draw\":[{\"file\":\"a.txt\",\"p99_us\":1000.0},{\"file\":\"b.txt\",\"p99_us\":3000.0}]}}\n"
+        );
+    }
+
+    #[test]
+    fn csv_rows() {
+        let mut out = Vec::new();
+        report().write_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "section,name,file,count,total_ms,mean_us,p50_us,p90_us,p99_us,max_us\n\
+             total,draw,,2,4.000,2000.0,1048.6,3000.0,3000.0,3000.0\n\
+             total,load,,2,2.000,1000.0,1000.0,1000.0,1000.0,1000.0\n\
+             chunk,draw,a.txt,,,,,,1000.0,\n\
+             chunk,draw,b.txt,,,,,,3000.0,\n"
+        );
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("trace-*.z", "trace-0001.z"));
+        assert!(glob_match("trace-?.z", "trace-1.z"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("trace-?.z", "trace-12.z"));
+        assert!(!glob_match("*.z", "trace.txt"));
+    }
+}
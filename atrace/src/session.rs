@@ -0,0 +1,68 @@
+// Session lock of the tracefs directory, so two atrace runs never drive the
+// same kernel buffer at once: a capture, the daemon, the flight recorder, a
+// traced command and a replay each hold it for as long as they run.
+//
+// The lock is a flock on tracing_on, which every tracefs directory has and
+// which can be opened for reading without side effects, unlike trace whose
+// readers pause tracing. The kernel drops it when the process exits however
+// it exits, so a crashed run never leaves a stale lock behind.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+const LOCK_FILE: &str = "tracing_on";
+
+pub struct SessionLock {
+    // Kept open for the lock, None when there was no file to lock.
+    _file: Option<File>,
+}
+
+// Take the lock of the directory, which ends with a slash. Fails with
+// WouldBlock while another run holds it. A directory without tracing_on
+// can't be traced at all, that is left for the setup to report.
+pub fn acquire(root: &str) -> io::Result<SessionLock> {
+    let file = match File::open(format!("{}{}", root, LOCK_FILE)) {
+        Ok(file) => file,
+        Err(_) => return Ok(SessionLock { _file: None }),
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(SessionLock { _file: Some(file) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn root(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("atrace-session-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(LOCK_FILE), "0\n").unwrap();
+        format!("{}/", dir.display())
+    }
+
+    #[test]
+    fn second_session_is_refused_until_the_first_ends() {
+        let root = root("busy");
+        let first = acquire(&root).unwrap();
+        let second = acquire(&root).err().unwrap();
+        assert_eq!(second.kind(), io::ErrorKind::WouldBlock);
+        drop(first);
+        assert!(acquire(&root).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn missing_lock_file_is_no_session() {
+        let root = root("missing");
+        fs::remove_file(format!("{}{}", root, LOCK_FILE)).unwrap();
+        let first = acquire(&root).unwrap();
+        assert!(acquire(&root).is_ok());
+        drop(first);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+// Estimation of the offset between the marker timestamps and the kernel
+// sched events, for devices where a clock setup mistake shifts one against
+// the other.
+//
+// A thread can only write a marker while running, so each marker of a
+// thread with sched_switch data must fall inside one of its on-cpu
+// intervals. A marker outside is a violation, at a signed distance from the
+// nearest interval. The median of the distances estimates the offset, but
+// underestimates it as markers near an interval edge violate by less than
+// the offset, so the estimate is refined on the shifted markers while that
+// removes violations.
+
+use std::collections::BTreeMap;
+
+use crate::capture::Capture;
+
+// Refinement rounds, each one usually halving the remaining offset.
+const MAX_ROUNDS: usize = 16;
+// Violations needed before an offset is reported, fewer are noise like a
+// marker written right at a switch.
+pub const MIN_VIOLATIONS: usize = 10;
+
+pub struct Skew {
+    // Seconds the markers are late against the sched events, negative if
+    // early. Subtracting it from marker timestamps corrects them.
+    pub offset: f64,
+    // Markers of threads with sched data, and how many of them fell outside
+    // the on-cpu intervals before and after correction.
+    pub checked: usize,
+    pub violations: usize,
+    pub remaining: usize,
+}
+
+// The signed distance from t to the nearest on-cpu interval, positive when
+// t is after it, or None when t is inside one. Intervals are sorted.
+pub fn violation(intervals: &[(f64, f64)], t: f64) -> Option<f64> {
+    let next = intervals.partition_point(|(start, _)| *start <= t);
+    let after_prev = match next.checked_sub(1).map(|prev| intervals[prev]) {
+        Some((_, end)) if t <= end => return None,
+        Some((_, end)) => Some(t - end),
+        None => None,
+    };
+    let before_next = intervals.get(next).map(|(start, _)| t - start);
+    match (after_prev, before_next) {
+        (Some(after), Some(before)) if after <= -before => Some(after),
+        (_, Some(before)) => Some(before),
+        (after, None) => after,
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+// The violation distances of the markers, shifted back by offset.
+fn distances(
+    running: &BTreeMap<i32, Vec<(f64, f64)>>,
+    markers: &[(i32, f64)],
+    offset: f64,
+) -> Vec<f64> {
+    markers
+        .iter()
+        .filter_map(|(tid, t)| {
+            running
+                .get(tid)
+                .and_then(|intervals| violation(intervals, t - offset))
+        })
+        .collect()
+}
+
+// Estimate the offset of markers given as (tid, timestamp) against the
+// on-cpu intervals per tid.
+pub fn estimate(running: &BTreeMap<i32, Vec<(f64, f64)>>, markers: &[(i32, f64)]) -> Skew {
+    let checked = markers
+        .iter()
+        .filter(|(tid, _)| running.contains_key(tid))
+        .count();
+    let mut remaining = distances(running, markers, 0.0);
+    let violations = remaining.len();
+    let mut offset = 0.0;
+    for _ in 0..MAX_ROUNDS {
+        if remaining.is_empty() {
+            break;
+        }
+        let step = median(&mut remaining);
+        let shifted = distances(running, markers, offset + step);
+        if step == 0.0 || shifted.len() >= remaining.len() {
+            break;
+        }
+        offset += step;
+        remaining = shifted;
+    }
+    Skew {
+        offset,
+        checked,
+        violations,
+        remaining: remaining.len(),
+    }
+}
+
+// The thread and timestamp of every marker written by a known thread.
+pub fn marker_times(capture: &Capture) -> Vec<(i32, f64)> {
+    let slices = capture.slices.iter().flat_map(|slice| {
+        vec![
+            (slice.tid, slice.start),
+            (slice.tid, slice.start + slice.duration),
+        ]
+    });
+    let counters = capture
+        .counters
+        .iter()
+        .map(|counter| (counter.tid, counter.timestamp));
+    let instants = capture
+        .instants
+        .iter()
+        .map(|instant| (instant.tid, instant.timestamp));
+    slices.chain(counters).chain(instants).collect()
+}
+
+// Shift every marker timestamp back by offset, leaving kernel events and
+// annotations alone.
+pub fn apply(capture: &mut Capture, offset: f64) {
+    for slice in capture.slices.iter_mut() {
+        slice.start -= offset;
+    }
+    for slice in capture.async_slices.iter_mut() {
+        slice.start -= offset;
+    }
+    for counter in capture.counters.iter_mut() {
+        counter.timestamp -= offset;
+    }
+    for instant in capture.instants.iter_mut() {
+        instant.timestamp -= offset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // On cpu for 5ms of every 10ms, from 0 to 1s.
+    fn running() -> BTreeMap<i32, Vec<(f64, f64)>> {
+        let intervals = (0..100)
+            .map(|k| (k as f64 * 0.01, k as f64 * 0.01 + 0.005))
+            .collect();
+        let mut running = BTreeMap::new();
+        running.insert(7, intervals);
+        running
+    }
+
+    // Markers spread over each on-cpu interval, then shifted by offset.
+    fn markers(offset: f64) -> Vec<(i32, f64)> {
+        (0..100)
+            .flat_map(|k| (0..10).map(move |i| k as f64 * 0.01 + 0.0002 + i as f64 * 0.0005))
+            .map(|t| (7, t + offset))
+            .collect()
+    }
+
+    #[test]
+    fn violation_distances() {
+        let intervals = [(1.0, 2.0), (4.0, 5.0)];
+        assert_eq!(violation(&intervals, 1.5), None);
+        assert_eq!(violation(&intervals, 1.0), None);
+        assert_eq!(violation(&intervals, 2.0), None);
+        assert_eq!(violation(&intervals, 0.5), Some(-0.5));
+        assert_eq!(violation(&intervals, 2.5), Some(0.5));
+        assert_eq!(violation(&intervals, 3.5), Some(-0.5));
+        assert_eq!(violation(&intervals, 6.0), Some(1.0));
+        assert_eq!(violation(&[], 1.0), None);
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), 2.5);
+        assert_eq!(median(&mut [-1.0]), -1.0);
+    }
+
+    #[test]
+    fn aligned_markers_have_no_skew() {
+        let skew = estimate(&running(), &markers(0.0));
+        assert_eq!(skew.checked, 1000);
+        assert_eq!(skew.violations, 0);
+        assert_eq!(skew.offset, 0.0);
+    }
+
+    #[test]
+    fn late_markers_are_corrected() {
+        let skew = estimate(&running(), &markers(0.002));
+        assert!(skew.violations >= MIN_VIOLATIONS);
+        assert_eq!(skew.remaining, 0);
+        // Refined past the first median, never past the true offset.
+        assert!(
+            skew.offset > 0.001 && skew.offset <= 0.002 + 1e-9,
+            "{}",
+            skew.offset
+        );
+    }
+
+    #[test]
+    fn early_markers_give_a_negative_offset() {
+        let skew = estimate(&running(), &markers(-0.002));
+        assert!(skew.violations >= MIN_VIOLATIONS);
+        assert_eq!(skew.remaining, 0);
+        assert!(
+            skew.offset < -0.001 && skew.offset >= -0.002 - 1e-9,
+            "{}",
+            skew.offset
+        );
+    }
+
+    #[test]
+    fn markers_of_threads_without_sched_data_are_left_out() {
+        let mut markers = markers(0.002);
+        for marker in markers.iter_mut() {
+            marker.0 = 8;
+        }
+        let skew = estimate(&running(), &markers);
+        assert_eq!(skew.checked, 0);
+        assert_eq!(skew.violations, 0);
+        assert_eq!(skew.offset, 0.0);
+    }
+
+    #[test]
+    fn apply_shifts_markers_only() {
+        let data = [
+            "  app-1234  ( 1234) [000] ...1  10.000000: tracing_mark_write: B|1234|draw",
+            "  app-1234  ( 1234) [000] ...1  10.500000: tracing_mark_write: E|1234",
+            "  app-1234  ( 1234) [000] ...1  10.600000: tracing_mark_write: C|1234|depth|3",
+            "  app-1234  ( 1234) [000] ...1  10.700000: tracing_mark_write: I|1234|tick",
+        ]
+        .join("\n");
+        let mut capture = Capture::from_bytes(data.as_bytes());
+        assert_eq!(marker_times(&capture).len(), 4);
+        apply(&mut capture, 0.25);
+        assert!((capture.slices[0].start - 9.75).abs() < 1e-9);
+        assert!((capture.slices[0].duration - 0.5).abs() < 1e-9);
+        assert!((capture.counters[0].timestamp - 10.35).abs() < 1e-9);
+        assert!((capture.instants[0].timestamp - 10.45).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,231 @@
+// Aggregation of marker slices and kernel function calls into a per-name
+// duration report.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::capture::Capture;
+
+pub struct SummaryEntry {
+    pub name: String,
+    pub count: usize,
+    /// Durations in seconds.
+    pub total: f64,
+    pub max: f64,
+    // Time the owning threads ran on a cpu within the slices, when the
+    // capture has sched_switch events.
+    pub on_cpu: Option<f64>,
+    // Sorted ascending, for percentiles.
+    durations: Vec<f64>,
+}
+
+impl SummaryEntry {
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total / self.count as f64
+    }
+
+    // The share of the wall time spent on a cpu, low for slices waiting on
+    // locks or io.
+    pub fn on_cpu_ratio(&self) -> Option<f64> {
+        match self.on_cpu {
+            Some(on_cpu) if self.total > 0.0 => Some(on_cpu / self.total),
+            _ => None,
+        }
+    }
+
+    // Nearest-rank percentile, p in 0..=100.
+    pub fn percentile(&self, p: f64) -> f64 {
+        if self.durations.is_empty() {
+            return 0.0;
+        }
+        let rank = (p / 100.0 * self.durations.len() as f64).ceil() as usize;
+        let index = rank.max(1).min(self.durations.len()) - 1;
+        self.durations[index]
+    }
+}
+
+/// Per-name statistics, sorted by total duration descending.
+pub struct Summary {
+    pub entries: Vec<SummaryEntry>,
+}
+
+impl Summary {
+    pub fn from_durations<'a, I>(items: I) -> Summary
+    where
+        I: Iterator<Item = (&'a str, f64)>,
+    {
+        let mut by_name: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+        for (name, duration) in items {
+            by_name.entry(name).or_default().push(duration);
+        }
+        let mut entries: Vec<SummaryEntry> = by_name
+            .into_iter()
+            .map(|(name, mut durations)| {
+                durations.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                SummaryEntry {
+                    name: name.to_string(),
+                    count: durations.len(),
+                    total: durations.iter().sum(),
+                    max: durations[durations.len() - 1],
+                    on_cpu: None,
+                    durations,
+                }
+            })
+            .collect();
+        entries.sort_by(|a, b| {
+            b.total
+                .partial_cmp(&a.total)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Summary { entries }
+    }
+
+    // Summary of the userspace marker slices, with their on-cpu time when
+    // the capture has sched_switch events.
+    pub fn markers(capture: &Capture) -> Summary {
+        let mut summary = Summary::from_durations(capture.slices.iter().filter_map(|slice| {
+            capture
+                .report_duration(slice)
+                .map(|duration| (slice.name.as_str(), duration))
+        }));
+        if capture.running.is_empty() {
+            return summary;
+        }
+        let mut on_cpu: BTreeMap<&str, f64> = BTreeMap::new();
+        for slice in capture.slices.iter() {
+            if let Some(time) = capture.report_on_cpu(slice) {
+                *on_cpu.entry(slice.name.as_str()).or_insert(0.0) += time;
+            }
+        }
+        for entry in summary.entries.iter_mut() {
+            entry.on_cpu = Some(on_cpu.get(entry.name.as_str()).copied().unwrap_or(0.0));
+        }
+        summary
+    }
+
+    // Summary of the kernel functions traced with -K.
+    pub fn kernel_calls(capture: &Capture) -> Summary {
+        Summary::from_durations(
+            capture
+                .kernel_calls
+                .iter()
+                .map(|call| (call.name.as_str(), call.duration)),
+        )
+    }
+
+    // Print the top entries by total duration as a table.
+    pub fn print(&self, title: &str, top: usize, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{} ({} names)", title, self.entries.len())?;
+        let on_cpu = self.entries.iter().any(|entry| entry.on_cpu.is_some());
+        write!(
+            out,
+            "{:<40} {:>8} {:>12} {:>10} {:>10} {:>10} {:>10}",
+            "name", "count", "total(ms)", "avg(us)", "p50(us)", "p99(us)", "max(us)"
+        )?;
+        if on_cpu {
+            write!(out, " {:>12} {:>6}", "on-cpu(ms)", "cpu%")?;
+        }
+        writeln!(out)?;
+        for entry in self.entries.iter().take(top) {
+            write!(
+                out,
+                "{:<40} {:>8} {:>12.3} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+                entry.name,
+                entry.count,
+                entry.total * 1_000.0,
+                entry.mean() * 1_000_000.0,
+                entry.percentile(50.0) * 1_000_000.0,
+                entry.percentile(99.0) * 1_000_000.0,
+                entry.max * 1_000_000.0
+            )?;
+            if on_cpu {
+                write!(
+                    out,
+                    " {:>12.3} {:>6.1}",
+                    entry.on_cpu.unwrap_or(0.0) * 1_000.0,
+                    entry.on_cpu_ratio().unwrap_or(0.0) * 100.0
+                )?;
+            }
+            writeln!(out)?;
+        }
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RenderThread runs 100.000-100.004, 100.006-100.013 and from 100.019
+    // on, through a 10s suspend, to 110.024. Its slices are draw over the
+    // first two runs, wait from mid-run to mid-run and suspend across the
+    // gap.
+    const ON_CPU: &[u8] = include_bytes!("../tests/fixtures/on_cpu.txt");
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    fn entry<'a>(summary: &'a Summary, name: &str) -> &'a SummaryEntry {
+        summary
+            .entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .unwrap_or_else(|| panic!("no entry {}", name))
+    }
+
+    #[test]
+    fn on_cpu_time_intersects_the_runs() {
+        let capture = Capture::from_bytes(ON_CPU);
+        assert_eq!(
+            capture.running.get(&1240),
+            Some(&vec![
+                (100.0, 100.004),
+                (100.006, 100.013),
+                (100.019, 110.024)
+            ])
+        );
+        assert_close(capture.on_cpu_time(1240, 100.0, 100.010), 0.008);
+        assert_close(capture.on_cpu_time(1240, 100.012, 100.020), 0.002);
+        assert_close(capture.on_cpu_time(1240, 100.004, 100.006), 0.0);
+        assert_close(capture.on_cpu_time(1234, 100.0, 110.0), 0.0);
+    }
+
+    #[test]
+    fn markers_have_exact_on_cpu_fractions() {
+        let summary = Summary::markers(&Capture::from_bytes(ON_CPU));
+        let draw = entry(&summary, "draw");
+        assert_close(draw.total, 0.010);
+        assert_close(draw.on_cpu.unwrap(), 0.008);
+        assert_close(draw.on_cpu_ratio().unwrap(), 0.8);
+
+        // Starting and ending mid-run counts only the parts inside.
+        let wait = entry(&summary, "wait");
+        assert_close(wait.total, 0.008);
+        assert_close(wait.on_cpu.unwrap(), 0.002);
+        assert_close(wait.on_cpu_ratio().unwrap(), 0.25);
+
+        // The suspend is taken out of both the wall and the on-cpu time.
+        let suspend = entry(&summary, "suspend");
+        assert_close(suspend.total, 0.002);
+        assert_close(suspend.on_cpu.unwrap(), 0.002);
+        assert_close(suspend.on_cpu_ratio().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn markers_without_sched_events_have_no_on_cpu() {
+        let summary = Summary::markers(&Capture::from_markers(&["B|1234|draw", "E|1234"]));
+        let draw = entry(&summary, "draw");
+        assert_close(draw.total, 0.001);
+        assert_eq!(draw.on_cpu, None);
+        assert_eq!(draw.on_cpu_ratio(), None);
+    }
+}
@@ -0,0 +1,415 @@
+// Rendering of a capture into a static SVG image, small enough to share where
+// a full trace can't be opened.
+//
+// Each thread gets a horizontal timeline with its marker slices stacked by
+// nesting depth, and each counter a small sparkline track below them. Only
+// the --window part of the capture is drawn to keep the image manageable.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::capture::Capture;
+
+const WIDTH: f64 = 1200.0;
+const LABEL_WIDTH: f64 = 180.0;
+const ROW_HEIGHT: f64 = 16.0;
+const TRACK_GAP: f64 = 6.0;
+const SPARKLINE_HEIGHT: f64 = 24.0;
+const HEADER_HEIGHT: f64 = 24.0;
+// Average glyph width of the 11px label font, for eliding names.
+const CHAR_WIDTH: f64 = 6.5;
+// Slices narrower than this are drawn without a label.
+const MIN_LABEL_CHARS: usize = 3;
+
+// The drawn time range in seconds from the capture start, end inclusive.
+#[derive(Clone, Copy)]
+pub struct Window {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Window {
+    pub const ALL: Window = Window {
+        start: 0.0,
+        end: f64::INFINITY,
+    };
+
+    // Parse a "start..end" range of durations like 1s..2500ms, either side
+    // may be left out.
+    pub fn parse(value: &str) -> Option<Window> {
+        let mut parts = value.splitn(2, "..");
+        let (start, end) = (parts.next()?.trim(), parts.next()?.trim());
+        let start = if start.is_empty() {
+            0.0
+        } else {
+            crate::cli::parse_duration(start)?
+        };
+        let end = if end.is_empty() {
+            f64::INFINITY
+        } else {
+            crate::cli::parse_duration(end)?
+        };
+        if end <= start {
+            return None;
+        }
+        Some(Window { start, end })
+    }
+}
+
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub name: String,
+    // The name elided to the rect width, None when nothing fits.
+    pub label: Option<String>,
+}
+
+pub struct Track {
+    pub y: f64,
+    pub height: f64,
+    pub title: String,
+}
+
+pub struct Sparkline {
+    pub points: Vec<(f64, f64)>,
+    pub min: i64,
+    pub max: i64,
+}
+
+#[derive(Default)]
+pub struct Layout {
+    pub width: f64,
+    pub height: f64,
+    // Window bounds on the trace clock.
+    pub start: f64,
+    pub end: f64,
+    pub tracks: Vec<Track>,
+    pub rects: Vec<Rect>,
+    pub sparklines: Vec<Sparkline>,
+}
+
+// Cut a name to the given pixel width, marking the cut with an ellipsis.
+pub fn elide(name: &str, width: f64) -> Option<String> {
+    let fits = (width / CHAR_WIDTH).floor() as usize;
+    let len = name.chars().count();
+    if len <= fits {
+        return Some(name.to_string());
+    }
+    if fits < MIN_LABEL_CHARS {
+        return None;
+    }
+    let mut label: String = name.chars().take(fits - 1).collect();
+    label.push('…');
+    Some(label)
+}
+
+// A stable fill color for a name, so the same marker keeps its color across
+// images.
+pub fn color(name: &str) -> String {
+    // FNV-1a, the std hasher isn't stable across releases.
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!(
+        "hsl({},{}%,{}%)",
+        hash % 360,
+        50 + (hash >> 16) % 30,
+        60 + (hash >> 32) % 15
+    )
+}
+
+// Place the slices and counters of the window.
+pub fn layout(capture: &Capture, window: Window) -> Layout {
+    let start = capture.first_timestamp + window.start;
+    let end = (capture.first_timestamp + window.end).min(capture.last_timestamp);
+    let mut layout = Layout {
+        width: WIDTH,
+        start,
+        end,
+        ..Default::default()
+    };
+    let span = end - start;
+    if span <= 0.0 {
+        layout.height = HEADER_HEIGHT;
+        return layout;
+    }
+    let scale = (WIDTH - LABEL_WIDTH) / span;
+    let x = |timestamp: f64| LABEL_WIDTH + (timestamp.max(start).min(end) - start) * scale;
+
+    // Slices of each thread overlapping the window, by pid and tid.
+    let mut threads: BTreeMap<(i32, i32), Vec<usize>> = BTreeMap::new();
+    for (index, slice) in capture.slices.iter().enumerate() {
+        if slice.start <= end && slice.start + slice.duration >= start {
+            threads
+                .entry((slice.pid, slice.tid))
+                .or_default()
+                .push(index);
+        }
+    }
+    let mut y = HEADER_HEIGHT;
+    for ((pid, tid), slices) in threads.iter() {
+        let depth = slices
+            .iter()
+            .map(|index| capture.slices[*index].depth)
+            .max()
+            .unwrap_or(0);
+        let height = f64::from(depth + 1) * ROW_HEIGHT;
+        let comm = capture
+            .threads
+            .get(tid)
+            .map(|thread| thread.comm.as_str())
+            .unwrap_or("");
+        layout.tracks.push(Track {
+            y,
+            height,
+            title: format!("{} {}/{}", comm, pid, tid),
+        });
+        // Nested slices sit one row below their parent, and slices of one
+        // depth never overlap on a thread, so the depth is the row.
+        for index in slices.iter() {
+            let slice = &capture.slices[*index];
+            let left = x(slice.start);
+            let width = (x(slice.start + slice.duration) - left).max(0.5);
+            layout.rects.push(Rect {
+                x: left,
+                y: y + f64::from(slice.depth) * ROW_HEIGHT,
+                width,
+                height: ROW_HEIGHT - 1.0,
+                name: slice.name.clone(),
+                label: elide(&slice.name, width - 4.0),
+            });
+        }
+        y += height + TRACK_GAP;
+    }
+
+    // Counter samples in the window, by pid and name.
+    let mut counters: BTreeMap<(i32, &str), Vec<(f64, i64)>> = BTreeMap::new();
+    for counter in capture.counters.iter() {
+        if counter.timestamp >= start && counter.timestamp <= end {
+            counters
+                .entry((counter.pid, counter.name.as_str()))
+                .or_default()
+                .push((counter.timestamp, counter.value as i64));
+        }
+    }
+    for ((pid, name), samples) in counters.iter() {
+        let min = samples.iter().map(|(_, value)| *value).min().unwrap_or(0);
+        let max = samples.iter().map(|(_, value)| *value).max().unwrap_or(0);
+        let range = (max - min).max(1) as f64;
+        let bottom = y + SPARKLINE_HEIGHT - 2.0;
+        let points = samples
+            .iter()
+            .map(|(timestamp, value)| {
+                let height = (*value - min) as f64 / range * (SPARKLINE_HEIGHT - 4.0);
+                (x(*timestamp), bottom - height)
+            })
+            .collect();
+        layout.tracks.push(Track {
+            y,
+            height: SPARKLINE_HEIGHT,
+            title: format!("{} {}", name, pid),
+        });
+        layout.sparklines.push(Sparkline { points, min, max });
+        y += SPARKLINE_HEIGHT + TRACK_GAP;
+    }
+    layout.height = y;
+    layout
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Write the layout as a standalone SVG document.
+pub fn write_svg(layout: &Layout, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}" font-family="monospace" font-size="11">"#,
+        layout.width, layout.height
+    )?;
+    writeln!(
+        out,
+        r#"<rect width="100%" height="100%" fill="white"/><text x="4" y="16">{:.6}s .. {:.6}s</text>"#,
+        layout.start, layout.end
+    )?;
+    for track in layout.tracks.iter() {
+        writeln!(
+            out,
+            r##"<rect x="0" y="{:.1}" width="{}" height="{:.1}" fill="#f4f4f4"/><text x="4" y="{:.1}">{}</text>"##,
+            track.y,
+            layout.width,
+            track.height,
+            track.y + 12.0,
+            escape(&track.title)
+        )?;
+    }
+    for rect in layout.rects.iter() {
+        write!(
+            out,
+            r#"<g><title>{}</title><rect x="{:.2}" y="{:.1}" width="{:.2}" height="{:.1}" fill="{}"/>"#,
+            escape(&rect.name),
+            rect.x,
+            rect.y,
+            rect.width,
+            rect.height,
+            color(&rect.name)
+        )?;
+        if let Some(label) = rect.label.as_ref() {
+            write!(
+                out,
+                r#"<text x="{:.2}" y="{:.1}">{}</text>"#,
+                rect.x + 2.0,
+                rect.y + 11.0,
+                escape(label)
+            )?;
+        }
+        writeln!(out, "</g>")?;
+    }
+    for sparkline in layout.sparklines.iter() {
+        let points: Vec<String> = sparkline
+            .points
+            .iter()
+            .map(|(x, y)| format!("{:.2},{:.1}", x, y))
+            .collect();
+        writeln!(
+            out,
+            r#"<polyline points="{}" fill="none" stroke="steelblue"><title>{} .. {}</title></polyline>"#,
+            points.join(" "),
+            sparkline.min,
+            sparkline.max
+        )?;
+    }
+    writeln!(out, "</svg>")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/svg.txt");
+
+    fn close(value: f64, expected: f64) -> bool {
+        (value - expected).abs() < 1e-6
+    }
+
+    fn layout_at(window: &str) -> Layout {
+        layout(
+            &Capture::from_bytes(FIXTURE),
+            Window::parse(window).unwrap(),
+        )
+    }
+
+    fn rect<'a>(layout: &'a Layout, name: &str) -> &'a Rect {
+        layout.rects.iter().find(|rect| rect.name == name).unwrap()
+    }
+
+    #[test]
+    fn windows_are_parsed() {
+        let window = Window::parse("1s..2500ms").unwrap();
+        assert_eq!((window.start, window.end), (1.0, 2.5));
+        let window = Window::parse("..500ms").unwrap();
+        assert_eq!((window.start, window.end), (0.0, 0.5));
+        let window = Window::parse("2s..").unwrap();
+        assert_eq!((window.start, window.end), (2.0, f64::INFINITY));
+        for bad in &["", "1s", "2s..1s", "1s..1s", "x..2s"] {
+            assert!(Window::parse(bad).is_none(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn names_are_elided_to_fit() {
+        assert_eq!(elide("draw", 26.0).as_deref(), Some("draw"));
+        assert_eq!(elide("inflate", 26.0).as_deref(), Some("inf…"));
+        assert_eq!(elide("inflate", 19.5).as_deref(), Some("in…"));
+        assert_eq!(elide("inflate", 19.0), None);
+        assert_eq!(elide("ab", 13.0).as_deref(), Some("ab"));
+    }
+
+    #[test]
+    fn colors_are_stable_per_name() {
+        assert_eq!(color("draw"), color("draw"));
+        assert_ne!(color("draw"), color("inflate"));
+        assert!(color("draw").starts_with("hsl("));
+    }
+
+    #[test]
+    fn slices_are_packed_by_thread_and_depth() {
+        let layout = layout(&Capture::from_bytes(FIXTURE), Window::ALL);
+        assert!(close(layout.start, 10.0) && close(layout.end, 11.0));
+        assert_eq!(layout.rects.len(), 3);
+        assert_eq!(layout.tracks.len(), 3);
+        assert_eq!(layout.sparklines.len(), 1);
+
+        // Tracks go by pid and tid, the counters come last.
+        let titles: Vec<&str> = layout
+            .tracks
+            .iter()
+            .map(|track| track.title.as_str())
+            .collect();
+        assert_eq!(
+            titles,
+            vec!["app 1234/1234", "RenderThread 1234/1240", "queue 1234"]
+        );
+        let ys: Vec<f64> = layout.tracks.iter().map(|track| track.y).collect();
+        assert_eq!(ys, vec![24.0, 46.0, 84.0]);
+        assert_eq!(layout.tracks[1].height, 32.0);
+        assert_eq!(layout.height, 114.0);
+
+        let draw = rect(&layout, "draw");
+        assert!(close(draw.x, 180.0) && close(draw.width, 510.0));
+        assert_eq!(draw.y, 46.0);
+        let upload = rect(&layout, "upload <atlas>");
+        assert!(close(upload.x, 282.0) && close(upload.width, 102.0));
+        assert_eq!(upload.y, 62.0);
+        assert_eq!(upload.label.as_deref(), Some("upload <atlas>"));
+        let inflate = rect(&layout, "inflate");
+        assert!(close(inflate.x, 792.0) && close(inflate.width, 408.0));
+        assert_eq!(inflate.y, 24.0);
+
+        let sparkline = &layout.sparklines[0];
+        assert_eq!((sparkline.min, sparkline.max), (2, 6));
+        assert_eq!(sparkline.points.len(), 2);
+        assert!(close(sparkline.points[0].0, 384.0) && close(sparkline.points[0].1, 106.0));
+        assert!(close(sparkline.points[1].0, 996.0) && close(sparkline.points[1].1, 86.0));
+    }
+
+    #[test]
+    fn only_the_window_is_drawn() {
+        let layout = layout(
+            &Capture::from_bytes(FIXTURE),
+            Window::parse("550ms..").unwrap(),
+        );
+        assert!(close(layout.start, 10.55) && close(layout.end, 11.0));
+        let names: Vec<&str> = layout.rects.iter().map(|rect| rect.name.as_str()).collect();
+        assert_eq!(names, vec!["inflate"]);
+        assert_eq!(layout.sparklines[0].points.len(), 1);
+        // A slice starting inside is clamped to the window start.
+        let layout = layout_at("0ms..300ms");
+        let draw = rect(&layout, "draw");
+        assert!(close(draw.x, 180.0) && close(draw.width, 1020.0));
+    }
+
+    #[test]
+    fn svg_has_an_element_per_item() {
+        let mut out = Vec::new();
+        write_svg(
+            &layout(&Capture::from_bytes(FIXTURE), Window::ALL),
+            &mut out,
+        )
+        .unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<g>").count(), 3);
+        assert_eq!(svg.matches("<polyline ").count(), 1);
+        // Background, one per track and one per slice.
+        assert_eq!(svg.matches("<rect ").count(), 1 + 3 + 3);
+        assert!(svg.contains("<title>upload &lt;atlas&gt;</title>"));
+        assert!(!svg.contains("<atlas>"));
+    }
+}
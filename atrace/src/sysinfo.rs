@@ -0,0 +1,242 @@
+// System context gathered at capture start with --system-metadata, which
+// scheduling traces need to be read correctly.
+//
+// Each item is written as an "atrace_metadata: <key>=<value>" marker so it
+// travels inside the trace itself, and the converters copy it into the
+// metadata section of the JSON trace as "system.<key>":
+//
+//   system.kernel_cmdline     contents of /proc/cmdline
+//   system.preempt            preemption model, from debugfs sched/preempt
+//   system.sched_features     scheduler features, from debugfs sched/features
+//   system.cpu_online         online cpu list, like "0-7"
+//   system.cpufreq_governors  "cpu0=schedutil,cpu1=schedutil,..."
+//
+// Items which can't be read are left out.
+
+use std::fs;
+use std::path::Path;
+
+// Markers larger than this may be cut by the kernel, keep values below.
+const MAX_VALUE_LEN: usize = 900;
+
+fn read_item(root: &Path, path: &str) -> Option<String> {
+    let value = fs::read_to_string(root.join(path)).ok()?;
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+pub fn kernel_cmdline(root: &Path) -> Option<String> {
+    read_item(root, "proc/cmdline")
+}
+
+// The selected model is shown in parentheses, like "none (voluntary) full".
+pub fn preempt(root: &Path) -> Option<String> {
+    let models = read_item(root, "sys/kernel/debug/sched/preempt")?;
+    models
+        .split_whitespace()
+        .find(|model| model.starts_with('('))
+        .map(|model| model.trim_matches(|c| c == '(' || c == ')').to_string())
+}
+
+pub fn sched_features(root: &Path) -> Option<String> {
+    // Kernels before 5.13 keep the file at the debugfs top.
+    read_item(root, "sys/kernel/debug/sched/features")
+        .or_else(|| read_item(root, "sys/kernel/debug/sched_features"))
+}
+
+pub fn cpu_online(root: &Path) -> Option<String> {
+    read_item(root, "sys/devices/system/cpu/online")
+}
+
+pub fn cpufreq_governors(root: &Path) -> Option<String> {
+    let mut cpus: Vec<u32> = fs::read_dir(root.join("sys/devices/system/cpu"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("cpu"))
+                .and_then(|cpu| cpu.parse().ok())
+        })
+        .collect();
+    cpus.sort();
+    let governors: Vec<String> = cpus
+        .iter()
+        .filter_map(|cpu| {
+            let path = format!("sys/devices/system/cpu/cpu{}/cpufreq/scaling_governor", cpu);
+            read_item(root, &path).map(|governor| format!("cpu{}={}", cpu, governor))
+        })
+        .collect();
+    if governors.is_empty() {
+        None
+    } else {
+        Some(governors.join(","))
+    }
+}
+
+type Collector = fn(&Path) -> Option<String>;
+
+const COLLECTORS: &[(&str, Collector)] = &[
+    ("kernel_cmdline", kernel_cmdline),
+    ("preempt", preempt),
+    ("sched_features", sched_features),
+    ("cpu_online", cpu_online),
+    ("cpufreq_governors", cpufreq_governors),
+];
+
+// All readable items under the root, "/" on a live system.
+pub fn collect(root: &Path) -> Vec<(String, String)> {
+    COLLECTORS
+        .iter()
+        .filter_map(|(key, collector)| {
+            collector(root).map(|mut value| {
+                if value.len() > MAX_VALUE_LEN {
+                    let mut end = MAX_VALUE_LEN;
+                    while !value.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    value.truncate(end);
+                }
+                (key.to_string(), value)
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A fake root holding the given files.
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(name: &str, files: &[(&str, &str)]) -> Root {
+            let path = std::env::temp_dir().join(format!(
+                "atrace-sysinfo-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            for (file, contents) in files {
+                let file = path.join(file);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, contents).unwrap();
+            }
+            fs::create_dir_all(&path).unwrap();
+            Root(path)
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn kernel_cmdline_whitespace_is_collapsed() {
+        let root = Root::new("cmdline", &[("proc/cmdline", "console=ttyS0  quiet\n")]);
+        assert_eq!(
+            kernel_cmdline(&root.0).as_deref(),
+            Some("console=ttyS0 quiet")
+        );
+        let empty = Root::new("cmdline-empty", &[("proc/cmdline", "\n")]);
+        assert_eq!(kernel_cmdline(&empty.0), None);
+    }
+
+    #[test]
+    fn preempt_takes_the_selected_model() {
+        let root = Root::new(
+            "preempt",
+            &[("sys/kernel/debug/sched/preempt", "none (voluntary) full\n")],
+        );
+        assert_eq!(preempt(&root.0).as_deref(), Some("voluntary"));
+        let unselected = Root::new(
+            "preempt-none",
+            &[("sys/kernel/debug/sched/preempt", "none voluntary full\n")],
+        );
+        assert_eq!(preempt(&unselected.0), None);
+    }
+
+    #[test]
+    fn sched_features_fall_back_to_the_old_path() {
+        let new = Root::new(
+            "features-new",
+            &[
+                (
+                    "sys/kernel/debug/sched/features",
+                    "GENTLE_FAIR_SLEEPERS NO_HRTICK\n",
+                ),
+                ("sys/kernel/debug/sched_features", "old\n"),
+            ],
+        );
+        assert_eq!(
+            sched_features(&new.0).as_deref(),
+            Some("GENTLE_FAIR_SLEEPERS NO_HRTICK")
+        );
+        let old = Root::new(
+            "features-old",
+            &[("sys/kernel/debug/sched_features", "START_DEBIT\n")],
+        );
+        assert_eq!(sched_features(&old.0).as_deref(), Some("START_DEBIT"));
+    }
+
+    #[test]
+    fn cpufreq_governors_are_listed_by_cpu_number() {
+        let root = Root::new(
+            "governors",
+            &[
+                ("sys/devices/system/cpu/online", "0-2,10\n"),
+                (
+                    "sys/devices/system/cpu/cpu10/cpufreq/scaling_governor",
+                    "performance\n",
+                ),
+                (
+                    "sys/devices/system/cpu/cpu2/cpufreq/scaling_governor",
+                    "schedutil\n",
+                ),
+                (
+                    "sys/devices/system/cpu/cpu0/cpufreq/scaling_governor",
+                    "schedutil\n",
+                ),
+                ("sys/devices/system/cpu/cpu1/online", "0\n"),
+                (
+                    "sys/devices/system/cpu/cpufreq/policy0/scaling_governor",
+                    "schedutil\n",
+                ),
+            ],
+        );
+        assert_eq!(cpu_online(&root.0).as_deref(), Some("0-2,10"));
+        assert_eq!(
+            cpufreq_governors(&root.0).as_deref(),
+            Some("cpu0=schedutil,cpu2=schedutil,cpu10=performance")
+        );
+    }
+
+    #[test]
+    fn unreadable_items_are_left_out() {
+        let root = Root::new("partial", &[("sys/devices/system/cpu/online", "0-3\n")]);
+        assert_eq!(
+            collect(&root.0),
+            vec![("cpu_online".to_string(), "0-3".to_string())]
+        );
+        let missing = root.0.join("missing");
+        assert!(collect(&missing).is_empty());
+    }
+
+    #[test]
+    fn long_values_are_cut_on_a_char_boundary() {
+        let cmdline = format!("x{}", "\u{e9}".repeat(MAX_VALUE_LEN));
+        let root = Root::new("long", &[("proc/cmdline", &cmdline)]);
+        let items = collect(&root.0);
+        assert_eq!(items[0].0, "kernel_cmdline");
+        assert_eq!(items[0].1.len(), MAX_VALUE_LEN - 1);
+    }
+}
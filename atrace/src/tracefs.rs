@@ -0,0 +1,232 @@
+// Selection of the tracefs directory atrace works in.
+//
+// Hardened images may mount tracefs with the top-level files root-only but
+// leave a pre-created instance directory under instances/ writable for the
+// tracing user. The directory is chosen once per run, in this order: the
+// --tracefs flag, the top level when writable, the first writable instance
+// by name. Without any, the top level is kept so its errors are reported.
+
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+pub const DEFAULT_ROOT: &str = "/sys/kernel/debug/tracing/";
+
+// The files a capture must write to, in the top level or an instance.
+const REQUIRED_FILES: &[&str] = &["trace_marker", "tracing_on"];
+
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    Flag,
+    TopLevel,
+    Instance,
+}
+
+#[derive(Debug)]
+pub struct Selection {
+    // Ends with a slash, file names are appended to it.
+    pub root: String,
+    pub source: Source,
+}
+
+fn with_slash(dir: &str) -> String {
+    if dir.ends_with('/') {
+        dir.to_string()
+    } else {
+        format!("{}/", dir)
+    }
+}
+
+fn usable(dir: &Path, writable: &dyn Fn(&Path) -> bool) -> bool {
+    REQUIRED_FILES.iter().all(|name| writable(&dir.join(name)))
+}
+
+// Choose the directory. The writable check is given so the policy can be
+// run over any permission setup.
+pub fn select(explicit: &str, top: &str, writable: &dyn Fn(&Path) -> bool) -> Selection {
+    if !explicit.is_empty() {
+        return Selection {
+            root: with_slash(explicit),
+            source: Source::Flag,
+        };
+    }
+    if usable(Path::new(top), writable) {
+        return Selection {
+            root: with_slash(top),
+            source: Source::TopLevel,
+        };
+    }
+    let mut instances: Vec<_> = fs::read_dir(Path::new(top).join("instances"))
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    instances.sort();
+    match instances.into_iter().find(|dir| usable(dir, writable)) {
+        Some(dir) => Selection {
+            root: with_slash(&dir.to_string_lossy()),
+            source: Source::Instance,
+        },
+        None => Selection {
+            root: with_slash(top),
+            source: Source::TopLevel,
+        },
+    }
+}
+
+// Whether this process may write the file.
+pub fn writable(path: &Path) -> bool {
+    match CString::new(path.to_string_lossy().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+static EXPLICIT: OnceLock<String> = OnceLock::new();
+static ROOT: OnceLock<String> = OnceLock::new();
+
+// Set the --tracefs flag, before the first root() call.
+pub fn configure(explicit: &str) {
+    let _ = EXPLICIT.set(explicit.to_string());
+}
+
+// The selected directory, chosen on first use so the modes which only read
+// trace files never probe tracefs.
+pub fn root() -> &'static str {
+    ROOT.get_or_init(|| {
+        let explicit = EXPLICIT.get().map(String::as_str).unwrap_or("");
+        let selection = select(explicit, DEFAULT_ROOT, &writable);
+        if selection.source == Source::Instance {
+            eprintln!(
+                "notice: {} is not writable, using the tracefs instance {}",
+                DEFAULT_ROOT, selection.root
+            );
+        }
+        selection.root
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A tracefs root with instance directories. Selections are made as if
+    // only the given files, relative to the root, were writable.
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(name: &str, instances: &[&str]) -> Root {
+            let path = std::env::temp_dir().join(format!(
+                "atrace-tracefs-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            for instance in instances {
+                fs::create_dir_all(path.join("instances").join(instance)).unwrap();
+            }
+            Root(path)
+        }
+
+        fn top(&self) -> String {
+            self.0.to_string_lossy().into_owned()
+        }
+
+        fn select(&self, explicit: &str, writable: &[&str]) -> Selection {
+            let writable: Vec<PathBuf> = writable.iter().map(|file| self.0.join(file)).collect();
+            select(explicit, &self.top(), &|path| {
+                writable.iter().any(|file| file == path)
+            })
+        }
+
+        fn instance(&self, name: &str) -> String {
+            format!("{}/instances/{}/", self.top(), name)
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const TOP: &[&str] = &["trace_marker", "tracing_on"];
+
+    #[test]
+    fn flag_wins_over_everything() {
+        let root = Root::new("flag", &["user"]);
+        let selection = root.select("/mnt/tracefs", &["instances/user/trace_marker"]);
+        assert_eq!(selection.source, Source::Flag);
+        assert_eq!(selection.root, "/mnt/tracefs/");
+        let selection = root.select("/mnt/tracefs/", TOP);
+        assert_eq!(selection.source, Source::Flag);
+        assert_eq!(selection.root, "/mnt/tracefs/");
+    }
+
+    #[test]
+    fn writable_top_level_wins_over_instances() {
+        let root = Root::new("top", &["user"]);
+        let selection = root.select(
+            "",
+            &[
+                "trace_marker",
+                "tracing_on",
+                "instances/user/trace_marker",
+                "instances/user/tracing_on",
+            ],
+        );
+        assert_eq!(selection.source, Source::TopLevel);
+        assert_eq!(selection.root, format!("{}/", root.top()));
+    }
+
+    #[test]
+    fn first_writable_instance_by_name() {
+        let root = Root::new("instances", &["c", "a", "b"]);
+        // The top level is only partly writable and instance a lacks
+        // tracing_on, so b is the first usable one.
+        let selection = root.select(
+            "",
+            &[
+                "trace_marker",
+                "instances/a/trace_marker",
+                "instances/b/trace_marker",
+                "instances/b/tracing_on",
+                "instances/c/trace_marker",
+                "instances/c/tracing_on",
+            ],
+        );
+        assert_eq!(selection.source, Source::Instance);
+        assert_eq!(selection.root, root.instance("b"));
+    }
+
+    #[test]
+    fn top_level_is_kept_without_a_usable_instance() {
+        let root = Root::new("none", &["user"]);
+        let selection = root.select("", &["instances/user/tracing_on"]);
+        assert_eq!(selection.source, Source::TopLevel);
+        assert_eq!(selection.root, format!("{}/", root.top()));
+
+        // Nor without an instances directory at all.
+        let root = Root::new("bare", &[]);
+        let selection = root.select("", &["tracing_on"]);
+        assert_eq!(selection.source, Source::TopLevel);
+        assert_eq!(selection.root, format!("{}/", root.top()));
+    }
+
+    #[test]
+    fn writable_checks_this_process() {
+        let root = Root::new("access", &[]);
+        let file = root.0.join("trace_marker");
+        assert!(!writable(&file));
+        fs::write(&file, "").unwrap();
+        assert!(writable(&file));
+        assert!(!writable(Path::new("/nonexistent/trace_marker")));
+        assert!(!writable(Path::new("nul\0byte")));
+    }
+}
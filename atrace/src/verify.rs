@@ -0,0 +1,359 @@
+// Consistency checks of a dumped capture for --verify, so CI fails a broken
+// capture when it is taken rather than when it is read.
+//
+// The trace is scanned once into the facts the checks need, then each check
+// looks at those facts alone and reports what it finds wrong.
+
+use std::collections::BTreeMap;
+
+use crate::input::{decode_line, split_lines};
+use crate::parser::{
+    is_clock_sync, parse_line, parse_lost_events, parse_marker, parse_metadata, Marker,
+};
+
+// Unmatched B/E markers allowed per tid: spans open when the capture started
+// or stopped are cut by it, one per nesting level.
+pub const BALANCE_TOLERANCE: usize = 32;
+
+const ENTRIES_HEADER: &str = "# entries-in-buffer/entries-written:";
+
+pub struct Stream {
+    pub clock_sync: bool,
+    // Marker lines, leaving out the ones atrace writes itself.
+    pub markers: usize,
+    // Per cpu, the times a timestamp went back and the first one it did at.
+    pub backwards: BTreeMap<u32, (usize, f64)>,
+    // Per tid, E markers without a B and B markers left open.
+    pub unbalanced: BTreeMap<i32, (usize, usize)>,
+    // Events dropped in LOST lines, and older events overwritten as the
+    // header counts them.
+    pub lost_events: u64,
+    pub overwritten: u64,
+}
+
+pub fn scan(data: &[u8]) -> Stream {
+    let mut stream = Stream {
+        clock_sync: false,
+        markers: 0,
+        backwards: BTreeMap::new(),
+        unbalanced: BTreeMap::new(),
+        lost_events: 0,
+        overwritten: 0,
+    };
+    let mut last: BTreeMap<u32, f64> = BTreeMap::new();
+    let mut depths: BTreeMap<i32, usize> = BTreeMap::new();
+    for raw in split_lines(data) {
+        let text = decode_line(raw).text;
+        if let Some(lost) = parse_lost_events(&text) {
+            stream.lost_events += lost;
+            continue;
+        }
+        if let Some(counts) = text.trim().strip_prefix(ENTRIES_HEADER) {
+            let mut counts = counts.split_whitespace().next().unwrap_or("").split('/');
+            let in_buffer = counts.next().and_then(|count| count.parse::<u64>().ok());
+            let written = counts.next().and_then(|count| count.parse::<u64>().ok());
+            if let (Some(in_buffer), Some(written)) = (in_buffer, written) {
+                stream.overwritten += written.saturating_sub(in_buffer);
+            }
+            continue;
+        }
+        let line = match parse_line(&text) {
+            Some(line) => line,
+            None => continue,
+        };
+        let previous = last.insert(line.cpu, line.timestamp);
+        if previous.is_some_and(|previous| line.timestamp < previous) {
+            let entry = stream
+                .backwards
+                .entry(line.cpu)
+                .or_insert((0, line.timestamp));
+            entry.0 += 1;
+        }
+        if !line.is_marker() {
+            continue;
+        }
+        if is_clock_sync(line.payload) {
+            stream.clock_sync = true;
+            continue;
+        }
+        if parse_metadata(line.payload).is_some() {
+            continue;
+        }
+        stream.markers += 1;
+        match parse_marker(line.payload) {
+            Some(Marker::Begin { .. }) => *depths.entry(line.tid).or_insert(0) += 1,
+            Some(Marker::End { .. }) => {
+                let depth = depths.entry(line.tid).or_insert(0);
+                if *depth > 0 {
+                    *depth -= 1;
+                } else {
+                    stream.unbalanced.entry(line.tid).or_insert((0, 0)).0 += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    for (tid, depth) in depths {
+        if depth > 0 {
+            stream.unbalanced.entry(tid).or_insert((0, 0)).1 = depth;
+        }
+    }
+    stream
+}
+
+pub struct Violation {
+    // The name of the failed check, stable for CI to match on.
+    pub check: &'static str,
+    pub detail: String,
+}
+
+fn violation(check: &'static str, detail: String) -> Violation {
+    Violation { check, detail }
+}
+
+pub fn check_clock_sync(stream: &Stream) -> Vec<Violation> {
+    if stream.clock_sync {
+        return Vec::new();
+    }
+    vec![violation(
+        "clock_sync",
+        "no clock sync marker, the trace can't be aligned with other clocks".to_string(),
+    )]
+}
+
+pub fn check_monotonic(stream: &Stream) -> Vec<Violation> {
+    stream
+        .backwards
+        .iter()
+        .map(|(cpu, (count, first))| {
+            violation(
+                "monotonic",
+                format!(
+                    "cpu {} timestamps went back {} times, first at {:.6}",
+                    cpu, count, first
+                ),
+            )
+        })
+        .collect()
+}
+
+pub fn check_balance(stream: &Stream, tolerance: usize) -> Vec<Violation> {
+    stream
+        .unbalanced
+        .iter()
+        .filter(|(_, (ends, begins))| ends + begins > tolerance)
+        .map(|(tid, (ends, begins))| {
+            violation(
+                "balance",
+                format!(
+                    "tid {} has {} E markers without a B and {} B markers left open",
+                    tid, ends, begins
+                ),
+            )
+        })
+        .collect()
+}
+
+// Expected is the least number of markers, 0 for no check.
+pub fn check_markers(stream: &Stream, expected: usize) -> Vec<Violation> {
+    if stream.markers >= expected {
+        return Vec::new();
+    }
+    vec![violation(
+        "markers",
+        format!(
+            "{} tracing_mark_write lines, expected at least {}",
+            stream.markers, expected
+        ),
+    )]
+}
+
+pub fn check_overruns(stream: &Stream) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if stream.lost_events > 0 {
+        violations.push(violation(
+            "overruns",
+            format!("{} events lost to buffer overruns", stream.lost_events),
+        ));
+    }
+    if stream.overwritten > 0 {
+        violations.push(violation(
+            "overruns",
+            format!("{} events overwritten by newer ones", stream.overwritten),
+        ));
+    }
+    violations
+}
+
+pub struct Expect {
+    pub markers: usize,
+    pub allow_overruns: bool,
+}
+
+// Run every check, giving all the violations found.
+pub fn verify(stream: &Stream, expect: &Expect) -> Vec<Violation> {
+    let mut violations = check_clock_sync(stream);
+    violations.extend(check_monotonic(stream));
+    violations.extend(check_balance(stream, BALANCE_TOLERANCE));
+    violations.extend(check_markers(stream, expect.markers));
+    if !expect.allow_overruns {
+        violations.extend(check_overruns(stream));
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(cpu: u32, tid: i32, ts: f64, payload: &str) -> String {
+        format!(
+            "  app-{}  ( 1000) [{:03}] ...1  {:.6}: tracing_mark_write: {}\n",
+            tid, cpu, ts, payload
+        )
+    }
+
+    fn scan_lines(lines: &[String]) -> Stream {
+        scan(lines.concat().as_bytes())
+    }
+
+    fn checks(violations: &[Violation]) -> Vec<&'static str> {
+        violations.iter().map(|violation| violation.check).collect()
+    }
+
+    fn good() -> Vec<String> {
+        vec![
+            "# tracer: nop\n".to_string(),
+            line(0, 1, 1.0, "trace_event_clock_sync: parent_ts=1.0"),
+            line(0, 1, 1.1, "atrace_metadata: capture_id=test"),
+            line(0, 1, 1.2, "B|1000|draw"),
+            line(1, 2, 1.25, "C|1000|queue|3"),
+            line(0, 1, 1.3, "E|1000"),
+        ]
+    }
+
+    #[test]
+    fn scan_counts_the_facts() {
+        let stream = scan_lines(&good());
+        assert!(stream.clock_sync);
+        // The clock sync and metadata markers are atrace's own.
+        assert_eq!(stream.markers, 3);
+        assert!(stream.backwards.is_empty());
+        assert!(stream.unbalanced.is_empty());
+        assert_eq!((stream.lost_events, stream.overwritten), (0, 0));
+        assert!(verify(
+            &stream,
+            &Expect {
+                markers: 3,
+                allow_overruns: false
+            }
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn missing_clock_sync_is_reported() {
+        let stream = scan_lines(&good()[2..]);
+        assert!(!stream.clock_sync);
+        assert_eq!(checks(&check_clock_sync(&stream)), vec!["clock_sync"]);
+    }
+
+    #[test]
+    fn backwards_timestamps_are_reported_per_cpu() {
+        let lines = vec![
+            line(0, 1, 2.0, "I|1000|a"),
+            line(1, 2, 1.0, "I|1000|b"),
+            line(0, 1, 1.5, "I|1000|c"),
+            line(0, 1, 1.7, "I|1000|d"),
+            line(0, 1, 1.6, "I|1000|e"),
+        ];
+        let stream = scan_lines(&lines);
+        assert_eq!(stream.backwards.get(&0), Some(&(2, 1.5)));
+        assert_eq!(stream.backwards.get(&1), None);
+        let violations = check_monotonic(&stream);
+        assert_eq!(checks(&violations), vec!["monotonic"]);
+        assert_eq!(
+            violations[0].detail,
+            "cpu 0 timestamps went back 2 times, first at 1.500000"
+        );
+    }
+
+    #[test]
+    fn unbalanced_markers_are_reported_past_the_tolerance() {
+        let lines = vec![
+            line(0, 1, 1.0, "E|1000"),
+            line(0, 1, 1.1, "B|1000|a"),
+            line(0, 1, 1.2, "B|1000|b"),
+            line(0, 1, 1.3, "E|1000"),
+            line(0, 2, 1.4, "B|1000|c"),
+            line(0, 2, 1.5, "E|1000"),
+        ];
+        let stream = scan_lines(&lines);
+        assert_eq!(stream.unbalanced.get(&1), Some(&(1, 1)));
+        assert_eq!(stream.unbalanced.get(&2), None);
+        assert!(check_balance(&stream, 2).is_empty());
+        let violations = check_balance(&stream, 1);
+        assert_eq!(checks(&violations), vec!["balance"]);
+        assert_eq!(
+            violations[0].detail,
+            "tid 1 has 1 E markers without a B and 1 B markers left open"
+        );
+    }
+
+    #[test]
+    fn too_few_markers_are_reported() {
+        let stream = scan_lines(&good());
+        assert!(check_markers(&stream, 0).is_empty());
+        assert!(check_markers(&stream, 3).is_empty());
+        let violations = check_markers(&stream, 4);
+        assert_eq!(checks(&violations), vec!["markers"]);
+        assert_eq!(
+            violations[0].detail,
+            "3 tracing_mark_write lines, expected at least 4"
+        );
+    }
+
+    #[test]
+    fn overruns_are_reported_unless_allowed() {
+        let mut lines = good();
+        lines.insert(
+            0,
+            "# entries-in-buffer/entries-written: 90/100   #P:2\n".to_string(),
+        );
+        lines.push("CPU:1 [LOST 7 EVENTS]\n".to_string());
+        let stream = scan_lines(&lines);
+        assert_eq!((stream.lost_events, stream.overwritten), (7, 10));
+        assert_eq!(
+            checks(&check_overruns(&stream)),
+            vec!["overruns", "overruns"]
+        );
+
+        let strict = Expect {
+            markers: 0,
+            allow_overruns: false,
+        };
+        assert_eq!(
+            checks(&verify(&stream, &strict)),
+            vec!["overruns", "overruns"]
+        );
+        let allowed = Expect {
+            markers: 0,
+            allow_overruns: true,
+        };
+        assert!(verify(&stream, &allowed).is_empty());
+    }
+
+    #[test]
+    fn verify_lists_every_violation() {
+        let lines = vec![line(0, 1, 2.0, "B|1000|a"), line(0, 1, 1.0, "I|1000|b")];
+        let expect = Expect {
+            markers: 5,
+            allow_overruns: false,
+        };
+        let violations = verify(&scan_lines(&lines), &expect);
+        assert_eq!(
+            checks(&violations),
+            vec!["clock_sync", "monotonic", "markers"]
+        );
+    }
+}
@@ -0,0 +1,254 @@
+// Watch mode: keep a rolling window of the trace_pipe lines and dump it
+// around the lines matching a trigger pattern.
+//
+// The windows are measured on the trace clock of the lines themselves, not
+// the wall clock, so a scripted stream behaves the same as a live one. Lines
+// without a timestamp, like the header comments, take the last one seen.
+
+use regex::Regex;
+use std::collections::VecDeque;
+
+use crate::parser::parse_line;
+
+pub struct WatchOptions {
+    pub pattern: Regex,
+    // Seconds of lines kept before and collected after the trigger line.
+    pub pre: f64,
+    pub post: f64,
+    // Upper bound of the bytes kept before and collected after a trigger.
+    pub max_bytes: usize,
+    // Number of dumps before watching stops, 0 for no limit.
+    pub max_triggers: usize,
+}
+
+// A dump of the lines around one trigger.
+pub struct Dump {
+    pub trigger: String,
+    pub lines: Vec<String>,
+    // Lines dropped to stay under max_bytes.
+    pub dropped: usize,
+}
+
+// A trigger collecting its post window.
+struct Collecting {
+    deadline: f64,
+    bytes: usize,
+    dump: Dump,
+}
+
+pub struct Watcher {
+    options: WatchOptions,
+    window: VecDeque<(f64, String)>,
+    window_bytes: usize,
+    dropped: usize,
+    collecting: Option<Collecting>,
+    last_timestamp: f64,
+    pub triggers: usize,
+}
+
+impl Watcher {
+    pub fn new(options: WatchOptions) -> Watcher {
+        Watcher {
+            options,
+            window: VecDeque::new(),
+            window_bytes: 0,
+            dropped: 0,
+            collecting: None,
+            last_timestamp: 0.0,
+            triggers: 0,
+        }
+    }
+
+    // Whether max_triggers dumps were written.
+    pub fn done(&self) -> bool {
+        self.options.max_triggers > 0 && self.triggers >= self.options.max_triggers
+    }
+
+    // Feed one line, returning a dump when its post window has ended.
+    pub fn push(&mut self, line: &str) -> Option<Dump> {
+        if self.done() {
+            return None;
+        }
+        let ts = match parse_line(line) {
+            Some(trace_line) => trace_line.timestamp,
+            None => self.last_timestamp,
+        };
+        self.last_timestamp = ts;
+
+        let mut finished = None;
+        if let Some(collecting) = self.collecting.as_mut() {
+            if ts <= collecting.deadline {
+                if collecting.bytes + line.len() <= self.options.max_bytes {
+                    collecting.bytes += line.len();
+                    collecting.dump.lines.push(line.to_string());
+                } else {
+                    collecting.dump.dropped += 1;
+                }
+                return None;
+            }
+            // This line is past the post window, it starts the next one.
+            finished = self.finish();
+            if self.done() {
+                return finished;
+            }
+        }
+
+        // The trigger line joins the window too, so the pre window and the
+        // byte bound are both measured up to it.
+        self.window.push_back((ts, line.to_string()));
+        self.window_bytes += line.len();
+        while self.window.len() > 1 {
+            let front_ts = self.window[0].0;
+            if ts - front_ts <= self.options.pre && self.window_bytes <= self.options.max_bytes {
+                break;
+            }
+            // Lines older than the pre window are expected to go, only
+            // count the ones evicted for the byte bound.
+            if ts - front_ts <= self.options.pre {
+                self.dropped += 1;
+            }
+            if let Some((_, front)) = self.window.pop_front() {
+                self.window_bytes -= front.len();
+            }
+        }
+
+        if self.options.pattern.is_match(line) {
+            self.collecting = Some(Collecting {
+                deadline: ts + self.options.post,
+                bytes: self.window_bytes,
+                dump: Dump {
+                    trigger: line.to_string(),
+                    lines: self.window.drain(..).map(|(_, line)| line).collect(),
+                    dropped: self.dropped,
+                },
+            });
+            self.window_bytes = 0;
+            self.dropped = 0;
+        }
+        finished
+    }
+
+    // End the trigger being collected, at the end of the stream or once its
+    // post window is over.
+    pub fn finish(&mut self) -> Option<Dump> {
+        let collecting = self.collecting.take()?;
+        self.triggers += 1;
+        Some(collecting.dump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(pre: f64, post: f64, max_bytes: usize, max_triggers: usize) -> WatchOptions {
+        WatchOptions {
+            pattern: Regex::new("error").unwrap(),
+            pre,
+            post,
+            max_bytes,
+            max_triggers,
+        }
+    }
+
+    // A marker line at the timestamp, all of the same length.
+    fn line(ts: f64, payload: &str) -> String {
+        format!(
+            "  app-1234  ( 1234) [000] ...1 {:10.6}: tracing_mark_write: B|1234|{:5}",
+            ts, payload
+        )
+    }
+
+    // Feed the script, returning the dumps in order and finishing the last.
+    fn run(watcher: &mut Watcher, script: &[String]) -> Vec<Dump> {
+        let mut dumps: Vec<Dump> = script
+            .iter()
+            .filter_map(|line| watcher.push(line))
+            .collect();
+        dumps.extend(watcher.finish());
+        dumps
+    }
+
+    #[test]
+    fn trigger_dumps_the_pre_and_post_windows() {
+        let script = [
+            line(1.0, "old"),
+            line(2.5, "pre"),
+            line(3.0, "error"),
+            line(3.2, "post"),
+            line(3.5, "post"),
+            line(3.6, "after"),
+        ];
+        let mut watcher = Watcher::new(options(1.0, 0.5, 1 << 20, 0));
+        let dumps: Vec<Dump> = script
+            .iter()
+            .filter_map(|line| watcher.push(line))
+            .collect();
+        assert_eq!(dumps.len(), 1);
+        assert_eq!(dumps[0].trigger, script[2]);
+        assert_eq!(dumps[0].lines, &script[1..5]);
+        assert_eq!(dumps[0].dropped, 0);
+        assert_eq!(watcher.triggers, 1);
+        // The line past the post window went back into the rolling window.
+        assert!(watcher.finish().is_none());
+    }
+
+    #[test]
+    fn lines_without_timestamps_take_the_last_one() {
+        let script = vec![
+            line(1.0, "pre"),
+            "# a comment".to_string(),
+            line(5.0, "error"),
+            "# another".to_string(),
+        ];
+        let mut watcher = Watcher::new(options(1.0, 1.0, 1 << 20, 0));
+        let dumps = run(&mut watcher, &script);
+        assert_eq!(dumps.len(), 1);
+        assert_eq!(dumps[0].lines, &script[2..]);
+    }
+
+    #[test]
+    fn windows_are_bounded_by_bytes() {
+        let size = line(0.0, "x").len();
+        let mut script: Vec<String> = (0..4).map(|i| line(1.0 + i as f64 / 10.0, "pre")).collect();
+        script.push(line(2.0, "error"));
+        script.push(line(2.1, "post"));
+        script.push(line(2.2, "post"));
+        let mut watcher = Watcher::new(options(10.0, 10.0, size * 4, 0));
+        let dumps = run(&mut watcher, &script);
+        assert_eq!(dumps.len(), 1);
+        // One pre line goes to fit the trigger, the post lines don't fit.
+        assert_eq!(dumps[0].lines, &script[1..5]);
+        assert_eq!(dumps[0].dropped, 3);
+    }
+
+    #[test]
+    fn watching_resumes_until_max_triggers() {
+        let script = vec![
+            line(1.0, "error"),
+            line(1.1, "post"),
+            line(3.0, "pre"),
+            line(3.5, "error"),
+            line(5.0, "pre"),
+            line(6.0, "error"),
+            line(8.0, "pre"),
+        ];
+        let mut watcher = Watcher::new(options(1.0, 0.5, 1 << 20, 2));
+        let dumps = run(&mut watcher, &script);
+        assert_eq!(dumps.len(), 2);
+        assert_eq!(dumps[0].lines, &script[0..2]);
+        assert_eq!(dumps[1].lines, &script[2..4]);
+        assert!(watcher.done());
+        assert!(watcher.push(&line(9.0, "error")).is_none());
+    }
+
+    #[test]
+    fn open_trigger_is_flushed_at_the_end() {
+        let script = vec![line(1.0, "error"), line(1.1, "post")];
+        let mut watcher = Watcher::new(options(1.0, 10.0, 1 << 20, 0));
+        assert!(script.iter().all(|line| watcher.push(line).is_none()));
+        let dump = watcher.finish().unwrap();
+        assert_eq!(dump.lines, script);
+        assert_eq!(watcher.triggers, 1);
+    }
+}
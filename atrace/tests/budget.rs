@@ -0,0 +1,82 @@
+// --max-output-bytes driving a dump and a convert past a small cap: the
+// output stops cleanly at the cap and the truncation is reported.
+
+mod common;
+
+use common::{markers, run, stderr, stdout, FakeRoot, TempDir};
+
+fn trace() -> String {
+    let lines: Vec<String> = (0..2000).map(|i| format!("I|1234|line {}", i)).collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    markers(&lines)
+}
+
+#[test]
+fn dump_stops_at_the_cap() {
+    let root = FakeRoot::new();
+    root.write("trace", trace());
+    let dir = TempDir::new("budget-dump");
+    let output = common::atrace()
+        .args([
+            "--tracefs",
+            &root.arg(),
+            "--async-dump",
+            "--max-output-bytes",
+            "1000",
+        ])
+        .args(["--verify", "--result", &dir.arg("result.json")])
+        .output()
+        .unwrap();
+    assert_eq!(output.stdout.len(), 1000);
+    assert!(trace().starts_with(&stdout(&output)));
+    assert!(stderr(&output).contains("trace output truncated at 1000 bytes"));
+
+    // The truncation fails --verify and shows in the result.
+    assert_eq!(output.status.code(), Some(5));
+    let result = dir.read("result.json");
+    assert!(
+        result.contains("\"output_bytes\":1000,\"truncated\":true"),
+        "{}",
+        result
+    );
+    assert!(result.contains("{\"check\":\"output\""), "{}", result);
+}
+
+#[test]
+fn dump_under_the_cap_is_whole() {
+    let root = FakeRoot::new();
+    root.write("trace", trace());
+    let output = common::atrace()
+        .args([
+            "--tracefs",
+            &root.arg(),
+            "--async-dump",
+            "--max-output-bytes",
+            "1000000",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), trace());
+    assert!(!stderr(&output).contains("truncated"));
+}
+
+#[test]
+fn convert_stops_at_the_cap() {
+    let dir = TempDir::new("budget-convert");
+    let input = dir.write("trace.txt", trace());
+    let json = dir.arg("trace.json");
+    let output = run(&[
+        "--convert",
+        &input,
+        "--format",
+        "json",
+        "-o",
+        &json,
+        "--max-output-bytes",
+        "5000",
+    ]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("output truncated at --max-output-bytes"));
+    assert_eq!(dir.read("trace.json").len(), 5000);
+}
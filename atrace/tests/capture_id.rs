@@ -0,0 +1,95 @@
+// The capture id of one session, found in the marker written to the trace,
+// the default output file name, the --result JSON and the converted trace.
+
+mod common;
+
+use common::{markers, run, stderr, stdout, FakeRoot, TempDir};
+
+// The id carried by the atrace_metadata marker among the written markers.
+fn marker_id(written: &str) -> String {
+    let id = written
+        .lines()
+        .find_map(|line| line.strip_prefix("atrace_metadata: capture_id="))
+        .unwrap_or_else(|| panic!("no capture id marker in {:?}", written));
+    // The local start time and a random suffix, like 20210601-140312-3fa9c2.
+    let parts: Vec<&str> = id.split('-').collect();
+    assert_eq!(parts.len(), 3, "{}", id);
+    assert!(parts[0].len() == 8 && parts[1].len() == 6, "{}", id);
+    assert!(parts[..2]
+        .iter()
+        .all(|part| part.bytes().all(|b| b.is_ascii_digit())));
+    assert!(parts[2].len() == 6 && parts[2].bytes().all(|b| b.is_ascii_hexdigit()));
+    id.to_string()
+}
+
+#[test]
+fn id_is_shared_by_marker_result_and_conversion() {
+    let root = FakeRoot::new();
+    let mut fifo = root.marker_fifo();
+    let dir = TempDir::new("capture-id");
+    let output = common::atrace()
+        .args(["--tracefs", &root.arg(), "-T", "0"])
+        .args(["--result", &dir.arg("result.json")])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let id = marker_id(&fifo.written());
+
+    let result = dir.read("result.json");
+    assert!(
+        result.starts_with(&format!("{{\"capture_id\":\"{}\",", id)),
+        "{}",
+        result
+    );
+
+    // The marker as the kernel would have recorded it, read back.
+    let trace = dir.write(
+        "trace.txt",
+        markers(&[
+            "trace_event_clock_sync: parent_ts=9000000",
+            &format!("atrace_metadata: capture_id={}", id),
+            "I|1234|work",
+        ]),
+    );
+    let json = dir.arg("trace.json");
+    let output = run(&["--convert", &trace, "--format", "json", "-o", &json]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir
+        .read("trace.json")
+        .contains(&format!("\"capture-id\":\"{}\"", id)));
+    let output = run(&["--status", &trace]);
+    assert!(stdout(&output).contains(&format!("capture id: {}\n", id)));
+}
+
+#[test]
+fn id_names_the_default_output_file() {
+    let root = FakeRoot::new();
+    let mut fifo = root.marker_fifo();
+    let dir = TempDir::new("capture-id-run");
+    let output = common::atrace()
+        .current_dir(dir.path())
+        .args(["--tracefs", &root.arg(), "run", "--", "/bin/true"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let id = marker_id(&fifo.written());
+    let name = format!("atrace-run-true-{}.txt", id);
+    assert!(dir.path().join(&name).exists(), "{}", stdout(&output));
+    assert!(stdout(&output).contains(&format!("trace dumped to {}", name)));
+}
+
+#[test]
+fn each_session_has_its_own_id() {
+    let root = FakeRoot::new();
+    let mut fifo = root.marker_fifo();
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let output = common::atrace()
+            .args(["--tracefs", &root.arg(), "-T", "0"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        ids.push(marker_id(&fifo.written()));
+    }
+    assert_ne!(ids[0], ids[1]);
+}
@@ -0,0 +1,18 @@
+// Marker line fixtures, shared by the command line tests and the unit tests
+// of the crate, which include this file as its fixture module.
+
+// Marker lines as the kernel prints them, written by thread 1234 a
+// millisecond apart from 10s on, one per payload.
+pub fn markers(payloads: &[&str]) -> String {
+    payloads
+        .iter()
+        .enumerate()
+        .map(|(i, payload)| {
+            format!(
+                "  app-1234  ( 1234) [000] ...1  10.{:06}: tracing_mark_write: {}\n",
+                i * 1000,
+                payload
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,222 @@
+// Helpers shared by the command line tests: the atrace binary, scratch
+// directories removed when dropped, a fake tracefs root to point --tracefs
+// at, a minimal zlib writer to build -Z captures with and the marker line
+// fixtures.
+#![allow(dead_code)]
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod fixture;
+
+// Not every test file uses it, like the rest of the helpers.
+#[allow(unused_imports)]
+pub use fixture::markers;
+
+pub fn atrace() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_atrace"))
+}
+
+// Run atrace with the arguments and give its output.
+pub fn run(args: &[&str]) -> Output {
+    atrace().args(args).output().expect("run atrace")
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "atrace-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("create temp dir");
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    pub fn arg(&self, name: &str) -> String {
+        self.join(name).to_string_lossy().into_owned()
+    }
+
+    pub fn write(&self, name: &str, data: impl AsRef<[u8]>) -> String {
+        let path = self.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("create parent dir");
+        }
+        fs::write(&path, data).expect("write temp file");
+        path.to_string_lossy().into_owned()
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.join(name)).unwrap_or_default()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// A tracefs directory as atrace finds it, with plain files standing in for
+// the kernel ones. The trace file holds what a dump reads back.
+pub struct FakeRoot {
+    pub dir: TempDir,
+}
+
+impl FakeRoot {
+    pub fn new() -> FakeRoot {
+        let dir = TempDir::new("tracefs");
+        for (name, content) in &[
+            ("trace", ""),
+            ("trace_marker", ""),
+            ("trace_pipe", ""),
+            ("tracing_on", "0\n"),
+            ("buffer_size_kb", "1408\n"),
+            (
+                "trace_clock",
+                "[local] global counter uptime perf mono boot\n",
+            ),
+            ("current_tracer", "nop\n"),
+            ("set_ftrace_filter", ""),
+            (
+                "available_events",
+                "sched:sched_switch\nsched:sched_wakeup\nirq:irq_handler_entry\n",
+            ),
+            ("options/overwrite", "1\n"),
+            ("options/print-tgid", "0\n"),
+            ("options/record-cmd", "1\n"),
+            ("events/enable", "0\n"),
+            ("events/sched/sched_switch/enable", "0\n"),
+            ("events/sched/sched_wakeup/enable", "0\n"),
+            ("events/irq/irq_handler_entry/enable", "0\n"),
+        ] {
+            dir.write(name, content);
+        }
+        FakeRoot { dir }
+    }
+
+    // The --tracefs argument, with the trailing slash atrace joins names to.
+    pub fn arg(&self) -> String {
+        format!("{}/", self.dir.path().display())
+    }
+
+    pub fn write(&self, name: &str, data: impl AsRef<[u8]>) -> String {
+        self.dir.write(name, data)
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        self.dir.read(name)
+    }
+
+    // Each marker write lands at the start of a plain file, so make
+    // trace_marker a fifo and keep it open to collect all of them.
+    pub fn marker_fifo(&self) -> MarkerFifo {
+        let path = self.dir.join("trace_marker");
+        fs::remove_file(&path).expect("remove trace_marker");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .expect("open trace_marker fifo");
+        MarkerFifo(file)
+    }
+}
+
+pub struct MarkerFifo(File);
+
+impl MarkerFifo {
+    // The markers written since the last call.
+    pub fn written(&mut self) -> String {
+        let mut data = Vec::new();
+        let _ = self.0.read_to_end(&mut data);
+        String::from_utf8(data).expect("utf-8 markers")
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + u32::from(*byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// A zlib stream of stored blocks, valid for any inflater.
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(if blocks.peek().is_none() { 1 } else { 0 });
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// The integrity footer -Z writes after the stream.
+pub fn footer(data: &[u8]) -> Vec<u8> {
+    let mut footer = b"ATRF".to_vec();
+    footer.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    footer.extend_from_slice(&crc32(data).to_le_bytes());
+    footer
+}
+
+// A capture as -Z writes it.
+pub fn compressed(data: &[u8]) -> Vec<u8> {
+    let mut out = zlib(data);
+    out.extend_from_slice(&footer(data));
+    out
+}
@@ -0,0 +1,40 @@
+// --diff over the two fixture captures: the table and the exit code CI
+// gates on.
+
+mod common;
+
+use common::{run, stderr, stdout};
+
+const BEFORE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/diff_before.txt"
+);
+const AFTER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/diff_after.txt");
+
+#[test]
+fn regressions_give_their_exit_code() {
+    let output = run(&["--diff", BEFORE, AFTER, "--strip-suffix", "#[0-9]+$"]);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    let table = stdout(&output);
+    assert!(
+        table.lines().nth(1).unwrap().starts_with("draw "),
+        "{}",
+        table
+    );
+    assert!(table.contains("2 regressions, 1 improvements"), "{}", table);
+}
+
+#[test]
+fn changes_under_the_threshold_pass() {
+    let output = run(&[
+        "--diff",
+        BEFORE,
+        AFTER,
+        "--strip-suffix",
+        "#[0-9]+$",
+        "--diff-min-us",
+        "10000",
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).contains("0 regressions, 0 improvements"));
+}
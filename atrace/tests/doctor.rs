@@ -0,0 +1,89 @@
+// `atrace --doctor` against a fake tracefs root. Linking trace to
+// trace_marker stands in for a kernel delivering the probe marker.
+
+mod common;
+
+use common::{stdout, FakeRoot};
+use std::fs;
+use std::os::unix::fs::symlink;
+
+fn doctor(root: &FakeRoot) -> (i32, String) {
+    let output = common::run(&["--tracefs", &root.arg(), "--doctor"]);
+    (output.status.code().unwrap(), stdout(&output))
+}
+
+// The root with trace showing what trace_marker was written.
+fn delivering() -> FakeRoot {
+    let root = FakeRoot::new();
+    let trace = root.dir.join("trace");
+    fs::remove_file(&trace).unwrap();
+    symlink(root.dir.join("trace_marker"), &trace).unwrap();
+    root
+}
+
+#[test]
+fn delivered_probe_passes() {
+    let root = delivering();
+    root.write("tracing_on", "1\n");
+    let (code, out) = doctor(&root);
+    assert_eq!(code, 0, "{}", out);
+    assert!(
+        out.contains(&format!("tracefs {}: found\n", root.arg())),
+        "{}",
+        out
+    );
+    assert!(out.contains("tracing_on: on\n"), "{}", out);
+    assert!(out.contains("trace_marker write: ok\n"), "{}", out);
+    assert!(
+        out.contains("kernel buffer: probe marker delivered\n"),
+        "{}",
+        out
+    );
+    assert!(root.read("trace").contains("atrace-doctor-probe-"));
+}
+
+#[test]
+fn tracing_off_is_reported() {
+    let root = delivering();
+    let (code, out) = doctor(&root);
+    assert_eq!(code, 0, "{}", out);
+    assert!(
+        out.contains("tracing_on: off, markers are not recorded\n"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn lost_probe_fails() {
+    let root = FakeRoot::new();
+    root.write("tracing_on", "1\n");
+    let (code, out) = doctor(&root);
+    assert_eq!(code, 1, "{}", out);
+    assert!(out.contains("trace_marker write: ok\n"), "{}", out);
+    assert!(
+        out.contains("kernel buffer: probe marker not found\n"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn unreadable_trace_cannot_be_verified() {
+    let root = FakeRoot::new();
+    let trace = root.dir.join("trace");
+    fs::remove_file(&trace).unwrap();
+    fs::create_dir(&trace).unwrap();
+    let (code, out) = doctor(&root);
+    assert_eq!(code, 0, "{}", out);
+    assert!(out.contains("kernel buffer: cannot verify"), "{}", out);
+}
+
+#[test]
+fn unwritable_marker_fails() {
+    let root = FakeRoot::new();
+    fs::remove_file(root.dir.join("trace_marker")).unwrap();
+    let (code, out) = doctor(&root);
+    assert_eq!(code, 1, "{}", out);
+    assert!(out.contains("trace_marker write: fail ("), "{}", out);
+}
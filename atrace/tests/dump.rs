@@ -0,0 +1,103 @@
+// Dumping the trace of a fake tracefs root into pipes and files, covering
+// the sendfile path, its read/write fallback and write errors.
+
+mod common;
+
+use common::{run, stderr, FakeRoot, TempDir};
+use std::fs::OpenOptions;
+use std::process::{Command, Stdio};
+
+// A trace larger than a pipe buffer, so a closed reader is noticed.
+fn trace() -> String {
+    (0..20000)
+        .map(|i| {
+            format!(
+                "  app-1234  ( 1234) [000] ...1  10.{:06}: tracing_mark_write: I|1234|line {}\n",
+                i, i
+            )
+        })
+        .collect()
+}
+
+fn dump(root: &FakeRoot, extra: &[&str]) -> Command {
+    let mut command = common::atrace();
+    command.args(["--tracefs", &root.arg(), "--async-dump"]);
+    command.args(extra);
+    command
+}
+
+#[test]
+fn dump_to_a_pipe() {
+    let root = FakeRoot::new();
+    root.write("trace", trace());
+    let output = dump(&root, &[]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with(&trace()));
+}
+
+#[test]
+fn dump_falls_back_to_read_write() {
+    // sendfile refuses an output opened for appending.
+    let root = FakeRoot::new();
+    root.write("trace", trace());
+    let dir = TempDir::new("dump");
+    dir.write("out.txt", "");
+    let out = OpenOptions::new()
+        .append(true)
+        .open(dir.join("out.txt"))
+        .unwrap();
+    let status = dump(&root, &[]).stdout(out).status().unwrap();
+    assert!(status.success());
+    assert!(dir.read("out.txt").ends_with(&trace()));
+}
+
+#[test]
+fn compressed_dump_reads_back() {
+    let root = FakeRoot::new();
+    root.write("trace", trace());
+    let dir = TempDir::new("dump");
+    dir.write("out.z", "");
+    let out = OpenOptions::new()
+        .write(true)
+        .open(dir.join("out.z"))
+        .unwrap();
+    let status = dump(&root, &["-Z"]).stdout(out).status().unwrap();
+    assert!(status.success());
+    let uncompressed = run(&["--uncompress", &dir.arg("out.z")]);
+    assert!(uncompressed.status.success(), "{}", stderr(&uncompressed));
+    assert_eq!(String::from_utf8_lossy(&uncompressed.stdout), trace());
+}
+
+fn dump_to_closed_pipe(extra: &[&str]) {
+    let root = FakeRoot::new();
+    root.write("trace", trace());
+    let mut child = dump(&root, extra)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    let stderr = stderr(&output);
+    assert!(stderr.contains("dump trace fail"), "{}", stderr);
+}
+
+#[test]
+fn dump_error_exits_non_zero() {
+    dump_to_closed_pipe(&[]);
+}
+
+#[test]
+fn compressed_dump_error_exits_non_zero() {
+    dump_to_closed_pipe(&["-Z"]);
+}
+
+#[test]
+fn missing_trace_file_exits_non_zero() {
+    let root = FakeRoot::new();
+    std::fs::remove_file(root.dir.join("trace")).unwrap();
+    let output = dump(&root, &[]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("open trace fail"));
+}
@@ -0,0 +1,46 @@
+// atrace extract over plain and -Z dumps, written back in their own form.
+
+mod common;
+
+use common::{compressed, markers, run, stderr, TempDir};
+
+fn trace() -> String {
+    // One marker every millisecond from 10s, an outer slice over all of it.
+    let mut payloads = vec!["B|1234|outer".to_string()];
+    payloads.extend((1..999).map(|i| format!("I|1234|tick {}", i)));
+    payloads.push("E|1234".to_string());
+    let payloads: Vec<&str> = payloads.iter().map(String::as_str).collect();
+    markers(&payloads)
+}
+
+#[test]
+fn plain_dump() {
+    let dir = TempDir::new("extract");
+    let input = dir.write("trace.txt", trace());
+    let output = dir.arg("range.txt");
+    let result = run(&[
+        "extract", "--from", "+100ms", "--to", "+199ms", "-o", &output, &input,
+    ]);
+    assert!(result.status.success(), "{}", stderr(&result));
+    assert!(stderr(&result).contains("kept 102 of 1000 lines"));
+    let range = dir.read("range.txt");
+    let lines: Vec<&str> = range.lines().collect();
+    assert!(lines[0].ends_with("B|1234|outer"));
+    assert!(lines[1].ends_with("I|1234|tick 100"));
+    assert!(lines[100].ends_with("I|1234|tick 199"));
+    assert!(lines[101].ends_with("E|1234"));
+}
+
+#[test]
+fn compressed_dump_stays_compressed() {
+    let dir = TempDir::new("extract");
+    let input = dir.write("trace.z", compressed(trace().as_bytes()));
+    let output = dir.arg("range.z");
+    let result = run(&["extract", "--from", "+500ms", "-o", &output, &input]);
+    assert!(result.status.success(), "{}", stderr(&result));
+    let uncompressed = run(&["--uncompress", &output]);
+    assert!(uncompressed.status.success(), "{}", stderr(&uncompressed));
+    let range = String::from_utf8(uncompressed.stdout).unwrap();
+    assert_eq!(range.lines().count(), 501);
+    assert!(range.lines().next().unwrap().ends_with("B|1234|outer"));
+}
@@ -0,0 +1,60 @@
+// `atrace replay` against a fake tracefs root: the markers of a capture end
+// up in trace_marker, without the clock syncs and kernel events around them.
+
+mod common;
+
+use common::{markers, stderr, stdout, FakeRoot, TempDir};
+
+#[test]
+fn replay_writes_only_the_markers() {
+    let root = FakeRoot::new();
+    let dir = TempDir::new("replay");
+    let capture = dir.write(
+        "capture.txt",
+        [
+            "# tracer: nop\n",
+            &markers(&[
+                "trace_event_clock_sync: parent_ts=10.0",
+                "B|1234|draw",
+                "C|1234|queue|3",
+                "E|1234",
+            ]),
+            "  <idle>-0  (-----) [001] d..2  10.004000: sched_switch: prev_comm=swapper\n",
+        ]
+        .concat(),
+    );
+    let output = common::run(&[
+        "--tracefs",
+        &root.arg(),
+        "replay",
+        "--speed",
+        "100x",
+        &capture,
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        root.read("trace_marker"),
+        "B|1234|draw\nC|1234|queue|3\nE|1234\n"
+    );
+    assert!(
+        stdout(&output).starts_with("replayed 3 of 3 markers in "),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn replay_warns_when_tracing_is_off() {
+    let root = FakeRoot::new();
+    root.write("tracing_on", "0\n");
+    let dir = TempDir::new("replay");
+    let capture = dir.write("capture.txt", markers(&["I|1234|tick"]));
+    let output = common::run(&["--tracefs", &root.arg(), "replay", &capture]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("warning: tracing is off"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(root.read("trace_marker"), "I|1234|tick\n");
+}
@@ -0,0 +1,100 @@
+// `atrace run` end to end against a fake tracefs root: the child's exit code
+// is passed on, its run is bracketed by markers and the trace is dumped.
+
+mod common;
+
+use common::{markers, stderr, stdout, FakeRoot, TempDir};
+fn run(root: &FakeRoot, output: &str, command: &[&str]) -> std::process::Output {
+    let tracefs = root.arg();
+    let mut args = vec!["--tracefs", &tracefs, "run", "-o", output, "--"];
+    args.extend_from_slice(command);
+    common::atrace().args(&args).output().unwrap()
+}
+
+#[test]
+fn successful_command_is_traced() {
+    let root = FakeRoot::new();
+    root.write("trace", markers(&["I|1234|cleared"]));
+    let mut fifo = root.marker_fifo();
+    let dir = TempDir::new("run");
+    let output = run(&root, &dir.arg("trace.txt"), &["/bin/true"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("true exited with 0"),
+        "{}",
+        stdout(&output)
+    );
+    // The trace is cleared before the command starts.
+    assert!(dir.path().join("trace.txt").exists());
+    assert!(!dir.read("trace.txt").contains("I|1234|cleared"));
+
+    let written = fifo.written();
+    let start = written.find("|run_start:true\n").unwrap();
+    let end = written.find("|run_end:true exit=0\n").unwrap();
+    assert!(start < end, "{}", written);
+    for counter in &["run_maxrss_kb", "run_utime_us", "run_stime_us"] {
+        assert!(written.contains(&format!("|{}|", counter)), "{}", written);
+    }
+    assert_eq!(root.read("tracing_on").trim(), "0");
+}
+
+#[test]
+fn failing_command_exit_code_is_passed_on() {
+    let root = FakeRoot::new();
+    let mut fifo = root.marker_fifo();
+    let dir = TempDir::new("run-false");
+    let output = run(&root, &dir.arg("trace.txt"), &["/bin/false"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(fifo.written().contains("|run_end:false exit=1\n"));
+
+    let output = run(&root, &dir.arg("trace.txt"), &["/bin/sh", "-c", "exit 3"]);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    assert!(fifo.written().contains("|run_end:sh exit=3\n"));
+}
+
+#[test]
+fn missing_command_is_reported() {
+    let root = FakeRoot::new();
+    let dir = TempDir::new("run-missing");
+    let output = run(&root, &dir.arg("trace.txt"), &["/nonexistent/command"]);
+    assert_eq!(output.status.code(), Some(127));
+    assert!(stdout(&output).contains("run \"/nonexistent/command\" fail"));
+    assert_eq!(root.read("tracing_on").trim(), "0");
+}
+
+#[test]
+fn failed_dump_is_an_error() {
+    let root = FakeRoot::new();
+    let output = run(&root, "/nonexistent/dir/trace.txt", &["/bin/true"]);
+    assert_eq!(output.status.code(), Some(255), "{}", stdout(&output));
+    assert!(stdout(&output).contains("open output file"));
+}
+
+#[test]
+fn signals_are_forwarded_to_the_command() {
+    let root = FakeRoot::new();
+    let mut fifo = root.marker_fifo();
+    let dir = TempDir::new("run-signal");
+    let ready = dir.arg("ready");
+    let tracefs = root.arg();
+    let trace = dir.arg("trace.txt");
+    let script = format!("touch {}; exec sleep 10", ready);
+    let mut child = common::atrace()
+        .args(["--tracefs", &tracefs, "run", "-o", &trace, "--"])
+        .args(["/bin/sh", "-c", &script])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let start = std::time::Instant::now();
+    while !dir.path().join("ready").exists() {
+        assert!(start.elapsed().as_secs() < 10);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(128 + libc::SIGTERM));
+    assert!(start.elapsed().as_secs() < 5);
+    assert!(fifo
+        .written()
+        .contains(&format!("|run_end:sh exit={}\n", 128 + libc::SIGTERM)));
+}
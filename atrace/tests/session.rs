@@ -0,0 +1,77 @@
+// The session lock of a tracefs directory, held here while atrace modes try
+// to drive the same fake root.
+
+mod common;
+
+use common::{markers, stderr, stdout, FakeRoot, TempDir};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+// Hold the lock as another atrace run would.
+fn hold(root: &FakeRoot) -> File {
+    let file = File::open(root.dir.join("tracing_on")).unwrap();
+    assert_eq!(
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+        0
+    );
+    file
+}
+
+fn refused(output: &std::process::Output, action: &str) {
+    assert!(!output.status.success());
+    let message = stdout(output);
+    assert!(
+        message.contains("another atrace session is using")
+            && message.contains(&format!("refusing to {}", action)),
+        "{}{}",
+        message,
+        stderr(output)
+    );
+}
+
+#[test]
+fn capture_is_refused_while_locked() {
+    let root = FakeRoot::new();
+    root.write("trace", markers(&["I|1234|kept"]));
+    let lock = hold(&root);
+    let output = common::atrace()
+        .args(["--tracefs", &root.arg(), "--async-dump"])
+        .output()
+        .unwrap();
+    refused(&output, "capture");
+    assert!(!stdout(&output).contains("I|1234|kept"));
+    drop(lock);
+    let output = common::atrace()
+        .args(["--tracefs", &root.arg(), "--async-dump"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("I|1234|kept"));
+}
+
+#[test]
+fn replay_is_refused_while_locked() {
+    let root = FakeRoot::new();
+    let dir = TempDir::new("session");
+    let capture = dir.write("capture.txt", markers(&["B|1234|draw", "E|1234"]));
+    let replay = || {
+        common::atrace()
+            .args([
+                "--tracefs",
+                &root.arg(),
+                "replay",
+                "--speed",
+                "100x",
+                &capture,
+            ])
+            .output()
+            .unwrap()
+    };
+    let lock = hold(&root);
+    refused(&replay(), "replay into it");
+    assert_eq!(root.read("trace_marker"), "");
+    drop(lock);
+    let output = replay();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(root.read("trace_marker"), "B|1234|draw\nE|1234\n");
+}
@@ -0,0 +1,78 @@
+// --stop-on-full against a fake tracefs root whose per-cpu stats fill up
+// while the capture runs.
+
+mod common;
+
+use common::{stderr, FakeRoot, TempDir};
+use std::time::{Duration, Instant};
+
+fn set_fill(root: &FakeRoot, cpu: u32, bytes: u64) {
+    let dir = format!("per_cpu/cpu{}", cpu);
+    root.write(&format!("{}/buffer_size_kb", dir), "4\n");
+    root.write(
+        &format!("{}/stats", dir),
+        format!("entries: 10\nbytes: {}\n", bytes),
+    );
+}
+
+fn capture(root: &FakeRoot, seconds: &str, result: &str) -> std::process::Command {
+    let mut command = common::atrace();
+    command.args([
+        "--tracefs",
+        &root.arg(),
+        "-T",
+        seconds,
+        "--stop-on-full",
+        "--full-threshold",
+        "80",
+        "--result",
+        result,
+    ]);
+    command
+}
+
+#[test]
+fn capture_stops_when_a_buffer_fills() {
+    let root = FakeRoot::new();
+    set_fill(&root, 0, 512);
+    set_fill(&root, 1, 1024);
+    let dir = TempDir::new("full");
+    let start = Instant::now();
+    let mut child = capture(&root, "10", &dir.arg("result.json"))
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(600));
+    set_fill(&root, 1, 2048);
+    std::thread::sleep(Duration::from_millis(600));
+    set_fill(&root, 1, 3584);
+    let status = child.wait().unwrap();
+    assert!(status.success());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let result = dir.read("result.json");
+    assert!(result.contains("\"duration_s\":10,"), "{}", result);
+    assert!(result.contains("\"stopped_early\":true"), "{}", result);
+    assert!(result.contains("\"reason\":\"buffer_full\""), "{}", result);
+    assert!(
+        result.contains("\"full_cpu\":1,\"fill_percent\":87.5"),
+        "{}",
+        result
+    );
+    assert_eq!(root.read("tracing_on").trim(), "0");
+}
+
+#[test]
+fn capture_runs_its_window_below_the_threshold() {
+    let root = FakeRoot::new();
+    set_fill(&root, 0, 512);
+    let dir = TempDir::new("not-full");
+    let output = capture(&root, "1", &dir.arg("result.json"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let result = dir.read("result.json");
+    assert!(result.contains("\"stopped_early\":false"), "{}", result);
+    assert!(result.contains("\"reason\":\"duration\""), "{}", result);
+    assert!(!result.contains("full_cpu"), "{}", result);
+}
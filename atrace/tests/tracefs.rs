@@ -0,0 +1,24 @@
+// The tracefs directory atrace picks, as --print-marker-path hands it to
+// applications.
+
+mod common;
+
+use common::{stdout, FakeRoot};
+
+#[test]
+fn print_marker_path_follows_the_flag() {
+    let root = FakeRoot::new();
+    let tracefs = root.arg();
+    let output = common::run(&["--tracefs", &tracefs, "--print-marker-path"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), format!("{}trace_marker\n", tracefs));
+
+    // A flag without the trailing slash gets one.
+    let output = common::run(&[
+        "--tracefs",
+        tracefs.trim_end_matches('/'),
+        "--print-marker-path",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), format!("{}trace_marker\n", tracefs));
+}
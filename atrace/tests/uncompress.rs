@@ -0,0 +1,79 @@
+// atrace --uncompress over -Z captures damaged in the ways a copy breaks them.
+
+mod common;
+
+use common::{compressed, markers, run, stderr, stdout, zlib, TempDir};
+
+fn trace() -> String {
+    let payloads: Vec<String> = (0..500).map(|i| format!("C|1234|count|{}", i)).collect();
+    let payloads: Vec<&str> = payloads.iter().map(String::as_str).collect();
+    markers(&payloads)
+}
+
+fn uncompress(dir: &TempDir, data: &[u8], extra: &[&str]) -> std::process::Output {
+    let path = dir.write("trace.z", data);
+    let mut args = vec!["--uncompress", path.as_str()];
+    args.extend_from_slice(extra);
+    run(&args)
+}
+
+#[test]
+fn intact_capture() {
+    let dir = TempDir::new("uncompress");
+    let output = uncompress(&dir, &compressed(trace().as_bytes()), &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), trace());
+}
+
+#[test]
+fn truncated_stream() {
+    let dir = TempDir::new("uncompress");
+    let data = compressed(trace().as_bytes());
+    let output = uncompress(&dir, &data[..data.len() / 2], &[]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("capture truncated at byte"));
+}
+
+#[test]
+fn flipped_byte() {
+    let dir = TempDir::new("uncompress");
+    let mut data = compressed(trace().as_bytes());
+    let middle = data.len() / 2;
+    data[middle] ^= 0x01;
+    let output = uncompress(&dir, &data, &[]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("capture corrupted"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn flipped_byte_salvaged_with_no_verify() {
+    let dir = TempDir::new("uncompress");
+    let mut data = compressed(trace().as_bytes());
+    let last = data.len() - 1;
+    data[last] ^= 0x01;
+    let output = uncompress(&dir, &data, &["--no-verify"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains("continuing with --no-verify"));
+    assert_eq!(stdout(&output), trace());
+}
+
+#[test]
+fn missing_footer() {
+    let dir = TempDir::new("uncompress");
+    let output = uncompress(&dir, &zlib(trace().as_bytes()), &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), trace());
+}
+
+#[test]
+fn partial_footer() {
+    let dir = TempDir::new("uncompress");
+    let data = compressed(trace().as_bytes());
+    let output = uncompress(&dir, &data[..data.len() - 6], &[]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("footer is incomplete"));
+}
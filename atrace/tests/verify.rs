@@ -0,0 +1,64 @@
+// --verify after an async dump of a fake tracefs root: the exit code and the
+// violations written to the --result JSON.
+
+mod common;
+
+use common::{markers, stderr, FakeRoot, TempDir};
+
+fn verify(root: &FakeRoot, result: &str, extra: &[&str]) -> std::process::Output {
+    common::atrace()
+        .args([
+            "--tracefs",
+            &root.arg(),
+            "--async-dump",
+            "--verify",
+            "--result",
+            result,
+        ])
+        .args(extra)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn consistent_capture_passes() {
+    let root = FakeRoot::new();
+    root.write(
+        "trace",
+        markers(&[
+            "trace_event_clock_sync: parent_ts=10.0",
+            "B|1234|draw",
+            "E|1234",
+        ]),
+    );
+    let dir = TempDir::new("verify-pass");
+    let output = verify(&root, &dir.arg("result.json"), &["--expect-markers", "2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let result = dir.read("result.json");
+    assert!(
+        result.contains("\"verify\":{\"passed\":true,\"violations\":[]}"),
+        "{}",
+        result
+    );
+    // No capture window was waited out.
+    assert!(!result.contains("duration_s"), "{}", result);
+}
+
+#[test]
+fn violations_fail_the_capture() {
+    let root = FakeRoot::new();
+    root.write("trace", markers(&["B|1234|draw", "E|1234"]));
+    let dir = TempDir::new("verify-fail");
+    let output = verify(&root, &dir.arg("result.json"), &["--expect-markers", "3"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(stderr(&output).contains("verify failed: clock_sync:"));
+    let result = dir.read("result.json");
+    assert!(result.contains("\"passed\":false"), "{}", result);
+    for check in &["clock_sync", "markers"] {
+        assert!(
+            result.contains(&format!("{{\"check\":\"{}\"", check)),
+            "{}",
+            result
+        );
+    }
+}